@@ -0,0 +1,167 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cpu::CPU;
+
+// about ten seconds of NTSC frames
+pub const DEFAULT_FLUSH_FRAMES: usize = 600;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaveOptions {
+    pub path: PathBuf,
+    // write changes every this many frames, 0 only on shutdown
+    pub flush_frames: usize,
+}
+
+// game.nes -> game.sav
+pub fn sav_path(rom: &Path) -> PathBuf {
+    rom.with_extension("sav")
+}
+
+// Writes next to the target and renames over it, so a crash mid-write
+// leaves the previous save intact.
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, path)
+}
+
+// What gets saved: PRG RAM, or for a disk what games wrote to it as a
+// patch against the loaded image.
+fn contents(cpu: &CPU) -> Vec<u8> {
+    match cpu.mapper.as_ref().and_then(|mapper| mapper.disk_drive()) {
+        Some(drive) => drive.diff(),
+        None => cpu.prg_ram().to_vec(),
+    }
+}
+
+// Copies a .sav into PRG RAM. A missing file is a fresh cartridge, a
+// shorter one fills the start of RAM like other emulators' saves do.
+pub fn load(cpu: &mut CPU, path: &Path) -> Result<bool, String> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(format!("{}: {}", path.display(), e)),
+    };
+    if let Some(drive) = cpu.disk_drive_mut() {
+        drive.apply_diff(&data).map_err(|e| format!("{}: {}", path.display(), e))?;
+        return Ok(true);
+    }
+    let ram = cpu.prg_ram_mut();
+    if data.len() > ram.len() {
+        return Err(format!(
+            "{}: {} bytes, the cartridge has {} bytes of RAM",
+            path.display(),
+            data.len(),
+            ram.len()
+        ));
+    }
+    ram[..data.len()].copy_from_slice(&data);
+    Ok(true)
+}
+
+pub struct Battery {
+    options: SaveOptions,
+    saved: Vec<u8>,
+    frames: usize,
+}
+
+impl Battery {
+    // loads the save, if any, into `cpu`
+    pub fn open(options: &SaveOptions, cpu: &mut CPU) -> Result<Self, String> {
+        load(cpu, &options.path)?;
+        Ok(Battery {
+            options: options.clone(),
+            saved: contents(cpu),
+            frames: 0,
+        })
+    }
+
+    // call once per frame
+    pub fn tick(&mut self, cpu: &CPU) -> io::Result<()> {
+        self.frames += 1;
+        if self.options.flush_frames != 0 && self.frames >= self.options.flush_frames {
+            self.frames = 0;
+            self.flush(cpu)?;
+        }
+        Ok(())
+    }
+
+    // writes only when RAM changed since the last flush
+    pub fn flush(&mut self, cpu: &CPU) -> io::Result<()> {
+        let contents = contents(cpu);
+        if contents == self.saved {
+            return Ok(());
+        }
+        write_atomic(&self.options.path, &contents)?;
+        self.saved = contents;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("nes-battery-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_sav_path() {
+        assert_eq!(sav_path(Path::new("roms/zelda.nes")), PathBuf::from("roms/zelda.sav"));
+    }
+
+    #[test]
+    fn test_flushes_changes_on_interval() {
+        let dir = scratch("interval");
+        let options = SaveOptions {
+            path: dir.join("game.sav"),
+            flush_frames: 3,
+        };
+        let mut cpu = CPU::new();
+        let mut battery = Battery::open(&options, &mut cpu).unwrap();
+
+        // unchanged RAM is never written
+        for _ in 0..3 {
+            battery.tick(&cpu).unwrap();
+        }
+        assert!(!options.path.exists());
+
+        cpu.prg_ram_mut()[0x10] = 0x42;
+        battery.tick(&cpu).unwrap();
+        battery.tick(&cpu).unwrap();
+        assert!(!options.path.exists());
+        battery.tick(&cpu).unwrap();
+        assert_eq!(fs::read(&options.path).unwrap()[0x10], 0x42);
+        assert!(!dir.join("game.sav.tmp").exists());
+
+        let mut fresh = CPU::new();
+        Battery::open(&options, &mut fresh).unwrap();
+        assert_eq!(fresh.prg_ram()[0x10], 0x42);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_rejects_oversized_save() {
+        let dir = scratch("oversized");
+        let path = dir.join("big.sav");
+        fs::write(&path, vec![0; 0x4000]).unwrap();
+        assert!(load(&mut CPU::new(), &path).is_err());
+        assert_eq!(load(&mut CPU::new(), &dir.join("missing.sav")), Ok(false));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+use crate::cpu::{Memory, CPU};
+use crate::headless::{self, Options, Outcome, Until};
+use crate::region::Region;
+use crate::rom::Rom;
+
+// Result protocol used by blargg's test ROMs: $6001-$6003 hold the
+// signature, $6000 the status and $6004 starts a null-terminated message.
+pub const STATUS: u16 = 0x6000;
+pub const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const SIGNATURE_ADDR: u16 = 0x6001;
+const MESSAGE_ADDR: u16 = 0x6004;
+const MESSAGE_MAX_LEN: u16 = 0x1000;
+
+pub const STATUS_RUNNING: u8 = 0x80;
+pub const STATUS_RESET: u8 = 0x81;
+
+// the ROM asks to be reset at least 100ms after writing $81
+pub const RESET_DELAY_FRAMES: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Running,
+    ResetRequested,
+    Done(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Passed,
+    Failed(u8),
+    Timeout,
+    Crashed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestResult {
+    pub verdict: Verdict,
+    pub message: String,
+    pub frames: usize,
+}
+
+pub fn signature_present(cpu: &CPU) -> bool {
+    (0..3).all(|i| cpu.memory_read(SIGNATURE_ADDR + i) == SIGNATURE[i as usize])
+}
+
+// None until the ROM has written the signature
+pub fn status(cpu: &CPU) -> Option<Status> {
+    if !signature_present(cpu) {
+        return None;
+    }
+    Some(match cpu.memory_read(STATUS) {
+        STATUS_RUNNING => Status::Running,
+        STATUS_RESET => Status::ResetRequested,
+        code => Status::Done(code),
+    })
+}
+
+pub fn message(cpu: &CPU) -> String {
+    let bytes: Vec<u8> = (0..MESSAGE_MAX_LEN)
+        .map(|i| cpu.memory_read(MESSAGE_ADDR + i))
+        .take_while(|byte| *byte != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+pub fn run(cpu: &mut CPU, max_frames: usize) -> TestResult {
+    run_in(cpu, max_frames, Region::Ntsc)
+}
+
+pub fn run_in(cpu: &mut CPU, max_frames: usize, region: Region) -> TestResult {
+    let options = Options {
+        frames: max_frames,
+        until: vec![Until::TestStatus],
+        region,
+        wav: None,
+        movie: None,
+        save: None,
+        screenshot: None,
+    };
+    let report = headless::run(cpu, "", &options);
+
+    let verdict = match (report.outcome, report.status) {
+        (Outcome::Reached(Until::TestStatus), Some(0)) => Verdict::Passed,
+        (Outcome::Reached(Until::TestStatus), Some(code)) => Verdict::Failed(code),
+        (Outcome::Halted, _) | (Outcome::Crashed(_), _) => Verdict::Crashed,
+        _ => Verdict::Timeout,
+    };
+
+    TestResult {
+        verdict,
+        message: report.message.unwrap_or_default(),
+        frames: report.frames,
+    }
+}
+
+pub fn run_rom(raw: &[u8], max_frames: usize) -> Result<TestResult, String> {
+    let rom = Rom::new(raw)?;
+    let mut cpu = CPU::new();
+    cpu.load_rom(&rom)?;
+    cpu.reset();
+    Ok(run_in(&mut cpu, max_frames, rom.region))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::test::test_rom;
+
+    fn store(program: &mut Vec<u8>, addr: u16, value: u8) {
+        // LDA #value, STA addr
+        program.extend([0xa9, value, 0x8d, addr as u8, (addr >> 8) as u8]);
+    }
+
+    // writes the signature and message, then the given status codes
+    fn protocol_rom(message: &str, codes: &[u8]) -> Vec<u8> {
+        let mut program = Vec::new();
+        store(&mut program, STATUS, STATUS_RUNNING);
+        for (i, byte) in SIGNATURE.iter().enumerate() {
+            store(&mut program, SIGNATURE_ADDR + i as u16, *byte);
+        }
+        for (i, byte) in message.bytes().chain([0]).enumerate() {
+            store(&mut program, MESSAGE_ADDR + i as u16, byte);
+        }
+        for code in codes {
+            store(&mut program, STATUS, *code);
+        }
+        // JMP to itself
+        let here = 0x8000 + program.len() as u16;
+        program.extend([0x4c, here as u8, (here >> 8) as u8]);
+        test_rom(&program)
+    }
+
+    #[test]
+    fn test_passing_rom() {
+        let result = run_rom(&protocol_rom("\nPassed\n", &[0]), 10).unwrap();
+        assert_eq!(result.verdict, Verdict::Passed);
+        assert_eq!(result.message, "\nPassed\n");
+    }
+
+    #[test]
+    fn test_failing_rom() {
+        let result = run_rom(&protocol_rom("Failed #3", &[3]), 10).unwrap();
+        assert_eq!(result.verdict, Verdict::Failed(3));
+        assert_eq!(result.message, "Failed #3");
+    }
+
+    #[test]
+    fn test_status_without_signature_is_ignored() {
+        let mut program = Vec::new();
+        store(&mut program, STATUS, 0);
+        program.extend([0x4c, 0x05, 0x80]);
+
+        let result = run_rom(&test_rom(&program), 3).unwrap();
+        assert_eq!(result.verdict, Verdict::Timeout);
+    }
+
+    #[test]
+    fn test_reset_request_resets_cpu() {
+        /*
+            LDA $10        ; counts resets
+            BNE done
+            INC $10
+            <signature and $81 to $6000>
+        spin:
+            JMP spin
+        done:
+            <$00 to $6000>
+            JMP done
+        */
+        let mut program = vec![0xa5, 0x10, 0xd0, 0x00, 0xe6, 0x10];
+        store(&mut program, STATUS, STATUS_RUNNING);
+        for (i, byte) in SIGNATURE.iter().enumerate() {
+            store(&mut program, SIGNATURE_ADDR + i as u16, *byte);
+        }
+        store(&mut program, STATUS, STATUS_RESET);
+        let spin = 0x8000 + program.len() as u16;
+        program.extend([0x4c, spin as u8, (spin >> 8) as u8]);
+        program[3] = (program.len() - 4) as u8;
+        let done = 0x8000 + program.len() as u16;
+        store(&mut program, STATUS, 0);
+        program.extend([0x4c, done as u8, (done >> 8) as u8]);
+
+        let result = run_rom(&test_rom(&program), 20).unwrap();
+        assert_eq!(result.verdict, Verdict::Passed);
+        assert!(result.frames > RESET_DELAY_FRAMES);
+    }
+}
@@ -0,0 +1,439 @@
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::cpu::CPU;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl CompareOp {
+    pub fn test(self, lhs: u16, rhs: u16) -> bool {
+        match self {
+            CompareOp::Equal => lhs == rhs,
+            CompareOp::NotEqual => lhs != rhs,
+            CompareOp::Less => lhs < rhs,
+            CompareOp::LessOrEqual => lhs <= rhs,
+            CompareOp::Greater => lhs > rhs,
+            CompareOp::GreaterOrEqual => lhs >= rhs,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    A,
+    X,
+    Y,
+    P,
+    SP,
+    PC,
+}
+
+impl Register {
+    pub fn value(self, cpu: &CPU) -> u16 {
+        match self {
+            Register::A => cpu.register_a as u16,
+            Register::X => cpu.register_x as u16,
+            Register::Y => cpu.register_y as u16,
+            Register::P => cpu.processor_status as u16,
+            Register::SP => cpu.stack_pointer as u16,
+            Register::PC => cpu.program_counter,
+        }
+    }
+}
+
+// Register condition such as `A == $FF && X > 3`.
+// `&&` binds tighter than `||`, parentheses are allowed and numbers are
+// decimal, `$hex` or `0xhex`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    Compare(Register, CompareOp, u16),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+impl Condition {
+    pub fn eval(&self, cpu: &CPU) -> bool {
+        match self {
+            Condition::Compare(register, op, value) => op.test(register.value(cpu), *value),
+            Condition::And(lhs, rhs) => lhs.eval(cpu) && rhs.eval(cpu),
+            Condition::Or(lhs, rhs) => lhs.eval(cpu) || rhs.eval(cpu),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Register(Register),
+    Number(u16),
+    Op(CompareOp),
+    And,
+    Or,
+    Open,
+    Close,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let (token, len) = match (c, next) {
+            ('(', _) => (Token::Open, 1),
+            (')', _) => (Token::Close, 1),
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('=', Some('=')) => (Token::Op(CompareOp::Equal), 2),
+            ('!', Some('=')) => (Token::Op(CompareOp::NotEqual), 2),
+            ('<', Some('=')) => (Token::Op(CompareOp::LessOrEqual), 2),
+            ('>', Some('=')) => (Token::Op(CompareOp::GreaterOrEqual), 2),
+            ('<', _) => (Token::Op(CompareOp::Less), 1),
+            ('>', _) => (Token::Op(CompareOp::Greater), 1),
+            _ if c == '$' || c.is_ascii_alphanumeric() => {
+                let start = i;
+                let mut end = i + 1;
+                while end < chars.len() && chars[end].is_ascii_alphanumeric() {
+                    end += 1;
+                }
+                let word: String = chars[start..end].iter().collect();
+                (parse_word(&word)?, end - start)
+            }
+            _ => return Err(format!("unexpected character '{}' in condition", c)),
+        };
+
+        tokens.push(token);
+        i += len;
+    }
+
+    Ok(tokens)
+}
+
+fn parse_word(word: &str) -> Result<Token, String> {
+    let register = match word.to_ascii_uppercase().as_str() {
+        "A" => Some(Register::A),
+        "X" => Some(Register::X),
+        "Y" => Some(Register::Y),
+        "P" => Some(Register::P),
+        "SP" | "S" => Some(Register::SP),
+        "PC" => Some(Register::PC),
+        _ => None,
+    };
+    if let Some(register) = register {
+        return Ok(Token::Register(register));
+    }
+
+    parse_number(word).map(Token::Number)
+}
+
+pub fn parse_number(word: &str) -> Result<u16, String> {
+    let parsed = if let Some(hex) = word.strip_prefix('$') {
+        u16::from_str_radix(hex, 16)
+    } else if let Some(hex) = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        u16::from_str_radix(hex, 16)
+    } else {
+        word.parse::<u16>()
+    };
+    parsed.map_err(|_| format!("invalid number '{}'", word))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Condition, String> {
+        let mut lhs = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            let rhs = self.and()?;
+            lhs = Condition::Or(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Condition, String> {
+        let mut lhs = self.term()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            let rhs = self.term()?;
+            lhs = Condition::And(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn term(&mut self) -> Result<Condition, String> {
+        match self.next() {
+            Some(Token::Open) => {
+                let inner = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(inner),
+                    _ => Err(String::from("missing ')' in condition")),
+                }
+            }
+            Some(Token::Register(register)) => {
+                let op = match self.next() {
+                    Some(Token::Op(op)) => op,
+                    _ => return Err(format!("expected comparison after {:?}", register)),
+                };
+                match self.next() {
+                    Some(Token::Number(value)) => Ok(Condition::Compare(register, op, value)),
+                    _ => Err(format!("expected number after {:?} {:?}", register, op)),
+                }
+            }
+            Some(token) => Err(format!("unexpected {:?} in condition", token)),
+            None => Err(String::from("unexpected end of condition")),
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(input)?,
+            pos: 0,
+        };
+        let condition = parser.or()?;
+        match parser.peek() {
+            None => Ok(condition),
+            Some(token) => Err(format!("unexpected {:?} in condition", token)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+    pub range: RangeInclusive<u16>,
+    // compared against the byte read, written or the opcode executed
+    pub value: Option<(CompareOp, u8)>,
+    pub condition: Option<Condition>,
+    pub enabled: bool,
+}
+
+impl Breakpoint {
+    pub fn new(range: RangeInclusive<u16>, read: bool, write: bool, execute: bool) -> Self {
+        Breakpoint {
+            read,
+            write,
+            execute,
+            range,
+            value: None,
+            condition: None,
+            enabled: true,
+        }
+    }
+
+    pub fn execute(addr: u16) -> Self {
+        Breakpoint::new(addr..=addr, false, false, true)
+    }
+
+    pub fn read(range: RangeInclusive<u16>) -> Self {
+        Breakpoint::new(range, true, false, false)
+    }
+
+    pub fn write(range: RangeInclusive<u16>) -> Self {
+        Breakpoint::new(range, false, true, false)
+    }
+
+    // breaks before any instruction once the register condition holds
+    pub fn when(condition: Condition) -> Self {
+        Breakpoint::new(0x0000..=0xFFFF, false, false, true).with_condition(condition)
+    }
+
+    pub fn with_value(mut self, op: CompareOp, value: u8) -> Self {
+        self.value = Some((op, value));
+        self
+    }
+
+    pub fn with_condition(mut self, condition: Condition) -> Self {
+        self.condition = Some(condition);
+        self
+    }
+
+    fn matches(&self, access: Access, addr: u16, value: u8, cpu: &CPU) -> bool {
+        let kind = match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        };
+
+        self.enabled
+            && kind
+            && self.range.contains(&addr)
+            && self.value.is_none_or(|(op, expected)| op.test(value as u16, expected as u16))
+            && self.condition.as_ref().is_none_or(|condition| condition.eval(cpu))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakHit {
+    pub id: usize,
+    pub access: Access,
+    pub addr: u16,
+    pub value: u8,
+}
+
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    list: Vec<(usize, Breakpoint)>,
+    next_id: usize,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Breakpoints::default()
+    }
+
+    pub fn add(&mut self, breakpoint: Breakpoint) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.list.push((id, breakpoint));
+        id
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Breakpoint> {
+        let index = self.list.iter().position(|(bp_id, _)| *bp_id == id)?;
+        Some(self.list.remove(index).1)
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Breakpoint> {
+        self.list.iter().find(|(bp_id, _)| *bp_id == id).map(|(_, bp)| bp)
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Breakpoint> {
+        self.list.iter_mut().find(|(bp_id, _)| *bp_id == id).map(|(_, bp)| bp)
+    }
+
+    pub fn set_enabled(&mut self, id: usize, enabled: bool) -> bool {
+        match self.get_mut(id) {
+            Some(bp) => {
+                bp.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Breakpoint)> {
+        self.list.iter().map(|(id, bp)| (*id, bp))
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub fn check(&self, access: Access, addr: u16, value: u8, cpu: &CPU) -> Option<BreakHit> {
+        self.list
+            .iter()
+            .find(|(_, bp)| bp.matches(access, addr, value, cpu))
+            .map(|(id, _)| BreakHit {
+                id: *id,
+                access,
+                addr,
+                value,
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_condition() {
+        let condition: Condition = "A == $FF && X > 3".parse().unwrap();
+        assert_eq!(
+            condition,
+            Condition::And(
+                Box::new(Condition::Compare(Register::A, CompareOp::Equal, 0xff)),
+                Box::new(Condition::Compare(Register::X, CompareOp::Greater, 3)),
+            )
+        );
+    }
+
+    #[test]
+    fn test_condition_precedence_and_parens() {
+        let mut cpu = CPU::new();
+        cpu.register_a = 1;
+        cpu.register_x = 0;
+        cpu.register_y = 0x10;
+
+        let condition: Condition = "a == 1 || x == 1 && y == 0".parse().unwrap();
+        assert!(condition.eval(&cpu));
+
+        let condition: Condition = "(a == 1 || x == 1) && y == 0".parse().unwrap();
+        assert!(!condition.eval(&cpu));
+
+        let condition: Condition = "PC >= 0x8000 || Y != 0x10".parse().unwrap();
+        assert!(!condition.eval(&cpu));
+    }
+
+    #[test]
+    fn test_parse_condition_errors() {
+        assert!("A ==".parse::<Condition>().is_err());
+        assert!("A = 1".parse::<Condition>().is_err());
+        assert!("(A == 1".parse::<Condition>().is_err());
+        assert!("Q == 1".parse::<Condition>().is_err());
+        assert!("A == 1 X".parse::<Condition>().is_err());
+    }
+
+    #[test]
+    fn test_check_matches_access_range_and_value() {
+        let cpu = CPU::new();
+        let mut breakpoints = Breakpoints::new();
+        let id = breakpoints.add(Breakpoint::write(0x0200..=0x02ff).with_value(CompareOp::Equal, 0x42));
+
+        assert_eq!(breakpoints.check(Access::Write, 0x0210, 0x41, &cpu), None);
+        assert_eq!(breakpoints.check(Access::Read, 0x0210, 0x42, &cpu), None);
+        assert_eq!(breakpoints.check(Access::Write, 0x0300, 0x42, &cpu), None);
+        assert_eq!(
+            breakpoints.check(Access::Write, 0x0210, 0x42, &cpu),
+            Some(BreakHit { id, access: Access::Write, addr: 0x0210, value: 0x42 })
+        );
+
+        breakpoints.set_enabled(id, false);
+        assert_eq!(breakpoints.check(Access::Write, 0x0210, 0x42, &cpu), None);
+    }
+}
@@ -0,0 +1,293 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+// Game Genie letters, each stands for a nibble
+const GAME_GENIE_LETTERS: &[u8; 16] = b"APZLGITYEOXUKSVN";
+
+// reads at or above this go to the cartridge
+pub const CARTRIDGE_START: u16 = 0x4020;
+// the console's 2 KiB of RAM
+const RAM_END: u16 = 0x800;
+// where the PPU registers start, the RAM mirrors sit between
+const REGISTERS_START: u16 = 0x2000;
+
+fn parse_hex(text: &str) -> Result<u16, String> {
+    let digits = text
+        .strip_prefix('$')
+        .or_else(|| text.strip_prefix("0x"))
+        .or_else(|| text.strip_prefix("0X"))
+        .unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid hex number '{}'", text))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cheat {
+    pub address: u16,
+    pub value: u8,
+    // only substitute when the cartridge returns this, for bank switched ROM
+    pub compare: Option<u8>,
+    pub enabled: bool,
+    pub description: String,
+}
+
+impl Cheat {
+    pub fn new(address: u16, value: u8, compare: Option<u8>) -> Self {
+        Cheat {
+            address,
+            value,
+            compare,
+            enabled: true,
+            description: String::new(),
+        }
+    }
+
+    pub fn game_genie(code: &str) -> Result<Cheat, String> {
+        let n: Vec<u16> = code
+            .bytes()
+            .map(|letter| {
+                GAME_GENIE_LETTERS
+                    .iter()
+                    .position(|c| *c == letter.to_ascii_uppercase())
+                    .map(|nibble| nibble as u16)
+                    .ok_or(format!("'{}' is not a Game Genie letter", letter as char))
+            })
+            .collect::<Result<_, _>>()?;
+        if n.len() != 6 && n.len() != 8 {
+            return Err(format!("Game Genie codes have 6 or 8 letters, got {}", n.len()));
+        }
+
+        let address = 0x8000
+            | (n[3] & 7) << 12
+            | (n[5] & 7) << 8
+            | (n[4] & 8) << 8
+            | (n[2] & 7) << 4
+            | (n[1] & 8) << 4
+            | (n[4] & 7)
+            | (n[3] & 8);
+        // the letter carrying the last value bit moves when there is a compare
+        let last = if n.len() == 8 { n[7] } else { n[5] };
+        let value = (n[1] & 7) << 4 | (n[0] & 8) << 4 | (n[0] & 7) | (last & 8);
+        let compare = (n.len() == 8).then(|| (n[7] & 7) << 4 | (n[6] & 8) << 4 | (n[6] & 7) | (n[5] & 8));
+
+        Ok(Cheat::new(address, value as u8, compare.map(|compare| compare as u8)))
+    }
+
+    // ADDR:VALUE or ADDR:VALUE:COMPARE, all hex with an optional $ or 0x.
+    // RAM below $0800 or cartridge addresses, the RAM mirrors and the
+    // registers between can't be patched.
+    pub fn raw(code: &str) -> Result<Cheat, String> {
+        let parts: Vec<&str> = code.split(':').collect();
+        let byte = |text: &str| {
+            u8::try_from(parse_hex(text)?).map_err(|_| format!("'{}' does not fit in a byte", text))
+        };
+        let cheat = match parts[..] {
+            [address, value] => Cheat::new(parse_hex(address)?, byte(value)?, None),
+            [address, value, compare] => Cheat::new(parse_hex(address)?, byte(value)?, Some(byte(compare)?)),
+            _ => return Err(format!("expected ADDR:VALUE[:COMPARE], got '{}'", code)),
+        };
+        if (RAM_END..REGISTERS_START).contains(&cheat.address) {
+            return Err(format!(
+                "${:04X} is a RAM mirror, use ${:04X}",
+                cheat.address,
+                cheat.address % RAM_END
+            ));
+        }
+        if (REGISTERS_START..CARTRIDGE_START).contains(&cheat.address) {
+            return Err(format!("${:04X} is a register, cheats go in RAM or the cartridge", cheat.address));
+        }
+        Ok(cheat)
+    }
+
+    fn applies(&self, addr: u16, data: u8) -> bool {
+        self.enabled && self.address == addr && self.compare.is_none_or(|compare| compare == data)
+    }
+}
+
+impl FromStr for Cheat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains(':') {
+            Cheat::raw(s)
+        } else {
+            Cheat::game_genie(s)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Cheats {
+    list: Vec<(usize, Cheat)>,
+    next_id: usize,
+}
+
+impl Cheats {
+    pub fn new() -> Self {
+        Cheats::default()
+    }
+
+    pub fn add(&mut self, cheat: Cheat) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.list.push((id, cheat));
+        id
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Cheat> {
+        let index = self.list.iter().position(|(cheat_id, _)| *cheat_id == id)?;
+        Some(self.list.remove(index).1)
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Cheat> {
+        self.list.iter().find(|(cheat_id, _)| *cheat_id == id).map(|(_, cheat)| cheat)
+    }
+
+    pub fn set_enabled(&mut self, id: usize, enabled: bool) -> bool {
+        match self.list.iter_mut().find(|(cheat_id, _)| *cheat_id == id) {
+            Some((_, cheat)) => {
+                cheat.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Cheat)> {
+        self.list.iter().map(|(id, cheat)| (*id, cheat))
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    // what the CPU sees when the cartridge returns `data` for `addr`
+    pub fn apply(&self, addr: u16, data: u8) -> u8 {
+        if addr < CARTRIDGE_START {
+            return data;
+        }
+        self.list
+            .iter()
+            .find(|(_, cheat)| cheat.applies(addr, data))
+            .map_or(data, |(_, cheat)| cheat.value)
+    }
+
+    // RAM codes pin their byte the way FCEUX does, the CPU calls this
+    // before every instruction so the game's own writes don't stick. With a
+    // compare value the byte is only replaced while it holds that value.
+    pub fn force_ram(&self, ram: &mut [u8; 0x800]) {
+        for (_, cheat) in self.list.iter().filter(|(_, cheat)| cheat.enabled && cheat.address < RAM_END) {
+            let byte = &mut ram[cheat.address as usize];
+            if cheat.compare.is_none_or(|compare| compare == *byte) {
+                *byte = cheat.value;
+            }
+        }
+    }
+
+    // One code per line, the rest of the line describes it. Lines starting
+    // with '#' are comments, a leading '-' adds the code disabled:
+    //
+    //   SXIOPO      infinite lives
+    //   -0075:09    start in world 8
+    pub fn parse_list(text: &str) -> Result<Vec<Cheat>, String> {
+        let mut cheats = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (code, description) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let (code, enabled) = match code.strip_prefix('-') {
+                Some(code) => (code, false),
+                None => (code, true),
+            };
+            let mut cheat: Cheat = code.parse().map_err(|e| format!("line {}: {}", n + 1, e))?;
+            cheat.enabled = enabled;
+            cheat.description = description.trim().to_string();
+            cheats.push(cheat);
+        }
+        Ok(cheats)
+    }
+
+    // adds every code in the file, returning their ids
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<usize>, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let cheats = Cheats::parse_list(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(cheats.into_iter().map(|cheat| self.add(cheat)).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_game_genie() {
+        let cheat = Cheat::game_genie("GOSSIP").unwrap();
+        assert_eq!((cheat.address, cheat.value, cheat.compare), (0xd1dd, 0x14, None));
+
+        let cheat = Cheat::game_genie("zexpygla").unwrap();
+        assert_eq!((cheat.address, cheat.value, cheat.compare), (0x94a7, 0x02, Some(0x03)));
+
+        assert!(Cheat::game_genie("GOSSI").is_err());
+        assert!(Cheat::game_genie("GOSSIB").is_err());
+    }
+
+    #[test]
+    fn test_raw_codes_and_apply() {
+        let mut cheats = Cheats::new();
+        let plain = cheats.add("$8000:12".parse().unwrap());
+        cheats.add("0x9000:34:56".parse().unwrap());
+        cheats.add("0x0075:09".parse().unwrap());
+
+        assert_eq!(cheats.apply(0x8000, 0xff), 0x12);
+        assert_eq!(cheats.apply(0x9000, 0x00), 0x00);
+        assert_eq!(cheats.apply(0x9000, 0x56), 0x34);
+        // RAM codes are forced into RAM rather than substituted on reads
+        assert_eq!(cheats.apply(0x0075, 0x01), 0x01);
+
+        cheats.set_enabled(plain, false);
+        assert_eq!(cheats.apply(0x8000, 0xff), 0xff);
+        assert!("8000:100".parse::<Cheat>().is_err());
+        assert!("2002:80".parse::<Cheat>().is_err());
+        assert!("0800:80".parse::<Cheat>().is_err());
+        assert!("1875:09".parse::<Cheat>().is_err());
+        assert!("4016:01".parse::<Cheat>().is_err());
+    }
+
+    #[test]
+    fn test_force_ram() {
+        let mut cheats = Cheats::new();
+        cheats.add("0075:09".parse().unwrap());
+        let lives = cheats.add("0100:05:01".parse().unwrap());
+        cheats.add("8000:12".parse().unwrap());
+        let mut ram = [0; 0x800];
+        ram[0x100] = 3;
+
+        cheats.force_ram(&mut ram);
+        assert_eq!(ram[0x75], 0x09);
+        assert_eq!(ram[0x100], 3);
+        ram[0x100] = 1;
+        cheats.force_ram(&mut ram);
+        assert_eq!(ram[0x100], 5);
+
+        cheats.set_enabled(lives, false);
+        ram[0x100] = 1;
+        cheats.force_ram(&mut ram);
+        assert_eq!(ram[0x100], 1);
+    }
+
+    #[test]
+    fn test_parse_list() {
+        let cheats = Cheats::parse_list("# smb\nGOSSIP  big jumps\n-8000:EA\n").unwrap();
+        assert_eq!(cheats.len(), 2);
+        assert_eq!(cheats[0].description, "big jumps");
+        assert!(cheats[0].enabled && !cheats[1].enabled);
+        assert!(Cheats::parse_list("QQQQQQ").is_err());
+    }
+}
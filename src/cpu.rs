@@ -1,5 +1,19 @@
+use std::cell::Cell;
 use std::collections::HashMap;
+use crate::breakpoint::{Access, BreakHit, Breakpoints};
+use crate::cheat::Cheats;
+use crate::fds::{DiskImage, Fds};
+use crate::frame::Frame;
+use crate::input::{Connector, InputDevice, Screen};
+use crate::joypad::Joypad;
+use crate::mapper::{self, Mapper};
+use crate::mask::MaskRegister;
+use crate::multitap::{FourScore, Multitap};
 use crate::opcodes;
+use crate::region::Region;
+use crate::rom::Rom;
+use crate::savestate;
+use crate::zapper::Beam;
 
 
 const STACK: u16 = 0x0100;
@@ -12,7 +26,38 @@ pub struct CPU {
     pub processor_status: u8,
     pub program_counter: u16,
     pub stack_pointer: u8,
-    memory: [u8; 0xFFFF]
+    pub cycles: usize,
+    pub breakpoints: Breakpoints,
+    // 3 and 4 are only read through a four player adapter
+    pub joypads: [Joypad; 4],
+    pub multitap: Multitap,
+    pub(crate) four_score: FourScore,
+    // Zappers, paddles, mats and such in place of the controller on that
+    // port
+    pub port_devices: [Option<Box<dyn InputDevice>>; 2],
+    // Famicom expansion port, read alongside the controllers
+    pub expansion: Option<Box<dyn InputDevice>>,
+    // the picture and where the PPU is drawing it, what light guns see.
+    // Nothing updates them without a PPU, hosts may fill them in
+    pub frame: Frame,
+    pub beam: Beam,
+    // PPUMASK as last written, draw_pixel applies its grayscale and
+    // emphasis
+    pub mask: MaskRegister,
+    // which PPU draws, PAL and Dendy swap red and green emphasis
+    pub region: Region,
+    pub cheats: Cheats,
+    // None for NROM, which lives in `memory`
+    pub mapper: Option<Box<dyn Mapper>>,
+    watch_hit: Cell<Option<BreakHit>>,
+    pub(crate) program_crc: u32,
+    pub(crate) memory: [u8; 0x10000]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    Brk,
+    Breakpoint(BreakHit),
 }
 
 #[derive(Debug)]
@@ -31,7 +76,7 @@ pub enum AddressingMode {
 }
 
 
-trait Memory {
+pub trait Memory {
     fn memory_read(&self, addr: u16) -> u8; 
 
     fn memory_write(&mut self, addr: u16, data: u8);
@@ -39,7 +84,7 @@ trait Memory {
     fn memory_read_u16(&self, pos: u16) -> u16 {
         let lo = self.memory_read(pos) as u16;
         let hi = self.memory_read(pos + 1) as u16;
-        (hi << 8) | (lo as u16)
+        (hi << 8) | lo
     }
 
     fn memory_write_u16(&mut self, pos: u16, data: u16) {
@@ -54,11 +99,41 @@ trait Memory {
 impl Memory for CPU {
     
     fn memory_read(&self, addr: u16) -> u8 { 
-        self.memory[addr as usize]
+        let data = match addr {
+            0x4016 => self.read_port(0) | self.read_expansion(0),
+            0x4017 => self.read_port(1) | self.read_expansion(1),
+            _ => self.cheats.apply(addr, self.bus_read(addr)),
+        };
+        self.watch(Access::Read, addr, data);
+        data
     }
 
     fn memory_write(&mut self, addr: u16, data: u8) { 
-        self.memory[addr as usize] = data;
+        self.watch(Access::Write, addr, data);
+        // the PPU registers repeat every 8 bytes up to $3FFF
+        if (0x2000..0x4000).contains(&addr) && addr & 7 == 1 {
+            self.mask.update(data);
+        }
+        // the strobe line goes to both ports, $4017 writes belong to the APU
+        if addr == 0x4016 {
+            for joypad in self.joypads.iter_mut() {
+                joypad.write(data);
+            }
+            self.four_score.write(data);
+            for device in self.port_devices.iter_mut().chain([&mut self.expansion]).flatten() {
+                device.write(data);
+            }
+        }
+        match self.mapper.as_mut() {
+            Some(mapper) if addr >= 0x4020 => mapper.cpu_write(addr, data),
+            _ => self.memory[addr as usize] = data,
+        }
+    }
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -71,57 +146,169 @@ impl CPU {
             stack_pointer: STACK_RESET,
             processor_status: 0,
             program_counter: 0,
-            memory: [0; 0xFFFF]
+            cycles: 0,
+            breakpoints: Breakpoints::new(),
+            joypads: [Joypad::new(), Joypad::new(), Joypad::new(), Joypad::new()],
+            multitap: Multitap::None,
+            four_score: FourScore::new(),
+            port_devices: [None, None],
+            expansion: None,
+            frame: Frame::new(),
+            beam: Beam::default(),
+            mask: MaskRegister::new(),
+            region: Region::Ntsc,
+            cheats: Cheats::new(),
+            mapper: None,
+            watch_hit: Cell::new(None),
+            program_crc: 0,
+            memory: [0; 0x10000]
+        }
+    }
+
+    // $4016 or $4017 from whatever is in the controller port, the Famicom
+    // adapter adds controllers 3 and 4 on bit 1
+    fn read_port(&self, port: usize) -> u8 {
+        if let Some(device) = &self.port_devices[port] {
+            return device.read(port, &self.screen());
+        }
+        match self.multitap {
+            Multitap::None => self.joypads[port].read(),
+            Multitap::FourScore => self.four_score.read(port, &self.joypads),
+            Multitap::Famicom => self.joypads[port].read() | self.joypads[port + 2].read() << 1,
         }
     }
 
+    fn read_expansion(&self, port: usize) -> u8 {
+        self.expansion.as_ref().map_or(0, |device| device.read(port, &self.screen()))
+    }
+
+    // stores a palette RAM value the way the PPU outputs it with the
+    // current PPUMASK
+    pub fn draw_pixel(&mut self, x: usize, y: usize, index: u8) {
+        let pixel = self.mask.output_in(index, self.region);
+        self.frame.set_pixel(x, y, pixel);
+    }
+
+    fn screen(&self) -> Screen<'_> {
+        Screen {
+            frame: &self.frame,
+            beam: self.beam,
+        }
+    }
+
+    fn connector_mut(&mut self, connector: Connector) -> &mut Option<Box<dyn InputDevice>> {
+        match connector {
+            Connector::Port1 => &mut self.port_devices[0],
+            Connector::Port2 => &mut self.port_devices[1],
+            Connector::Expansion => &mut self.expansion,
+        }
+    }
+
+    // returns what was plugged in there before
+    pub fn plug(&mut self, connector: Connector, device: Box<dyn InputDevice>) -> Option<Box<dyn InputDevice>> {
+        self.connector_mut(connector).replace(device)
+    }
+
+    // back to the joypad on a controller port
+    pub fn unplug(&mut self, connector: Connector) -> Option<Box<dyn InputDevice>> {
+        self.connector_mut(connector).take()
+    }
+
+    // the first plugged in device of type T, to feed it input
+    pub fn input_device_mut<T: InputDevice + 'static>(&mut self) -> Option<&mut T> {
+        self.port_devices
+            .iter_mut()
+            .chain([&mut self.expansion])
+            .flatten()
+            .find_map(|device| device.as_any_mut().downcast_mut::<T>())
+    }
+
+    // opcode and operand fetches bypass read watchpoints, but not cheats
+    fn fetch(&self, addr: u16) -> u8 {
+        self.cheats.apply(addr, self.bus_read(addr))
+    }
+
+    // what the CPU would read, without clocking the controllers, applying
+    // cheats or tripping watchpoints
+    pub fn peek(&self, addr: u16) -> u8 {
+        match self.mapper.as_ref() {
+            Some(mapper) if addr >= 0x4020 => mapper.cpu_peek(addr),
+            _ => self.memory[addr as usize],
+        }
+    }
+
+    fn bus_read(&self, addr: u16) -> u8 {
+        match self.mapper.as_ref() {
+            Some(mapper) if addr >= 0x4020 => mapper.cpu_read(addr),
+            _ => self.memory[addr as usize],
+        }
+    }
+
+    fn fetch_u16(&self, pos: u16) -> u16 {
+        let lo = self.fetch(pos) as u16;
+        let hi = self.fetch(pos + 1) as u16;
+        (hi << 8) | lo
+    }
+
+    fn watch(&self, access: Access, addr: u16, data: u8) {
+        if self.breakpoints.is_empty() || self.watch_hit.get().is_some() {
+            return;
+        }
+        if let Some(hit) = self.breakpoints.check(access, addr, data, self) {
+            self.watch_hit.set(Some(hit));
+        }
+    }
+
+    fn check_execute(&self) -> Option<BreakHit> {
+        if self.breakpoints.is_empty() {
+            return None;
+        }
+        let opcode = self.fetch(self.program_counter);
+        self.breakpoints.check(Access::Execute, self.program_counter, opcode, self)
+    }
+
     fn get_operand_address(&self, mode: &AddressingMode) -> u16 {
 
         match mode {
             AddressingMode::Immediate => self.program_counter,
 
-            AddressingMode::ZeroPage  => self.memory_read(self.program_counter) as u16,
+            AddressingMode::ZeroPage  => self.fetch(self.program_counter) as u16,
             
-            AddressingMode::Absolute => self.memory_read_u16(self.program_counter),
+            AddressingMode::Absolute => self.fetch_u16(self.program_counter),
           
             AddressingMode::ZeroPage_X => {
-                let pos = self.memory_read(self.program_counter);
-                let addr = pos.wrapping_add(self.register_x) as u16;
-                addr
+                let pos = self.fetch(self.program_counter);
+                pos.wrapping_add(self.register_x) as u16
             }
             AddressingMode::ZeroPage_Y => {
-                let pos = self.memory_read(self.program_counter);
-                let addr = pos.wrapping_add(self.register_y) as u16;
-                addr
+                let pos = self.fetch(self.program_counter);
+                pos.wrapping_add(self.register_y) as u16
             }
 
             AddressingMode::Absolute_X => {
-                let base = self.memory_read_u16(self.program_counter);
-                let addr = base.wrapping_add(self.register_x as u16);
-                addr
+                let base = self.fetch_u16(self.program_counter);
+                base.wrapping_add(self.register_x as u16)
             }
             AddressingMode::Absolute_Y => {
-                let base = self.memory_read_u16(self.program_counter);
-                let addr = base.wrapping_add(self.register_y as u16);
-                addr
+                let base = self.fetch_u16(self.program_counter);
+                base.wrapping_add(self.register_y as u16)
             }
 
             AddressingMode::Indirect_X => {
-                let base = self.memory_read(self.program_counter);
+                let base = self.fetch(self.program_counter);
 
-                let ptr: u8 = (base as u8).wrapping_add(self.register_x);
+                let ptr: u8 = base.wrapping_add(self.register_x);
                 let lo = self.memory_read(ptr as u16);
                 let hi = self.memory_read(ptr.wrapping_add(1) as u16);
                 (hi as u16) << 8 | (lo as u16)
             }
             AddressingMode::Indirect_Y => {
-                let base = self.memory_read(self.program_counter);
+                let base = self.fetch(self.program_counter);
 
                 let lo = self.memory_read(base as u16);
-                let hi = self.memory_read((base as u8).wrapping_add(1) as u16);
+                let hi = self.memory_read(base.wrapping_add(1) as u16);
                 let deref_base = (hi as u16) << 8 | (lo as u16);
-                let deref = deref_base.wrapping_add(self.register_y as u16);
-                deref
+                deref_base.wrapping_add(self.register_y as u16)
             }
            
             AddressingMode::NoneAddressing => {
@@ -131,6 +318,15 @@ impl CPU {
 
     }
 
+    // immediate operands are fetched like the opcode, so they don't trigger
+    // read watchpoints
+    fn read_operand(&self, mode: &AddressingMode) -> u8 {
+        match mode {
+            AddressingMode::Immediate => self.fetch(self.program_counter),
+            _ => self.memory_read(self.get_operand_address(mode)),
+        }
+    }
+
     pub fn reset(&mut self) {
         self.register_a = 0;
         self.register_x = 0;
@@ -140,52 +336,135 @@ impl CPU {
  
         self.program_counter = self.memory_read_u16(0xFFFC);
     }
+
+    // like pressing the power button: internal RAM is lost, the cartridge stays
+    pub fn power_cycle(&mut self) {
+        self.memory[0x0000..0x0800].fill(0);
+        self.joypads = [Joypad::new(), Joypad::new(), Joypad::new(), Joypad::new()];
+        self.four_score = FourScore::new();
+        self.mask = MaskRegister::new();
+        self.reset();
+    }
  
     pub fn load(&mut self, program: Vec<u8>) {
         self.memory[0x8000 .. (0x8000 + program.len())].copy_from_slice(&program[..]);
         self.memory_write_u16(0xFFFC, 0x8000);
+        self.program_crc = savestate::crc32(&program);
+    }
+
+    // NROM goes into flat memory, 16 KiB images are mirrored into $C000
+    pub fn load_rom(&mut self, rom: &Rom) -> Result<(), String> {
+        if rom.mapper != 0 {
+            self.mapper = Some(mapper::create(rom)?);
+            self.program_crc = savestate::crc32(&rom.prg_rom);
+            return Ok(());
+        }
+        self.mapper = None;
+        match rom.prg_rom.len() {
+            0x4000 => {
+                self.memory[0x8000..0xC000].copy_from_slice(&rom.prg_rom);
+                self.memory[0xC000..].copy_from_slice(&rom.prg_rom);
+            }
+            0x8000 => self.memory[0x8000..].copy_from_slice(&rom.prg_rom),
+            len => return Err(format!("unexpected PRG ROM size {}", len)),
+        }
+        self.program_crc = savestate::crc32(&rom.prg_rom);
+        Ok(())
+    }
+
+    // the RAM adapter with its BIOS, and the disk in the drive
+    pub fn load_disk(&mut self, bios: &[u8], disk: &DiskImage) -> Result<(), String> {
+        self.mapper = Some(Box::new(Fds::new(bios, disk)?));
+        self.program_crc = savestate::crc32(&disk.to_bytes());
+        Ok(())
+    }
+
+    pub fn disk_drive_mut(&mut self) -> Option<&mut Fds> {
+        self.mapper.as_mut().and_then(|mapper| mapper.disk_drive_mut())
+    }
+
+    // cartridge RAM at $6000-$7FFF, what a battery keeps alive
+    pub fn prg_ram(&self) -> &[u8] {
+        match self.mapper.as_ref() {
+            Some(mapper) => mapper.prg_ram(),
+            None => &self.memory[0x6000..0x8000],
+        }
+    }
+
+    pub fn prg_ram_mut(&mut self) -> &mut [u8] {
+        match self.mapper.as_mut() {
+            Some(mapper) => mapper.prg_ram_mut(),
+            None => &mut self.memory[0x6000..0x8000],
+        }
+    }
+
+    // expansion audio from the cartridge, 0 for boards without any
+    pub fn expansion_audio(&self) -> f32 {
+        self.mapper.as_ref().map_or(0.0, |mapper| mapper.audio())
+    }
+
+    fn add_cycles(&mut self, cycles: usize) {
+        self.cycles += cycles;
+        if let Some(mapper) = self.mapper.as_mut() {
+            mapper.clock(cycles);
+        }
+    }
+
+    // only cartridge hardware drives the IRQ line so far
+    fn poll_irq(&mut self) {
+        let asserted = self.mapper.as_ref().is_some_and(|mapper| mapper.irq());
+        if !asserted || self.processor_status & 0b0000_0100 != 0 {
+            return;
+        }
+        self.stack_push_u16(self.program_counter);
+        self.stack_push((self.processor_status | 0b0010_0000) & !0b0001_0000);
+        self.processor_status |= 0b0000_0100;
+        self.program_counter = self.memory_read_u16(0xfffe);
+        self.add_cycles(7);
+    }
+
+    // identifies the loaded program, save states only restore onto the same one
+    pub fn program_checksum(&self) -> u32 {
+        self.program_crc
     }
  
     pub fn load_and_run(&mut self, program: Vec<u8>) {
         self.load(program);
         self.reset();
-        self.execute()
+        self.execute();
     }
 
     fn lda(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(&mode);
-        let value = self.memory_read(addr);
+        let value = self.read_operand(mode);
 
         self.register_a = value;
         self.update_zero_and_negative_flags(self.register_a);
     }
 
     fn ldx(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let value = self.memory_read(addr);
+        let value = self.read_operand(mode);
         self.register_x = value;
         self.update_zero_and_negative_flags(self.register_x);
     }
 
     fn ldy(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let value = self.memory_read(addr);
+        let value = self.read_operand(mode);
         self.register_y = value;
         self.update_zero_and_negative_flags(self.register_y);
     }
 
     fn sta(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(&mode);
+        let addr = self.get_operand_address(mode);
         self.memory_write(addr, self.register_a);
     }
 
     fn stx(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(&mode);
+        let addr = self.get_operand_address(mode);
         self.memory_write(addr, self.register_x);
     }
 
     fn sty(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(&mode);
+        let addr = self.get_operand_address(mode);
         self.memory_write(addr, self.register_y);
     }
 
@@ -257,35 +536,31 @@ impl CPU {
     }
 
     fn cmp(&mut self, mode: &AddressingMode, compared_register: u8){
-        let addr = self.get_operand_address(mode);
-        let value = self.memory_read(addr);
+        let value = self.read_operand(mode);
 
         if value <= compared_register {
-            self.processor_status = self.processor_status | 0b0000_0001;
+            self.processor_status |= 0b0000_0001;
         }
         else {
-            self.processor_status = self.processor_status & 0b1111_1110;
+            self.processor_status &= 0b1111_1110;
         }
 
         self.update_zero_and_negative_flags(compared_register.wrapping_sub(value));
     }
 
     fn adc(&mut self, mode: &AddressingMode){
-        let addr = self.get_operand_address(mode);
-        let value = self.memory_read(addr);
+        let value = self.read_operand(mode);
         self.operation_with_carry(value);
     }
 
     fn sbc(&mut self, mode: &AddressingMode){
-        let addr = self.get_operand_address(mode);
-        let value = self.memory_read(addr);
+        let value = self.read_operand(mode);
         self.operation_with_carry(0xff - value);
     }
 
     fn and(&mut self, mode: &AddressingMode){
-        let addr = self.get_operand_address(mode);
-        let value = self.memory_read(addr);
-        self.register_a = self.register_a & value;
+        let value = self.read_operand(mode);
+        self.register_a &= value;
         self.update_zero_and_negative_flags(self.register_a);
     }
 
@@ -294,24 +569,24 @@ impl CPU {
         let mut value = self.memory_read(addr);
 
         if value >> 7 == 1 {
-            self.processor_status = self.processor_status | 0b0000_0001;
+            self.processor_status |= 0b0000_0001;
         } 
         else {
-            self.processor_status = self.processor_status & 0b1111_1110;
+            self.processor_status &= 0b1111_1110;
         }
 
-        value = value << 1;
+        value <<= 1;
         self.memory_write(addr, value);
         self.update_zero_and_negative_flags(value);
     }
 
     fn stack_pop(&mut self) -> u8 {
         self.stack_pointer = self.stack_pointer.wrapping_add(1);
-        self.memory_read((STACK as u16) + self.stack_pointer as u16)
+        self.memory_read(STACK + self.stack_pointer as u16)
     }
 
     fn stack_push(&mut self, data: u8) {
-        self.memory_write((STACK as u16) + self.stack_pointer as u16, data);
+        self.memory_write(STACK + self.stack_pointer as u16, data);
         self.stack_pointer = self.stack_pointer.wrapping_sub(1)
     }
 
@@ -330,9 +605,8 @@ impl CPU {
     }
 
     fn ora(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let value = self.memory_read(addr);
-        self.register_a = value | self.register_a;
+        let value = self.read_operand(mode);
+        self.register_a |= value;
         self.update_zero_and_negative_flags(self.register_a);
     }
 
@@ -344,61 +618,60 @@ impl CPU {
 
     fn php(&mut self) {
         //http://wiki.nesdev.com/w/index.php/CPU_status_flag_behavior
-        let mut flags = self.processor_status.clone();
-        flags = flags | 0b0011_0000;
+        let mut flags = self.processor_status;
+        flags |= 0b0011_0000;
         self.stack_push(flags);
     }
 
     fn plp(&mut self) {
         self.processor_status = self.stack_pop();
-        self.processor_status = self.processor_status & 0b1110_1111;
-        self.processor_status = self.processor_status | 0b0010_0000;
+        self.processor_status &= 0b1110_1111;
+        self.processor_status |= 0b0010_0000;
     }
 
     fn asl_accumulator(&mut self){
         let mut value = self.register_a;
         if value >> 7 == 1 {
-            self.processor_status = self.processor_status | 0b0000_0001;
+            self.processor_status |= 0b0000_0001;
         }
         else {
-            self.processor_status = self.processor_status & 0b1111_1110;
+            self.processor_status &= 0b1111_1110;
         }
 
-        value = value << 1;
+        value <<= 1;
         self.register_a = value;
         self.update_zero_and_negative_flags(value);
     }
 
     fn bit(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let value = self.memory_read(addr);
+        let value = self.read_operand(mode);
         let and = self.register_a & value;
         if and == 0 {
-            self.processor_status = self.processor_status | 0b0000_0010;
+            self.processor_status |= 0b0000_0010;
         } 
         else {
-            self.processor_status = self.processor_status & 0b1111_1101;
+            self.processor_status &= 0b1111_1101;
         }
 
         if value & 0b10000000 > 0 {
-            self.processor_status = self.processor_status | 0b1000_0000;
+            self.processor_status |= 0b1000_0000;
         }
         else {
-            self.processor_status = self.processor_status | 0b0111_1111;
+            self.processor_status |= 0b0111_1111;
         }
 
         if value & 0b01000000 > 0 {
-            self.processor_status = self.processor_status | 0b0100_0000;
+            self.processor_status |= 0b0100_0000;
         }
         else {
-            self.processor_status = self.processor_status | 0b1011_1111;
+            self.processor_status |= 0b1011_1111;
         }
     }
 
 
     fn branch(&mut self, condition: bool) {
         if condition {
-            let jump: i8 = self.memory_read(self.program_counter) as i8;
+            let jump: i8 = self.fetch(self.program_counter) as i8;
             let jump_addr = self
                 .program_counter
                 .wrapping_add(1)
@@ -409,22 +682,21 @@ impl CPU {
     }
 
     fn eor(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let value = self.memory_read(addr);
-        self.register_a = value ^ self.register_a;
+        let value = self.read_operand(mode);
+        self.register_a ^= value;
         self.update_zero_and_negative_flags(self.register_a);
     }
 
     fn lsr_accumulator(&mut self){
         let mut value = self.register_a;
         if value & 1 == 1 {
-            self.processor_status = self.processor_status | 0b0000_0001;
+            self.processor_status |= 0b0000_0001;
         }
         else {
-            self.processor_status = self.processor_status & 0b1111_1110;
+            self.processor_status &= 0b1111_1110;
         }
 
-        value = value >> 1;
+        value >>= 1;
 
         self.register_a = value;
         self.update_zero_and_negative_flags(self.register_a);
@@ -434,13 +706,13 @@ impl CPU {
         let addr = self.get_operand_address(mode);
         let mut value = self.memory_read(addr);
         if value & 1 == 1 {
-            self.processor_status = self.processor_status | 0b0000_0001;
+            self.processor_status |= 0b0000_0001;
         } 
         else {
-            self.processor_status = self.processor_status & 0b1111_1110;
+            self.processor_status &= 0b1111_1110;
         }
 
-        value = value >> 1;
+        value >>= 1;
         self.memory_write(addr, value);
         self.update_zero_and_negative_flags(value);
         value
@@ -456,15 +728,15 @@ impl CPU {
         }
 
         if value >> 7 == 1 {
-            self.processor_status = self.processor_status | 0b0000_0001;
+            self.processor_status |= 0b0000_0001;
         } 
         else {
-            self.processor_status = self.processor_status & 0b1111_1110;
+            self.processor_status &= 0b1111_1110;
         }
 
-        value = value << 1;
+        value <<= 1;
         if old_carry {
-            value = value | 1;
+            value |= 1;
         }
         self.memory_write(addr, value);
         self.update_zero_and_negative_flags(value);
@@ -480,15 +752,15 @@ impl CPU {
         }
 
         if value >> 7 == 1 {
-            self.processor_status = self.processor_status | 0b0000_0001;
+            self.processor_status |= 0b0000_0001;
         } 
         else {
-            self.processor_status = self.processor_status & 0b1111_1110;
+            self.processor_status &= 0b1111_1110;
         }
 
-        value = value << 1;
+        value <<= 1;
         if old_carry {
-            value = value | 1;
+            value |= 1;
         }
 
         self.register_a = value;
@@ -505,13 +777,13 @@ impl CPU {
         }
 
         if value & 1 == 1 {
-            self.processor_status = self.processor_status | 0b0000_0001;
+            self.processor_status |= 0b0000_0001;
         } else {
-            self.processor_status = self.processor_status & 0b1111_1110;
+            self.processor_status &= 0b1111_1110;
         }
-        value = value >> 1;
+        value >>= 1;
         if old_carry {
-            value = value | 0b10000000;
+            value |= 0b10000000;
         }
         self.memory_write(addr, value);
         self.update_zero_and_negative_flags(value);
@@ -527,13 +799,13 @@ impl CPU {
         }
 
         if value & 1 == 1 {
-            self.processor_status = self.processor_status | 0b0000_0001;
+            self.processor_status |= 0b0000_0001;
         } else {
-            self.processor_status = self.processor_status & 0b1111_1110;
+            self.processor_status &= 0b1111_1110;
         }
-        value = value >> 1;
+        value >>= 1;
         if old_carry {
-            value = value | 0b10000000;
+            value |= 0b10000000;
         }
         self.register_a = value;
         self.update_zero_and_negative_flags(self.register_a);
@@ -545,19 +817,19 @@ impl CPU {
         let carry_out = sum > 0xff;
 
         if carry_out {
-            self.processor_status = self.processor_status | 0b0000_0001;
+            self.processor_status |= 0b0000_0001;
         }
         else {
-            self.processor_status = self.processor_status & 0b1111_1110;
+            self.processor_status &= 0b1111_1110;
         }
 
         let result = sum as u8;
 
         if (value ^ result) & (result ^ self.register_a) & 0x80 != 0 {
-            self.processor_status = self.processor_status | 0b0100_0000;
+            self.processor_status |= 0b0100_0000;
         } 
         else {
-            self.processor_status = self.processor_status & 0b1011_1111;
+            self.processor_status &= 0b1011_1111;
         }
 
         self.register_a = result;
@@ -567,273 +839,317 @@ impl CPU {
 
     fn update_zero_and_negative_flags(&mut self, result: u8) {
         if result == 0 {
-            self.processor_status = self.processor_status | 0b0000_0010;
+            self.processor_status |= 0b0000_0010;
         } else {
-            self.processor_status = self.processor_status & 0b1111_1101;
+            self.processor_status &= 0b1111_1101;
         }
 
         if result & 0b1000_0000 != 0 {
-            self.processor_status = self.processor_status | 0b1000_0000;
+            self.processor_status |= 0b1000_0000;
         } else {
-            self.processor_status = self.processor_status & 0b0111_1111;
+            self.processor_status &= 0b0111_1111;
         }
     }
 
-    pub fn execute(&mut self) {
-
-        let ref opcodes: HashMap<u8, opcodes::OpCode> = *opcodes::MAP;
-        
+    pub fn execute(&mut self) -> Stop {
         loop {
-            let instruction = self.memory_read(self.program_counter);
-            self.program_counter += 1;
-            let program_counter_state = self.program_counter;
+            if let Some(hit) = self.check_execute() {
+                return Stop::Breakpoint(hit);
+            }
+            if let Some(stop) = self.step() {
+                return stop;
+            }
+        }
+    }
 
-            let opcode = opcodes.get(&instruction).expect(&format!("OpCode {:x} is not recognized", instruction));
-    
-            match instruction {
-                0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 => {
-                    self.lda(&opcode.mode);
-                }
+    // runs at most `instructions` instructions, None if none of them stopped
+    pub fn run_for(&mut self, instructions: usize) -> Option<Stop> {
+        for _ in 0..instructions {
+            if let Some(hit) = self.check_execute() {
+                return Some(Stop::Breakpoint(hit));
+            }
+            if let Some(stop) = self.step() {
+                return Some(stop);
+            }
+        }
+        None
+    }
 
-                0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => {
-                    self.ldx(&opcode.mode);
-                }
+    // continues from a breakpoint without triggering it again
+    pub fn resume(&mut self) -> Stop {
+        match self.step() {
+            Some(stop) => stop,
+            None => self.execute(),
+        }
+    }
 
-                0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => {
-                    self.ldy(&opcode.mode);
-                }
+    pub fn step(&mut self) -> Option<Stop> {
+        let opcodes: &HashMap<u8, opcodes::OpCode> = &opcodes::MAP;
 
-                0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => {
-                    self.sta(&opcode.mode);
-                }
+        self.watch_hit.set(None);
+        if !self.cheats.is_empty() {
+            let ram: &mut [u8; 0x800] = (&mut self.memory[..0x800]).try_into().unwrap();
+            self.cheats.force_ram(ram);
+        }
+        self.poll_irq();
+
+        let instruction = self.fetch(self.program_counter);
+        self.program_counter += 1;
+        let program_counter_state = self.program_counter;
 
-                0x86 | 0x96 | 0x8E => {
-                    self.stx(&opcode.mode);
-                }
+        let opcode = opcodes.get(&instruction).unwrap_or_else(|| panic!("OpCode {:x} is not recognized", instruction));
+        self.add_cycles(opcode.cycles as usize);
 
-                0x84 | 0x94 | 0x8C => {
-                    self.sty(&opcode.mode);
-                }
+        match instruction {
+            0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 => {
+                self.lda(&opcode.mode);
+            }
 
-                0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => {
-                    self.cmp(&opcode.mode, self.register_a);
-                }
+            0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => {
+                self.ldx(&opcode.mode);
+            }
 
-                0xE0 | 0xE4 | 0xEC  => {
-                    self.cmp(&opcode.mode, self.register_x);
-                }
+            0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => {
+                self.ldy(&opcode.mode);
+            }
 
-                0xC0 | 0xC4 | 0xCC  => {
-                    self.cmp(&opcode.mode, self.register_y);
-                }
+            0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => {
+                self.sta(&opcode.mode);
+            }
 
-                0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => {
-                    self.adc(&opcode.mode);
-                }
+            0x86 | 0x96 | 0x8E => {
+                self.stx(&opcode.mode);
+            }
 
-                0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 => {
-                    self.sbc(&opcode.mode);
-                }
-
-                0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => {
-                    self.and(&opcode.mode);
-                }
-
-                0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => {
-                    self.eor(&opcode.mode);
-                }
-
-                0x06 | 0x16 | 0x0E | 0x1E => {
-                    self.asl(&opcode.mode);
-                }
-
-                0x24 | 0x2c => {
-                    self.bit(&opcode.mode);
-                }
-
-                0xC6 | 0xD6 | 0xCE | 0xDE => {
-                    self.dec(&opcode.mode);
-                }
-
-                0xE6 | 0xF6 | 0xEE | 0xFE => {
-                    self.inc(&opcode.mode);
-                }
-
-                0x4A => {
-                    self.lsr_accumulator();
-                }
-
-                0x46 | 0x56 | 0x4E | 0x5E => {
-                    self.lsr(&opcode.mode);
-                }
-
-                0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => {
-                    self.ora(&opcode.mode);
-                }
-
-                0xd0 => {
-                    self.branch(self.processor_status & 0b0000_0010 != 0b0000_0010);
-                }
-
-                0x70 => {
-                    self.branch(self.processor_status & 0b0100_0000 == 0b0100_0000);
-                }
-
-                0x50 => {
-                    self.branch(self.processor_status & 0b0100_0000 != 0b0100_0000);
-                }
-
-                0x10 => {
-                    self.branch(self.processor_status & 0b1000_0000 != 0b1000_0000);
-                }
-
-                0x30 => {
-                    self.branch(self.processor_status & 0b1000_0000 == 0b1000_0000);
-                }
-
-                0xf0 => {
-                    self.branch(self.processor_status & 0b0000_0010 == 0b0000_0010);
-                }
+            0x84 | 0x94 | 0x8C => {
+                self.sty(&opcode.mode);
+            }
 
-                0xb0 => {
-                    self.branch(self.processor_status & 0b0000_0001 == 0b0000_0001);
-                }
+            0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => {
+                self.cmp(&opcode.mode, self.register_a);
+            }
 
-                0x90 => {
-                    self.branch(self.processor_status & 0b0000_0001 != 0b0000_0001);
-                }
+            0xE0 | 0xE4 | 0xEC  => {
+                self.cmp(&opcode.mode, self.register_x);
+            }
 
-                0xD8 => {
-                    self.processor_status = self.processor_status & 0b1111_0111;
-                }
+            0xC0 | 0xC4 | 0xCC  => {
+                self.cmp(&opcode.mode, self.register_y);
+            }
 
-                0x58 => {
-                    self.processor_status = self.processor_status & 0b1111_1011;
-                }
+            0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => {
+                self.adc(&opcode.mode);
+            }
 
-                0xB8 => {
-                    self.processor_status = self.processor_status & 0b1011_1111;
-                }
+            0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 => {
+                self.sbc(&opcode.mode);
+            }
 
-                0x18 => {
-                    self.processor_status = self.processor_status & 0b1111_1110;
-                }
+            0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => {
+                self.and(&opcode.mode);
+            }
 
-                0x38 => {
-                    self.processor_status = self.processor_status | 0b0000_0001;
-                }
+            0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => {
+                self.eor(&opcode.mode);
+            }
 
-                0x78 => {
-                    self.processor_status = self.processor_status | 0b0000_0100;
-                }
+            0x06 | 0x16 | 0x0E | 0x1E => {
+                self.asl(&opcode.mode);
+            }
 
-                0xF8 => {
-                    self.processor_status = self.processor_status | 0b0000_1000;
-                }
+            0x24 | 0x2c => {
+                self.bit(&opcode.mode);
+            }
 
-                0x4C => {
-                    let mem_address = self.memory_read_u16(self.program_counter);
-                    self.program_counter = mem_address;
-                }
+            0xC6 | 0xD6 | 0xCE | 0xDE => {
+                self.dec(&opcode.mode);
+            }
 
-                0x6c => {
-                    let mem_address = self.memory_read_u16(self.program_counter);
-                    // let indirect_ref = self.memory_read_u16(mem_address);
-                    //6502 bug mode with with page boundary:
-                    //  if address $3000 contains $40, $30FF contains $80, and $3100 contains $50,
-                    // the result of JMP ($30FF) will be a transfer of control to $4080 rather than $5080 as you intended
-                    // i.e. the 6502 took the low byte of the address from $30FF and the high byte from $3000
+            0xE6 | 0xF6 | 0xEE | 0xFE => {
+                self.inc(&opcode.mode);
+            }
 
-                    let indirect_ref = if mem_address & 0x00FF == 0x00FF {
-                        let lo = self.memory_read(mem_address);
-                        let hi = self.memory_read(mem_address & 0xFF00);
-                        (hi as u16) << 8 | (lo as u16)
-                    } else {
-                        self.memory_read_u16(mem_address)
-                    };
+            0x4A => {
+                self.lsr_accumulator();
+            }
 
-                    self.program_counter = indirect_ref;
-                }
+            0x46 | 0x56 | 0x4E | 0x5E => {
+                self.lsr(&opcode.mode);
+            }
 
-                0x20 => {
-                    self.stack_push_u16(self.program_counter + 2 - 1);
-                    let target_address = self.memory_read_u16(self.program_counter);
-                    self.program_counter = target_address
-                }
+            0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => {
+                self.ora(&opcode.mode);
+            }
 
-                0x2a => self.rol_accumulator(),
-                
-                0x26 | 0x36 | 0x2e | 0x3e => {
-                    self.rol(&opcode.mode);
-                }
+            0xd0 => {
+                self.branch(self.processor_status & 0b0000_0010 != 0b0000_0010);
+            }
 
-                0x6a => self.ror_accumulator(),
+            0x70 => {
+                self.branch(self.processor_status & 0b0100_0000 == 0b0100_0000);
+            }
 
-                0x66 | 0x76 | 0x6e | 0x7e => {
-                    self.ror(&opcode.mode);
-                }
+            0x50 => {
+                self.branch(self.processor_status & 0b0100_0000 != 0b0100_0000);
+            }
 
-                0x40 => {
-                    self.processor_status = self.stack_pop();
-                    self.processor_status = self.processor_status & 0b1110_1111;
-                    self.processor_status = self.processor_status | 0b0010_0000;
+            0x10 => {
+                self.branch(self.processor_status & 0b1000_0000 != 0b1000_0000);
+            }
 
-                    self.program_counter = self.stack_pop_u16();
-                }
+            0x30 => {
+                self.branch(self.processor_status & 0b1000_0000 == 0b1000_0000);
+            }
 
-                0x60 => {
-                    self.program_counter = self.stack_pop_u16() + 1;
-                }
+            0xf0 => {
+                self.branch(self.processor_status & 0b0000_0010 == 0b0000_0010);
+            }
 
-                0x0A => self.asl_accumulator(),
+            0xb0 => {
+                self.branch(self.processor_status & 0b0000_0001 == 0b0000_0001);
+            }
 
-                0xAA => self.tax(),
+            0x90 => {
+                self.branch(self.processor_status & 0b0000_0001 != 0b0000_0001);
+            }
 
-                0x8A => self.txa(),
+            0xD8 => {
+                self.processor_status &= 0b1111_0111;
+            }
 
-                0xA8 => self.tay(),
+            0x58 => {
+                self.processor_status &= 0b1111_1011;
+            }
 
-                0xBA => self.tsx(),
+            0xB8 => {
+                self.processor_status &= 0b1011_1111;
+            }
 
-                0x9a => self.txs(),
+            0x18 => {
+                self.processor_status &= 0b1111_1110;
+            }
 
-                0xE8 => self.inx(),
+            0x38 => {
+                self.processor_status |= 0b0000_0001;
+            }
 
-                0xC8 => self.iny(),
+            0x78 => {
+                self.processor_status |= 0b0000_0100;
+            }
 
-                0xCA => self.dex(),
+            0xF8 => {
+                self.processor_status |= 0b0000_1000;
+            }
 
-                0x88 => self.dey(),
+            0x4C => {
+                let mem_address = self.fetch_u16(self.program_counter);
+                self.program_counter = mem_address;
+            }
 
-                0x98 => self.tya(),
+            0x6c => {
+                let mem_address = self.fetch_u16(self.program_counter);
+                // let indirect_ref = self.memory_read_u16(mem_address);
+                //6502 bug mode with with page boundary:
+                //  if address $3000 contains $40, $30FF contains $80, and $3100 contains $50,
+                // the result of JMP ($30FF) will be a transfer of control to $4080 rather than $5080 as you intended
+                // i.e. the 6502 took the low byte of the address from $30FF and the high byte from $3000
+
+                let indirect_ref = if mem_address & 0x00FF == 0x00FF {
+                    let lo = self.memory_read(mem_address);
+                    let hi = self.memory_read(mem_address & 0xFF00);
+                    (hi as u16) << 8 | (lo as u16)
+                } else {
+                    self.memory_read_u16(mem_address)
+                };
+
+                self.program_counter = indirect_ref;
+            }
 
-                0x48 => self.stack_push(self.register_a),
+            0x20 => {
+                self.stack_push_u16(self.program_counter + 2 - 1);
+                let target_address = self.fetch_u16(self.program_counter);
+                self.program_counter = target_address
+            }
 
-                0x68 => self.pla(),
+            0x2a => self.rol_accumulator(),
+            
+            0x26 | 0x36 | 0x2e | 0x3e => {
+                self.rol(&opcode.mode);
+            }
 
-                0x08 => self.php(),
+            0x6a => self.ror_accumulator(),
 
-                0x28 => self.plp(),
+            0x66 | 0x76 | 0x6e | 0x7e => {
+                self.ror(&opcode.mode);
+            }
 
-                0x00 => return,
+            0x40 => {
+                self.processor_status = self.stack_pop();
+                self.processor_status &= 0b1110_1111;
+                self.processor_status |= 0b0010_0000;
 
-                0xea => {
-                    //do nothing
-                }
+                self.program_counter = self.stack_pop_u16();
+            }
 
-                _ => todo!(),
+            0x60 => {
+                self.program_counter = self.stack_pop_u16() + 1;
             }
 
-            if program_counter_state == self.program_counter {
-                self.program_counter += (opcode.len - 1) as u16;
+            0x0A => self.asl_accumulator(),
+
+            0xAA => self.tax(),
+
+            0x8A => self.txa(),
+
+            0xA8 => self.tay(),
+
+            0xBA => self.tsx(),
+
+            0x9a => self.txs(),
+
+            0xE8 => self.inx(),
+
+            0xC8 => self.iny(),
+
+            0xCA => self.dex(),
+
+            0x88 => self.dey(),
+
+            0x98 => self.tya(),
+
+            0x48 => self.stack_push(self.register_a),
+
+            0x68 => self.pla(),
+
+            0x08 => self.php(),
+
+            0x28 => self.plp(),
+
+            0x00 => return Some(Stop::Brk),
+
+            0xea => {
+                //do nothing
             }
+
+            _ => todo!(),
         }
+
+        if program_counter_state == self.program_counter {
+            self.program_counter += (opcode.len - 1) as u16;
+        }
+
+        self.watch_hit.take().map(Stop::Breakpoint)
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::breakpoint::{Breakpoint, CompareOp};
+    use crate::keyboard::Keyboard;
+    use crate::paddle::{Paddle, PaddleModel};
+    use crate::power_pad::PowerPad;
+    use crate::zapper::Zapper;
 
     #[test]
     fn test_0xa9_lda_is_loading_accumulator() {
@@ -1220,4 +1536,259 @@ mod test {
         assert!(cpu.processor_status & 1 == 1);
     }
 
+
+    #[test]
+    fn test_execute_breakpoint_stops_before_instruction() {
+        let mut cpu = CPU::new();
+        let id = cpu.breakpoints.add(Breakpoint::execute(0x8002));
+        cpu.load_and_run(vec![0xa9, 0x05, 0xaa, 0x00]);
+
+        assert_eq!(cpu.program_counter, 0x8002);
+        assert_eq!(cpu.register_a, 0x05);
+        assert_eq!(cpu.register_x, 0x00);
+
+        assert_eq!(cpu.resume(), Stop::Brk);
+        assert_eq!(cpu.register_x, 0x05);
+        assert!(cpu.breakpoints.remove(id).is_some());
+    }
+
+    #[test]
+    fn test_write_watchpoint_with_value() {
+        let mut cpu = CPU::new();
+        cpu.breakpoints.add(Breakpoint::write(0x0010..=0x001f).with_value(CompareOp::Equal, 0x02));
+        cpu.load(vec![0xa9, 0x01, 0x85, 0x10, 0xa9, 0x02, 0x85, 0x11, 0xe8, 0x00]);
+        cpu.reset();
+
+        match cpu.execute() {
+            Stop::Breakpoint(hit) => {
+                assert_eq!(hit.access, Access::Write);
+                assert_eq!(hit.addr, 0x0011);
+                assert_eq!(hit.value, 0x02);
+            }
+            stop => panic!("unexpected stop {:?}", stop),
+        }
+        assert_eq!(cpu.program_counter, 0x8008);
+        assert_eq!(cpu.resume(), Stop::Brk);
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_read_watchpoint_ignores_operand_fetch() {
+        let mut cpu = CPU::new();
+        cpu.breakpoints.add(Breakpoint::read(0x8000..=0xffff));
+        cpu.load(vec![0xa2, 0x00, 0xbd, 0x08, 0x80, 0xe8, 0x00, 0x00, 0x42]);
+        cpu.reset();
+
+        match cpu.execute() {
+            Stop::Breakpoint(hit) => assert_eq!((hit.addr, hit.value), (0x8008, 0x42)),
+            stop => panic!("unexpected stop {:?}", stop),
+        }
+        assert_eq!(cpu.register_a, 0x42);
+    }
+
+    #[test]
+    fn test_read_watchpoint_at_program_counter() {
+        let mut cpu = CPU::new();
+        cpu.breakpoints.add(Breakpoint::read(0x8001..=0x8001));
+        // LDA $8001 reads its own operand while the PC points at it
+        cpu.load(vec![0xad, 0x01, 0x80, 0x00]);
+        cpu.reset();
+
+        match cpu.execute() {
+            Stop::Breakpoint(hit) => assert_eq!((hit.addr, hit.value), (0x8001, 0x01)),
+            stop => panic!("unexpected stop {:?}", stop),
+        }
+        assert_eq!(cpu.register_a, 0x01);
+    }
+
+    #[test]
+    fn test_register_condition_breakpoint() {
+        let mut cpu = CPU::new();
+        cpu.breakpoints.add(Breakpoint::when("A == $FF && X > 3".parse().unwrap()));
+
+        /*
+            LDA #$FF
+        loop:
+            INX
+            CPX #$08
+            BNE loop
+            BRK
+        */
+        cpu.load(vec![0xa9, 0xff, 0xe8, 0xe0, 0x08, 0xd0, 0xfb, 0x00]);
+        cpu.reset();
+
+        assert!(matches!(cpu.execute(), Stop::Breakpoint(_)));
+        assert_eq!(cpu.register_x, 4);
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.register_x, 4);
+    }
+
+    #[test]
+    fn test_load_rom_mirrors_16k_prg() {
+        let rom = Rom::new(&crate::rom::test::test_rom(&[0xa9, 0x07, 0x00])).unwrap();
+        let mut cpu = CPU::new();
+        cpu.load_rom(&rom).unwrap();
+        cpu.reset();
+        cpu.execute();
+
+        assert_eq!(cpu.register_a, 0x07);
+        assert_eq!(cpu.memory_read(0xc000), 0xa9);
+        assert_eq!(cpu.program_checksum(), savestate::crc32(&rom.prg_rom));
+    }
+
+    #[test]
+    fn test_cheats_patch_code_fetches() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xa9, 0x07, 0x00]);
+        cpu.reset();
+        let id = cpu.cheats.add("8001:09:07".parse().unwrap());
+        cpu.execute();
+        assert_eq!(cpu.register_a, 0x09);
+
+        cpu.cheats.set_enabled(id, false);
+        cpu.reset();
+        cpu.execute();
+        assert_eq!(cpu.register_a, 0x07);
+    }
+
+    #[test]
+    fn test_cheats_force_ram() {
+        let mut cpu = CPU::new();
+        // LDA #$01, STA $75, LDA $75, BRK
+        cpu.load(vec![0xa9, 0x01, 0x85, 0x75, 0xa5, 0x75, 0x00]);
+        cpu.reset();
+        cpu.cheats.add("0075:09".parse().unwrap());
+        cpu.execute();
+        assert_eq!(cpu.register_a, 0x09);
+        assert_eq!(cpu.memory[0x75], 0x09);
+    }
+
+    #[test]
+    fn test_ppumask_writes_reach_pixels() {
+        let mut cpu = CPU::new();
+        // LDA #$21, STA $2009 (grayscale, emphasize red through a mirror)
+        cpu.load(vec![0xa9, 0x21, 0x8d, 0x09, 0x20, 0x00]);
+        cpu.reset();
+        cpu.draw_pixel(0, 0, 0x16);
+        assert_eq!(cpu.frame.pixel(0, 0), 0x16);
+
+        cpu.execute();
+        cpu.draw_pixel(1, 0, 0x16);
+        assert_eq!(cpu.frame.pixel(1, 0), 0x10 | 0b001 << 6);
+
+        cpu.region = Region::Pal;
+        cpu.draw_pixel(2, 0, 0x16);
+        assert_eq!(cpu.frame.pixel(2, 0), 0x10 | 0b010 << 6);
+    }
+
+    #[test]
+    fn test_peek_has_no_side_effects() {
+        let mut cpu = CPU::new();
+        cpu.joypads[0].set_buttons(0b0000_0001);
+        cpu.memory_write(0x4016, 1);
+        cpu.memory_write(0x4016, 0);
+        cpu.memory_write(0x0075, 0x01);
+        cpu.cheats.add("0075:09".parse().unwrap());
+        cpu.breakpoints.add(Breakpoint::read(0x0000..=0xffff));
+
+        cpu.peek(0x4016);
+        assert_eq!(cpu.peek(0x0075), 0x01);
+        assert_eq!(cpu.watch_hit.get(), None);
+        assert_eq!(cpu.memory_read(0x4016) & 1, 1);
+    }
+
+    #[test]
+    fn test_mapper_registers_and_irq() {
+        // MMC5 powers on with the last 8 KiB bank at $E000
+        let mut prg_rom = vec![0; 0x8000];
+        let program = [
+            0xa9, 0x0a, 0x8d, 0x05, 0x52, // LDA #$0A, STA $5205
+            0xa9, 0x0b, 0x8d, 0x06, 0x52, // LDA #$0B, STA $5206
+            0xad, 0x05, 0x52, 0xaa, // LDA $5205, TAX
+            0xa9, 0x01, 0x8d, 0x03, 0x52, // LDA #$01, STA $5203
+            0xa9, 0x80, 0x8d, 0x04, 0x52, // LDA #$80, STA $5204
+            0x58, 0x4c, 0x19, 0xe0, // CLI, loop: JMP loop
+        ];
+        prg_rom[0x6000..0x6000 + program.len()].copy_from_slice(&program);
+        prg_rom[0x6100..0x6104].copy_from_slice(&[0xad, 0x04, 0x52, 0x00]); // LDA $5204, BRK
+        prg_rom[0x7ffc..].copy_from_slice(&[0x00, 0xe0, 0x00, 0xe1]);
+        let mut raw = vec![0x4e, 0x45, 0x53, 0x1a, 0x02, 0x01, 0x50, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        raw.extend(prg_rom);
+        raw.extend(vec![0; 0x2000]);
+
+        let mut cpu = CPU::new();
+        cpu.load_rom(&Rom::new(&raw).unwrap()).unwrap();
+        cpu.reset();
+        cpu.run_for(14);
+        assert_eq!(cpu.register_x, 110);
+        assert_eq!(cpu.program_counter, 0xe019);
+
+        let mapper = cpu.mapper.as_mut().unwrap();
+        mapper.ppu_scanline(0, true);
+        mapper.ppu_scanline(1, true);
+        let cycles = cpu.cycles;
+        assert_eq!(cpu.run_for(2), Some(Stop::Brk));
+        assert_eq!(cpu.register_a, 0xc0);
+        assert_eq!(cpu.cycles, cycles + 7 + 4 + 7);
+        assert_eq!(cpu.processor_status & 0b0000_0100, 0b0000_0100);
+        // P went on the stack with B clear, above it the interrupted PC
+        assert_eq!(cpu.memory_read(0x01fb) & 0b0011_0000, 0b0010_0000);
+        assert_eq!(cpu.memory_read_u16(0x01fc), 0xe019);
+    }
+
+    #[test]
+    fn test_famicom_four_players() {
+        let mut cpu = CPU::new();
+        cpu.multitap = Multitap::Famicom;
+        cpu.joypads[1].set_buttons(0b0000_0001);
+        cpu.joypads[3].set_buttons(0b0000_0010);
+        cpu.memory_write(0x4016, 1);
+        cpu.memory_write(0x4016, 0);
+        let bits: Vec<u8> = (0..3).map(|_| cpu.memory_read(0x4017)).collect();
+        assert_eq!(bits, vec![0b01, 0b10, 0b00]);
+        assert_eq!(cpu.memory_read(0x4016), 0);
+    }
+
+    #[test]
+    fn test_input_devices() {
+        let mut cpu = CPU::new();
+        cpu.joypads[0].set_buttons(0b0000_0001);
+        cpu.plug(Connector::Expansion, Box::new(Paddle::new(PaddleModel::Famicom)));
+        cpu.plug(Connector::Port2, Box::new(PowerPad::new()));
+        cpu.input_device_mut::<Paddle>().unwrap().set_button(true);
+        cpu.input_device_mut::<PowerPad>().unwrap().set_button(2, true).unwrap();
+        assert!(cpu.input_device_mut::<Keyboard>().is_none());
+
+        cpu.memory_write(0x4016, 1);
+        cpu.memory_write(0x4016, 0);
+        // the joypad and the paddle's button share $4016
+        assert_eq!(cpu.memory_read(0x4016), 0b0_0011);
+        // mat button 2 on bit 3, the paddle's knob inverted on bit 1
+        assert_eq!(cpu.memory_read(0x4017) & 0b0_1010, 0b0_1010);
+    }
+
+    #[test]
+    fn test_zapper_on_port_2() {
+        let mut cpu = CPU::new();
+        cpu.joypads[1].set_buttons(0xff);
+        assert_eq!(cpu.memory_read(0x4017), 1);
+
+        let mut zapper = Zapper::new();
+        zapper.aim(10, 10);
+        zapper.set_trigger(true);
+        assert!(cpu.plug(Connector::Port2, Box::new(zapper)).is_none());
+        cpu.frame.set_pixel(10, 10, 0x30);
+        assert_eq!(cpu.memory_read(0x4017), 0b0001_1000);
+        cpu.beam = Beam { scanline: 12, dot: 0 };
+        assert_eq!(cpu.memory_read(0x4017), 0b0001_0000);
+        cpu.input_device_mut::<Zapper>().unwrap().set_trigger(false);
+        assert_eq!(cpu.memory_read(0x4017), 0b0000_0000);
+
+        // a mat plugged in takes the Zapper's place
+        let zapper = cpu.plug(Connector::Port2, Box::new(PowerPad::new())).unwrap();
+        assert!(cpu.input_device_mut::<Zapper>().is_none());
+        cpu.plug(Connector::Port2, zapper);
+        assert!(cpu.unplug(Connector::Port2).is_some());
+        assert_eq!(cpu.memory_read(0x4017), 1);
+    }
 }
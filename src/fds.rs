@@ -0,0 +1,1206 @@
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
+
+use crate::mapper::{mirror_vram, mirroring_from_u8, Fetch, Mapper, StateReader, StateWriter};
+use crate::rom::Mirroring;
+
+const FWNES_TAG: &[u8; 4] = b"FDS\x1a";
+pub const SIDE_SIZE: usize = 65500;
+const BIOS_SIZE: usize = 0x2000;
+const PRG_RAM_SIZE: usize = 0x8000;
+const CHR_RAM_SIZE: usize = 0x2000;
+const DISK_INFO: &[u8] = b"\x01*NINTENDO-HVC*";
+
+// gaps as the drive sees them, in bytes
+const LEADING_GAP: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+const START_MARK: u8 = 0x80;
+// CPU cycles per byte at about 96 kbit/s
+const BYTE_CYCLES: usize = 149;
+// the head travelling back to the start of the disk
+const REWIND_CYCLES: usize = 50000;
+// how long a disk stays out while switching sides, about a second, long
+// enough for the BIOS to notice
+const SWAP_CYCLES: usize = 1_800_000;
+
+// full volume is about 2.4 times a full volume APU pulse
+pub const LEVEL: f32 = 0.36 / (63.0 * 32.0);
+
+// disk sides as stored in a .fds file: blocks back to back, no gaps or CRCs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskImage {
+    pub sides: Vec<Vec<u8>>,
+}
+
+impl DiskImage {
+    // with or without the 16 byte fwNES header
+    pub fn new(raw: &[u8]) -> Result<DiskImage, String> {
+        let data = if raw.starts_with(FWNES_TAG) {
+            if raw.len() < 16 {
+                return Err("FDS header is truncated".to_string());
+            }
+            let len = raw[4] as usize * SIDE_SIZE;
+            if raw.len() < 16 + len {
+                return Err("FDS image is truncated".to_string());
+            }
+            &raw[16..16 + len]
+        } else {
+            raw
+        };
+        if data.is_empty() || !data.len().is_multiple_of(SIDE_SIZE) {
+            return Err(format!("FDS image of {} bytes is not made of {} byte sides", data.len(), SIDE_SIZE));
+        }
+
+        let sides: Vec<Vec<u8>> = data.chunks(SIDE_SIZE).map(|side| side.to_vec()).collect();
+        if let Some(side) = sides.iter().position(|side| !side.starts_with(DISK_INFO)) {
+            return Err(format!("side {} has no disk info block", side));
+        }
+        Ok(DiskImage { sides })
+    }
+
+    // the headerless image, what diff files are made against
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.sides.concat()
+    }
+}
+
+// a fwNES header or the .fds extension, headerless images have no magic
+pub fn is_disk_image(path: &Path, raw: &[u8]) -> bool {
+    raw.starts_with(FWNES_TAG) || path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("fds"))
+}
+
+// game.fds -> game.ips, disk writes go there and the image stays untouched
+pub fn diff_path(image: &Path) -> PathBuf {
+    image.with_extension("ips")
+}
+
+// disk info, file count, file header, file data
+fn block_len(kind: u8, file_size: usize) -> Option<usize> {
+    match kind {
+        1 => Some(56),
+        2 => Some(2),
+        3 => Some(16),
+        4 => Some(1 + file_size),
+        _ => None,
+    }
+}
+
+fn update_crc(mut crc: u16, data: u8) -> u16 {
+    for bit in 0..8 {
+        let carry = crc & 1 != 0;
+        crc = (crc >> 1) | ((data >> bit) as u16 & 1) << 15;
+        if carry {
+            crc ^= 0x8408;
+        }
+    }
+    crc
+}
+
+fn finish_crc(mut crc: u16) -> u16 {
+    for _ in 0..16 {
+        let carry = crc & 1 != 0;
+        crc >>= 1;
+        if carry {
+            crc ^= 0x8408;
+        }
+    }
+    crc
+}
+
+fn block_crc(block: &[u8]) -> u16 {
+    finish_crc(block.iter().fold(0x8000, |crc, &data| update_crc(crc, data)))
+}
+
+// the side as it passes under the head: gaps, start marks and CRCs around
+// each block, then the side's free space
+fn encode_side(side: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0; LEADING_GAP];
+    let mut pos = 0;
+    let mut file_size = 0;
+    while pos < side.len() {
+        let Some(len) = block_len(side[pos], file_size).filter(|len| pos + len <= side.len()) else {
+            break;
+        };
+        let block = &side[pos..pos + len];
+        if block[0] == 3 {
+            file_size = u16::from_le_bytes([block[13], block[14]]) as usize;
+        }
+        raw.push(START_MARK);
+        raw.extend_from_slice(block);
+        raw.extend_from_slice(&block_crc(block).to_le_bytes());
+        raw.resize(raw.len() + BLOCK_GAP, 0);
+        pos += len;
+    }
+    raw.resize(raw.len() + side.len() - pos, 0);
+    raw
+}
+
+fn decode_side(raw: &[u8]) -> Vec<u8> {
+    let mut side = Vec::with_capacity(SIDE_SIZE);
+    let mut pos = 0;
+    let mut file_size = 0;
+    loop {
+        while pos < raw.len() && raw[pos] == 0 {
+            pos += 1;
+        }
+        if raw.get(pos) != Some(&START_MARK) {
+            break;
+        }
+        pos += 1;
+        let Some(len) = raw.get(pos).and_then(|&kind| block_len(kind, file_size)).filter(|len| pos + len <= raw.len()) else {
+            break;
+        };
+        let block = &raw[pos..pos + len];
+        if block[0] == 3 {
+            file_size = u16::from_le_bytes([block[13], block[14]]) as usize;
+        }
+        side.extend_from_slice(block);
+        pos += len + 2;
+    }
+    side.resize(SIDE_SIZE, 0);
+    side
+}
+
+// IPS patch from `original` to `modified`, both the same length
+fn ips_diff(original: &[u8], modified: &[u8]) -> Vec<u8> {
+    let mut patch = b"PATCH".to_vec();
+    let mut pos = 0;
+    while pos < modified.len() {
+        if original[pos] == modified[pos] {
+            pos += 1;
+            continue;
+        }
+        // an offset spelling "EOF" would end the patch
+        let start = if pos == 0x454f46 { pos - 1 } else { pos };
+        let mut end = pos;
+        while end < modified.len() && end - start < 0xffff && original[end] != modified[end] {
+            end += 1;
+        }
+        patch.extend_from_slice(&(start as u32).to_be_bytes()[1..]);
+        patch.extend_from_slice(&((end - start) as u16).to_be_bytes());
+        patch.extend_from_slice(&modified[start..end]);
+        pos = end;
+    }
+    patch.extend_from_slice(b"EOF");
+    patch
+}
+
+fn ips_apply(data: &mut [u8], patch: &[u8]) -> Result<(), String> {
+    let truncated = || "diff file is truncated".to_string();
+    if !patch.starts_with(b"PATCH") {
+        return Err("diff file is not an IPS patch".to_string());
+    }
+    let mut pos = 5;
+    loop {
+        let record = patch.get(pos..pos + 3).ok_or_else(truncated)?;
+        if record == b"EOF" {
+            return Ok(());
+        }
+        let offset = u32::from_be_bytes([0, record[0], record[1], record[2]]) as usize;
+        let size = patch.get(pos + 3..pos + 5).ok_or_else(truncated)?;
+        let size = u16::from_be_bytes([size[0], size[1]]) as usize;
+        pos += 5;
+        // a zero size is a run of one byte
+        let (len, bytes) = if size == 0 {
+            let run = patch.get(pos..pos + 3).ok_or_else(truncated)?;
+            pos += 3;
+            (u16::from_be_bytes([run[0], run[1]]) as usize, None)
+        } else {
+            let bytes = patch.get(pos..pos + size).ok_or_else(truncated)?;
+            pos += size;
+            (size, Some(bytes))
+        };
+        let target = data
+            .get_mut(offset..offset + len)
+            .ok_or_else(|| format!("diff writes past the end of the disk at {:#x}", offset))?;
+        match bytes {
+            Some(bytes) => target.copy_from_slice(bytes),
+            None => target.fill(patch[pos - 1]),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Envelope {
+    gain: u8,
+    speed: u8,
+    increase: bool,
+    disabled: bool,
+    timer: usize,
+}
+
+impl Envelope {
+    fn write(&mut self, data: u8) {
+        self.speed = data & 0x3f;
+        self.increase = data & 0x40 != 0;
+        self.disabled = data & 0x80 != 0;
+        self.timer = 0;
+        if self.disabled {
+            self.gain = data & 0x3f;
+        }
+    }
+
+    fn clock(&mut self, master_speed: u8) {
+        if self.disabled {
+            return;
+        }
+        self.timer += 1;
+        if self.timer < 8 * (master_speed as usize + 1) * (self.speed as usize + 1) {
+            return;
+        }
+        self.timer = 0;
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        state.u8(self.gain);
+        state.u8(self.speed);
+        state.bool(self.increase);
+        state.bool(self.disabled);
+        state.u32(self.timer as u32);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.gain = state.u8()?;
+        self.speed = state.u8()?;
+        self.increase = state.bool()?;
+        self.disabled = state.bool()?;
+        self.timer = state.u32()? as usize;
+        Ok(())
+    }
+}
+
+// modulation table entries: counter steps, 4 resets the counter
+const MOD_STEPS: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+
+// one 64 step wavetable channel with a frequency modulator
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FdsAudio {
+    wave: [u8; 64],
+    wave_write: bool,
+    master_volume: u8,
+    envelope_speed: u8,
+    volume: Envelope,
+    sweep: Envelope,
+    frequency: u16,
+    wave_halted: bool,
+    envelopes_halted: bool,
+    wave_accumulator: u32,
+    sample: u8,
+    mod_table: [u8; 64],
+    mod_position: u8,
+    // 7 bit signed
+    mod_counter: i8,
+    mod_frequency: u16,
+    mod_halted: bool,
+    mod_accumulator: u32,
+}
+
+impl Default for FdsAudio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FdsAudio {
+    pub fn new() -> Self {
+        FdsAudio {
+            wave: [0; 64],
+            wave_write: false,
+            master_volume: 0,
+            envelope_speed: 0xe8,
+            volume: Envelope::default(),
+            sweep: Envelope::default(),
+            frequency: 0,
+            wave_halted: true,
+            envelopes_halted: false,
+            wave_accumulator: 0,
+            sample: 0,
+            mod_table: [0; 64],
+            mod_position: 0,
+            mod_counter: 0,
+            mod_frequency: 0,
+            mod_halted: true,
+            mod_accumulator: 0,
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x4040..=0x407f => self.wave[addr as usize - 0x4040] | 0x40,
+            0x4090 => self.volume.gain | 0x40,
+            0x4092 => self.sweep.gain | 0x40,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4040..=0x407f if self.wave_write => self.wave[addr as usize - 0x4040] = data & 0x3f,
+            0x4080 => self.volume.write(data),
+            0x4082 => self.frequency = (self.frequency & 0x0f00) | data as u16,
+            0x4083 => {
+                self.frequency = (self.frequency & 0x00ff) | (data as u16 & 0x0f) << 8;
+                self.wave_halted = data & 0x80 != 0;
+                self.envelopes_halted = data & 0x40 != 0;
+                if self.wave_halted {
+                    self.wave_accumulator = 0;
+                }
+            }
+            0x4084 => self.sweep.write(data),
+            0x4085 => self.mod_counter = ((data << 1) as i8) >> 1,
+            0x4086 => self.mod_frequency = (self.mod_frequency & 0x0f00) | data as u16,
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0x00ff) | (data as u16 & 0x0f) << 8;
+                self.mod_halted = data & 0x80 != 0;
+                if self.mod_halted {
+                    self.mod_accumulator = 0;
+                }
+            }
+            // written in pairs while the modulator is halted
+            0x4088 if self.mod_halted => {
+                let position = self.mod_position as usize;
+                self.mod_table[position] = data & 0x07;
+                self.mod_table[position + 1] = data & 0x07;
+                self.mod_position = (self.mod_position + 2) & 0x3f;
+            }
+            0x4089 => {
+                self.wave_write = data & 0x80 != 0;
+                self.master_volume = data & 0x03;
+            }
+            0x408a => self.envelope_speed = data,
+            _ => {}
+        }
+    }
+
+    // the frequency bent by the modulator, as the hardware rounds it
+    fn pitch(&self) -> u32 {
+        let counter = self.mod_counter as i32;
+        let mut bend = counter * self.sweep.gain as i32;
+        let remainder = bend & 0x0f;
+        bend >>= 4;
+        if remainder > 0 && bend & 0x80 == 0 {
+            bend += if counter < 0 { -1 } else { 2 };
+        }
+        if bend >= 192 {
+            bend -= 256;
+        } else if bend < -64 {
+            bend += 256;
+        }
+        let mut bend = self.frequency as i32 * bend;
+        let remainder = bend & 0x3f;
+        bend >>= 6;
+        if remainder >= 32 {
+            bend += 1;
+        }
+        (self.frequency as i32 + bend).max(0) as u32
+    }
+
+    fn step_modulator(&mut self) {
+        let entry = self.mod_table[self.mod_position as usize];
+        self.mod_position = (self.mod_position + 1) & 0x3f;
+        self.mod_counter = if entry == 4 {
+            0
+        } else {
+            // wraps within 7 bits
+            (self.mod_counter.wrapping_add(MOD_STEPS[entry as usize]) << 1) >> 1
+        };
+    }
+
+    // one CPU cycle
+    pub fn clock(&mut self) {
+        if !self.envelopes_halted && !self.wave_halted && self.envelope_speed != 0 {
+            self.volume.clock(self.envelope_speed);
+            self.sweep.clock(self.envelope_speed);
+        }
+
+        if !self.mod_halted && self.mod_frequency != 0 {
+            self.mod_accumulator += self.mod_frequency as u32;
+            if self.mod_accumulator >= 0x10000 {
+                self.mod_accumulator -= 0x10000;
+                self.step_modulator();
+            }
+        }
+
+        // the output holds while the table is written
+        if !self.wave_halted && !self.wave_write {
+            self.wave_accumulator = (self.wave_accumulator + self.pitch()) & 0x3f_ffff;
+            self.sample = self.wave[(self.wave_accumulator >> 16) as usize];
+        }
+    }
+
+    pub fn output(&self) -> f32 {
+        let master = [1.0, 2.0 / 3.0, 2.0 / 4.0, 2.0 / 5.0][self.master_volume as usize];
+        self.sample as f32 * self.volume.gain.min(32) as f32 * master * LEVEL
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        state.bytes(&self.wave);
+        state.bool(self.wave_write);
+        state.u8(self.master_volume);
+        state.u8(self.envelope_speed);
+        self.volume.save(state);
+        self.sweep.save(state);
+        state.u16(self.frequency);
+        state.bool(self.wave_halted);
+        state.bool(self.envelopes_halted);
+        state.u32(self.wave_accumulator);
+        state.u8(self.sample);
+        state.bytes(&self.mod_table);
+        state.u8(self.mod_position);
+        state.u8(self.mod_counter as u8);
+        state.u16(self.mod_frequency);
+        state.bool(self.mod_halted);
+        state.u32(self.mod_accumulator);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.wave.copy_from_slice(state.bytes(64)?);
+        self.wave_write = state.bool()?;
+        self.master_volume = state.u8()? & 0x03;
+        self.envelope_speed = state.u8()?;
+        self.volume.load(state)?;
+        self.sweep.load(state)?;
+        self.frequency = state.u16()?;
+        self.wave_halted = state.bool()?;
+        self.envelopes_halted = state.bool()?;
+        self.wave_accumulator = state.u32()? & 0x3f_ffff;
+        self.sample = state.u8()? & 0x3f;
+        self.mod_table.copy_from_slice(state.bytes(64)?);
+        self.mod_position = state.u8()? & 0x3f;
+        self.mod_counter = state.u8()? as i8;
+        self.mod_frequency = state.u16()?;
+        self.mod_halted = state.bool()?;
+        self.mod_accumulator = state.u32()?;
+        if self.mod_table.iter().any(|&entry| entry > 7) {
+            return Err("FDS modulation table is corrupt".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Famicom Disk System: the RAM adapter with the BIOS, the drive and the
+// sound chip. Games load from disk into 32 KiB of PRG RAM and 8 KiB of CHR
+// RAM.
+#[derive(Clone)]
+pub struct Fds {
+    bios: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr_ram: Vec<u8>,
+    // what the diff file is made against
+    original: Vec<u8>,
+    // sides as the drive sees them, see encode_side
+    sides: Vec<Vec<u8>>,
+    side: Option<usize>,
+    // inserted once `swap_delay` runs out
+    next_side: Option<usize>,
+    swap_delay: usize,
+
+    disk_io_enabled: bool,
+    sound_enabled: bool,
+    mirroring: Mirroring,
+
+    timer_reload: u16,
+    timer_counter: u16,
+    timer_repeat: bool,
+    timer_enabled: bool,
+    timer_irq: Cell<bool>,
+
+    // $4025
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    crc_control: bool,
+    disk_ready: bool,
+    disk_irq_enabled: bool,
+
+    position: usize,
+    delay: usize,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    // inside a block, since its start mark
+    in_block: bool,
+    crc: u16,
+    crc_error: bool,
+    read_data: u8,
+    write_data: u8,
+    transfer_flag: Cell<bool>,
+    disk_irq: Cell<bool>,
+    external: u8,
+
+    audio: FdsAudio,
+}
+
+impl Fds {
+    pub fn new(bios: &[u8], disk: &DiskImage) -> Result<Self, String> {
+        if bios.len() != BIOS_SIZE {
+            return Err(format!("FDS BIOS must be {} bytes, got {}", BIOS_SIZE, bios.len()));
+        }
+        Ok(Fds {
+            bios: bios.to_vec(),
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            chr_ram: vec![0; CHR_RAM_SIZE],
+            original: disk.to_bytes(),
+            sides: disk.sides.iter().map(|side| encode_side(side)).collect(),
+            side: Some(0),
+            next_side: None,
+            swap_delay: 0,
+            disk_io_enabled: false,
+            sound_enabled: false,
+            mirroring: Mirroring::Vertical,
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_repeat: false,
+            timer_enabled: false,
+            timer_irq: Cell::new(false),
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            crc_control: false,
+            disk_ready: false,
+            disk_irq_enabled: false,
+            position: 0,
+            delay: 0,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            in_block: false,
+            crc: 0,
+            crc_error: false,
+            read_data: 0,
+            write_data: 0,
+            transfer_flag: Cell::new(false),
+            disk_irq: Cell::new(false),
+            external: 0,
+            audio: FdsAudio::new(),
+        })
+    }
+
+    pub fn side_count(&self) -> usize {
+        self.sides.len()
+    }
+
+    // the inserted side, None while the drive is empty
+    pub fn side(&self) -> Option<usize> {
+        self.side
+    }
+
+    pub fn eject(&mut self) {
+        self.side = None;
+        self.next_side = None;
+        self.scanning = false;
+        self.end_of_head = true;
+    }
+
+    pub fn insert(&mut self, side: usize) -> Result<(), String> {
+        if side >= self.sides.len() {
+            return Err(format!("the disk has {} sides, no side {}", self.sides.len(), side));
+        }
+        self.eject();
+        self.side = Some(side);
+        Ok(())
+    }
+
+    // ejects now and inserts `side` a second later, like a player flipping
+    // the disk
+    pub fn switch_side(&mut self, side: usize) -> Result<(), String> {
+        if side >= self.sides.len() {
+            return Err(format!("the disk has {} sides, no side {}", self.sides.len(), side));
+        }
+        self.eject();
+        self.next_side = Some(side);
+        self.swap_delay = SWAP_CYCLES;
+        Ok(())
+    }
+
+    // the disk as a headerless .fds image, with everything games wrote
+    pub fn image(&self) -> Vec<u8> {
+        self.sides.iter().flat_map(|side| decode_side(side)).collect()
+    }
+
+    // IPS patch from the loaded image to the current disk
+    pub fn diff(&self) -> Vec<u8> {
+        ips_diff(&self.original, &self.image())
+    }
+
+    pub fn apply_diff(&mut self, patch: &[u8]) -> Result<(), String> {
+        let mut image = self.original.clone();
+        ips_apply(&mut image, patch)?;
+        let disk = DiskImage::new(&image)?;
+        self.sides = disk.sides.iter().map(|side| encode_side(side)).collect();
+        Ok(())
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = self.timer_irq.get() as u8 | (self.transfer_flag.get() as u8) << 1;
+        if self.crc_error {
+            status |= 0x10;
+        }
+        if self.end_of_head {
+            status |= 0x40;
+        }
+        status
+    }
+
+    fn drive_status(&self) -> u8 {
+        let mut status = 0x40;
+        if self.side.is_none() {
+            status |= 0x05;
+        }
+        if self.side.is_none() || !self.scanning {
+            status |= 0x02;
+        }
+        status
+    }
+
+    fn write_control(&mut self, data: u8) {
+        self.disk_irq.set(false);
+        self.motor_on = data & 0x01 != 0;
+        self.reset_transfer = data & 0x02 != 0;
+        self.read_mode = data & 0x04 != 0;
+        self.mirroring = if data & 0x08 != 0 { Mirroring::Horizontal } else { Mirroring::Vertical };
+        self.crc_control = data & 0x10 != 0;
+        self.disk_ready = data & 0x40 != 0;
+        self.disk_irq_enabled = data & 0x80 != 0;
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.timer_enabled {
+            return;
+        }
+        if self.timer_counter == 0 {
+            self.timer_irq.set(true);
+            self.timer_counter = self.timer_reload;
+            if !self.timer_repeat {
+                self.timer_enabled = false;
+            }
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
+
+    fn read_byte(&mut self, data: u8) -> bool {
+        if !self.disk_ready {
+            self.gap_ended = false;
+            return false;
+        }
+        // the start mark is latched, but doesn't interrupt
+        if data != 0 && !self.gap_ended {
+            self.gap_ended = true;
+            self.in_block = true;
+            self.crc = 0x8000;
+            self.crc_error = false;
+            self.read_data = data;
+            self.transfer_flag.set(true);
+            return false;
+        }
+        if !self.gap_ended {
+            return false;
+        }
+        if self.crc_control {
+            if self.in_block {
+                self.crc = finish_crc(self.crc);
+                self.in_block = false;
+                self.crc_error |= data != self.crc as u8;
+            } else {
+                self.crc_error |= data != (self.crc >> 8) as u8;
+            }
+        } else {
+            self.crc = update_crc(self.crc, data);
+        }
+        self.read_data = data;
+        self.transfer_flag.set(true);
+        true
+    }
+
+    fn write_byte(&mut self) -> (u8, bool) {
+        self.gap_ended = false;
+        if !self.disk_ready {
+            self.in_block = false;
+            return (0, true);
+        }
+        if self.crc_control {
+            if self.in_block {
+                self.crc = finish_crc(self.crc);
+                self.in_block = false;
+                return (self.crc as u8, false);
+            }
+            return ((self.crc >> 8) as u8, false);
+        }
+        let data = self.write_data;
+        if self.in_block {
+            self.crc = update_crc(self.crc, data);
+        } else if data == START_MARK {
+            self.in_block = true;
+            self.crc = 0x8000;
+        }
+        (data, true)
+    }
+
+    fn clock_drive(&mut self) {
+        if self.next_side.is_some() {
+            self.swap_delay = self.swap_delay.saturating_sub(1);
+            if self.swap_delay == 0 {
+                self.side = self.next_side.take();
+            }
+        }
+        let Some(side) = self.side.filter(|_| self.motor_on) else {
+            self.end_of_head = true;
+            self.scanning = false;
+            return;
+        };
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = REWIND_CYCLES;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+        self.scanning = true;
+
+        let interrupt = if self.read_mode {
+            let data = self.sides[side][self.position];
+            self.read_byte(data)
+        } else {
+            let (data, transfer) = self.write_byte();
+            self.sides[side][self.position] = data;
+            if transfer {
+                self.transfer_flag.set(true);
+            }
+            transfer
+        };
+        if interrupt && self.disk_irq_enabled {
+            self.disk_irq.set(true);
+        }
+
+        self.position += 1;
+        // the motor stops at the end and rewinds when it's turned back on
+        if self.position >= self.sides[side].len() {
+            self.motor_on = false;
+            self.end_of_head = true;
+            self.scanning = false;
+        } else {
+            self.delay = BYTE_CYCLES;
+        }
+    }
+}
+
+impl Mapper for Fds {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            // reading the status acknowledges both interrupts
+            0x4030 if self.disk_io_enabled => {
+                let status = self.status();
+                self.timer_irq.set(false);
+                self.transfer_flag.set(false);
+                self.disk_irq.set(false);
+                status
+            }
+            0x4031 if self.disk_io_enabled => {
+                self.transfer_flag.set(false);
+                self.disk_irq.set(false);
+                self.read_data
+            }
+            0x4032 if self.disk_io_enabled => self.drive_status(),
+            // the expansion port, bit 7 is the battery
+            0x4033 if self.disk_io_enabled => 0x80,
+            0x4040..=0x4097 if self.sound_enabled => self.audio.read(addr),
+            0x6000..=0xdfff => self.prg_ram[addr as usize - 0x6000],
+            0xe000..=0xffff => self.bios[addr as usize - 0xe000],
+            _ => 0,
+        }
+    }
+
+    fn cpu_peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x4030 if self.disk_io_enabled => self.status(),
+            0x4031 if self.disk_io_enabled => self.read_data,
+            _ => self.cpu_read(addr),
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4020 => self.timer_reload = (self.timer_reload & 0xff00) | data as u16,
+            0x4021 => self.timer_reload = (self.timer_reload & 0x00ff) | (data as u16) << 8,
+            0x4022 => {
+                self.timer_repeat = data & 0x01 != 0;
+                self.timer_enabled = data & 0x02 != 0 && self.disk_io_enabled;
+                self.timer_irq.set(false);
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                }
+            }
+            0x4023 => {
+                self.disk_io_enabled = data & 0x01 != 0;
+                self.sound_enabled = data & 0x02 != 0;
+                if !self.disk_io_enabled {
+                    self.timer_enabled = false;
+                    self.timer_irq.set(false);
+                    self.disk_irq.set(false);
+                }
+            }
+            0x4024 if self.disk_io_enabled => {
+                self.write_data = data;
+                self.transfer_flag.set(false);
+                self.disk_irq.set(false);
+            }
+            0x4025 if self.disk_io_enabled => self.write_control(data),
+            0x4026 if self.disk_io_enabled => self.external = data,
+            0x4040..=0x4097 if self.sound_enabled => self.audio.write(addr, data),
+            0x6000..=0xdfff => self.prg_ram[addr as usize - 0x6000] = data,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16, _fetch: Fetch, vram: &[u8; 0x800]) -> u8 {
+        let addr = addr & 0x3fff;
+        if addr < 0x2000 {
+            self.chr_ram[addr as usize]
+        } else {
+            vram[mirror_vram(addr, self.mirroring)]
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8, vram: &mut [u8; 0x800]) {
+        let addr = addr & 0x3fff;
+        if addr < 0x2000 {
+            self.chr_ram[addr as usize] = data;
+        } else {
+            vram[mirror_vram(addr, self.mirroring)] = data;
+        }
+    }
+
+    fn clock(&mut self, cycles: usize) {
+        for _ in 0..cycles {
+            self.clock_timer();
+            self.clock_drive();
+            self.audio.clock();
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.timer_irq.get() || self.disk_irq.get()
+    }
+
+    fn audio(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn disk_drive(&self) -> Option<&Fds> {
+        Some(self)
+    }
+
+    fn disk_drive_mut(&mut self) -> Option<&mut Fds> {
+        Some(self)
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        state.bytes(&self.prg_ram);
+        state.bytes(&self.chr_ram);
+        for side in &self.sides {
+            state.u32(side.len() as u32);
+            state.bytes(side);
+        }
+        state.u8(self.side.map_or(0xff, |side| side as u8));
+        state.u8(self.next_side.map_or(0xff, |side| side as u8));
+        state.u32(self.swap_delay as u32);
+        state.bool(self.disk_io_enabled);
+        state.bool(self.sound_enabled);
+        state.u8(self.mirroring as u8);
+        state.u16(self.timer_reload);
+        state.u16(self.timer_counter);
+        state.bool(self.timer_repeat);
+        state.bool(self.timer_enabled);
+        state.bool(self.timer_irq.get());
+        state.bool(self.motor_on);
+        state.bool(self.reset_transfer);
+        state.bool(self.read_mode);
+        state.bool(self.crc_control);
+        state.bool(self.disk_ready);
+        state.bool(self.disk_irq_enabled);
+        state.u32(self.position as u32);
+        state.u32(self.delay as u32);
+        state.bool(self.end_of_head);
+        state.bool(self.scanning);
+        state.bool(self.gap_ended);
+        state.bool(self.in_block);
+        state.u16(self.crc);
+        state.bool(self.crc_error);
+        state.u8(self.read_data);
+        state.u8(self.write_data);
+        state.bool(self.transfer_flag.get());
+        state.bool(self.disk_irq.get());
+        state.u8(self.external);
+        self.audio.save(&mut state);
+        state.data
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut state = StateReader::new(data);
+        let mut restored = self.clone();
+        restored.prg_ram.copy_from_slice(state.bytes(PRG_RAM_SIZE)?);
+        restored.chr_ram.copy_from_slice(state.bytes(CHR_RAM_SIZE)?);
+        for side in restored.sides.iter_mut() {
+            let len = state.u32()? as usize;
+            *side = state.bytes(len)?.to_vec();
+        }
+        let side_number = |value: u8| match value {
+            0xff => Ok(None),
+            side if (side as usize) < self.sides.len() => Ok(Some(side as usize)),
+            side => Err(format!("the disk has no side {}", side)),
+        };
+        restored.side = side_number(state.u8()?)?;
+        restored.next_side = side_number(state.u8()?)?;
+        restored.swap_delay = state.u32()? as usize;
+        restored.disk_io_enabled = state.bool()?;
+        restored.sound_enabled = state.bool()?;
+        restored.mirroring = mirroring_from_u8(state.u8()?)?;
+        restored.timer_reload = state.u16()?;
+        restored.timer_counter = state.u16()?;
+        restored.timer_repeat = state.bool()?;
+        restored.timer_enabled = state.bool()?;
+        restored.timer_irq.set(state.bool()?);
+        restored.motor_on = state.bool()?;
+        restored.reset_transfer = state.bool()?;
+        restored.read_mode = state.bool()?;
+        restored.crc_control = state.bool()?;
+        restored.disk_ready = state.bool()?;
+        restored.disk_irq_enabled = state.bool()?;
+        restored.position = state.u32()? as usize;
+        restored.delay = state.u32()? as usize;
+        restored.end_of_head = state.bool()?;
+        restored.scanning = state.bool()?;
+        restored.gap_ended = state.bool()?;
+        restored.in_block = state.bool()?;
+        restored.crc = state.u16()?;
+        restored.crc_error = state.bool()?;
+        restored.read_data = state.u8()?;
+        restored.write_data = state.u8()?;
+        restored.transfer_flag.set(state.bool()?);
+        restored.disk_irq.set(state.bool()?);
+        restored.external = state.u8()?;
+        restored.audio.load(&mut state)?;
+        state.finish()?;
+        if let Some(side) = restored.side {
+            let len = restored.sides[side].len();
+            // at the end the head only ever rewinds
+            if restored.position > len || (restored.position == len && restored.motor_on && !restored.end_of_head) {
+                return Err("FDS head position is past the end of the disk".to_string());
+            }
+        }
+        *self = restored;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // disk info, file count and one 3 byte file
+    fn test_side(data: &[u8; 3]) -> Vec<u8> {
+        let mut side = DISK_INFO.to_vec();
+        side.resize(56, 0x20);
+        side.extend_from_slice(&[2, 1]);
+        let mut header = vec![3, 0, 0];
+        header.extend_from_slice(b"TESTFILE");
+        header.extend_from_slice(&[0x00, 0x60, 3, 0, 0]);
+        side.extend_from_slice(&header);
+        side.push(4);
+        side.extend_from_slice(data);
+        side.resize(SIDE_SIZE, 0);
+        side
+    }
+
+    fn test_disk() -> DiskImage {
+        DiskImage::new(&[test_side(&[1, 2, 3]), test_side(&[4, 5, 6])].concat()).unwrap()
+    }
+
+    fn test_fds() -> Fds {
+        let mut bios = vec![0; BIOS_SIZE];
+        bios[0x1ffc] = 0x24;
+        Fds::new(&bios, &test_disk()).unwrap()
+    }
+
+    #[test]
+    fn test_disk_image_formats() {
+        let plain = test_side(&[1, 2, 3]);
+        let mut headered = b"FDS\x1a\x01".to_vec();
+        headered.resize(16, 0);
+        headered.extend_from_slice(&plain);
+        assert_eq!(DiskImage::new(&plain).unwrap(), DiskImage::new(&headered).unwrap());
+        assert!(DiskImage::new(&headered[..100]).is_err());
+        assert!(DiskImage::new(&plain[..SIDE_SIZE - 1]).is_err());
+        assert!(DiskImage::new(&vec![0; SIDE_SIZE]).is_err());
+
+        assert!(is_disk_image(Path::new("game.FDS"), &plain));
+        assert!(is_disk_image(Path::new("game.bin"), &headered));
+        assert!(!is_disk_image(Path::new("game.nes"), b"NES\x1a"));
+        assert_eq!(diff_path(Path::new("games/zelda.fds")), PathBuf::from("games/zelda.ips"));
+    }
+
+    #[test]
+    fn test_raw_side_round_trip() {
+        let side = test_side(&[7, 8, 9]);
+        let raw = encode_side(&side);
+        assert_eq!(raw[LEADING_GAP], START_MARK);
+        assert_eq!(&raw[LEADING_GAP + 1..LEADING_GAP + 57], &side[..56]);
+        assert_eq!(&raw[LEADING_GAP + 57..LEADING_GAP + 59], &block_crc(&side[..56]).to_le_bytes());
+        assert_eq!(decode_side(&raw), side);
+    }
+
+    #[test]
+    fn test_diff_round_trip() {
+        let mut fds = test_fds();
+        assert_eq!(fds.diff(), b"PATCHEOF");
+
+        // overwrite the second side's file data on the raw disk
+        let file = LEADING_GAP + 3 * (3 + BLOCK_GAP) + 56 + 2 + 16 + 1;
+        assert_eq!(fds.sides[1][file], 4);
+        fds.sides[1][file + 1] = 0x99;
+        let diff = fds.diff();
+        assert_eq!(diff, [b"PATCH".as_slice(), &[0x01, 0x00, 0x27, 0x00, 0x01, 0x99], b"EOF"].concat());
+
+        let mut other = test_fds();
+        other.apply_diff(&diff).unwrap();
+        assert_eq!(other.image(), fds.image());
+        assert!(other.apply_diff(b"PATCH\x10\x00\x00\x00\x01\x00EOF").is_err());
+    }
+
+    #[test]
+    fn test_timer_irq() {
+        let mut fds = test_fds();
+        fds.cpu_write(0x4020, 3);
+        fds.cpu_write(0x4021, 0);
+        // ignored while disk registers are disabled
+        fds.cpu_write(0x4022, 0x02);
+        fds.clock(10);
+        assert!(!fds.irq());
+
+        fds.cpu_write(0x4023, 0x01);
+        fds.cpu_write(0x4022, 0x03);
+        fds.clock(3);
+        assert!(!fds.irq());
+        fds.clock(1);
+        assert!(fds.irq());
+        assert_eq!(fds.cpu_read(0x4030) & 0x01, 0x01);
+        assert!(!fds.irq());
+        // repeats
+        fds.clock(4);
+        assert!(fds.irq());
+    }
+
+    #[test]
+    fn test_reading_the_disk() {
+        let mut fds = test_fds();
+        fds.cpu_write(0x4023, 0x01);
+        assert_eq!(fds.cpu_read(0x4032) & 0x07, 0x02);
+        // motor on, read mode, looking for a block
+        fds.cpu_write(0x4025, 0x65);
+
+        let mut bytes = Vec::new();
+        while bytes.len() < 16 {
+            fds.clock(1);
+            if fds.cpu_read(0x4030) & 0x02 != 0 {
+                bytes.push(fds.cpu_read(0x4031));
+            }
+        }
+        assert_eq!(bytes[0], START_MARK);
+        assert_eq!(&bytes[1..], DISK_INFO);
+        assert_eq!(fds.cpu_read(0x4032) & 0x07, 0x00);
+
+        fds.eject();
+        assert_eq!(fds.cpu_read(0x4032) & 0x07, 0x07);
+        fds.switch_side(1).unwrap();
+        fds.clock(SWAP_CYCLES);
+        assert_eq!(fds.side(), Some(1));
+        assert!(fds.insert(2).is_err());
+    }
+
+    #[test]
+    fn test_motor_restart_after_last_byte() {
+        let mut fds = test_fds();
+        fds.cpu_write(0x4023, 0x01);
+        fds.cpu_write(0x4025, 0x65);
+        fds.end_of_head = false;
+        fds.scanning = true;
+        fds.position = fds.sides[0].len() - 1;
+        fds.delay = 0;
+        fds.clock(1);
+        assert!(!fds.motor_on);
+        assert_eq!(fds.cpu_read(0x4030) & 0x40, 0x40);
+
+        // the BIOS rewrites $4025 with the motor on to change mirroring
+        fds.cpu_write(0x4025, 0x6d);
+        fds.clock(1);
+        assert_eq!(fds.position, 0);
+        assert_eq!(fds.delay, REWIND_CYCLES);
+
+        let mut state = fds.save_state();
+        fds.load_state(&state).unwrap();
+        fds.position = fds.sides[0].len();
+        fds.end_of_head = false;
+        state = fds.save_state();
+        assert!(fds.load_state(&state).is_err());
+    }
+
+    #[test]
+    fn test_wavetable_and_modulation() {
+        let mut fds = test_fds();
+        fds.cpu_write(0x4023, 0x03);
+        fds.cpu_write(0x4089, 0x80);
+        for i in 0..64 {
+            fds.cpu_write(0x4040 + i, if i < 32 { 63 } else { 0 });
+        }
+        fds.cpu_write(0x4089, 0x00);
+        assert_eq!(fds.cpu_read(0x4040), 0x7f);
+        // full volume, one table step per 64 cycles
+        fds.cpu_write(0x4080, 0xa0);
+        fds.cpu_write(0x4082, 0x00);
+        fds.cpu_write(0x4083, 0x04);
+        fds.clock(1);
+        assert_eq!(fds.audio(), 63.0 * 32.0 * LEVEL);
+        fds.clock(32 * 64);
+        assert_eq!(fds.audio(), 0.0);
+
+        // a counter of +8 at sweep gain 32 raises the pitch by a quarter
+        fds.cpu_write(0x4084, 0xa0);
+        fds.cpu_write(0x4085, 0x08);
+        assert_eq!(fds.audio.pitch(), 0x400 + 0x100);
+        fds.cpu_write(0x4085, 0x78);
+        assert_eq!(fds.audio.pitch(), 0x400 - 0x100);
+
+        // the table steps the counter
+        fds.cpu_write(0x4087, 0x80);
+        for _ in 0..32 {
+            fds.cpu_write(0x4088, 0x01);
+        }
+        fds.cpu_write(0x4085, 0x00);
+        fds.cpu_write(0x4086, 0xff);
+        fds.cpu_write(0x4087, 0x0f);
+        fds.clock(17);
+        assert_eq!(fds.audio.mod_counter, 1);
+    }
+}
@@ -0,0 +1,492 @@
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use crate::cpu::CPU;
+use crate::multitap::Multitap;
+use crate::rom::Rom;
+use crate::savestate;
+
+// FCEUX 2.2.2, the oldest version that reads what we write
+const EMU_VERSION: u32 = 22020;
+
+pub const COMMAND_SOFT_RESET: u8 = 0b0000_0001;
+pub const COMMAND_POWER: u8 = 0b0000_0010;
+
+// FM2 writes buttons as "RLDUTSBA", most significant bit first
+const BUTTON_CHARS: &[u8; 8] = b"RLDUTSBA";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Port {
+    None,
+    Gamepad,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MovieFrame {
+    pub commands: u8,
+    // controllers 3 and 4 only with a Four Score
+    pub buttons: [u8; 4],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub version: u32,
+    pub emu_version: u32,
+    pub rerecord_count: u32,
+    pub pal: bool,
+    pub rom_filename: String,
+    pub rom_checksum: String,
+    pub guid: String,
+    pub ports: [Port; 2],
+    // four gamepads through a Four Score, `ports` don't apply then
+    pub four_score: bool,
+    // our own save state format, FCEUX states can't be loaded
+    pub savestate: Option<Vec<u8>>,
+    // comments, subtitles and keys we don't interpret, kept in order
+    pub other: Vec<(String, String)>,
+    pub frames: Vec<MovieFrame>,
+}
+
+// FCEUX identifies ROMs by the MD5 of everything after the header
+pub fn rom_checksum(rom: &Rom) -> String {
+    let mut context = md5::Context::new();
+    context.consume(&rom.prg_rom);
+    context.consume(&rom.chr_rom);
+    format!("base64:{}", STANDARD.encode(context.compute().0))
+}
+
+fn new_guid(seed: &str) -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos());
+    let hex: String = md5::compute(format!("{}{}", seed, nanos))
+        .0
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect();
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+fn parse_flag(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        _ => Err(format!("{}: expected 0 or 1, got '{}'", key, value)),
+    }
+}
+
+fn parse_gamepad(field: &str) -> Result<u8, String> {
+    if field.len() != 8 {
+        return Err(format!("gamepad input '{}' is not 8 characters", field));
+    }
+    // anything but space or '.' counts as pressed
+    Ok(field
+        .bytes()
+        .enumerate()
+        .filter(|(_, c)| *c != b' ' && *c != b'.')
+        .fold(0, |buttons, (i, _)| buttons | (0x80 >> i)))
+}
+
+fn format_gamepad(buttons: u8) -> String {
+    BUTTON_CHARS
+        .iter()
+        .enumerate()
+        .map(|(i, c)| if buttons & (0x80 >> i) != 0 { *c as char } else { '.' })
+        .collect()
+}
+
+impl Movie {
+    // an empty movie that starts from power on
+    pub fn new(rom: &Rom, rom_filename: &str) -> Self {
+        let rom_checksum = rom_checksum(rom);
+        Movie {
+            version: 3,
+            emu_version: EMU_VERSION,
+            rerecord_count: 0,
+            pal: false,
+            rom_filename: rom_filename.to_string(),
+            guid: new_guid(&rom_checksum),
+            rom_checksum,
+            ports: [Port::Gamepad, Port::Gamepad],
+            four_score: false,
+            savestate: None,
+            other: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    // an empty movie that starts from the current state of `cpu`
+    pub fn from_state(rom: &Rom, rom_filename: &str, cpu: &CPU) -> Self {
+        Movie {
+            savestate: Some(savestate::save(cpu)),
+            ..Movie::new(rom, rom_filename)
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Movie, String> {
+        let mut movie = Movie {
+            version: 0,
+            emu_version: 0,
+            rerecord_count: 0,
+            pal: false,
+            rom_filename: String::new(),
+            rom_checksum: String::new(),
+            guid: String::new(),
+            ports: [Port::Gamepad, Port::Gamepad],
+            four_score: false,
+            savestate: None,
+            other: Vec::new(),
+            frames: Vec::new(),
+        };
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() {
+                continue;
+            }
+            let error = |e: String| format!("line {}: {}", n + 1, e);
+
+            if line.starts_with('|') {
+                movie.frames.push(movie.parse_frame(line).map_err(error)?);
+                continue;
+            }
+
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            let number = || value.parse::<u32>().map_err(|_| error(format!("{}: invalid number '{}'", key, value)));
+            match key {
+                "version" => movie.version = number()?,
+                "emuVersion" => movie.emu_version = number()?,
+                "rerecordCount" => movie.rerecord_count = number()?,
+                "palFlag" => movie.pal = parse_flag(key, value).map_err(error)?,
+                "romFilename" => movie.rom_filename = value.to_string(),
+                "romChecksum" => movie.rom_checksum = value.to_string(),
+                "guid" => movie.guid = value.to_string(),
+                "port0" | "port1" => {
+                    let port = match value {
+                        "0" => Port::None,
+                        "1" => Port::Gamepad,
+                        _ => return Err(error(format!("{}: unsupported device {}", key, value))),
+                    };
+                    movie.ports[(key == "port1") as usize] = port;
+                }
+                "fourscore" => movie.four_score = parse_flag(key, value).map_err(error)?,
+                "binary" => {
+                    if parse_flag(key, value).map_err(error)? {
+                        return Err(error(String::from("binary input logs are not supported")));
+                    }
+                }
+                // the expansion port, nothing to plug in there yet
+                "port2" => {
+                    if value != "0" {
+                        return Err(error(format!("{}: unsupported device {}", key, value)));
+                    }
+                }
+                "savestate" => {
+                    let data = value.strip_prefix("base64:").ok_or(error(String::from("savestate must be base64")))?;
+                    movie.savestate = Some(STANDARD.decode(data).map_err(|e| error(e.to_string()))?);
+                }
+                _ => movie.other.push((key.to_string(), value.to_string())),
+            }
+        }
+
+        if movie.version != 3 {
+            return Err(format!("unsupported FM2 version {}", movie.version));
+        }
+        Ok(movie)
+    }
+
+    fn parse_frame(&self, line: &str) -> Result<MovieFrame, String> {
+        let fields: Vec<&str> = line.split('|').collect();
+        // "", commands, port0, port1, port2, ""
+        if fields.len() < 5 {
+            return Err(String::from("expected |commands|port0|port1|port2|"));
+        }
+        let commands = fields[1].trim().parse().map_err(|_| format!("invalid commands '{}'", fields[1]))?;
+        let mut buttons = [0; 4];
+        if self.four_score {
+            // "", commands, four gamepads, port2, ""
+            if fields.len() < 7 {
+                return Err(String::from("expected |commands|pad1|pad2|pad3|pad4|port2|"));
+            }
+            for (i, field) in fields[2..6].iter().enumerate() {
+                buttons[i] = parse_gamepad(field)?;
+            }
+            return Ok(MovieFrame { commands, buttons });
+        }
+        for (i, port) in self.ports.iter().enumerate() {
+            if *port == Port::Gamepad {
+                buttons[i] = parse_gamepad(fields[2 + i])?;
+            }
+        }
+        Ok(MovieFrame { commands, buttons })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Movie, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Movie::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        fs::write(path, self.to_fm2()).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn to_fm2(&self) -> String {
+        let port = |port: Port| if port == Port::Gamepad { 1 } else { 0 };
+        let mut out = format!(
+            concat!(
+                "version {}\nemuVersion {}\nrerecordCount {}\npalFlag {}\nromFilename {}\n",
+                "romChecksum {}\nguid {}\nfourscore {}\nport0 {}\nport1 {}\nport2 0\n"
+            ),
+            self.version,
+            self.emu_version,
+            self.rerecord_count,
+            self.pal as u8,
+            self.rom_filename,
+            self.rom_checksum,
+            self.guid,
+            self.four_score as u8,
+            port(self.ports[0]),
+            port(self.ports[1]),
+        );
+        for (key, value) in &self.other {
+            out.push_str(&format!("{} {}\n", key, value));
+        }
+        if let Some(state) = &self.savestate {
+            out.push_str(&format!("savestate base64:{}\n", STANDARD.encode(state)));
+        }
+
+        for frame in &self.frames {
+            out.push_str(&format!("|{}|", frame.commands));
+            if self.four_score {
+                for buttons in frame.buttons {
+                    out.push_str(&format_gamepad(buttons));
+                    out.push('|');
+                }
+                out.push_str("|\n");
+                continue;
+            }
+            for (port, buttons) in self.ports.iter().zip(frame.buttons) {
+                if *port == Port::Gamepad {
+                    out.push_str(&format_gamepad(buttons));
+                }
+                out.push('|');
+            }
+            out.push_str("|\n");
+        }
+        out
+    }
+
+    pub fn check_rom(&self, rom: &Rom) -> Result<(), String> {
+        let checksum = rom_checksum(rom);
+        if self.rom_checksum != checksum {
+            return Err(format!(
+                "movie was recorded with ROM {}, this one is {}",
+                self.rom_checksum, checksum
+            ));
+        }
+        Ok(())
+    }
+
+    // Puts `cpu` where the movie begins: the embedded save state, or the
+    // power on state the caller already set up.
+    pub fn start(&self, cpu: &mut CPU) -> Result<(), String> {
+        if self.four_score {
+            cpu.multitap = Multitap::FourScore;
+        }
+        match &self.savestate {
+            Some(state) => savestate::restore(cpu, state),
+            None => Ok(()),
+        }
+    }
+
+    // called at the start of every frame while recording, carries out the
+    // commands the way playback will
+    pub fn record_frame(&mut self, cpu: &mut CPU, commands: u8) {
+        apply_commands(commands, cpu);
+        let mut buttons = cpu.joypads.each_ref().map(|joypad| joypad.button_status);
+        if !self.four_score {
+            buttons[2..].fill(0);
+        }
+        self.frames.push(MovieFrame { commands, buttons });
+    }
+
+    // Feeds frame `frame` into `cpu`, returns false once the movie is over.
+    pub fn apply(&self, frame: usize, cpu: &mut CPU) -> bool {
+        let Some(input) = self.frames.get(frame) else {
+            return false;
+        };
+        apply_commands(input.commands, cpu);
+        for (joypad, buttons) in cpu.joypads.iter_mut().zip(input.buttons) {
+            joypad.set_buttons(buttons);
+        }
+        true
+    }
+}
+
+fn apply_commands(commands: u8, cpu: &mut CPU) {
+    if commands & COMMAND_POWER != 0 {
+        cpu.power_cycle();
+    } else if commands & COMMAND_SOFT_RESET != 0 {
+        cpu.reset();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::headless::{self, Options, CYCLES_PER_FRAME};
+    use crate::joypad::{BUTTON_A, BUTTON_RIGHT, BUTTON_START};
+    use crate::region::Region;
+    use crate::rom::test::test_rom;
+
+    const FCEUX_MOVIE: &str = "version 3
+emuVersion 22020
+rerecordCount 5
+palFlag 0
+romFilename smb
+romChecksum base64:jjYwGG411HcjG/j9UOVM3Q==
+guid 452DE2C3-EF43-2FA9-77AC-0677FC51543B
+fourscore 0
+microphone 0
+port0 1
+port1 0
+port2 0
+FDS 0
+NewPPU 0
+comment author someone
+|1|........|||
+|0|....T...|||
+|0|R......A|||
+|0|R D    A|||
+";
+
+    #[test]
+    fn test_parse_fceux_movie() {
+        let movie = Movie::parse(FCEUX_MOVIE).unwrap();
+
+        assert_eq!(movie.rerecord_count, 5);
+        assert_eq!(movie.ports, [Port::Gamepad, Port::None]);
+        assert_eq!(movie.frames.len(), 4);
+        assert_eq!(movie.frames[0].commands, COMMAND_SOFT_RESET);
+        assert_eq!(movie.frames[1].buttons[0], BUTTON_START);
+        assert_eq!(movie.frames[2].buttons[0], BUTTON_RIGHT | BUTTON_A);
+        assert!(movie.other.contains(&(String::from("comment"), String::from("author someone"))));
+
+        let again = Movie::parse(&movie.to_fm2()).unwrap();
+        assert_eq!(again, movie);
+        assert!(again.to_fm2().contains("|0|R.D....A|||\n"));
+    }
+
+    #[test]
+    fn test_rejects_unsupported() {
+        assert!(Movie::parse(&FCEUX_MOVIE.replace("port0 1", "port0 2")).is_err());
+        assert!(Movie::parse(&FCEUX_MOVIE.replace("version 3", "version 2")).is_err());
+    }
+
+    #[test]
+    fn test_four_score_movie() {
+        let header = FCEUX_MOVIE.split_once("|1|").unwrap().0.replace("fourscore 0", "fourscore 1");
+        let text = format!("{}|1|........|........|........|........||\n|0|R......A|........|....T...|.......A||\n", header);
+        let movie = Movie::parse(&text).unwrap();
+        assert!(movie.four_score);
+        assert_eq!(movie.frames[1].buttons, [BUTTON_RIGHT | BUTTON_A, 0, BUTTON_START, BUTTON_A]);
+        assert!(movie.to_fm2().contains("|0|R......A|........|....T...|.......A||\n"));
+        // two player frames don't fit
+        assert!(Movie::parse(&format!("{}|0|R......A|||\n", header)).is_err());
+
+        let mut cpu = CPU::new();
+        movie.start(&mut cpu).unwrap();
+        assert_eq!(cpu.multitap, Multitap::FourScore);
+        assert!(movie.apply(1, &mut cpu));
+        assert_eq!(cpu.joypads[3].button_status, BUTTON_A);
+    }
+
+    /*
+    loop:
+        LDA #1, STA $4016, LDA #0, STA $4016, LDX #8
+    read:
+        LDA $4016, LSR A, LDA $10, ROL A, STA $10, DEX, BNE read
+        LDA $11, CLC, ADC $10, STA $11
+        JMP loop
+    */
+    const READ_PAD: [u8; 34] = [
+        0xa9, 0x01, 0x8d, 0x16, 0x40, 0xa9, 0x00, 0x8d, 0x16, 0x40, 0xa2, 0x08, 0xad, 0x16, 0x40, 0x4a, 0xa5, 0x10,
+        0x2a, 0x85, 0x10, 0xca, 0xd0, 0xf4, 0xa5, 0x11, 0x18, 0x65, 0x10, 0x85, 0x11, 0x4c, 0x00, 0x80,
+    ];
+
+    fn power_on(rom: &Rom) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.load_rom(rom).unwrap();
+        cpu.reset();
+        cpu
+    }
+
+    // records 20 frames with `commands` for each, replays them and checks
+    // both runs end up in the same place
+    fn record_and_replay(commands: impl Fn(usize) -> u8) -> CPU {
+        let rom = Rom::new(&test_rom(&READ_PAD)).unwrap();
+        let mut cpu = power_on(&rom);
+        let mut movie = Movie::new(&rom, "pad.nes");
+
+        for frame in 0..20 {
+            cpu.joypads[0].set_buttons((frame * 37) as u8);
+            movie.record_frame(&mut cpu, commands(frame));
+            let end = (frame + 1) * CYCLES_PER_FRAME;
+            while cpu.cycles < end {
+                cpu.run_for(1);
+            }
+        }
+        let recorded = (cpu.memory, cpu.cycles, cpu.program_counter);
+
+        let movie = Movie::parse(&movie.to_fm2()).unwrap();
+        movie.check_rom(&rom).unwrap();
+        let mut replay = power_on(&rom);
+        movie.start(&mut replay).unwrap();
+        let options = Options {
+            frames: movie.frames.len(),
+            until: vec![],
+            region: Region::Ntsc,
+            wav: None,
+            movie: Some(movie),
+            save: None,
+            screenshot: None,
+        };
+        headless::run(&mut replay, "pad.nes", &options);
+
+        assert!(recorded == (replay.memory, replay.cycles, replay.program_counter));
+        replay
+    }
+
+    #[test]
+    fn test_record_and_replay_is_exact() {
+        let replay = record_and_replay(|_| 0);
+        assert_ne!(replay.memory[0x11], 0);
+    }
+
+    #[test]
+    fn test_record_and_replay_with_resets() {
+        let replay = record_and_replay(|frame| match frame {
+            7 => COMMAND_SOFT_RESET,
+            13 => COMMAND_POWER,
+            _ => 0,
+        });
+        assert_ne!(replay.memory[0x11], 0);
+    }
+
+    #[test]
+    fn test_movie_from_save_state() {
+        let rom = Rom::new(&test_rom(&READ_PAD)).unwrap();
+        let mut cpu = power_on(&rom);
+        cpu.run_for(1000);
+        let movie = Movie::from_state(&rom, "pad.nes", &cpu);
+
+        let mut replay = power_on(&rom);
+        Movie::parse(&movie.to_fm2()).unwrap().start(&mut replay).unwrap();
+        assert_eq!(replay.cycles, cpu.cycles);
+        assert_eq!(replay.program_counter, cpu.program_counter);
+    }
+}
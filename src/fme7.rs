@@ -0,0 +1,520 @@
+use crate::mapper::{bank_offset, chr_memory, mirror_vram, mirroring_from_u8, Fetch, Mapper, StateReader, StateWriter};
+use crate::rom::{Mirroring, Rom};
+
+const PRG_RAM_SIZE: usize = 0x2000;
+
+// tone and noise periods count in units of 16 CPU cycles, the 32 step
+// envelope in units of 8
+const TONE_DIVIDER: u8 = 16;
+const ENVELOPE_DIVIDER: u8 = 8;
+
+// a full volume channel is as loud as a full volume APU pulse
+pub const LEVEL: f32 = 0.15;
+
+// The AY-3-8910 derivative in the Sunsoft 5B: three square channels, a
+// shared noise generator and a shared envelope.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sunsoft5b {
+    registers: [u8; 16],
+    divider: u8,
+    tone_timers: [u16; 3],
+    tone_outputs: [bool; 3],
+    noise_timer: u8,
+    // 17 bit LFSR
+    noise: u32,
+    envelope_timer: u16,
+    envelope_step: u8,
+    envelope_attack: bool,
+    envelope_holding: bool,
+}
+
+impl Default for Sunsoft5b {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 3 dB per volume step, 1.5 dB per envelope step; 0 is silent
+fn amplitude(level: u8) -> f32 {
+    if level == 0 {
+        0.0
+    } else {
+        10f32.powf((level as f32 - 31.0) * 1.5 / 20.0)
+    }
+}
+
+impl Sunsoft5b {
+    pub fn new() -> Self {
+        Sunsoft5b {
+            registers: [0; 16],
+            divider: 0,
+            tone_timers: [0; 3],
+            tone_outputs: [false; 3],
+            noise_timer: 0,
+            noise: 1,
+            envelope_timer: 0,
+            envelope_step: 0,
+            envelope_attack: false,
+            envelope_holding: false,
+        }
+    }
+
+    pub fn write(&mut self, register: u8, data: u8) {
+        let register = register as usize & 0x0f;
+        self.registers[register] = data;
+        if register == 13 {
+            self.envelope_step = 0;
+            self.envelope_timer = 0;
+            self.envelope_attack = data & 0x04 != 0;
+            self.envelope_holding = false;
+        }
+    }
+
+    fn tone_period(&self, channel: usize) -> u16 {
+        (self.registers[channel * 2] as u16 | (self.registers[channel * 2 + 1] as u16 & 0x0f) << 8).max(1)
+    }
+
+    fn envelope_period(&self) -> u16 {
+        (self.registers[11] as u16 | (self.registers[12] as u16) << 8).max(1)
+    }
+
+    fn envelope_level(&self) -> u8 {
+        if self.envelope_attack {
+            self.envelope_step
+        } else {
+            31 - self.envelope_step
+        }
+    }
+
+    fn step_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+        self.envelope_step += 1;
+        if self.envelope_step < 32 {
+            return;
+        }
+        let shape = self.registers[13];
+        let (continues, alternate, hold) = (shape & 0x08 != 0, shape & 0x02 != 0, shape & 0x01 != 0);
+        if !continues {
+            // shapes 0-7 end silent
+            self.envelope_step = 31;
+            self.envelope_attack = false;
+            self.envelope_holding = true;
+        } else if hold {
+            // hold where the ramp ended, or at the other end with alternate
+            self.envelope_step = 31;
+            if alternate {
+                self.envelope_attack = !self.envelope_attack;
+            }
+            self.envelope_holding = true;
+        } else {
+            self.envelope_step = 0;
+            if alternate {
+                self.envelope_attack = !self.envelope_attack;
+            }
+        }
+    }
+
+    // one CPU cycle
+    pub fn clock(&mut self) {
+        self.divider += 1;
+        if self.divider.is_multiple_of(ENVELOPE_DIVIDER) {
+            self.envelope_timer += 1;
+            if self.envelope_timer >= self.envelope_period() {
+                self.envelope_timer = 0;
+                self.step_envelope();
+            }
+        }
+        if self.divider < TONE_DIVIDER {
+            return;
+        }
+        self.divider = 0;
+
+        for channel in 0..3 {
+            self.tone_timers[channel] += 1;
+            if self.tone_timers[channel] >= self.tone_period(channel) {
+                self.tone_timers[channel] = 0;
+                self.tone_outputs[channel] = !self.tone_outputs[channel];
+            }
+        }
+
+        self.noise_timer += 1;
+        if self.noise_timer >= (self.registers[6] & 0x1f).max(1) {
+            self.noise_timer = 0;
+            let feedback = (self.noise ^ (self.noise >> 3)) & 1;
+            self.noise = (self.noise >> 1) | feedback << 16;
+        }
+    }
+
+    pub fn channel_output(&self, channel: usize) -> f32 {
+        let mixer = self.registers[7];
+        let tone = self.tone_outputs[channel] || mixer & (1 << channel) != 0;
+        let noise = self.noise & 1 != 0 || mixer & (8 << channel) != 0;
+        if !(tone && noise) {
+            return 0.0;
+        }
+        let volume = self.registers[8 + channel];
+        let level = if volume & 0x10 != 0 {
+            self.envelope_level()
+        } else if volume & 0x0f == 0 {
+            0
+        } else {
+            (volume & 0x0f) * 2 + 1
+        };
+        amplitude(level)
+    }
+
+    pub fn output(&self) -> f32 {
+        (0..3).map(|channel| self.channel_output(channel)).sum::<f32>() * LEVEL
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        state.bytes(&self.registers);
+        state.u8(self.divider);
+        for channel in 0..3 {
+            state.u16(self.tone_timers[channel]);
+            state.bool(self.tone_outputs[channel]);
+        }
+        state.u8(self.noise_timer);
+        state.u32(self.noise);
+        state.u16(self.envelope_timer);
+        state.u8(self.envelope_step);
+        state.bool(self.envelope_attack);
+        state.bool(self.envelope_holding);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.registers.copy_from_slice(state.bytes(16)?);
+        self.divider = state.u8()?;
+        for channel in 0..3 {
+            self.tone_timers[channel] = state.u16()?;
+            self.tone_outputs[channel] = state.bool()?;
+        }
+        self.noise_timer = state.u8()?;
+        self.noise = state.u32()?;
+        self.envelope_timer = state.u16()?;
+        self.envelope_step = state.u8()? & 0x1f;
+        self.envelope_attack = state.bool()?;
+        self.envelope_holding = state.bool()?;
+        Ok(())
+    }
+}
+
+// Sunsoft FME-7 and 5B (mapper 69): Batman: Return of the Joker, Gimmick!
+#[derive(Clone)]
+pub struct Fme7 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    prg_ram: Vec<u8>,
+
+    command: u8,
+    chr_banks: [u8; 8],
+    // $6000: bit 7 enables, bit 6 picks RAM over ROM, bits 0-5 bank
+    prg_6000: u8,
+    prg_banks: [u8; 3],
+    mirroring: Mirroring,
+
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_counting: bool,
+    irq_pending: bool,
+
+    audio_register: u8,
+    audio: Sunsoft5b,
+}
+
+impl Fme7 {
+    pub fn new(rom: &Rom) -> Self {
+        let (chr, chr_ram) = chr_memory(rom);
+        Fme7 {
+            prg_rom: rom.prg_rom.clone(),
+            chr,
+            chr_ram,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            command: 0,
+            chr_banks: [0; 8],
+            prg_6000: 0,
+            prg_banks: [0; 3],
+            mirroring: rom.screen_mirroring,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_counting: false,
+            irq_pending: false,
+            audio_register: 0,
+            audio: Sunsoft5b::new(),
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let len = self.prg_rom.len();
+        let bank = match addr {
+            0x6000..=0x7fff => (self.prg_6000 & 0x3f) as usize,
+            0x8000..=0xdfff => self.prg_banks[(addr as usize - 0x8000) / 0x2000] as usize,
+            _ => len / 0x2000 - 1,
+        };
+        bank_offset(len, bank, 0x2000, addr)
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        bank_offset(self.chr.len(), self.chr_banks[addr as usize / 0x400] as usize, 0x400, addr)
+    }
+
+    fn write_parameter(&mut self, data: u8) {
+        match self.command {
+            0..=7 => self.chr_banks[self.command as usize] = data,
+            8 => self.prg_6000 = data,
+            9..=11 => self.prg_banks[self.command as usize - 9] = data & 0x3f,
+            12 => {
+                self.mirroring = match data & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::OneScreenLower,
+                    _ => Mirroring::OneScreenUpper,
+                };
+            }
+            13 => {
+                self.irq_enabled = data & 0x01 != 0;
+                self.irq_counting = data & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            14 => self.irq_counter = (self.irq_counter & 0xff00) | data as u16,
+            _ => self.irq_counter = (self.irq_counter & 0x00ff) | (data as u16) << 8,
+        }
+    }
+}
+
+impl Mapper for Fme7 {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7fff if self.prg_6000 & 0xc0 == 0xc0 => self.prg_ram[addr as usize - 0x6000],
+            // RAM selected but disabled
+            0x6000..=0x7fff if self.prg_6000 & 0x40 != 0 => 0,
+            0x6000..=0xffff => self.prg_rom[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff if self.prg_6000 & 0xc0 == 0xc0 => self.prg_ram[addr as usize - 0x6000] = data,
+            0x8000..=0x9fff => self.command = data & 0x0f,
+            0xa000..=0xbfff => self.write_parameter(data),
+            0xc000..=0xdfff => self.audio_register = data & 0x0f,
+            0xe000..=0xffff => self.audio.write(self.audio_register, data),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16, _fetch: Fetch, vram: &[u8; 0x800]) -> u8 {
+        let addr = addr & 0x3fff;
+        if addr < 0x2000 {
+            self.chr[self.chr_offset(addr)]
+        } else {
+            vram[mirror_vram(addr, self.mirroring)]
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8, vram: &mut [u8; 0x800]) {
+        let addr = addr & 0x3fff;
+        if addr >= 0x2000 {
+            vram[mirror_vram(addr, self.mirroring)] = data;
+        } else if self.chr_ram {
+            let offset = self.chr_offset(addr);
+            self.chr[offset] = data;
+        }
+    }
+
+    fn clock(&mut self, cycles: usize) {
+        for _ in 0..cycles {
+            if self.irq_counting {
+                self.irq_counter = self.irq_counter.wrapping_sub(1);
+                if self.irq_counter == 0xffff && self.irq_enabled {
+                    self.irq_pending = true;
+                }
+            }
+            self.audio.clock();
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn audio(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        state.bytes(&self.prg_ram);
+        if self.chr_ram {
+            state.bytes(&self.chr);
+        }
+        state.u8(self.command);
+        state.bytes(&self.chr_banks);
+        state.u8(self.prg_6000);
+        state.bytes(&self.prg_banks);
+        state.u8(self.mirroring as u8);
+        state.u16(self.irq_counter);
+        state.bool(self.irq_enabled);
+        state.bool(self.irq_counting);
+        state.bool(self.irq_pending);
+        state.u8(self.audio_register);
+        self.audio.save(&mut state);
+        state.data
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut state = StateReader::new(data);
+        let mut restored = self.clone();
+        restored.prg_ram.copy_from_slice(state.bytes(PRG_RAM_SIZE)?);
+        if self.chr_ram {
+            restored.chr.copy_from_slice(state.bytes(0x2000)?);
+        }
+        restored.command = state.u8()?;
+        restored.chr_banks.copy_from_slice(state.bytes(8)?);
+        restored.prg_6000 = state.u8()?;
+        restored.prg_banks.copy_from_slice(state.bytes(3)?);
+        restored.mirroring = mirroring_from_u8(state.u8()?)?;
+        restored.irq_counter = state.u16()?;
+        restored.irq_enabled = state.bool()?;
+        restored.irq_counting = state.bool()?;
+        restored.irq_pending = state.bool()?;
+        restored.audio_register = state.u8()?;
+        restored.audio.load(&mut state)?;
+        state.finish()?;
+        *self = restored;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_board() -> Fme7 {
+        Fme7::new(&Rom {
+            prg_rom: (0..32).flat_map(|bank| vec![bank as u8; 0x2000]).collect(),
+            chr_rom: (0..256).flat_map(|bank| vec![bank as u8; 0x400]).collect(),
+            mapper: 69,
+            submapper: 0,
+            screen_mirroring: Mirroring::Vertical,
+            battery: true,
+            region: Default::default(),
+        })
+    }
+
+    fn command(fme7: &mut Fme7, command: u8, parameter: u8) {
+        fme7.cpu_write(0x8000, command);
+        fme7.cpu_write(0xa000, parameter);
+    }
+
+    #[test]
+    fn test_banking() {
+        let mut fme7 = test_board();
+        let vram = [0; 0x800];
+        for (bank, addr) in [(9, 0x8000), (10, 0xa000), (11, 0xc000)] {
+            command(&mut fme7, bank, bank + 1);
+            assert_eq!(fme7.cpu_read(addr), bank + 1);
+        }
+        assert_eq!(fme7.cpu_read(0xe000), 31);
+        command(&mut fme7, 5, 0x42);
+        assert_eq!(fme7.ppu_read(0x1400, Fetch::Data, &vram), 0x42);
+        command(&mut fme7, 12, 2);
+        assert_eq!(fme7.mirroring, Mirroring::OneScreenLower);
+
+        // $6000 holds ROM, disabled RAM, or RAM
+        command(&mut fme7, 8, 7);
+        assert_eq!(fme7.cpu_read(0x6000), 7);
+        command(&mut fme7, 8, 0x40);
+        fme7.cpu_write(0x6000, 0x99);
+        assert_eq!(fme7.cpu_read(0x6000), 0);
+        command(&mut fme7, 8, 0xc0);
+        fme7.cpu_write(0x6000, 0x99);
+        assert_eq!(fme7.cpu_read(0x6000), 0x99);
+    }
+
+    #[test]
+    fn test_irq_counter() {
+        let mut fme7 = test_board();
+        command(&mut fme7, 14, 3);
+        command(&mut fme7, 15, 0);
+        command(&mut fme7, 13, 0x81);
+        fme7.clock(3);
+        assert!(!fme7.irq());
+        fme7.clock(1);
+        assert!(fme7.irq());
+        // writing the control register acknowledges, the counter keeps going
+        command(&mut fme7, 13, 0x80);
+        assert!(!fme7.irq());
+        fme7.clock(0x10000);
+        assert!(!fme7.irq());
+    }
+
+    #[test]
+    fn test_tone_and_volume() {
+        let mut audio = Sunsoft5b::new();
+        // channel A only, period 2, volume 15
+        audio.write(7, 0b0011_1110);
+        audio.write(0, 2);
+        audio.write(8, 0x0f);
+        let mut levels = Vec::new();
+        for _ in 0..4 {
+            for _ in 0..TONE_DIVIDER * 2 {
+                audio.clock();
+            }
+            levels.push(audio.output());
+        }
+        assert_eq!(levels, [LEVEL, 0.0, LEVEL, 0.0]);
+
+        // each volume step is 3 dB
+        for _ in 0..TONE_DIVIDER * 2 {
+            audio.clock();
+        }
+        audio.write(8, 0x0d);
+        let ratio = audio.channel_output(0) / amplitude(31);
+        assert!((20.0 * ratio.log10() + 6.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_envelope_shapes() {
+        let mut audio = Sunsoft5b::new();
+        audio.write(11, 1);
+        // shape 0: decay once, then silence
+        audio.write(13, 0x00);
+        assert_eq!(audio.envelope_level(), 31);
+        for _ in 0..32 * ENVELOPE_DIVIDER as usize {
+            audio.clock();
+        }
+        assert_eq!(audio.envelope_level(), 0);
+        assert!(audio.envelope_holding);
+
+        // shape 14: attack, decay, attack...
+        audio.write(13, 0x0e);
+        let mut levels = Vec::new();
+        for _ in 0..64 * ENVELOPE_DIVIDER as usize {
+            audio.clock();
+            levels.push(audio.envelope_level());
+        }
+        assert_eq!(levels.iter().copied().max(), Some(31));
+        assert_eq!(levels[levels.len() - 1], 0);
+
+        // shape 11: decay, then hold at the top
+        audio.write(13, 0x0b);
+        for _ in 0..40 * ENVELOPE_DIVIDER as usize {
+            audio.clock();
+        }
+        assert_eq!(audio.envelope_level(), 31);
+        assert!(audio.envelope_holding);
+    }
+}
@@ -0,0 +1,44 @@
+// 9-bit pixels: master palette index in bits 0-5, PPUMASK emphasis in 6-8
+pub const EMPHASIS_SHIFT: u16 = 6;
+
+pub fn pixel_index(pixel: u16) -> u8 {
+    (pixel & 0x3f) as u8
+}
+
+pub fn pixel_emphasis(pixel: u16) -> u8 {
+    ((pixel >> EMPHASIS_SHIFT) & 0x07) as u8
+}
+
+// What the PPU produces each frame, one 9-bit pixel per dot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub data: Vec<u16>,
+}
+
+impl Frame {
+    pub const WIDTH: usize = 256;
+    pub const HEIGHT: usize = 240;
+
+    pub fn new() -> Self {
+        Frame {
+            data: vec![0; Frame::WIDTH * Frame::HEIGHT],
+        }
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, pixel: u16) {
+        let base = y * Frame::WIDTH + x;
+        if base < self.data.len() {
+            self.data[base] = pixel;
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u16 {
+        self.data[y * Frame::WIDTH + x]
+    }
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
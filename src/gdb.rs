@@ -0,0 +1,515 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+
+use crate::breakpoint::{Access, Breakpoint};
+use crate::cpu::{Memory, Stop, CPU};
+
+// how many instructions run between checks for a ^C from the debugger
+const POLL_INTERVAL: usize = 4096;
+
+// register numbers as seen by the debugger: A, X, Y, P, SP are one byte
+// each, PC is two bytes little endian
+const REGISTER_COUNT: usize = 6;
+
+pub struct GdbStub {
+    listener: TcpListener,
+}
+
+impl GdbStub {
+    // only ever listens on loopback
+    pub fn bind(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        Ok(GdbStub { listener })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // waits for a debugger and serves it until it detaches or kills the target
+    pub fn serve(&self, cpu: &mut CPU) -> io::Result<SessionEnd> {
+        let (stream, _) = self.listener.accept()?;
+        stream.set_nodelay(true)?;
+        Session::new(stream).run(cpu)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEnd {
+    // detached or disconnected, the emulator carries on
+    Detached,
+    Killed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Software,
+    Write,
+    Read,
+    Access,
+}
+
+enum Action {
+    Reply(String),
+    Resume,
+    Step,
+    Close(SessionEnd),
+}
+
+struct Session {
+    stream: TcpStream,
+    ack: bool,
+    breakpoints: HashMap<(Kind, u16, u16), usize>,
+    last_stop: String,
+}
+
+impl Session {
+    fn new(stream: TcpStream) -> Self {
+        Session {
+            stream,
+            ack: true,
+            breakpoints: HashMap::new(),
+            last_stop: String::from("S05"),
+        }
+    }
+
+    fn run(&mut self, cpu: &mut CPU) -> io::Result<SessionEnd> {
+        loop {
+            let packet = match self.read_packet()? {
+                Some(packet) => packet,
+                None => return Ok(SessionEnd::Detached),
+            };
+
+            // the protocol is ASCII, anything else would trip up the
+            // slicing in the handlers
+            let action = match std::str::from_utf8(&packet) {
+                Ok(packet) if packet.is_ascii() => self.handle(cpu, packet),
+                _ => Action::Reply(String::from("E01")),
+            };
+            match action {
+                Action::Reply(reply) => self.send(&reply)?,
+                Action::Step => {
+                    let stop = cpu.step();
+                    self.last_stop = self.stop_reply(stop);
+                    self.send(&self.last_stop.clone())?;
+                }
+                Action::Resume => {
+                    self.last_stop = self.resume(cpu)?;
+                    self.send(&self.last_stop.clone())?;
+                }
+                Action::Close(end) => {
+                    self.send("OK")?;
+                    return Ok(end);
+                }
+            }
+        }
+    }
+
+    fn resume(&mut self, cpu: &mut CPU) -> io::Result<String> {
+        if let Some(stop) = cpu.step() {
+            return Ok(self.stop_reply(Some(stop)));
+        }
+        loop {
+            if let Some(stop) = cpu.run_for(POLL_INTERVAL) {
+                return Ok(self.stop_reply(Some(stop)));
+            }
+            if self.interrupted()? {
+                return Ok(String::from("S02"));
+            }
+        }
+    }
+
+    // watchpoints are reported as the kind the debugger set, an access
+    // watchpoint fires on reads and writes alike
+    fn stop_reply(&self, stop: Option<Stop>) -> String {
+        let hit = match stop {
+            Some(Stop::Breakpoint(hit)) => hit,
+            _ => return String::from("S05"),
+        };
+        let kind = self
+            .breakpoints
+            .iter()
+            .find(|(_, id)| **id == hit.id)
+            .map(|((kind, _, _), _)| *kind);
+
+        match (kind, hit.access) {
+            (Some(Kind::Access), _) => format!("T05awatch:{:04x};", hit.addr),
+            (Some(Kind::Read), _) | (None, Access::Read) => format!("T05rwatch:{:04x};", hit.addr),
+            (Some(Kind::Write), _) | (None, Access::Write) => format!("T05watch:{:04x};", hit.addr),
+            _ => String::from("S05"),
+        }
+    }
+
+    fn interrupted(&mut self) -> io::Result<bool> {
+        let mut byte = [0u8; 1];
+        self.stream.set_nonblocking(true)?;
+        let result = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+
+        match result {
+            Ok(0) => Err(io::Error::new(ErrorKind::UnexpectedEof, "debugger disconnected")),
+            Ok(_) => Ok(byte[0] == 0x03),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn handle(&mut self, cpu: &mut CPU, packet: &str) -> Action {
+        let (command, args) = packet.split_at(packet.len().min(1));
+
+        let reply = match command {
+            "?" => self.last_stop.clone(),
+            "g" => read_registers(cpu),
+            "G" => ok_or_error(write_registers(cpu, args)),
+            "p" => ok_or(read_register(cpu, args)),
+            "P" => ok_or_error(write_register(cpu, args)),
+            "m" => ok_or(read_memory(cpu, args)),
+            "M" => ok_or_error(write_memory(cpu, args)),
+            "s" => return Action::Step,
+            "c" => return Action::Resume,
+            "Z" => ok_or_error(self.insert_breakpoint(cpu, args)),
+            "z" => ok_or_error(self.remove_breakpoint(cpu, args)),
+            "D" => return Action::Close(SessionEnd::Detached),
+            "k" => return Action::Close(SessionEnd::Killed),
+            "q" if args.starts_with("Supported") => String::from("PacketSize=1000;QStartNoAckMode+"),
+            "q" if args == "Attached" => String::from("1"),
+            "q" if args == "C" => String::new(),
+            "Q" if args == "StartNoAckMode" => {
+                self.ack = false;
+                String::from("OK")
+            }
+            "H" => String::from("OK"),
+            _ => String::new(),
+        };
+
+        Action::Reply(reply)
+    }
+
+    fn insert_breakpoint(&mut self, cpu: &mut CPU, args: &str) -> Result<(), String> {
+        let (kind, addr, len) = parse_breakpoint(args)?;
+        let end = addr.saturating_add(len.max(1) - 1);
+
+        let breakpoint = match kind {
+            Kind::Software => Breakpoint::execute(addr),
+            Kind::Write => Breakpoint::write(addr..=end),
+            Kind::Read => Breakpoint::read(addr..=end),
+            Kind::Access => Breakpoint::new(addr..=end, true, true, false),
+        };
+
+        self.breakpoints
+            .entry((kind, addr, len))
+            .or_insert_with(|| cpu.breakpoints.add(breakpoint));
+        Ok(())
+    }
+
+    fn remove_breakpoint(&mut self, cpu: &mut CPU, args: &str) -> Result<(), String> {
+        let key = parse_breakpoint(args)?;
+        let id = self.breakpoints.remove(&key).ok_or("no such breakpoint")?;
+        cpu.breakpoints.remove(id);
+        Ok(())
+    }
+
+    fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut byte = [0u8; 1];
+
+        loop {
+            // skip acks, interrupts and noise until a packet starts
+            loop {
+                if self.stream.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+
+            let mut data = Vec::new();
+            loop {
+                if self.stream.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+
+            let mut checksum = [0u8; 2];
+            self.stream.read_exact(&mut checksum)?;
+
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if expected == Some(checksum_of(&data)) {
+                if self.ack {
+                    self.stream.write_all(b"+")?;
+                }
+                return Ok(Some(data));
+            }
+
+            if self.ack {
+                self.stream.write_all(b"-")?;
+            }
+        }
+    }
+
+    fn send(&mut self, reply: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", reply, checksum_of(reply.as_bytes()));
+        self.stream.write_all(packet.as_bytes())?;
+        self.stream.flush()
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn ok_or(result: Result<String, String>) -> String {
+    result.unwrap_or_else(|_| String::from("E01"))
+}
+
+fn ok_or_error(result: Result<(), String>) -> String {
+    match result {
+        Ok(()) => String::from("OK"),
+        Err(_) => String::from("E01"),
+    }
+}
+
+fn parse_hex(hex: &str) -> Result<u16, String> {
+    u16::from_str_radix(hex, 16).map_err(|_| format!("invalid hex '{}'", hex))
+}
+
+fn decode_bytes(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(String::from("odd number of hex digits"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("invalid hex '{}'", hex)))
+        .collect()
+}
+
+fn encode_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn register_bytes(cpu: &CPU, n: usize) -> Vec<u8> {
+    match n {
+        0 => vec![cpu.register_a],
+        1 => vec![cpu.register_x],
+        2 => vec![cpu.register_y],
+        3 => vec![cpu.processor_status],
+        4 => vec![cpu.stack_pointer],
+        _ => cpu.program_counter.to_le_bytes().to_vec(),
+    }
+}
+
+fn set_register(cpu: &mut CPU, n: usize, bytes: &[u8]) -> Result<usize, String> {
+    let width = if n == 5 { 2 } else { 1 };
+    if bytes.len() < width {
+        return Err(String::from("register value too short"));
+    }
+    match n {
+        0 => cpu.register_a = bytes[0],
+        1 => cpu.register_x = bytes[0],
+        2 => cpu.register_y = bytes[0],
+        3 => cpu.processor_status = bytes[0],
+        4 => cpu.stack_pointer = bytes[0],
+        _ => cpu.program_counter = u16::from_le_bytes([bytes[0], bytes[1]]),
+    }
+    Ok(width)
+}
+
+fn read_registers(cpu: &CPU) -> String {
+    let bytes: Vec<u8> = (0..REGISTER_COUNT).flat_map(|n| register_bytes(cpu, n)).collect();
+    encode_bytes(&bytes)
+}
+
+fn write_registers(cpu: &mut CPU, args: &str) -> Result<(), String> {
+    let bytes = decode_bytes(args)?;
+    let mut offset = 0;
+    for n in 0..REGISTER_COUNT {
+        offset += set_register(cpu, n, &bytes[offset.min(bytes.len())..])?;
+    }
+    Ok(())
+}
+
+fn register_number(hex: &str) -> Result<usize, String> {
+    let n = parse_hex(hex)? as usize;
+    if n >= REGISTER_COUNT {
+        return Err(format!("unknown register {}", n));
+    }
+    Ok(n)
+}
+
+fn read_register(cpu: &CPU, args: &str) -> Result<String, String> {
+    Ok(encode_bytes(&register_bytes(cpu, register_number(args)?)))
+}
+
+fn write_register(cpu: &mut CPU, args: &str) -> Result<(), String> {
+    let (n, value) = args.split_once('=').ok_or("missing '='")?;
+    set_register(cpu, register_number(n)?, &decode_bytes(value)?).map(|_| ())
+}
+
+fn parse_range(args: &str) -> Result<(u16, u16), String> {
+    let (addr, len) = args.split_once(',').ok_or("missing ','")?;
+    Ok((parse_hex(addr)?, parse_hex(len)?))
+}
+
+fn read_memory(cpu: &CPU, args: &str) -> Result<String, String> {
+    let (addr, len) = parse_range(args)?;
+    let bytes: Vec<u8> = (0..len).map(|i| cpu.peek(addr.wrapping_add(i))).collect();
+    Ok(encode_bytes(&bytes))
+}
+
+fn write_memory(cpu: &mut CPU, args: &str) -> Result<(), String> {
+    let (range, data) = args.split_once(':').ok_or("missing ':'")?;
+    let (addr, len) = parse_range(range)?;
+    let bytes = decode_bytes(data)?;
+    if bytes.len() != len as usize {
+        return Err(String::from("length mismatch"));
+    }
+    for (i, byte) in bytes.into_iter().enumerate() {
+        cpu.memory_write(addr.wrapping_add(i as u16), byte);
+    }
+    Ok(())
+}
+
+fn parse_breakpoint(args: &str) -> Result<(Kind, u16, u16), String> {
+    let mut parts = args.split(',');
+    let kind = match parts.next() {
+        Some("0") | Some("1") => Kind::Software,
+        Some("2") => Kind::Write,
+        Some("3") => Kind::Read,
+        Some("4") => Kind::Access,
+        _ => return Err(String::from("unsupported breakpoint type")),
+    };
+    let addr = parse_hex(parts.next().ok_or("missing address")?)?;
+    let len = parse_hex(parts.next().ok_or("missing kind")?)?;
+    Ok((kind, addr, len))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn request(&mut self, packet: &str) -> String {
+            self.request_bytes(packet.as_bytes())
+        }
+
+        fn request_bytes(&mut self, packet: &[u8]) -> String {
+            let framed = [b"$", packet, format!("#{:02x}", checksum_of(packet)).as_bytes()].concat();
+            self.stream.write_all(&framed).unwrap();
+
+            let mut ack = [0u8; 1];
+            self.stream.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+
+            let mut byte = [0u8; 1];
+            let mut reply = Vec::new();
+            self.stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'$');
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                reply.push(byte[0]);
+            }
+            let mut checksum = [0u8; 2];
+            self.stream.read_exact(&mut checksum).unwrap();
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(reply).unwrap()
+        }
+    }
+
+    fn attach(program: Vec<u8>) -> (Client, thread::JoinHandle<(CPU, SessionEnd)>) {
+        let stub = GdbStub::bind(0).unwrap();
+        let addr = stub.local_addr().unwrap();
+
+        let handle = thread::spawn(move || {
+            let mut cpu = CPU::new();
+            cpu.load(program);
+            cpu.reset();
+            let end = stub.serve(&mut cpu).unwrap();
+            (cpu, end)
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        (Client { stream }, handle)
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let (mut client, handle) = attach(vec![0xa9, 0x05, 0x00]);
+
+        assert_eq!(client.request("g"), "00000000fd0080");
+        assert_eq!(client.request("P0=42"), "OK");
+        assert_eq!(client.request("p0"), "42");
+        assert_eq!(client.request("p5"), "0080");
+        assert_eq!(client.request("m8000,3"), "a90500");
+        assert_eq!(client.request("M0200,2:beef"), "OK");
+        assert_eq!(client.request("m0200,2"), "beef");
+        assert_eq!(client.request("p9"), "E01");
+        assert_eq!(client.request_bytes(b"M0200,1:\xff"), "E01");
+        assert_eq!(client.request_bytes(b"\xffg"), "E01");
+        assert_eq!(client.request("m0200,1"), "be");
+        assert_eq!(client.request("D"), "OK");
+
+        let (cpu, end) = handle.join().unwrap();
+        assert_eq!(cpu.register_a, 0x42);
+        assert_eq!(end, SessionEnd::Detached);
+    }
+
+    #[test]
+    fn test_step_continue_and_breakpoints() {
+        /*
+            LDA #$05
+            TAX
+            STA $10
+            INX
+            BRK
+        */
+        let (mut client, handle) = attach(vec![0xa9, 0x05, 0xaa, 0x85, 0x10, 0xe8, 0x00]);
+
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.request("p5"), "0280");
+
+        assert_eq!(client.request("Z2,0010,1"), "OK");
+        assert_eq!(client.request("c"), "T05watch:0010;");
+        assert_eq!(client.request("p5"), "0580");
+        assert_eq!(client.request("z2,0010,1"), "OK");
+
+        assert_eq!(client.request("Z0,8006,1"), "OK");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(client.request("p5"), "0680");
+        assert_eq!(client.request("p1"), "06");
+        assert_eq!(client.request("k"), "OK");
+
+        assert_eq!(handle.join().unwrap().1, SessionEnd::Killed);
+    }
+
+    #[test]
+    fn test_access_watchpoint() {
+        /*
+            LDA $10
+            STA $10
+            BRK
+        */
+        let (mut client, handle) = attach(vec![0xa5, 0x10, 0x85, 0x10, 0x00]);
+
+        assert_eq!(client.request("Z4,0010,1"), "OK");
+        assert_eq!(client.request("c"), "T05awatch:0010;");
+        assert_eq!(client.request("c"), "T05awatch:0010;");
+        assert_eq!(client.request("p5"), "0480");
+        assert_eq!(client.request("z4,0010,1"), "OK");
+        assert_eq!(client.request("k"), "OK");
+
+        assert_eq!(handle.join().unwrap().1, SessionEnd::Killed);
+    }
+}
@@ -0,0 +1,521 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+
+use crate::battery::{Battery, SaveOptions};
+use crate::blargg;
+use crate::breakpoint::{Breakpoint, CompareOp};
+use crate::cpu::{Memory, Stop, CPU};
+use crate::fm2::Movie;
+use crate::region::Region;
+use crate::screenshot::{self, ScreenshotOptions};
+use crate::wav::{Recorder, WavOptions, NO_APU_CHANNELS};
+
+// NTSC frame: 341 * 262 PPU dots at three dots per CPU cycle
+pub const CYCLES_PER_FRAME: usize = 29781;
+
+pub const EXIT_PASSED: i32 = 0;
+pub const EXIT_FAILED: i32 = 1;
+pub const EXIT_TIMEOUT: i32 = 2;
+pub const EXIT_CRASHED: i32 = 3;
+pub const EXIT_USAGE: i32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Until {
+    // the instruction at this address is about to run
+    Pc(u16),
+    // this value is written to this address
+    Memory(u16, u8),
+    // a test ROM using the blargg protocol writes its result code to $6000
+    TestStatus,
+}
+
+impl Until {
+    fn breakpoint(self) -> Breakpoint {
+        match self {
+            Until::Pc(addr) => Breakpoint::execute(addr),
+            Until::Memory(addr, value) => Breakpoint::write(addr..=addr).with_value(CompareOp::Equal, value),
+            Until::TestStatus => {
+                Breakpoint::write(blargg::STATUS..=blargg::STATUS).with_value(CompareOp::Less, blargg::STATUS_RUNNING)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    pub frames: usize,
+    pub until: Vec<Until>,
+    pub region: Region,
+    // record the audio output while running
+    pub wav: Option<WavOptions>,
+    // input for each frame, controllers are released once it ends
+    pub movie: Option<Movie>,
+    // battery-backed PRG RAM, loaded before the first frame
+    pub save: Option<SaveOptions>,
+    // the last frame, written once the run ends
+    pub screenshot: Option<ScreenshotOptions>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    // ran every frame, nothing to wait for
+    Finished,
+    Reached(Until),
+    Timeout,
+    Halted,
+    Crashed(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub rom: String,
+    pub region: Region,
+    pub outcome: Outcome,
+    pub frames: usize,
+    pub cycles: usize,
+    pub status: Option<u8>,
+    pub message: Option<String>,
+    pub registers: [u8; 5],
+    pub program_counter: u16,
+    // where the final frame was written
+    pub screenshot: Option<PathBuf>,
+}
+
+impl Report {
+    pub fn exit_code(&self) -> i32 {
+        match self.outcome {
+            Outcome::Finished => EXIT_PASSED,
+            Outcome::Reached(Until::TestStatus) if self.status != Some(0) => EXIT_FAILED,
+            Outcome::Reached(_) => EXIT_PASSED,
+            Outcome::Timeout => EXIT_TIMEOUT,
+            Outcome::Halted | Outcome::Crashed(_) => EXIT_CRASHED,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let (outcome, reached, error) = match &self.outcome {
+            Outcome::Finished => ("finished", None, None),
+            Outcome::Reached(until) => ("reached", Some(*until), None),
+            Outcome::Timeout => ("timeout", None, None),
+            Outcome::Halted => ("halted", None, None),
+            Outcome::Crashed(message) => ("crashed", None, Some(message.as_str())),
+        };
+        let reached = match reached {
+            Some(Until::Pc(addr)) => format!("\"pc:${:04X}\"", addr),
+            Some(Until::Memory(addr, value)) => format!("\"memory:${:04X}=${:02X}\"", addr, value),
+            Some(Until::TestStatus) => String::from("\"test-status\""),
+            None => String::from("null"),
+        };
+        let [a, x, y, p, sp] = self.registers;
+
+        format!(
+            concat!(
+                "{{\"rom\":{},\"region\":\"{}\",\"passed\":{},\"exit_code\":{},\"outcome\":\"{}\",\"reached\":{},",
+                "\"error\":{},\"frames\":{},\"cycles\":{},\"status\":{},\"message\":{},",
+                "\"registers\":{{\"a\":{},\"x\":{},\"y\":{},\"p\":{},\"sp\":{},\"pc\":{}}},\"screenshot\":{}}}"
+            ),
+            json_string(&self.rom),
+            self.region,
+            self.exit_code() == EXIT_PASSED,
+            self.exit_code(),
+            outcome,
+            reached,
+            error.map_or(String::from("null"), json_string),
+            self.frames,
+            self.cycles,
+            self.status.map_or(String::from("null"), |status| status.to_string()),
+            self.message.as_deref().map_or(String::from("null"), json_string),
+            a,
+            x,
+            y,
+            p,
+            sp,
+            self.program_counter,
+            self.screenshot
+                .as_ref()
+                .map_or(String::from("null"), |path| json_string(&path.display().to_string())),
+        )
+    }
+}
+
+pub fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// files written while running
+struct Outputs {
+    recorder: Option<Recorder>,
+    battery: Option<Battery>,
+    screenshot: Option<ScreenshotOptions>,
+}
+
+impl Outputs {
+    fn open(cpu: &mut CPU, options: &Options) -> Result<Self, String> {
+        if options.wav.as_ref().is_some_and(|wav| wav.channels) {
+            return Err(String::from(NO_APU_CHANNELS));
+        }
+        let recorder = options
+            .wav
+            .as_ref()
+            .map(|wav| Recorder::create(wav, options.region.cpu_clock()))
+            .transpose()
+            .map_err(|e| format!("cannot record audio: {}", e))?;
+        let battery = options.save.as_ref().map(|save| Battery::open(save, cpu)).transpose()?;
+        Ok(Outputs {
+            recorder,
+            battery,
+            screenshot: options.screenshot.clone(),
+        })
+    }
+
+    fn frame_done(&mut self, cpu: &CPU) -> Result<(), String> {
+        match &mut self.battery {
+            Some(battery) => battery.tick(cpu).map_err(|e| format!("cannot write save: {}", e)),
+            None => Ok(()),
+        }
+    }
+
+    // returns where the screenshot went
+    fn finish(self, cpu: &CPU) -> Result<Option<PathBuf>, String> {
+        if let Some(recorder) = self.recorder {
+            recorder.finish().map_err(|e| format!("cannot record audio: {}", e))?;
+        }
+        if let Some(mut battery) = self.battery {
+            battery.flush(cpu).map_err(|e| format!("cannot write save: {}", e))?;
+        }
+        match self.screenshot {
+            Some(shot) => {
+                screenshot::save(&shot.path, &cpu.frame, &shot.palette, shot.scale)
+                    .map_err(|e| format!("cannot write screenshot: {}", e))?;
+                Ok(Some(shot.path))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+fn run_frames(
+    cpu: &mut CPU,
+    options: &Options,
+    conditions: &[(usize, Until)],
+    outputs: &mut Outputs,
+    frames_run: &mut usize,
+) -> Outcome {
+    let start = cpu.cycles;
+    let cycles_per_frame = options.region.cycles_per_frame();
+    let test_status = conditions.iter().any(|(_, until)| *until == Until::TestStatus);
+    let mut reset_requested = 0;
+
+    for frame in 0..options.frames {
+        *frames_run = frame + 1;
+        let end = start + (frame + 1) * cycles_per_frame;
+
+        if let Some(movie) = &options.movie {
+            if !movie.apply(frame, cpu) {
+                for joypad in cpu.joypads.iter_mut() {
+                    joypad.set_buttons(0);
+                }
+            }
+        }
+
+        while cpu.cycles < end {
+            let before = cpu.cycles;
+            let stop = cpu.run_for(1);
+            if let Some(recorder) = &mut outputs.recorder {
+                // there is no APU yet, only cartridge audio is captured
+                if let Err(e) = recorder.advance(cpu.cycles - before, &[0; 5], cpu.expansion_audio()) {
+                    return Outcome::Crashed(format!("cannot record audio: {}", e));
+                }
+            }
+            match stop {
+                None => {}
+                Some(Stop::Brk) => return Outcome::Halted,
+                Some(Stop::Breakpoint(hit)) => {
+                    if let Some((_, until)) = conditions.iter().find(|(id, _)| *id == hit.id) {
+                        if *until != Until::TestStatus || blargg::signature_present(cpu) {
+                            return Outcome::Reached(*until);
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Err(e) = outputs.frame_done(cpu) {
+            return Outcome::Crashed(e);
+        }
+
+        if test_status && blargg::status(cpu) == Some(blargg::Status::ResetRequested) {
+            reset_requested += 1;
+            if reset_requested >= blargg::RESET_DELAY_FRAMES {
+                reset_requested = 0;
+                cpu.reset();
+            }
+        } else {
+            reset_requested = 0;
+        }
+    }
+
+    if conditions.is_empty() {
+        Outcome::Finished
+    } else {
+        Outcome::Timeout
+    }
+}
+
+// Runs up to `options.frames` frames or until one of the conditions holds.
+// A panic inside the emulator is reported as a crash instead of unwinding.
+pub fn run(cpu: &mut CPU, rom: &str, options: &Options) -> Report {
+    let conditions: Vec<(usize, Until)> = options
+        .until
+        .iter()
+        .map(|until| (cpu.breakpoints.add(until.breakpoint()), *until))
+        .collect();
+
+    let mut frames = 0;
+    let mut screenshot = None;
+    let outcome = match Outputs::open(cpu, options) {
+        Err(e) => Outcome::Crashed(e),
+        Ok(mut outputs) => {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                run_frames(cpu, options, &conditions, &mut outputs, &mut frames)
+            }));
+            let outcome = result.unwrap_or_else(|payload| {
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| String::from("unknown panic"));
+                Outcome::Crashed(message)
+            });
+            // a crash still saves, the game may have written its RAM already
+            match outputs.finish(cpu) {
+                Err(e) => Outcome::Crashed(e),
+                Ok(written) => {
+                    screenshot = written;
+                    outcome
+                }
+            }
+        }
+    };
+
+    for (id, _) in &conditions {
+        cpu.breakpoints.remove(*id);
+    }
+
+    let test_status = options.until.contains(&Until::TestStatus);
+    let status = if test_status {
+        Some(cpu.memory_read(blargg::STATUS))
+    } else {
+        None
+    };
+    let message = if test_status && blargg::signature_present(cpu) {
+        Some(blargg::message(cpu))
+    } else {
+        None
+    };
+
+    Report {
+        rom: rom.to_string(),
+        region: options.region,
+        outcome,
+        frames,
+        cycles: cpu.cycles,
+        status,
+        message,
+        registers: [
+            cpu.register_a,
+            cpu.register_x,
+            cpu.register_y,
+            cpu.processor_status,
+            cpu.stack_pointer,
+        ],
+        program_counter: cpu.program_counter,
+        screenshot,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::palette::Palette;
+
+    fn cpu_with(program: Vec<u8>) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.load(program);
+        cpu.reset();
+        cpu
+    }
+
+    fn options(frames: usize, until: Vec<Until>) -> Options {
+        Options {
+            frames,
+            until,
+            region: Region::Ntsc,
+            wav: None,
+            movie: None,
+            save: None,
+            screenshot: None,
+        }
+    }
+
+    // INX, JMP $8000
+    const SPIN: [u8; 4] = [0xe8, 0x4c, 0x00, 0x80];
+
+    #[test]
+    fn test_runs_all_frames_without_conditions() {
+        let mut cpu = cpu_with(SPIN.to_vec());
+        let report = run(&mut cpu, "spin.nes", &options(2, vec![]));
+
+        assert_eq!(report.outcome, Outcome::Finished);
+        assert_eq!(report.frames, 2);
+        assert!(report.cycles >= 2 * CYCLES_PER_FRAME);
+        assert_eq!(report.exit_code(), EXIT_PASSED);
+    }
+
+    #[test]
+    fn test_region_sets_frame_length() {
+        let mut cpu = cpu_with(SPIN.to_vec());
+        let options = Options {
+            region: Region::Pal,
+            ..options(1, vec![])
+        };
+        let report = run(&mut cpu, "spin.nes", &options);
+        assert!(report.cycles >= Region::Pal.cycles_per_frame());
+        assert!(report.cycles < Region::Pal.cycles_per_frame() + 8);
+    }
+
+    #[test]
+    fn test_timeout_and_pc_condition() {
+        let mut cpu = cpu_with(SPIN.to_vec());
+        let report = run(&mut cpu, "spin.nes", &options(1, vec![Until::Pc(0x9000)]));
+        assert_eq!(report.outcome, Outcome::Timeout);
+        assert_eq!(report.exit_code(), EXIT_TIMEOUT);
+
+        let report = run(&mut cpu, "spin.nes", &options(1, vec![Until::Pc(0x8001)]));
+        assert_eq!(report.outcome, Outcome::Reached(Until::Pc(0x8001)));
+        assert_eq!(report.program_counter, 0x8001);
+        assert!(cpu.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn test_test_status_maps_to_exit_code() {
+        // LDA #$80, STA $6000, LDA #$02, STA $6000, JMP $800a
+        let program = vec![0xa9, 0x80, 0x8d, 0x00, 0x60, 0xa9, 0x02, 0x8d, 0x00, 0x60, 0x4c, 0x0a, 0x80];
+        let mut cpu = cpu_with(program);
+        for (i, byte) in blargg::SIGNATURE.iter().enumerate() {
+            cpu.memory_write(0x6001 + i as u16, *byte);
+        }
+        let report = run(&mut cpu, "fail.nes", &options(10, vec![Until::TestStatus]));
+
+        assert_eq!(report.outcome, Outcome::Reached(Until::TestStatus));
+        assert_eq!(report.status, Some(2));
+        assert_eq!(report.exit_code(), EXIT_FAILED);
+        assert!(report.to_json().contains("\"passed\":false"));
+    }
+
+    #[test]
+    fn test_crash_is_reported() {
+        // unofficial opcode
+        let mut cpu = cpu_with(vec![0x02]);
+        let report = run(&mut cpu, "crash.nes", &options(1, vec![]));
+
+        assert!(matches!(report.outcome, Outcome::Crashed(_)));
+        assert_eq!(report.exit_code(), EXIT_CRASHED);
+    }
+
+    #[test]
+    fn test_records_wav() {
+        let path = std::env::temp_dir().join(format!("nes-headless-{}.wav", std::process::id()));
+        let wav = WavOptions {
+            path: path.clone(),
+            sample_rate: 44100,
+            channels: false,
+        };
+        let mut cpu = cpu_with(SPIN.to_vec());
+        let report = run(&mut cpu, "spin.nes", &Options { wav: Some(wav), ..options(60, vec![]) });
+
+        assert_eq!(report.outcome, Outcome::Finished);
+        // about a second of audio
+        let len = std::fs::metadata(&path).unwrap().len();
+        assert!((len as i64 - 44 - 88200).abs() < 200, "{}", len);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_refuses_silent_channel_files() {
+        let path = std::env::temp_dir().join(format!("nes-headless-channels-{}.wav", std::process::id()));
+        let wav = WavOptions {
+            path: path.clone(),
+            sample_rate: 44100,
+            channels: true,
+        };
+        let mut cpu = cpu_with(SPIN.to_vec());
+        let report = run(&mut cpu, "spin.nes", &Options { wav: Some(wav), ..options(1, vec![]) });
+
+        assert_eq!(report.outcome, Outcome::Crashed(String::from(NO_APU_CHANNELS)));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_writes_final_screenshot() {
+        let path = std::env::temp_dir().join(format!("nes-headless-{}.ppm", std::process::id()));
+        let shot = ScreenshotOptions {
+            path: path.clone(),
+            scale: 2,
+            palette: Palette::default(),
+        };
+        let mut cpu = cpu_with(SPIN.to_vec());
+        cpu.frame.set_pixel(0, 0, 0x30);
+        let report = run(&mut cpu, "spin.nes", &Options { screenshot: Some(shot), ..options(2, vec![]) });
+        assert_eq!(report.outcome, Outcome::Finished);
+        assert_eq!(report.screenshot.as_ref(), Some(&path));
+        assert!(report.to_json().contains(&format!("\"screenshot\":{}", json_string(&path.display().to_string()))));
+
+        let data = std::fs::read(&path).unwrap();
+        let header = b"P6\n512 480\n255\n";
+        assert_eq!(&data[..header.len()], header);
+        assert_eq!(data.len(), header.len() + 512 * 480 * 3);
+        let (r, g, b) = Palette::default().rgb(0x30);
+        assert_eq!(&data[header.len()..header.len() + 6], &[r, g, b, r, g, b]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_battery_save_round_trip() {
+        let path = std::env::temp_dir().join(format!("nes-headless-{}.sav", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let save = SaveOptions {
+            path: path.clone(),
+            flush_frames: 0,
+        };
+
+        // INC $6000, JMP to itself
+        let mut cpu = cpu_with(vec![0xee, 0x00, 0x60, 0x4c, 0x03, 0x80]);
+        run(&mut cpu, "save.nes", &Options { save: Some(save.clone()), ..options(1, vec![]) });
+        assert_eq!(std::fs::read(&path).unwrap()[0], 1);
+
+        let mut cpu = cpu_with(vec![0xee, 0x00, 0x60, 0x4c, 0x03, 0x80]);
+        run(&mut cpu, "save.nes", &Options { save: Some(save), ..options(1, vec![]) });
+        assert_eq!(std::fs::read(&path).unwrap()[0], 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_json_string_escapes() {
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+}
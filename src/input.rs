@@ -0,0 +1,136 @@
+use std::any::Any;
+
+use crate::frame::Frame;
+use crate::joypad::Joypad;
+use crate::keyboard::Keyboard;
+use crate::mapper::{StateReader, StateWriter};
+use crate::paddle::{Paddle, PaddleModel};
+use crate::power_pad::PowerPad;
+use crate::zapper::{Beam, Zapper};
+
+// What the television shows when a device is read, for light guns: the
+// picture and where the PPU is drawing it.
+#[derive(Debug, Clone, Copy)]
+pub struct Screen<'a> {
+    pub frame: &'a Frame,
+    pub beam: Beam,
+}
+
+// Something plugged into a controller port or the Famicom expansion port.
+// Every $4016 write reaches all of them, reads go to the ones wired to
+// that register. Reads through `&self` may shift, devices keep that state
+// in Cells like the joypads do.
+pub trait InputDevice: Send {
+    // OUT0-OUT2 in bits 0-2
+    fn write(&mut self, data: u8);
+
+    // bits 0-4 the device drives on $4016 (`port` 0) or $4017 (1)
+    fn read(&self, port: usize, screen: &Screen) -> u8;
+
+    // for hosts to get at the concrete device and feed it input
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+
+    // shift registers and the host's input, for save states
+    fn save_state(&self) -> Vec<u8>;
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String>;
+}
+
+impl InputDevice for Joypad {
+    fn write(&mut self, data: u8) {
+        Joypad::write(self, data);
+    }
+
+    fn read(&self, _port: usize, _screen: &Screen) -> u8 {
+        Joypad::read(self)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        self.save(&mut state);
+        state.data
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut state = StateReader::new(data);
+        let mut restored = self.clone();
+        restored.load(&mut state)?;
+        state.finish()?;
+        *self = restored;
+        Ok(())
+    }
+}
+
+impl InputDevice for Zapper {
+    fn write(&mut self, _data: u8) {}
+
+    fn read(&self, _port: usize, screen: &Screen) -> u8 {
+        Zapper::read(self, screen.frame, screen.beam)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        self.save(&mut state);
+        state.data
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut state = StateReader::new(data);
+        let mut restored = self.clone();
+        restored.load(&mut state)?;
+        state.finish()?;
+        *self = restored;
+        Ok(())
+    }
+}
+
+// where a device plugs in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Connector {
+    // controller ports, in place of the joypad
+    Port1,
+    Port2,
+    // Famicom expansion port, alongside the joypads
+    Expansion,
+}
+
+pub fn create(name: &str) -> Result<(Connector, Box<dyn InputDevice>), String> {
+    match name.to_ascii_lowercase().as_str() {
+        "zapper" => Ok((Connector::Port2, Box::new(Zapper::new()))),
+        "paddle" | "vaus" => Ok((Connector::Port2, Box::new(Paddle::new(PaddleModel::Nes)))),
+        "famicom-paddle" | "famicom-vaus" => Ok((Connector::Expansion, Box::new(Paddle::new(PaddleModel::Famicom)))),
+        "power-pad" | "powerpad" | "family-trainer" => Ok((Connector::Port2, Box::new(PowerPad::new()))),
+        "keyboard" | "family-basic" => Ok((Connector::Expansion, Box::new(Keyboard::new()))),
+        _ => Err(format!(
+            "unknown input device '{}', expected zapper, paddle, famicom-paddle, power-pad or keyboard",
+            name
+        )),
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // reads a device that doesn't look at the screen
+    pub fn read(device: &dyn InputDevice, port: usize) -> u8 {
+        device.read(port, &Screen { frame: &Frame::new(), beam: Beam::default() })
+    }
+
+    #[test]
+    fn test_create() {
+        let (connector, mut device) = create("Famicom-Paddle").unwrap();
+        assert_eq!(connector, Connector::Expansion);
+        assert!(device.as_any_mut().downcast_mut::<Paddle>().is_some());
+        assert_eq!(create("power-pad").unwrap().0, Connector::Port2);
+        assert!(create("mouse").is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use std::cell::Cell;
+
+use crate::mapper::{StateReader, StateWriter};
+
+pub const BUTTON_A: u8 = 0b0000_0001;
+pub const BUTTON_B: u8 = 0b0000_0010;
+pub const BUTTON_SELECT: u8 = 0b0000_0100;
+pub const BUTTON_START: u8 = 0b0000_1000;
+pub const BUTTON_UP: u8 = 0b0001_0000;
+pub const BUTTON_DOWN: u8 = 0b0010_0000;
+pub const BUTTON_LEFT: u8 = 0b0100_0000;
+pub const BUTTON_RIGHT: u8 = 0b1000_0000;
+
+// in the order the controller shifts them out
+pub const BUTTONS: [(&str, u8); 8] = [
+    ("A", BUTTON_A),
+    ("B", BUTTON_B),
+    ("SELECT", BUTTON_SELECT),
+    ("START", BUTTON_START),
+    ("UP", BUTTON_UP),
+    ("DOWN", BUTTON_DOWN),
+    ("LEFT", BUTTON_LEFT),
+    ("RIGHT", BUTTON_RIGHT),
+];
+
+// Standard controller on $4016/$4017. Reads shift the next button out, so
+// the index is a Cell to allow reading through `&self` like the rest of
+// memory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Joypad {
+    strobe: bool,
+    button_index: Cell<u8>,
+    pub button_status: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            strobe: false,
+            button_index: Cell::new(0),
+            button_status: 0,
+        }
+    }
+
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.button_index.set(0);
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        let index = self.button_index.get();
+        // official controllers return 1 once all eight buttons are read
+        if index > 7 {
+            return 1;
+        }
+        let response = (self.button_status >> index) & 1;
+        if !self.strobe {
+            self.button_index.set(index + 1);
+        }
+        response
+    }
+
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.button_status = buttons;
+    }
+
+    pub fn save(&self, state: &mut StateWriter) {
+        state.bool(self.strobe);
+        state.u8(self.button_index.get());
+        state.u8(self.button_status);
+    }
+
+    pub fn load(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.strobe = state.bool()?;
+        self.button_index.set(state.u8()?);
+        self.button_status = state.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_strobe_and_shift() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(BUTTON_A | BUTTON_START | BUTTON_RIGHT);
+
+        joypad.write(1);
+        // strobe held: always A
+        assert_eq!(joypad.read(), 1);
+        assert_eq!(joypad.read(), 1);
+
+        joypad.write(0);
+        let bits: Vec<u8> = (0..10).map(|_| joypad.read()).collect();
+        assert_eq!(bits, vec![1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+
+        joypad.write(1);
+        joypad.write(0);
+        assert_eq!(joypad.read(), 1);
+        assert_eq!(joypad.read(), 0);
+    }
+}
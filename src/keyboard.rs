@@ -0,0 +1,169 @@
+use std::any::Any;
+
+use crate::input::{InputDevice, Screen};
+use crate::mapper::{StateReader, StateWriter};
+
+const ROWS: usize = 9;
+
+// key names by row, column and $4017 bit 1-4
+const KEYS: [[[&str; 4]; 2]; ROWS] = [
+    [["]", "[", "RETURN", "F8"], ["STOP", "YEN", "RSHIFT", "KANA"]],
+    [[";", ":", "@", "F7"], ["^", "-", "/", "_"]],
+    [["K", "L", "O", "F6"], ["0", "P", ",", "."]],
+    [["J", "U", "I", "F5"], ["8", "9", "N", "M"]],
+    [["H", "G", "Y", "F4"], ["6", "7", "V", "B"]],
+    [["D", "R", "T", "F3"], ["4", "5", "C", "F"]],
+    [["A", "S", "W", "F2"], ["3", "E", "Z", "X"]],
+    [["CTR", "Q", "ESC", "F1"], ["2", "1", "GRPH", "LSHIFT"]],
+    [["LEFT", "RIGHT", "UP", "CLR"], ["INS", "DEL", "SPACE", "DOWN"]],
+];
+
+// Family BASIC keyboard on the expansion port. $4016 writes walk a 9 row
+// matrix, bit 0 back to the first row, bit 1 picks the column and moving
+// it from 1 to 0 goes to the next row, bit 2 enables the keyboard. $4017
+// bits 1-4 read the selected four keys, 0 when pressed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Keyboard {
+    // four keys per row and column, 1 when pressed
+    pressed: [[u8; 2]; ROWS],
+    row: usize,
+    column: usize,
+    enabled: bool,
+}
+
+impl Keyboard {
+    pub fn new() -> Self {
+        Keyboard::default()
+    }
+
+    // names as printed on the keys, CTR, GRPH, KANA, YEN and LSHIFT/RSHIFT
+    // for the ones that aren't
+    pub fn set_key(&mut self, name: &str, pressed: bool) -> Result<(), String> {
+        let (row, column, bit) = key_position(name).ok_or(format!("unknown key '{}'", name))?;
+        if pressed {
+            self.pressed[row][column] |= 1 << bit;
+        } else {
+            self.pressed[row][column] &= !(1 << bit);
+        }
+        Ok(())
+    }
+
+    pub fn release_all(&mut self) {
+        self.pressed = Default::default();
+    }
+}
+
+fn key_position(name: &str) -> Option<(usize, usize, usize)> {
+    let name = name.to_ascii_uppercase();
+    for (row, columns) in KEYS.iter().enumerate() {
+        for (column, keys) in columns.iter().enumerate() {
+            if let Some(bit) = keys.iter().position(|key| *key == name) {
+                return Some((row, column, bit));
+            }
+        }
+    }
+    None
+}
+
+impl InputDevice for Keyboard {
+    fn write(&mut self, data: u8) {
+        let column = (data >> 1 & 1) as usize;
+        if data & 1 == 1 {
+            self.row = 0;
+        } else if self.column == 1 && column == 0 {
+            self.row = (self.row + 1).min(ROWS);
+        }
+        self.column = column;
+        self.enabled = data & 0b100 != 0;
+    }
+
+    fn read(&self, port: usize, _screen: &Screen) -> u8 {
+        if port != 1 || !self.enabled {
+            return 0;
+        }
+        // past the last row nothing is pressed
+        let pressed = self.pressed.get(self.row).map_or(0, |row| row[self.column]);
+        (!pressed & 0x0f) << 1
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        for row in &self.pressed {
+            state.bytes(row);
+        }
+        state.u8(self.row as u8);
+        state.u8(self.column as u8);
+        state.bool(self.enabled);
+        state.data
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut state = StateReader::new(data);
+        let mut restored = self.clone();
+        for row in restored.pressed.iter_mut() {
+            row.copy_from_slice(state.bytes(2)?);
+        }
+        restored.row = (state.u8()? as usize).min(ROWS);
+        restored.column = (state.u8()? & 1) as usize;
+        restored.enabled = state.bool()?;
+        state.finish()?;
+        *self = restored;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::input::test::read;
+
+    // what Family BASIC does each frame: reset, then both columns of
+    // every row
+    fn scan(keyboard: &mut Keyboard) -> Vec<u8> {
+        let mut rows = Vec::new();
+        keyboard.write(0b101);
+        for _ in 0..ROWS {
+            keyboard.write(0b100);
+            rows.push(read(keyboard, 1));
+            keyboard.write(0b110);
+            rows.push(read(keyboard, 1));
+        }
+        keyboard.write(0b100);
+        rows.push(read(keyboard, 1));
+        rows
+    }
+
+    #[test]
+    fn test_matrix_scan() {
+        let mut keyboard = Keyboard::new();
+        keyboard.set_key("return", true).unwrap();
+        keyboard.set_key("A", true).unwrap();
+        keyboard.set_key("SPACE", true).unwrap();
+        keyboard.set_key("DOWN", true).unwrap();
+
+        let rows = scan(&mut keyboard);
+        assert_eq!(rows.len(), 19);
+        assert_eq!(rows[0], 0b10110);
+        assert_eq!(rows[12], 0b11100);
+        assert_eq!(rows[17], 0b00110);
+        assert!(rows.iter().enumerate().all(|(i, row)| [0, 12, 17].contains(&i) || *row == 0b11110));
+
+        keyboard.release_all();
+        assert!(scan(&mut keyboard).iter().all(|row| *row == 0b11110));
+        assert!(keyboard.set_key("HENKAN", true).is_err());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut keyboard = Keyboard::new();
+        keyboard.write(0b001);
+        assert_eq!(read(&keyboard, 1), 0);
+        keyboard.write(0b100);
+        assert_eq!(read(&keyboard, 1), 0b11110);
+        assert_eq!(read(&keyboard, 0), 0);
+    }
+}
@@ -1,5 +1,39 @@
+pub mod battery;
+pub mod blargg;
+pub mod breakpoint;
+pub mod cheat;
 pub mod cpu;
+pub mod fds;
+pub mod fme7;
+pub mod fm2;
+pub mod frame;
+pub mod gdb;
+pub mod headless;
+pub mod input;
+pub mod joypad;
+pub mod keyboard;
+pub mod mapper;
+pub mod mask;
+pub mod mmc5;
+pub mod multitap;
+pub mod namco163;
+pub mod nsf;
 pub mod opcodes;
+pub mod opll;
+pub mod paddle;
+pub mod palette;
+pub mod power_pad;
+pub mod region;
+pub mod regression;
+pub mod rewind;
+pub mod rom;
+pub mod savestate;
+pub mod screenshot;
+pub mod vrc;
+pub mod vrc6;
+pub mod vrc7;
+pub mod wav;
+pub mod zapper;
 
 #[macro_use]
 extern crate lazy_static;
\ No newline at end of file
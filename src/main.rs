@@ -1,3 +1,371 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use nes_rust_project::battery::{sav_path, SaveOptions, DEFAULT_FLUSH_FRAMES};
+use nes_rust_project::breakpoint::parse_number;
+use nes_rust_project::cheat::Cheat;
+use nes_rust_project::cpu::CPU;
+use nes_rust_project::fds::{self, DiskImage};
+use nes_rust_project::fm2::Movie;
+use nes_rust_project::gdb::{GdbStub, SessionEnd};
+use nes_rust_project::headless::{self, json_string, Options, Until, EXIT_CRASHED, EXIT_PASSED, EXIT_USAGE};
+use nes_rust_project::input::{self, Connector, InputDevice};
+use nes_rust_project::multitap::Multitap;
+use nes_rust_project::nsf::{self, Nsf, Player};
+use nes_rust_project::palette::Palette;
+use nes_rust_project::region::Region;
+use nes_rust_project::rom::Rom;
+use nes_rust_project::screenshot::ScreenshotOptions;
+use nes_rust_project::wav::{WavOptions, DEFAULT_SAMPLE_RATE, NO_APU_CHANNELS};
+
+const USAGE: &str = "usage: nes_rust_project <rom.nes | disk.fds | music.nsf> [options]
+
+  --frames N              run at most N frames (default 600)
+  --until-pc ADDR         stop when the instruction at ADDR is reached
+  --until-mem ADDR=VALUE  stop when VALUE is written to ADDR
+  --test-status           stop when a test ROM writes its result to $6000
+  --region REGION         ntsc, pal, dendy or auto (default, from the header)
+  --movie PATH            play back an FM2 movie, runs its length unless --frames
+  --sav PATH              battery save file (default <rom>.sav, when the cart has one,
+                          and <disk>.ips with what was written to a disk)
+  --sav-interval FRAMES   write battery RAM every FRAMES frames, 0 only on exit
+                          (default 600)
+  --no-sav                don't load or write battery saves
+  --cheat CODE            apply a Game Genie or ADDR:VALUE[:COMPARE] code
+  --cheats PATH           load a cheat list, one code per line
+  --wav PATH              record the audio output to a 16-bit WAV file
+  --wav-rate HZ           sample rate of the recording (default 44100)
+  --screenshot PATH       write the final frame, PNG or .ppm
+  --screenshot-scale N    integer scaling of the screenshot (default 1)
+  --palette PATH          .pal file for the screenshot (default the built-in one)
+  --clean-audio           average time-multiplexed expansion channels (Namco 163)
+                          instead of switching between them like the hardware
+  --bios PATH             Famicom Disk System BIOS, needed for .fds images
+  --four-player ADAPTER   fourscore (NES Four Score / Satellite), famicom (expansion
+                          port adapter) or none (default), for controllers 3 and 4
+  --input DEVICE          plug in zapper, paddle (Arkanoid) or power-pad on port 2,
+                          famicom-paddle or keyboard (Family BASIC) on the expansion port
+  --track N               NSF track to render with --wav (default the file's first)
+  --gdb PORT              wait for GDB on localhost:PORT before running, the run
+                          continues once it detaches, kill exits
+  --report PATH           write the JSON report to PATH instead of stdout
+
+Numbers are decimal, $hex or 0xhex.";
+
+struct Args {
+    rom: String,
+    options: Options,
+    // None picks the region from the ROM header
+    region: Option<Region>,
+    frames_given: bool,
+    movie: Option<String>,
+    sav: Option<PathBuf>,
+    sav_interval: usize,
+    no_sav: bool,
+    cheats: Vec<Cheat>,
+    cheat_files: Vec<String>,
+    clean_audio: bool,
+    bios: Option<String>,
+    multitap: Multitap,
+    inputs: Vec<(Connector, Box<dyn InputDevice>)>,
+    // 1 based, as players number them
+    track: Option<usize>,
+    gdb: Option<u16>,
+    report: Option<String>,
+}
+
+fn parse_byte(value: &str) -> Result<u8, String> {
+    let number = parse_number(value)?;
+    u8::try_from(number).map_err(|_| format!("value '{}' does not fit in a byte", value))
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut rom = None;
+    let mut options = Options {
+        frames: 600,
+        until: Vec::new(),
+        region: Region::Ntsc,
+        wav: None,
+        movie: None,
+        save: None,
+        screenshot: None,
+    };
+    let mut wav = WavOptions {
+        path: PathBuf::new(),
+        sample_rate: DEFAULT_SAMPLE_RATE,
+        channels: false,
+    };
+    let mut screenshot = ScreenshotOptions {
+        path: PathBuf::new(),
+        scale: 1,
+        palette: Palette::default(),
+    };
+    let mut palette_given = false;
+    let mut region = None;
+    let mut frames_given = false;
+    let mut movie = None;
+    let mut sav = None;
+    let mut sav_interval = DEFAULT_FLUSH_FRAMES;
+    let mut no_sav = false;
+    let mut cheats = Vec::new();
+    let mut cheat_files = Vec::new();
+    let mut clean_audio = false;
+    let mut bios = None;
+    let mut multitap = Multitap::None;
+    let mut inputs = Vec::new();
+    let mut track = None;
+    let mut gdb = None;
+    let mut report = None;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().ok_or(format!("missing value for {}", arg));
+
+        match arg.as_str() {
+            "--frames" => {
+                options.frames = value()?.parse().map_err(|_| String::from("invalid frame count"))?;
+                frames_given = true;
+            }
+            "--until-pc" => options.until.push(Until::Pc(parse_number(value()?)?)),
+            "--until-mem" => {
+                let (addr, byte) = value()?.split_once('=').ok_or("expected ADDR=VALUE")?;
+                options.until.push(Until::Memory(parse_number(addr)?, parse_byte(byte)?));
+            }
+            "--test-status" => options.until.push(Until::TestStatus),
+            "--region" => {
+                region = match value()?.as_str() {
+                    "auto" => None,
+                    name => Some(name.parse()?),
+                }
+            }
+            "--movie" => movie = Some(value()?.clone()),
+            "--sav" => sav = Some(PathBuf::from(value()?)),
+            "--sav-interval" => {
+                sav_interval = value()?.parse().map_err(|_| String::from("invalid save interval"))?;
+            }
+            "--no-sav" => no_sav = true,
+            "--cheat" => cheats.push(value()?.parse()?),
+            "--cheats" => cheat_files.push(value()?.clone()),
+            "--wav" => wav.path = PathBuf::from(value()?),
+            "--wav-rate" => {
+                wav.sample_rate = value()?.parse().map_err(|_| String::from("invalid sample rate"))?;
+            }
+            "--wav-channels" => return Err(String::from(NO_APU_CHANNELS)),
+            "--screenshot" => screenshot.path = PathBuf::from(value()?),
+            "--screenshot-scale" => {
+                screenshot.scale = value()?.parse().map_err(|_| String::from("invalid screenshot scale"))?;
+            }
+            "--palette" => {
+                screenshot.palette = Palette::load(value()?)?;
+                palette_given = true;
+            }
+            "--clean-audio" => clean_audio = true,
+            "--bios" => bios = Some(value()?.clone()),
+            "--four-player" => multitap = value()?.parse()?,
+            "--input" => inputs.push(input::create(value()?)?),
+            "--track" => track = Some(value()?.parse().map_err(|_| String::from("invalid track number"))?),
+            "--gdb" => gdb = Some(value()?.parse().map_err(|_| String::from("invalid port"))?),
+            "--report" => report = Some(value()?.clone()),
+            "-h" | "--help" => return Err(String::new()),
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            path if rom.is_none() => rom = Some(path.to_string()),
+            extra => return Err(format!("unexpected argument {}", extra)),
+        }
+    }
+
+    if !wav.path.as_os_str().is_empty() {
+        options.wav = Some(wav);
+    } else if wav.sample_rate != DEFAULT_SAMPLE_RATE {
+        return Err(String::from("--wav-rate needs --wav"));
+    }
+    if !screenshot.path.as_os_str().is_empty() {
+        options.screenshot = Some(screenshot);
+    } else if palette_given || screenshot.scale != 1 {
+        return Err(String::from("--screenshot-scale and --palette need --screenshot"));
+    }
+
+    Ok(Args {
+        rom: rom.ok_or("missing ROM path")?,
+        options,
+        region,
+        frames_given,
+        movie,
+        sav,
+        sav_interval,
+        no_sav,
+        cheats,
+        cheat_files,
+        clean_audio,
+        bios,
+        multitap,
+        inputs,
+        track,
+        gdb,
+        report,
+    })
+}
+
+fn fail(message: &str) -> ! {
+    if !message.is_empty() {
+        eprintln!("error: {}", message);
+    }
+    eprintln!("{}", USAGE);
+    process::exit(EXIT_USAGE);
+}
+
+fn write_report(path: Option<&String>, json: String) {
+    match path {
+        Some(path) => {
+            if let Err(e) = fs::write(path, json + "\n") {
+                eprintln!("error: cannot write {}: {}", path, e);
+                process::exit(EXIT_USAGE);
+            }
+        }
+        None => println!("{}", json),
+    }
+}
+
+// Lists the tracks and renders one to --wav. Reports the same way as a ROM
+// run, without the CPU fields.
+fn play_nsf(args: &Args, raw: &[u8]) -> i32 {
+    let nsf = Nsf::new(raw).unwrap_or_else(|e| fail(&format!("{}: {}", args.rom, e)));
+    let region = args.region.unwrap_or(nsf.region);
+    let track = match args.track {
+        Some(0) => fail("tracks are numbered from 1"),
+        Some(track) => track - 1,
+        None => nsf.starting_track,
+    };
+    if track >= nsf.tracks.len() {
+        fail(&format!("{}: has {} tracks", args.rom, nsf.tracks.len()));
+    }
+
+    let mut player = Player::new(nsf, region);
+    let error = match &args.options.wav {
+        Some(wav) => player.render(track, wav).err(),
+        None => None,
+    };
+
+    let tracks: Vec<String> = player
+        .nsf
+        .tracks
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            let (length, fade) = entry.duration();
+            format!(
+                "{{\"track\":{},\"title\":{},\"length_ms\":{},\"fade_ms\":{}}}",
+                i + 1,
+                entry.title.as_deref().map_or(String::from("null"), json_string),
+                length,
+                fade
+            )
+        })
+        .collect();
+    let json = format!(
+        "{{\"nsf\":{},\"title\":{},\"artist\":{},\"copyright\":{},\"region\":\"{}\",\"expansion\":{},\"track\":{},\"error\":{},\"tracks\":[{}]}}",
+        json_string(&args.rom),
+        json_string(&player.nsf.title),
+        json_string(&player.nsf.artist),
+        json_string(&player.nsf.copyright),
+        region,
+        player.nsf.expansion,
+        track + 1,
+        error.as_deref().map_or(String::from("null"), json_string),
+        tracks.join(",")
+    );
+    write_report(args.report.as_ref(), json);
+    if error.is_some() {
+        EXIT_CRASHED
+    } else {
+        EXIT_PASSED
+    }
+}
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut args = parse_args(&args).unwrap_or_else(|e| fail(&e));
+
+    let raw = fs::read(&args.rom).unwrap_or_else(|e| fail(&format!("cannot read {}: {}", args.rom, e)));
+    if nsf::is_nsf(&raw) {
+        process::exit(play_nsf(&args, &raw));
+    }
+    let mut cpu = CPU::new();
+
+    // disks have no header to take a region or battery from, the FDS is
+    // Japanese only and always saves
+    let rom = if fds::is_disk_image(Path::new(&args.rom), &raw) {
+        let disk = DiskImage::new(&raw).unwrap_or_else(|e| fail(&format!("{}: {}", args.rom, e)));
+        let path = args.bios.as_ref().unwrap_or_else(|| fail("disk images need --bios"));
+        let bios = fs::read(path).unwrap_or_else(|e| fail(&format!("cannot read {}: {}", path, e)));
+        cpu.load_disk(&bios, &disk).unwrap_or_else(|e| fail(&format!("{}: {}", args.rom, e)));
+        None
+    } else {
+        let rom = Rom::new(&raw).unwrap_or_else(|e| fail(&format!("{}: {}", args.rom, e)));
+        cpu.load_rom(&rom).unwrap_or_else(|e| fail(&format!("{}: {}", args.rom, e)));
+        Some(rom)
+    };
+
+    cpu.multitap = args.multitap;
+    for (connector, device) in args.inputs.drain(..) {
+        cpu.plug(connector, device);
+    }
+    args.options.region = args.region.unwrap_or(rom.as_ref().map_or(Region::Ntsc, |rom| rom.region));
+    if let Some(mapper) = cpu.mapper.as_mut() {
+        mapper.set_clean_audio(args.clean_audio);
+    }
+    for path in &args.cheat_files {
+        cpu.cheats.load(path).unwrap_or_else(|e| fail(&e));
+    }
+    for cheat in args.cheats.drain(..) {
+        cpu.cheats.add(cheat);
+    }
+    cpu.reset();
+
+    // movies start from clean RAM or their own state, a .sav would desync them
+    let battery = rom.as_ref().is_none_or(|rom| rom.battery);
+    if battery && !args.no_sav && args.movie.is_none() {
+        let default_path = match rom {
+            Some(_) => sav_path(Path::new(&args.rom)),
+            None => fds::diff_path(Path::new(&args.rom)),
+        };
+        args.options.save = Some(SaveOptions {
+            path: args.sav.clone().unwrap_or(default_path),
+            flush_frames: args.sav_interval,
+        });
+    }
+
+    if let Some(path) = &args.movie {
+        let movie = Movie::load(path).unwrap_or_else(|e| fail(&e));
+        if let Some(Err(e)) = rom.as_ref().map(|rom| movie.check_rom(rom)) {
+            eprintln!("warning: {}", e);
+        }
+        movie.start(&mut cpu).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+        if !args.frames_given {
+            args.options.frames = movie.frames.len();
+        }
+        if args.region.is_none() && movie.pal {
+            args.options.region = Region::Pal;
+        }
+        args.options.movie = Some(movie);
+    }
+
+    if let Some(port) = args.gdb {
+        let stub = GdbStub::bind(port).unwrap_or_else(|e| fail(&format!("cannot listen on port {}: {}", port, e)));
+        if let Ok(addr) = stub.local_addr() {
+            eprintln!("waiting for gdb on {}", addr);
+        }
+        match stub.serve(&mut cpu) {
+            Ok(SessionEnd::Detached) => {}
+            Ok(SessionEnd::Killed) => process::exit(EXIT_PASSED),
+            Err(e) => fail(&format!("gdb: {}", e)),
+        }
+    }
+
+    cpu.region = args.options.region;
+    let report = headless::run(&mut cpu, &args.rom, &args.options);
+    write_report(args.report.as_ref(), report.to_json());
+
+    process::exit(report.exit_code());
 }
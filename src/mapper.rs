@@ -0,0 +1,255 @@
+use crate::fds::Fds;
+use crate::fme7::Fme7;
+use crate::mmc5::Mmc5;
+use crate::namco163::Namco163;
+use crate::rom::{Mirroring, Rom};
+use crate::vrc::Vrc24;
+use crate::vrc6::Vrc6;
+use crate::vrc7::Vrc7;
+
+// What the PPU is fetching, mappers like MMC5 substitute data per kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fetch {
+    Nametable,
+    Attribute,
+    BackgroundPattern,
+    SpritePattern,
+    // $2007 reads and writes
+    Data,
+}
+
+// A cartridge board. The CPU side covers $4020-$FFFF, the PPU side
+// $0000-$3EFF where `vram` is the console's own 2 KiB of nametable RAM.
+// Reads through `&self` may have side effects, implementations keep those
+// in Cells the way the joypads do.
+pub trait Mapper: Send {
+    fn cpu_read(&self, addr: u16) -> u8;
+
+    fn cpu_write(&mut self, addr: u16, data: u8);
+
+    // what a read would return, without its side effects, for debuggers
+    fn cpu_peek(&self, addr: u16) -> u8 {
+        self.cpu_read(addr)
+    }
+
+    fn ppu_read(&mut self, addr: u16, fetch: Fetch, vram: &[u8; 0x800]) -> u8;
+
+    fn ppu_write(&mut self, addr: u16, data: u8, vram: &mut [u8; 0x800]);
+
+    // writes to $2000-$2007 as the PPU sees them, for boards that snoop
+    fn ppu_register_write(&mut self, _addr: u16, _data: u8) {}
+
+    // start of each scanline, 0-239 are visible
+    fn ppu_scanline(&mut self, _scanline: usize, _rendering: bool) {}
+
+    // CPU cycles that passed since the last call
+    fn clock(&mut self, _cycles: usize) {}
+
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // expansion audio, on the same scale as wav::mix
+    fn audio(&self) -> f32 {
+        0.0
+    }
+
+    // boards that time-multiplex their channels can average them instead,
+    // cleaner than the real thing
+    fn set_clean_audio(&mut self, _clean: bool) {}
+
+    // the Famicom Disk System's drive, for inserting and flipping disks
+    fn disk_drive(&self) -> Option<&Fds> {
+        None
+    }
+
+    fn disk_drive_mut(&mut self) -> Option<&mut Fds> {
+        None
+    }
+
+    fn prg_ram(&self) -> &[u8];
+
+    fn prg_ram_mut(&mut self) -> &mut [u8];
+
+    fn save_state(&self) -> Vec<u8>;
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String>;
+}
+
+pub fn create(rom: &Rom) -> Result<Box<dyn Mapper>, String> {
+    // the boards here fix their last one or two 8 KiB pages, so they need
+    // at least one 16 KiB iNES page
+    if rom.prg_rom.len() < 0x4000 {
+        return Err(format!("mapper {} needs at least 16 KiB of PRG ROM", rom.mapper));
+    }
+    match rom.mapper {
+        5 => Ok(Box::new(Mmc5::new(rom))),
+        19 => Ok(Box::new(Namco163::new(rom))),
+        21 | 22 | 23 | 25 => Ok(Box::new(Vrc24::new(rom))),
+        24 | 26 => Ok(Box::new(Vrc6::new(rom))),
+        69 => Ok(Box::new(Fme7::new(rom))),
+        85 => Ok(Box::new(Vrc7::new(rom))),
+        mapper => Err(format!("mapper {} is not supported", mapper)),
+    }
+}
+
+// CHR ROM, or 8 KiB of CHR RAM (true) for boards without any
+pub fn chr_memory(rom: &Rom) -> (Vec<u8>, bool) {
+    if rom.chr_rom.is_empty() {
+        (vec![0; 0x2000], true)
+    } else {
+        (rom.chr_rom.clone(), false)
+    }
+}
+
+// offset of `addr` in bank `bank` of `size` bytes, banks past the end wrap
+pub fn bank_offset(len: usize, bank: usize, size: usize, addr: u16) -> usize {
+    (bank * size + addr as usize % size) % len
+}
+
+// index into the 2 KiB of console VRAM for a nametable address
+pub fn mirror_vram(addr: u16, mirroring: Mirroring) -> usize {
+    let index = (addr & 0x0fff) as usize;
+    let table = index / 0x400;
+    let page = match (mirroring, table) {
+        (Mirroring::Horizontal, 0 | 1) => 0,
+        (Mirroring::Horizontal, _) => 1,
+        (Mirroring::Vertical, 0 | 2) => 0,
+        (Mirroring::Vertical, _) => 1,
+        // four screen boards bring their own RAM, wrap into ours
+        (Mirroring::FourScreen, table) => table & 1,
+        (Mirroring::OneScreenLower, _) => 0,
+        (Mirroring::OneScreenUpper, _) => 1,
+    };
+    page * 0x400 + index % 0x400
+}
+
+// inverse of `Mirroring as u8`, for mapper states
+pub fn mirroring_from_u8(value: u8) -> Result<Mirroring, String> {
+    match value {
+        0 => Ok(Mirroring::Vertical),
+        1 => Ok(Mirroring::Horizontal),
+        2 => Ok(Mirroring::FourScreen),
+        3 => Ok(Mirroring::OneScreenLower),
+        4 => Ok(Mirroring::OneScreenUpper),
+        _ => Err(format!("bad mirroring {} in mapper state", value)),
+    }
+}
+
+// Mapper and input device state is a flat list of fields, these keep save
+// and load in step.
+#[derive(Default)]
+pub struct StateWriter {
+    pub data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.data.extend_from_slice(value);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data, pos: 0 }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < len {
+            return Err(String::from("device state is truncated"));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn finish(&self) -> Result<(), String> {
+        if self.pos != self.data.len() {
+            return Err(String::from("device state has trailing data"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::region::Region;
+
+    #[test]
+    fn test_mirror_vram() {
+        assert_eq!(mirror_vram(0x2400, Mirroring::Horizontal), 0x000);
+        assert_eq!(mirror_vram(0x2800, Mirroring::Horizontal), 0x400);
+        assert_eq!(mirror_vram(0x2800, Mirroring::Vertical), 0x000);
+        assert_eq!(mirror_vram(0x2c05, Mirroring::Vertical), 0x405);
+        assert_eq!(mirror_vram(0x3000, Mirroring::Vertical), 0x000);
+        assert_eq!(mirror_vram(0x2805, Mirroring::OneScreenUpper), 0x405);
+    }
+
+    #[test]
+    fn test_create_refuses_missing_prg() {
+        let mut rom = Rom {
+            prg_rom: vec![],
+            chr_rom: vec![0; 0x2000],
+            mapper: 24,
+            submapper: 0,
+            screen_mirroring: Mirroring::Horizontal,
+            battery: false,
+            region: Region::Ntsc,
+        };
+        for mapper in [5, 19, 23, 24, 69, 85] {
+            rom.mapper = mapper;
+            assert!(create(&rom).is_err());
+        }
+        rom.prg_rom = vec![0; 0x2000];
+        assert!(create(&rom).is_err());
+
+        rom.prg_rom = vec![0; 0x4000];
+        for mapper in [5, 19, 23, 24, 69, 85] {
+            rom.mapper = mapper;
+            let board = create(&rom).unwrap();
+            for addr in [0x8000, 0xa000, 0xc000, 0xe000] {
+                board.cpu_read(addr);
+            }
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use crate::frame::EMPHASIS_SHIFT;
+use crate::region::Region;
+
+// PPUMASK ($2001)
+pub const GRAYSCALE: u8 = 0b0000_0001;
+pub const LEFTMOST_BACKGROUND: u8 = 0b0000_0010;
+pub const LEFTMOST_SPRITES: u8 = 0b0000_0100;
+pub const SHOW_BACKGROUND: u8 = 0b0000_1000;
+pub const SHOW_SPRITES: u8 = 0b0001_0000;
+pub const EMPHASIZE_RED: u8 = 0b0010_0000;
+pub const EMPHASIZE_GREEN: u8 = 0b0100_0000;
+pub const EMPHASIZE_BLUE: u8 = 0b1000_0000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MaskRegister {
+    pub bits: u8,
+}
+
+impl MaskRegister {
+    pub fn new() -> Self {
+        MaskRegister { bits: 0 }
+    }
+
+    pub fn update(&mut self, data: u8) {
+        self.bits = data;
+    }
+
+    pub fn is_grayscale(&self) -> bool {
+        self.bits & GRAYSCALE != 0
+    }
+
+    pub fn leftmost_background(&self) -> bool {
+        self.bits & LEFTMOST_BACKGROUND != 0
+    }
+
+    pub fn leftmost_sprites(&self) -> bool {
+        self.bits & LEFTMOST_SPRITES != 0
+    }
+
+    pub fn show_background(&self) -> bool {
+        self.bits & SHOW_BACKGROUND != 0
+    }
+
+    pub fn show_sprites(&self) -> bool {
+        self.bits & SHOW_SPRITES != 0
+    }
+
+    // red, green, blue in bits 0-2, the order palette emphasis tables use
+    pub fn emphasis(&self) -> u8 {
+        self.bits >> 5
+    }
+
+    // PAL and Dendy PPUs wire bit 5 to green and bit 6 to red
+    pub fn emphasis_in(&self, region: Region) -> u8 {
+        let emphasis = self.emphasis();
+        if region.swaps_red_green_emphasis() {
+            (emphasis & 0b100) | (emphasis & 1) << 1 | (emphasis >> 1) & 1
+        } else {
+            emphasis
+        }
+    }
+
+    // Turns a palette RAM value into the 9-bit pixel the PPU outputs.
+    // Grayscale forces the hue to 0, the same as ANDing with $30 on reads.
+    pub fn output(&self, index: u8) -> u16 {
+        self.output_in(index, Region::Ntsc)
+    }
+
+    pub fn output_in(&self, index: u8, region: Region) -> u16 {
+        let index = if self.is_grayscale() { index & 0x30 } else { index & 0x3f };
+        index as u16 | (self.emphasis_in(region) as u16) << EMPHASIS_SHIFT
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_output_applies_grayscale_and_emphasis() {
+        let mut mask = MaskRegister::new();
+        assert_eq!(mask.output(0x16), 0x16);
+
+        mask.update(GRAYSCALE);
+        assert_eq!(mask.output(0x16), 0x10);
+
+        mask.update(EMPHASIZE_RED | EMPHASIZE_BLUE | SHOW_BACKGROUND);
+        assert_eq!(mask.emphasis(), 0b101);
+        assert_eq!(mask.output(0x16), 0x16 | 0b101 << 6);
+        assert!(mask.show_background() && !mask.show_sprites());
+    }
+
+    #[test]
+    fn test_pal_swaps_red_and_green() {
+        let mut mask = MaskRegister::new();
+        mask.update(EMPHASIZE_RED | EMPHASIZE_BLUE);
+        assert_eq!(mask.emphasis_in(Region::Pal), 0b110);
+        assert_eq!(mask.emphasis_in(Region::Ntsc), 0b101);
+        assert_eq!(mask.output_in(0x0f, Region::Dendy), 0x0f | 0b110 << 6);
+    }
+}
@@ -0,0 +1,860 @@
+use std::cell::Cell;
+
+use crate::mapper::{Fetch, Mapper, StateReader, StateWriter};
+use crate::rom::Rom;
+
+const PRG_RAM_SIZE: usize = 0x10000;
+const EXRAM_SIZE: usize = 0x400;
+const CHR_RAM_SIZE: usize = 0x2000;
+
+// the audio frame sequencer runs at a fixed 240 Hz
+const AUDIO_FRAME_CYCLES: usize = 7457;
+
+pub const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16,
+    28, 32, 30,
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+// An APU pulse channel without the sweep unit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Pulse {
+    enabled: bool,
+    duty: u8,
+    halt: bool,
+    constant_volume: bool,
+    volume: u8,
+    period: u16,
+    timer: u16,
+    step: u8,
+    length: u8,
+    envelope_start: bool,
+    envelope_divider: u8,
+    envelope_decay: u8,
+}
+
+impl Pulse {
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.duty = data >> 6;
+                self.halt = data & 0x20 != 0;
+                self.constant_volume = data & 0x10 != 0;
+                self.volume = data & 0x0f;
+            }
+            2 => self.period = (self.period & 0x0700) | data as u16,
+            3 => {
+                self.period = (self.period & 0x00ff) | ((data as u16 & 0x07) << 8);
+                if self.enabled {
+                    self.length = LENGTH_TABLE[(data >> 3) as usize];
+                }
+                self.step = 0;
+                self.envelope_start = true;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length = 0;
+        }
+    }
+
+    // every other CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_frame(&mut self) {
+        if self.envelope_start {
+            self.envelope_start = false;
+            self.envelope_decay = 15;
+            self.envelope_divider = self.volume;
+        } else if self.envelope_divider == 0 {
+            self.envelope_divider = self.volume;
+            if self.envelope_decay > 0 {
+                self.envelope_decay -= 1;
+            } else if self.halt {
+                self.envelope_decay = 15;
+            }
+        } else {
+            self.envelope_divider -= 1;
+        }
+
+        if !self.halt && self.length > 0 {
+            self.length -= 1;
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.length > 0
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.enabled || self.length == 0 || DUTY_TABLE[self.duty as usize][self.step as usize] == 0 {
+            return 0;
+        }
+        if self.constant_volume {
+            self.volume
+        } else {
+            self.envelope_decay
+        }
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.u8(self.duty);
+        state.bool(self.halt);
+        state.bool(self.constant_volume);
+        state.u8(self.volume);
+        state.u16(self.period);
+        state.u16(self.timer);
+        state.u8(self.step);
+        state.u8(self.length);
+        state.bool(self.envelope_start);
+        state.u8(self.envelope_divider);
+        state.u8(self.envelope_decay);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.enabled = state.bool()?;
+        self.duty = state.u8()?;
+        self.halt = state.bool()?;
+        self.constant_volume = state.bool()?;
+        self.volume = state.u8()?;
+        self.period = state.u16()?;
+        self.timer = state.u16()?;
+        self.step = state.u8()?;
+        self.length = state.u8()?;
+        self.envelope_start = state.bool()?;
+        self.envelope_divider = state.u8()?;
+        self.envelope_decay = state.u8()?;
+        Ok(())
+    }
+}
+
+// Nintendo's MMC5 (ExROM): Castlevania III, Koei games.
+#[derive(Clone)]
+pub struct Mmc5 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    prg_ram: Vec<u8>,
+    exram: Vec<u8>,
+
+    prg_mode: u8,
+    chr_mode: u8,
+    ram_protect: [u8; 2],
+    exram_mode: u8,
+    nametables: u8,
+    fill_tile: u8,
+    fill_attribute: u8,
+    // $5113-$5117
+    prg_banks: [u8; 5],
+    // $5120-$512B with the $5130 bits already applied
+    chr_banks: [u16; 12],
+    chr_upper: u8,
+    last_chr_set_b: bool,
+    large_sprites: bool,
+
+    split_control: u8,
+    split_scroll: u8,
+    split_page: u8,
+
+    multiplicand: u8,
+    multiplier: u8,
+
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: Cell<bool>,
+    in_frame: bool,
+    scanline_counter: u8,
+
+    // where the PPU is in the current scanline
+    scanline: usize,
+    fetch_index: usize,
+    ext_attribute: u8,
+    in_split: bool,
+    split_column: usize,
+    split_y: usize,
+
+    pulses: [Pulse; 2],
+    pcm: Cell<u8>,
+    pcm_read_mode: bool,
+    pcm_irq_enabled: bool,
+    pcm_irq: Cell<bool>,
+    audio_cycles: usize,
+}
+
+impl Mmc5 {
+    pub fn new(rom: &Rom) -> Self {
+        let chr_ram = rom.chr_rom.is_empty();
+        Mmc5 {
+            prg_rom: rom.prg_rom.clone(),
+            chr: if chr_ram { vec![0; CHR_RAM_SIZE] } else { rom.chr_rom.clone() },
+            chr_ram,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            exram: vec![0; EXRAM_SIZE],
+            prg_mode: 3,
+            chr_mode: 0,
+            ram_protect: [0; 2],
+            exram_mode: 0,
+            nametables: 0,
+            fill_tile: 0,
+            fill_attribute: 0,
+            prg_banks: [0, 0xff, 0xff, 0xff, 0xff],
+            chr_banks: [0; 12],
+            chr_upper: 0,
+            last_chr_set_b: false,
+            large_sprites: false,
+            split_control: 0,
+            split_scroll: 0,
+            split_page: 0,
+            multiplicand: 0xff,
+            multiplier: 0xff,
+            irq_compare: 0,
+            irq_enabled: false,
+            irq_pending: Cell::new(false),
+            in_frame: false,
+            scanline_counter: 0,
+            scanline: 0,
+            fetch_index: 0,
+            ext_attribute: 0,
+            in_split: false,
+            split_column: 0,
+            split_y: 0,
+            pulses: [Pulse::default(), Pulse::default()],
+            pcm: Cell::new(0),
+            pcm_read_mode: false,
+            pcm_irq_enabled: false,
+            pcm_irq: Cell::new(false),
+            audio_cycles: 0,
+        }
+    }
+
+    // which register maps $8000-$FFFF slot `slot` (8 KiB each), and how many
+    // 8 KiB banks that register switches at once
+    fn prg_register(&self, slot: usize) -> (usize, usize) {
+        match (self.prg_mode, slot) {
+            (0, _) => (4, 4),
+            (1, 0 | 1) => (2, 2),
+            (1, _) => (4, 2),
+            (2, 0 | 1) => (2, 2),
+            (2, 2) => (3, 1),
+            (2, _) => (4, 1),
+            (_, slot) => (slot + 1, 1),
+        }
+    }
+
+    // (ROM?, offset) for a CPU address in $8000-$FFFF
+    fn prg_target(&self, addr: u16) -> (bool, usize) {
+        let slot = (addr as usize - 0x8000) / 0x2000;
+        let (register, size) = self.prg_register(slot);
+        let value = self.prg_banks[register];
+        // $5117 is always ROM
+        let rom = register == 4 || value & 0x80 != 0;
+        let bank = ((value & 0x7f) as usize & !(size - 1)) + slot % size;
+        let offset = addr as usize & 0x1fff;
+        if rom {
+            (true, (bank * 0x2000 + offset) % self.prg_rom.len())
+        } else {
+            (false, ((bank & 0x07) * 0x2000 + offset) % PRG_RAM_SIZE)
+        }
+    }
+
+    fn ram_writable(&self) -> bool {
+        self.ram_protect == [0b10, 0b01]
+    }
+
+    fn ram_offset(&self, addr: u16) -> usize {
+        (self.prg_banks[0] & 0x07) as usize * 0x2000 + (addr as usize & 0x1fff)
+    }
+
+    // index into CHR for a pattern fetch
+    fn chr_offset(&self, addr: u16, fetch: Fetch) -> usize {
+        if fetch == Fetch::BackgroundPattern && self.in_split {
+            let offset = (addr as usize & 0x0ff8) | (self.split_y & 7);
+            return (self.split_page as usize * 0x1000 + offset) % self.chr.len();
+        }
+        if fetch == Fetch::BackgroundPattern && self.exram_mode == 1 {
+            let bank = (self.ext_attribute & 0x3f) as usize | (self.chr_upper as usize) << 6;
+            return (bank * 0x1000 + (addr as usize & 0x0fff)) % self.chr.len();
+        }
+
+        // 8x16 sprites get set A and backgrounds set B, otherwise the last
+        // set written is used for everything
+        let set_b = match (self.large_sprites, fetch) {
+            (true, Fetch::BackgroundPattern) => true,
+            (true, Fetch::SpritePattern) => false,
+            _ => self.last_chr_set_b,
+        };
+        let addr = addr as usize & 0x1fff;
+        let (register, size) = match (self.chr_mode, set_b) {
+            (0, false) => (7, 0x2000),
+            (0, true) => (11, 0x2000),
+            (1, false) => (3 + (addr / 0x1000) * 4, 0x1000),
+            (1, true) => (11, 0x1000),
+            (2, false) => (1 + (addr / 0x800) * 2, 0x800),
+            (2, true) => (9 + (addr / 0x800 % 2) * 2, 0x800),
+            (_, false) => (addr / 0x400, 0x400),
+            (_, true) => (8 + addr / 0x400 % 4, 0x400),
+        };
+        (self.chr_banks[register] as usize * size + addr % size) % self.chr.len()
+    }
+
+    fn split_active(&self, column: usize) -> bool {
+        if self.split_control & 0x80 == 0 || self.exram_mode > 1 {
+            return false;
+        }
+        let threshold = (self.split_control & 0x1f) as usize;
+        if self.split_control & 0x40 != 0 {
+            column >= threshold
+        } else {
+            column < threshold
+        }
+    }
+
+    fn nametable_read(&self, addr: u16, vram: &[u8; 0x800]) -> u8 {
+        let table = (addr as usize >> 10) & 3;
+        let offset = addr as usize & 0x3ff;
+        match (self.nametables >> (table * 2)) & 3 {
+            0 => vram[offset],
+            1 => vram[0x400 + offset],
+            2 if self.exram_mode <= 1 => self.exram[offset],
+            2 => 0,
+            _ if offset < 0x3c0 => self.fill_tile,
+            _ => self.fill_attribute * 0x55,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5000..=0x5003 => self.pulses[0].write(addr - 0x5000, data),
+            0x5004..=0x5007 => self.pulses[1].write(addr - 0x5004, data),
+            0x5010 => {
+                self.pcm_read_mode = data & 0x01 != 0;
+                self.pcm_irq_enabled = data & 0x80 != 0;
+            }
+            // writing 0 does nothing in write mode
+            0x5011 if !self.pcm_read_mode && data != 0 => self.pcm.set(data),
+            0x5015 => {
+                self.pulses[0].set_enabled(data & 0x01 != 0);
+                self.pulses[1].set_enabled(data & 0x02 != 0);
+            }
+            0x5100 => self.prg_mode = data & 0x03,
+            0x5101 => self.chr_mode = data & 0x03,
+            0x5102 => self.ram_protect[0] = data & 0x03,
+            0x5103 => self.ram_protect[1] = data & 0x03,
+            0x5104 => self.exram_mode = data & 0x03,
+            0x5105 => self.nametables = data,
+            0x5106 => self.fill_tile = data,
+            0x5107 => self.fill_attribute = data & 0x03,
+            0x5113..=0x5117 => self.prg_banks[(addr - 0x5113) as usize] = data,
+            0x5120..=0x512b => {
+                let register = (addr - 0x5120) as usize;
+                self.chr_banks[register] = (self.chr_upper as u16) << 8 | data as u16;
+                self.last_chr_set_b = register >= 8;
+            }
+            0x5130 => self.chr_upper = data & 0x03,
+            0x5200 => self.split_control = data,
+            0x5201 => self.split_scroll = data,
+            0x5202 => self.split_page = data,
+            0x5203 => self.irq_compare = data,
+            0x5204 => self.irq_enabled = data & 0x80 != 0,
+            0x5205 => self.multiplicand = data,
+            0x5206 => self.multiplier = data,
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x5010 => {
+                let status = (self.pcm_irq.get() as u8) << 7 | self.pcm_read_mode as u8;
+                self.pcm_irq.set(false);
+                status
+            }
+            0x5015 => self.pulses[0].active() as u8 | (self.pulses[1].active() as u8) << 1,
+            0x5204 => {
+                let status = (self.irq_pending.get() as u8) << 7 | (self.in_frame as u8) << 6;
+                self.irq_pending.set(false);
+                status
+            }
+            0x5205 => (self.multiplicand as u16 * self.multiplier as u16) as u8,
+            0x5206 => ((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8,
+            0x5c00..=0x5fff if self.exram_mode >= 2 => self.exram[addr as usize - 0x5c00],
+            0x6000..=0x7fff => self.prg_ram[self.ram_offset(addr)],
+            0x8000..=0xffff => {
+                let data = match self.prg_target(addr) {
+                    (true, offset) => self.prg_rom[offset],
+                    (false, offset) => self.prg_ram[offset],
+                };
+                // read mode PCM samples whatever the CPU reads from $8000-$BFFF
+                if self.pcm_read_mode && addr < 0xc000 {
+                    if data == 0 {
+                        self.pcm_irq.set(true);
+                    } else {
+                        self.pcm.set(data);
+                    }
+                }
+                data
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x5010 => (self.pcm_irq.get() as u8) << 7 | self.pcm_read_mode as u8,
+            0x5204 => (self.irq_pending.get() as u8) << 7 | (self.in_frame as u8) << 6,
+            0x8000..=0xffff => match self.prg_target(addr) {
+                (true, offset) => self.prg_rom[offset],
+                (false, offset) => self.prg_ram[offset],
+            },
+            _ => self.cpu_read(addr),
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5000..=0x5bff => self.write_register(addr, data),
+            0x5c00..=0x5fff if self.exram_mode != 3 => self.exram[addr as usize - 0x5c00] = data,
+            0x6000..=0x7fff if self.ram_writable() => {
+                let offset = self.ram_offset(addr);
+                self.prg_ram[offset] = data;
+            }
+            0x8000..=0xdfff if self.ram_writable() => {
+                if let (false, offset) = self.prg_target(addr) {
+                    self.prg_ram[offset] = data;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16, fetch: Fetch, vram: &[u8; 0x800]) -> u8 {
+        let addr = addr & 0x3fff;
+        if addr < 0x2000 {
+            return self.chr[self.chr_offset(addr, fetch)];
+        }
+
+        match fetch {
+            Fetch::Nametable => {
+                // 32 tiles for this line, then two prefetched for the next
+                let index = self.fetch_index;
+                self.fetch_index += 1;
+                let (column, line) = if index < 32 {
+                    (index + 2, self.scanline)
+                } else {
+                    (index - 32, self.scanline + 1)
+                };
+                self.in_split = index < 34 && column < 32 && self.split_active(column);
+                if self.in_split {
+                    self.split_column = column;
+                    self.split_y = (self.split_scroll as usize + line) % 240;
+                    return self.exram[(self.split_y / 8) * 32 + column];
+                }
+                let offset = addr as usize & 0x3ff;
+                if self.exram_mode == 1 && offset < 0x3c0 {
+                    self.ext_attribute = self.exram[offset];
+                }
+                self.nametable_read(addr, vram)
+            }
+            Fetch::Attribute if self.in_split => {
+                let row = self.split_y / 8;
+                let attribute = self.exram[0x3c0 + (row / 4) * 8 + self.split_column / 4];
+                let shift = (row & 2) * 2 + (self.split_column & 2);
+                ((attribute >> shift) & 0x03) * 0x55
+            }
+            Fetch::Attribute if self.exram_mode == 1 => (self.ext_attribute >> 6) * 0x55,
+            _ => self.nametable_read(addr, vram),
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8, vram: &mut [u8; 0x800]) {
+        let addr = addr & 0x3fff;
+        if addr < 0x2000 {
+            if self.chr_ram {
+                let offset = self.chr_offset(addr, Fetch::Data);
+                self.chr[offset] = data;
+            }
+            return;
+        }
+        let table = (addr as usize >> 10) & 3;
+        let offset = addr as usize & 0x3ff;
+        match (self.nametables >> (table * 2)) & 3 {
+            0 => vram[offset] = data,
+            1 => vram[0x400 + offset] = data,
+            2 if self.exram_mode <= 1 => self.exram[offset] = data,
+            _ => {}
+        }
+    }
+
+    fn ppu_register_write(&mut self, addr: u16, data: u8) {
+        if addr & 0x2007 == 0x2000 {
+            self.large_sprites = data & 0x20 != 0;
+        }
+    }
+
+    fn ppu_scanline(&mut self, scanline: usize, rendering: bool) {
+        self.scanline = scanline;
+        self.fetch_index = 0;
+        self.in_split = false;
+
+        if !rendering || scanline >= 240 {
+            self.in_frame = false;
+            return;
+        }
+        if !self.in_frame {
+            self.in_frame = true;
+            self.scanline_counter = 0;
+        } else {
+            self.scanline_counter = self.scanline_counter.wrapping_add(1);
+            if self.scanline_counter == self.irq_compare {
+                self.irq_pending.set(true);
+            }
+        }
+    }
+
+    fn clock(&mut self, cycles: usize) {
+        for _ in 0..cycles {
+            self.audio_cycles += 1;
+            if self.audio_cycles.is_multiple_of(2) {
+                self.pulses.iter_mut().for_each(Pulse::clock_timer);
+            }
+            if self.audio_cycles >= AUDIO_FRAME_CYCLES {
+                self.audio_cycles = 0;
+                self.pulses.iter_mut().for_each(Pulse::clock_frame);
+            }
+        }
+    }
+
+    fn irq(&self) -> bool {
+        (self.irq_enabled && self.irq_pending.get()) || (self.pcm_irq_enabled && self.pcm_irq.get())
+    }
+
+    // the pulses mix like the APU's, PCM like a DMC with twice the range
+    fn audio(&self) -> f32 {
+        let pulses = (self.pulses[0].output() + self.pulses[1].output()) as f32;
+        let pulse = if pulses == 0.0 { 0.0 } else { 95.88 / (8128.0 / pulses + 100.0) };
+        let pcm = self.pcm.get() as f32 / 2.0;
+        let pcm = if pcm == 0.0 { 0.0 } else { 159.79 / (22638.0 / pcm + 100.0) };
+        pulse + pcm
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        state.bytes(&self.prg_ram);
+        state.bytes(&self.exram);
+        if self.chr_ram {
+            state.bytes(&self.chr);
+        }
+        for value in [
+            self.prg_mode,
+            self.chr_mode,
+            self.ram_protect[0],
+            self.ram_protect[1],
+            self.exram_mode,
+            self.nametables,
+            self.fill_tile,
+            self.fill_attribute,
+            self.chr_upper,
+            self.split_control,
+            self.split_scroll,
+            self.split_page,
+            self.multiplicand,
+            self.multiplier,
+            self.irq_compare,
+            self.scanline_counter,
+            self.ext_attribute,
+            self.pcm.get(),
+        ] {
+            state.u8(value);
+        }
+        state.bytes(&self.prg_banks);
+        for bank in self.chr_banks {
+            state.u16(bank);
+        }
+        for flag in [
+            self.last_chr_set_b,
+            self.large_sprites,
+            self.irq_enabled,
+            self.irq_pending.get(),
+            self.in_frame,
+            self.in_split,
+            self.pcm_read_mode,
+            self.pcm_irq_enabled,
+            self.pcm_irq.get(),
+        ] {
+            state.bool(flag);
+        }
+        state.u32(self.scanline as u32);
+        state.u32(self.fetch_index as u32);
+        state.u32(self.split_column as u32);
+        state.u32(self.split_y as u32);
+        state.u32(self.audio_cycles as u32);
+        for pulse in &self.pulses {
+            pulse.save(&mut state);
+        }
+        state.data
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut state = StateReader::new(data);
+        // load into a copy so a bad state leaves the board untouched
+        let mut restored = self.clone();
+        restored.prg_ram.copy_from_slice(state.bytes(PRG_RAM_SIZE)?);
+        restored.exram.copy_from_slice(state.bytes(EXRAM_SIZE)?);
+        if self.chr_ram {
+            restored.chr.copy_from_slice(state.bytes(CHR_RAM_SIZE)?);
+        }
+        restored.prg_mode = state.u8()?;
+        restored.chr_mode = state.u8()?;
+        restored.ram_protect = [state.u8()?, state.u8()?];
+        restored.exram_mode = state.u8()?;
+        restored.nametables = state.u8()?;
+        restored.fill_tile = state.u8()?;
+        restored.fill_attribute = state.u8()?;
+        restored.chr_upper = state.u8()?;
+        restored.split_control = state.u8()?;
+        restored.split_scroll = state.u8()?;
+        restored.split_page = state.u8()?;
+        restored.multiplicand = state.u8()?;
+        restored.multiplier = state.u8()?;
+        restored.irq_compare = state.u8()?;
+        restored.scanline_counter = state.u8()?;
+        restored.ext_attribute = state.u8()?;
+        restored.pcm.set(state.u8()?);
+        restored.prg_banks.copy_from_slice(state.bytes(5)?);
+        for bank in restored.chr_banks.iter_mut() {
+            *bank = state.u16()?;
+        }
+        restored.last_chr_set_b = state.bool()?;
+        restored.large_sprites = state.bool()?;
+        restored.irq_enabled = state.bool()?;
+        restored.irq_pending.set(state.bool()?);
+        restored.in_frame = state.bool()?;
+        restored.in_split = state.bool()?;
+        restored.pcm_read_mode = state.bool()?;
+        restored.pcm_irq_enabled = state.bool()?;
+        restored.pcm_irq.set(state.bool()?);
+        restored.scanline = state.u32()? as usize;
+        restored.fetch_index = state.u32()? as usize;
+        restored.split_column = state.u32()? as usize;
+        restored.split_y = state.u32()? as usize;
+        restored.audio_cycles = state.u32()? as usize;
+        for pulse in restored.pulses.iter_mut() {
+            pulse.load(&mut state)?;
+        }
+        state.finish()?;
+
+        *self = restored;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::Mirroring;
+
+    // 128 KiB PRG with every 8 KiB bank filled with its number, 256 KiB CHR
+    // with every 1 KiB bank filled with its number
+    fn test_board() -> Mmc5 {
+        let prg_rom = (0..16).flat_map(|bank| vec![bank as u8; 0x2000]).collect();
+        let chr_rom = (0..256).flat_map(|bank| vec![bank as u8; 0x400]).collect();
+        Mmc5::new(&Rom {
+            prg_rom,
+            chr_rom,
+            mapper: 5,
+            submapper: 0,
+            screen_mirroring: Mirroring::Horizontal,
+            battery: false,
+            region: Default::default(),
+        })
+    }
+
+    #[test]
+    fn test_prg_modes_and_ram() {
+        let mut mmc5 = test_board();
+        // powers on in mode 3 with the last bank at $E000
+        assert_eq!(mmc5.cpu_read(0xe000), 15);
+
+        mmc5.cpu_write(0x5100, 3);
+        mmc5.cpu_write(0x5114, 0x83);
+        mmc5.cpu_write(0x5115, 0x85);
+        mmc5.cpu_write(0x5116, 0x87);
+        assert_eq!([mmc5.cpu_read(0x8000), mmc5.cpu_read(0xa000), mmc5.cpu_read(0xc000)], [3, 5, 7]);
+
+        mmc5.cpu_write(0x5100, 1);
+        mmc5.cpu_write(0x5117, 0x85);
+        assert_eq!([mmc5.cpu_read(0x8000), mmc5.cpu_read(0xa000)], [4, 5]);
+        assert_eq!([mmc5.cpu_read(0xc000), mmc5.cpu_read(0xe000)], [4, 5]);
+
+        mmc5.cpu_write(0x5100, 0);
+        assert_eq!([mmc5.cpu_read(0x8000), mmc5.cpu_read(0xe000)], [4, 7]);
+
+        // RAM only takes writes once both protect registers are unlocked
+        mmc5.cpu_write(0x5113, 2);
+        mmc5.cpu_write(0x6000, 0x42);
+        assert_eq!(mmc5.cpu_read(0x6000), 0);
+        mmc5.cpu_write(0x5102, 0b10);
+        mmc5.cpu_write(0x5103, 0b01);
+        mmc5.cpu_write(0x6000, 0x42);
+        assert_eq!(mmc5.prg_ram()[2 * 0x2000], 0x42);
+
+        // and RAM can be banked into $8000 too
+        mmc5.cpu_write(0x5100, 3);
+        mmc5.cpu_write(0x5114, 0x02);
+        assert_eq!(mmc5.cpu_read(0x8000), 0x42);
+    }
+
+    #[test]
+    fn test_chr_sets_for_large_sprites() {
+        let mut mmc5 = test_board();
+        let vram = [0; 0x800];
+        mmc5.cpu_write(0x5101, 3);
+        mmc5.cpu_write(0x5130, 1);
+        for register in 0..12 {
+            mmc5.cpu_write(0x5120 + register, 0x10 + register as u8);
+        }
+
+        // 8x8 sprites use the last set written for everything
+        assert_eq!(mmc5.ppu_read(0x0400, Fetch::SpritePattern, &vram), 0x19);
+        assert_eq!(mmc5.ppu_read(0x1400, Fetch::BackgroundPattern, &vram), 0x19);
+
+        mmc5.ppu_register_write(0x2000, 0x20);
+        assert_eq!(mmc5.ppu_read(0x0400, Fetch::SpritePattern, &vram), 0x11);
+        assert_eq!(mmc5.ppu_read(0x1c00, Fetch::SpritePattern, &vram), 0x17);
+        assert_eq!(mmc5.ppu_read(0x0400, Fetch::BackgroundPattern, &vram), 0x19);
+        assert_eq!(mmc5.ppu_read(0x1400, Fetch::BackgroundPattern, &vram), 0x19);
+        // the upper bits went into the bank number
+        assert_eq!(mmc5.chr_banks[0], 0x110);
+    }
+
+    #[test]
+    fn test_nametables_and_extended_attributes() {
+        let mut mmc5 = test_board();
+        let mut vram = [0; 0x800];
+        // A, B, ExRAM, fill
+        mmc5.cpu_write(0x5105, 0b11_10_01_00);
+        mmc5.cpu_write(0x5106, 0x24);
+        mmc5.cpu_write(0x5107, 2);
+        mmc5.ppu_write(0x2401, 0x11, &mut vram);
+        mmc5.ppu_write(0x2802, 0x22, &mut vram);
+        assert_eq!(vram[0x401], 0x11);
+        assert_eq!(mmc5.exram[2], 0x22);
+        assert_eq!(mmc5.ppu_read(0x2c10, Fetch::Data, &vram), 0x24);
+        assert_eq!(mmc5.ppu_read(0x2fc0, Fetch::Data, &vram), 0xaa);
+
+        // extended attributes: ExRAM picks the palette and a 4 KiB CHR bank per tile
+        mmc5.cpu_write(0x5104, 1);
+        mmc5.cpu_write(0x5c00, 0b1100_0101);
+        mmc5.ppu_scanline(0, true);
+        mmc5.ppu_read(0x2000, Fetch::Nametable, &vram);
+        assert_eq!(mmc5.ppu_read(0x23c0, Fetch::Attribute, &vram), 0xff);
+        assert_eq!(mmc5.ppu_read(0x0010, Fetch::BackgroundPattern, &vram), 5 * 4);
+
+        // CPU access to ExRAM depends on the mode
+        assert_eq!(mmc5.cpu_read(0x5c00), 0);
+        mmc5.cpu_write(0x5104, 3);
+        assert_eq!(mmc5.cpu_read(0x5c00), 0b1100_0101);
+        mmc5.cpu_write(0x5c00, 0);
+        assert_eq!(mmc5.cpu_read(0x5c00), 0b1100_0101);
+    }
+
+    #[test]
+    fn test_vertical_split() {
+        let mut mmc5 = test_board();
+        let vram = [0; 0x800];
+        // left split 4 tiles wide, scrolled down 16 lines, CHR page 3
+        mmc5.cpu_write(0x5200, 0x80 | 4);
+        mmc5.cpu_write(0x5201, 16);
+        mmc5.cpu_write(0x5202, 3);
+        mmc5.exram[2 * 32 + 2] = 0x77;
+        mmc5.exram[0x3c0] = 0b11_00_00_00;
+
+        mmc5.ppu_scanline(3, true);
+        // the first fetch of a line is for column 2
+        assert_eq!(mmc5.ppu_read(0x2000, Fetch::Nametable, &vram), 0x77);
+        assert_eq!(mmc5.ppu_read(0x23c0, Fetch::Attribute, &vram), 0xff);
+        assert_eq!(mmc5.ppu_read(0x0770, Fetch::BackgroundPattern, &vram), 12 + 1);
+        // column 3 is still split, column 4 is not
+        mmc5.ppu_read(0x2001, Fetch::Nametable, &vram);
+        assert!(mmc5.in_split);
+        mmc5.ppu_read(0x2002, Fetch::Nametable, &vram);
+        assert!(!mmc5.in_split);
+    }
+
+    #[test]
+    fn test_multiplier_and_scanline_irq() {
+        let mut mmc5 = test_board();
+        mmc5.cpu_write(0x5205, 200);
+        mmc5.cpu_write(0x5206, 150);
+        assert_eq!((mmc5.cpu_read(0x5206) as u16) << 8 | mmc5.cpu_read(0x5205) as u16, 30000);
+
+        mmc5.cpu_write(0x5203, 2);
+        mmc5.cpu_write(0x5204, 0x80);
+        mmc5.ppu_scanline(0, true);
+        mmc5.ppu_scanline(1, true);
+        assert!(!mmc5.irq());
+        mmc5.ppu_scanline(2, true);
+        assert!(mmc5.irq());
+        // peeking doesn't, reading the status does
+        assert_eq!(mmc5.cpu_peek(0x5204), 0xc0);
+        assert!(mmc5.irq());
+        assert_eq!(mmc5.cpu_read(0x5204), 0xc0);
+        assert!(!mmc5.irq());
+        mmc5.ppu_scanline(240, true);
+        assert_eq!(mmc5.cpu_read(0x5204), 0x00);
+    }
+
+    #[test]
+    fn test_audio_and_state_round_trip() {
+        let mut mmc5 = test_board();
+        mmc5.cpu_write(0x5015, 0x01);
+        mmc5.cpu_write(0x5000, 0b1011_1111);
+        mmc5.cpu_write(0x5002, 0x20);
+        mmc5.cpu_write(0x5003, 0x08);
+        assert_eq!(mmc5.cpu_read(0x5015), 0x01);
+        mmc5.cpu_write(0x5011, 0x80);
+
+        let mut levels = Vec::new();
+        for _ in 0..200 {
+            mmc5.clock(1);
+            levels.push(mmc5.audio());
+        }
+        assert!(levels.iter().any(|&level| level > levels[0]));
+        assert!(levels.iter().all(|&level| level > 0.0));
+
+        let state = mmc5.save_state();
+        let mut restored = test_board();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+        assert_eq!(restored.audio(), mmc5.audio());
+        assert!(restored.load_state(&state[1..]).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use std::cell::Cell;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::joypad::Joypad;
+use crate::mapper::{StateReader, StateWriter};
+
+// Which four player adapter is plugged in. Games support one or the
+// other, so it's picked per game.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Multitap {
+    // controllers 1 and 2 only
+    #[default]
+    None,
+    // NES Four Score / Satellite: controllers 3 and 4 follow 1 and 2 in a
+    // 24-bit report per port
+    FourScore,
+    // Famicom expansion port: controllers 3 and 4 on bit 1 of $4016/$4017
+    Famicom,
+}
+
+impl fmt::Display for Multitap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Multitap::None => "none",
+            Multitap::FourScore => "fourscore",
+            Multitap::Famicom => "famicom",
+        })
+    }
+}
+
+impl FromStr for Multitap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Multitap::None),
+            "fourscore" | "four-score" | "satellite" => Ok(Multitap::FourScore),
+            "famicom" => Ok(Multitap::Famicom),
+            _ => Err(format!("unknown four player adapter '{}', expected none, fourscore or famicom", s)),
+        }
+    }
+}
+
+// the bits after both controllers' buttons that identify a Four Score,
+// $4016 reads a 1 in bit 19 and $4017 in bit 18
+const SIGNATURES: [u8; 2] = [0b0000_1000, 0b0000_0100];
+
+// Four Score shift state for both ports
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FourScore {
+    strobe: bool,
+    index: [Cell<u8>; 2],
+}
+
+impl FourScore {
+    pub fn new() -> Self {
+        FourScore::default()
+    }
+
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.index = Default::default();
+        }
+    }
+
+    // `port` 0 reports controllers 1 and 3, port 1 controllers 2 and 4
+    pub fn read(&self, port: usize, joypads: &[Joypad; 4]) -> u8 {
+        let index = self.index[port].get();
+        let response = match index {
+            0..=7 => joypads[port].button_status >> index,
+            8..=15 => joypads[port + 2].button_status >> (index - 8),
+            16..=23 => SIGNATURES[port] >> (index - 16),
+            _ => 1,
+        } & 1;
+        if !self.strobe && index < 24 {
+            self.index[port].set(index + 1);
+        }
+        response
+    }
+
+    pub fn save(&self, state: &mut StateWriter) {
+        state.bool(self.strobe);
+        state.u8(self.index[0].get());
+        state.u8(self.index[1].get());
+    }
+
+    pub fn load(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.strobe = state.bool()?;
+        self.index[0].set(state.u8()?);
+        self.index[1].set(state.u8()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::joypad::{BUTTON_A, BUTTON_RIGHT, BUTTON_START};
+
+    #[test]
+    fn test_four_score_reports() {
+        let mut joypads = [Joypad::new(), Joypad::new(), Joypad::new(), Joypad::new()];
+        joypads[0].set_buttons(BUTTON_A);
+        joypads[2].set_buttons(BUTTON_START);
+        joypads[3].set_buttons(BUTTON_RIGHT);
+        let mut four_score = FourScore::new();
+        four_score.write(1);
+        four_score.write(0);
+
+        let report = |port| (0..26).map(|_| four_score.read(port, &joypads)).collect::<Vec<u8>>();
+        let port1 = report(0);
+        assert_eq!(&port1[0..8], &[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&port1[8..16], &[0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(&port1[16..24], &[0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(&port1[24..], &[1, 1]);
+
+        let port2 = report(1);
+        assert_eq!(&port2[0..16], &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(&port2[16..24], &[0, 0, 1, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("FourScore".parse(), Ok(Multitap::FourScore));
+        assert_eq!("famicom".parse(), Ok(Multitap::Famicom));
+        assert!("hori".parse::<Multitap>().is_err());
+        assert_eq!(Multitap::FourScore.to_string(), "fourscore");
+    }
+}
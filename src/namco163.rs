@@ -0,0 +1,390 @@
+use std::cell::Cell;
+
+use crate::mapper::{bank_offset, chr_memory, Fetch, Mapper, StateReader, StateWriter};
+use crate::rom::Rom;
+
+const PRG_RAM_SIZE: usize = 0x2000;
+
+// each channel is updated once every 15 CPU cycles, in turn
+const CHANNEL_CYCLES: usize = 15;
+
+// a full volume channel reaches -120 to 105, scaled to swing like a full volume
+// APU pulse
+pub const LEVEL: f32 = 0.075 / 120.0;
+
+// Namco 163 (mapper 19): Megami Tensei II, King of Kings, Erika to Satoru.
+#[derive(Clone)]
+pub struct Namco163 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    prg_ram: Vec<u8>,
+    // wavetables and channel registers
+    sound_ram: [u8; 0x80],
+    // $F800: bits 0-6 address, bit 7 auto increment
+    sound_address: Cell<u8>,
+    write_protect: u8,
+
+    prg_banks: [u8; 3],
+    // $8000-$BFFF pattern tables, then $C000-$DFFF nametables
+    chr_banks: [u8; 12],
+    // $E800 bits 6 and 7: no CIRAM in the low / high pattern table
+    ciram_disabled: [bool; 2],
+    sound_disabled: bool,
+
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_pending: bool,
+
+    // average the enabled channels instead of switching between them
+    clean: bool,
+    cycles: usize,
+    channel: usize,
+    outputs: [i16; 8],
+}
+
+impl Namco163 {
+    pub fn new(rom: &Rom) -> Self {
+        let (chr, chr_ram) = chr_memory(rom);
+        Namco163 {
+            prg_rom: rom.prg_rom.clone(),
+            chr,
+            chr_ram,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            sound_ram: [0; 0x80],
+            sound_address: Cell::new(0),
+            write_protect: 0,
+            prg_banks: [0; 3],
+            chr_banks: [0; 12],
+            ciram_disabled: [false; 2],
+            sound_disabled: false,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            clean: false,
+            cycles: 0,
+            channel: 7,
+            outputs: [0; 8],
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let len = self.prg_rom.len();
+        let bank = match addr {
+            0x8000..=0xdfff => self.prg_banks[(addr as usize - 0x8000) / 0x2000] as usize,
+            _ => len / 0x2000 - 1,
+        };
+        bank_offset(len, bank, 0x2000, addr)
+    }
+
+    // 1 KiB pages: CHR, or one of the two CIRAM pages for values $E0 and up
+    fn page(&self, addr: u16) -> Result<usize, usize> {
+        let slot = if addr < 0x2000 { addr as usize / 0x400 } else { 8 + (addr as usize >> 10 & 3) };
+        let value = self.chr_banks[slot];
+        let ciram = value >= 0xe0 && (slot >= 8 || !self.ciram_disabled[slot / 4]);
+        if ciram {
+            Err((value as usize & 1) * 0x400 + (addr as usize & 0x3ff))
+        } else {
+            Ok(bank_offset(self.chr.len(), value as usize, 0x400, addr))
+        }
+    }
+
+    fn channels(&self) -> usize {
+        ((self.sound_ram[0x7f] >> 4) & 0x07) as usize + 1
+    }
+
+    fn ram_writable(&self, addr: u16) -> bool {
+        let chunk = (addr as usize - 0x6000) / 0x800;
+        self.write_protect & 0xf0 == 0x40 && self.write_protect & (1 << chunk) == 0
+    }
+
+    fn update_channel(&mut self, channel: usize) {
+        let base = 0x40 + channel * 8;
+        let ram = &mut self.sound_ram;
+        let frequency = ram[base] as u32 | (ram[base + 2] as u32) << 8 | (ram[base + 4] as u32 & 0x03) << 16;
+        let phase = ram[base + 1] as u32 | (ram[base + 3] as u32) << 8 | (ram[base + 5] as u32) << 16;
+        let length = 256 - (ram[base + 4] & 0xfc) as u32;
+        let phase = (phase + frequency) % (length << 16);
+        ram[base + 1] = phase as u8;
+        ram[base + 3] = (phase >> 8) as u8;
+        ram[base + 5] = (phase >> 16) as u8;
+
+        // samples are nibbles, low one first
+        let sample = ((phase >> 16) as usize + ram[base + 6] as usize) & 0xff;
+        let nibble = (ram[sample / 2] >> ((sample & 1) * 4)) & 0x0f;
+        self.outputs[channel] = (nibble as i16 - 8) * (ram[base + 7] & 0x0f) as i16;
+    }
+}
+
+impl Mapper for Namco163 {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x4800..=0x4fff => {
+                let address = self.sound_address.get();
+                if address & 0x80 != 0 {
+                    self.sound_address.set(0x80 | (address.wrapping_add(1) & 0x7f));
+                }
+                self.sound_ram[(address & 0x7f) as usize]
+            }
+            0x5000..=0x57ff => self.irq_counter as u8,
+            0x5800..=0x5fff => (self.irq_counter >> 8) as u8 | (self.irq_enabled as u8) << 7,
+            0x6000..=0x7fff => self.prg_ram[addr as usize - 0x6000],
+            0x8000..=0xffff => self.prg_rom[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn cpu_peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x4800..=0x4fff => self.sound_ram[(self.sound_address.get() & 0x7f) as usize],
+            _ => self.cpu_read(addr),
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4800..=0x4fff => {
+                let address = self.sound_address.get();
+                self.sound_ram[(address & 0x7f) as usize] = data;
+                if address & 0x80 != 0 {
+                    self.sound_address.set(0x80 | (address.wrapping_add(1) & 0x7f));
+                }
+            }
+            0x5000..=0x57ff => {
+                self.irq_counter = (self.irq_counter & 0x7f00) | data as u16;
+                self.irq_pending = false;
+            }
+            0x5800..=0x5fff => {
+                self.irq_counter = (self.irq_counter & 0x00ff) | (data as u16 & 0x7f) << 8;
+                self.irq_enabled = data & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0x6000..=0x7fff if self.ram_writable(addr) => self.prg_ram[addr as usize - 0x6000] = data,
+            0x8000..=0xdfff => self.chr_banks[(addr as usize - 0x8000) / 0x800] = data,
+            0xe000..=0xe7ff => {
+                self.prg_banks[0] = data & 0x3f;
+                self.sound_disabled = data & 0x40 != 0;
+            }
+            0xe800..=0xefff => {
+                self.prg_banks[1] = data & 0x3f;
+                self.ciram_disabled = [data & 0x40 != 0, data & 0x80 != 0];
+            }
+            0xf000..=0xf7ff => self.prg_banks[2] = data & 0x3f,
+            0xf800..=0xffff => {
+                self.write_protect = data;
+                self.sound_address.set(data);
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16, _fetch: Fetch, vram: &[u8; 0x800]) -> u8 {
+        match self.page(addr & 0x2fff) {
+            Ok(offset) => self.chr[offset],
+            Err(offset) => vram[offset],
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8, vram: &mut [u8; 0x800]) {
+        match self.page(addr & 0x2fff) {
+            Ok(offset) if self.chr_ram => self.chr[offset] = data,
+            Ok(_) => {}
+            Err(offset) => vram[offset] = data,
+        }
+    }
+
+    fn clock(&mut self, cycles: usize) {
+        if self.irq_enabled && self.irq_counter < 0x7fff {
+            self.irq_counter = (self.irq_counter + cycles as u16).min(0x7fff);
+            self.irq_pending |= self.irq_counter == 0x7fff;
+        }
+
+        self.cycles += cycles;
+        while self.cycles >= CHANNEL_CYCLES {
+            self.cycles -= CHANNEL_CYCLES;
+            // from channel 7 down to however many are enabled
+            self.channel = if self.channel <= 8 - self.channels() { 7 } else { self.channel - 1 };
+            self.update_channel(self.channel);
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn audio(&self) -> f32 {
+        if self.sound_disabled {
+            return 0.0;
+        }
+        let output = if self.clean {
+            let channels = self.channels();
+            self.outputs[8 - channels..].iter().sum::<i16>() as f32 / channels as f32
+        } else {
+            self.outputs[self.channel] as f32
+        };
+        output * LEVEL
+    }
+
+    fn set_clean_audio(&mut self, clean: bool) {
+        self.clean = clean;
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        state.bytes(&self.prg_ram);
+        if self.chr_ram {
+            state.bytes(&self.chr);
+        }
+        state.bytes(&self.sound_ram);
+        state.u8(self.sound_address.get());
+        state.u8(self.write_protect);
+        state.bytes(&self.prg_banks);
+        state.bytes(&self.chr_banks);
+        state.bool(self.ciram_disabled[0]);
+        state.bool(self.ciram_disabled[1]);
+        state.bool(self.sound_disabled);
+        state.u16(self.irq_counter);
+        state.bool(self.irq_enabled);
+        state.bool(self.irq_pending);
+        state.u8(self.cycles as u8);
+        state.u8(self.channel as u8);
+        for output in self.outputs {
+            state.u16(output as u16);
+        }
+        state.data
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut state = StateReader::new(data);
+        let mut restored = self.clone();
+        restored.prg_ram.copy_from_slice(state.bytes(PRG_RAM_SIZE)?);
+        if self.chr_ram {
+            restored.chr.copy_from_slice(state.bytes(0x2000)?);
+        }
+        restored.sound_ram.copy_from_slice(state.bytes(0x80)?);
+        restored.sound_address.set(state.u8()?);
+        restored.write_protect = state.u8()?;
+        restored.prg_banks.copy_from_slice(state.bytes(3)?);
+        restored.chr_banks.copy_from_slice(state.bytes(12)?);
+        restored.ciram_disabled = [state.bool()?, state.bool()?];
+        restored.sound_disabled = state.bool()?;
+        restored.irq_counter = state.u16()?;
+        restored.irq_enabled = state.bool()?;
+        restored.irq_pending = state.bool()?;
+        restored.cycles = state.u8()? as usize;
+        restored.channel = state.u8()? as usize & 0x07;
+        for output in restored.outputs.iter_mut() {
+            *output = state.u16()? as i16;
+        }
+        state.finish()?;
+        *self = restored;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::Mirroring;
+
+    fn test_board() -> Namco163 {
+        Namco163::new(&Rom {
+            prg_rom: (0..16).flat_map(|bank| vec![bank as u8; 0x2000]).collect(),
+            chr_rom: (0..256).flat_map(|bank| vec![bank as u8; 0x400]).collect(),
+            mapper: 19,
+            submapper: 0,
+            screen_mirroring: Mirroring::Horizontal,
+            battery: true,
+            region: Default::default(),
+        })
+    }
+
+    #[test]
+    fn test_banking_and_ciram_pages() {
+        let mut n163 = test_board();
+        let mut vram = [0; 0x800];
+        n163.cpu_write(0xe000, 3);
+        n163.cpu_write(0xe800, 4);
+        n163.cpu_write(0xf000, 5);
+        let banks = [0x8000, 0xa000, 0xc000, 0xe000].map(|addr| n163.cpu_read(addr));
+        assert_eq!(banks, [3, 4, 5, 15]);
+
+        // nametables: CHR bank $20, then CIRAM page 1
+        n163.cpu_write(0xc000, 0x20);
+        n163.cpu_write(0xc800, 0xe1);
+        n163.ppu_write(0x2410, 0x77, &mut vram);
+        assert_eq!(vram[0x410], 0x77);
+        assert_eq!(n163.ppu_read(0x2000, Fetch::Nametable, &vram), 0x20);
+
+        // pattern tables only reach CIRAM while $E800 allows it
+        n163.cpu_write(0x9000, 0xe1);
+        assert_eq!(n163.ppu_read(0x0810, Fetch::Data, &vram), 0x77);
+        n163.cpu_write(0xe800, 0x40);
+        assert_eq!(n163.ppu_read(0x0810, Fetch::Data, &vram), 0xe1);
+
+        // PRG RAM writes need $40 in the upper nibble of $F800
+        n163.cpu_write(0x6000, 0x12);
+        assert_eq!(n163.cpu_read(0x6000), 0);
+        n163.cpu_write(0xf800, 0x40);
+        n163.cpu_write(0x6000, 0x12);
+        assert_eq!(n163.cpu_read(0x6000), 0x12);
+    }
+
+    #[test]
+    fn test_sound_ram_and_irq() {
+        let mut n163 = test_board();
+        n163.cpu_write(0xf800, 0x80 | 0x7e);
+        n163.cpu_write(0x4800, 0x11);
+        n163.cpu_write(0x4800, 0x22);
+        n163.cpu_write(0x4800, 0x33);
+        assert_eq!(&n163.sound_ram[0x7e..], &[0x11, 0x22]);
+        assert_eq!(n163.sound_ram[0], 0x33);
+        n163.cpu_write(0xf800, 0x7f);
+        assert_eq!(n163.cpu_read(0x4800), 0x22);
+        assert_eq!(n163.cpu_read(0x4800), 0x22);
+
+        n163.cpu_write(0x5000, 0xf0);
+        n163.cpu_write(0x5800, 0xff);
+        n163.clock(14);
+        assert!(!n163.irq());
+        n163.clock(1);
+        assert!(n163.irq());
+        assert_eq!(n163.cpu_read(0x5000), 0xff);
+        n163.cpu_write(0x5800, 0x80);
+        assert!(!n163.irq());
+    }
+
+    #[test]
+    fn test_wavetable_channels() {
+        let mut n163 = test_board();
+        // a 4 sample wave at address 0: 15, 15, 0, 0
+        n163.sound_ram[0] = 0xff;
+        // channel 7: one step per update, 4 samples long, volume 15
+        n163.sound_ram[0x7c] = 0xfc | 0x01;
+        n163.sound_ram[0x7f] = 0x0f;
+
+        let mut levels = Vec::new();
+        for _ in 0..8 {
+            n163.clock(CHANNEL_CYCLES);
+            levels.push((n163.audio() / LEVEL).round() as i16);
+        }
+        assert_eq!(levels, [105, -120, -120, 105, 105, -120, -120, 105]);
+
+        // with two channels enabled the output alternates between them,
+        // clean mode averages them
+        n163.sound_ram[0x7f] = 0x1f;
+        n163.clock(CHANNEL_CYCLES);
+        assert_eq!(n163.channel, 6);
+        assert_eq!(n163.audio(), 0.0);
+        n163.set_clean_audio(true);
+        assert_eq!(n163.audio(), n163.outputs[7] as f32 / 2.0 * LEVEL);
+    }
+}
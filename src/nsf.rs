@@ -0,0 +1,724 @@
+use crate::cpu::{Memory, Stop, CPU};
+use crate::fds::FdsAudio;
+use crate::mapper::{self, Fetch, Mapper, StateReader, StateWriter};
+use crate::region::Region;
+use crate::rom::{Mirroring, Rom};
+use crate::wav::{Recorder, WavOptions, NO_APU_CHANNELS};
+
+const NSF_TAG: &[u8; 5] = b"NESM\x1a";
+const NSFE_TAG: &[u8; 4] = b"NSFE";
+const HEADER_SIZE: usize = 0x80;
+
+// play routine rates in microseconds when a file doesn't give one
+const NTSC_SPEED: u16 = 16639;
+const PAL_SPEED: u16 = 19997;
+
+// for tracks without a length, like most players
+pub const DEFAULT_LENGTH_MS: u32 = 150_000;
+pub const DEFAULT_FADE_MS: u32 = 2_000;
+
+// INIT and PLAY return into a JMP to itself, the player waits there
+const IDLE: u16 = 0x4100;
+
+// header byte $7B
+pub const VRC6: u8 = 0x01;
+pub const VRC7: u8 = 0x02;
+pub const FDS: u8 = 0x04;
+pub const MMC5: u8 = 0x08;
+pub const NAMCO163: u8 = 0x10;
+pub const SUNSOFT5B: u8 = 0x20;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Track {
+    pub title: Option<String>,
+    pub length: Option<u32>,
+    pub fade: Option<u32>,
+}
+
+impl Track {
+    // milliseconds played, then faded out
+    pub fn duration(&self) -> (u32, u32) {
+        (self.length.unwrap_or(DEFAULT_LENGTH_MS), self.fade.unwrap_or(DEFAULT_FADE_MS))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nsf {
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    // 0 based
+    pub starting_track: usize,
+    pub tracks: Vec<Track>,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub banks: [u8; 8],
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+    // what the file was made for, NTSC when it plays on both
+    pub region: Region,
+    pub expansion: u8,
+    pub data: Vec<u8>,
+}
+
+pub fn is_nsf(raw: &[u8]) -> bool {
+    raw.starts_with(NSF_TAG) || raw.starts_with(NSFE_TAG)
+}
+
+fn le_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+// fixed size header fields are NUL padded
+fn text(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+fn strings(bytes: &[u8]) -> Vec<String> {
+    let bytes = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+    bytes.split(|&byte| byte == 0).map(text).collect()
+}
+
+// NSFe lengths, -1 for the player's default
+fn times(bytes: &[u8]) -> Vec<Option<u32>> {
+    bytes
+        .chunks_exact(4)
+        .map(|time| u32::try_from(i32::from_le_bytes([time[0], time[1], time[2], time[3]])).ok())
+        .collect()
+}
+
+fn region_flags(flags: u8) -> Region {
+    if flags & 0x03 == 0x01 {
+        Region::Pal
+    } else {
+        Region::Ntsc
+    }
+}
+
+impl Nsf {
+    pub fn new(raw: &[u8]) -> Result<Nsf, String> {
+        if raw.starts_with(NSF_TAG) {
+            Nsf::from_nsf(raw)
+        } else if raw.starts_with(NSFE_TAG) {
+            Nsf::from_nsfe(raw)
+        } else {
+            Err("File is not an NSF or NSFe".to_string())
+        }
+    }
+
+    fn from_nsf(raw: &[u8]) -> Result<Nsf, String> {
+        if raw.len() < HEADER_SIZE {
+            return Err("NSF header is truncated".to_string());
+        }
+        let header = &raw[..HEADER_SIZE];
+        let count = header[6] as usize;
+        if count == 0 {
+            return Err("NSF has no tracks".to_string());
+        }
+        let speed = |bytes: &[u8]| match le_u16(bytes) {
+            0 => None,
+            speed => Some(speed),
+        };
+
+        // NSF2 can give the data length and follow it with NSFe metadata
+        let data_len = u32::from_le_bytes([header[0x7d], header[0x7e], header[0x7f], 0]) as usize;
+        let (data, metadata) = if header[5] >= 2 && data_len != 0 {
+            let end = HEADER_SIZE + data_len;
+            if raw.len() < end {
+                return Err("NSF data is truncated".to_string());
+            }
+            (&raw[HEADER_SIZE..end], &raw[end..])
+        } else {
+            (&raw[HEADER_SIZE..], &[][..])
+        };
+
+        let mut nsf = Nsf {
+            title: text(&header[0x0e..0x2e]),
+            artist: text(&header[0x2e..0x4e]),
+            copyright: text(&header[0x4e..0x6e]),
+            starting_track: (header[7] as usize).clamp(1, count) - 1,
+            tracks: vec![Track::default(); count],
+            load_address: le_u16(&header[0x08..]),
+            init_address: le_u16(&header[0x0a..]),
+            play_address: le_u16(&header[0x0c..]),
+            banks: header[0x70..0x78].try_into().unwrap(),
+            ntsc_speed: speed(&header[0x6e..]).unwrap_or(NTSC_SPEED),
+            pal_speed: speed(&header[0x78..]).unwrap_or(PAL_SPEED),
+            region: region_flags(header[0x7a]),
+            expansion: header[0x7b],
+            data: data.to_vec(),
+        };
+        if !metadata.is_empty() {
+            nsf.read_chunks(metadata, false)?;
+        }
+        nsf.check()?;
+        Ok(nsf)
+    }
+
+    fn from_nsfe(raw: &[u8]) -> Result<Nsf, String> {
+        let mut nsf = Nsf {
+            title: String::new(),
+            artist: String::new(),
+            copyright: String::new(),
+            starting_track: 0,
+            tracks: Vec::new(),
+            load_address: 0,
+            init_address: 0,
+            play_address: 0,
+            banks: [0; 8],
+            ntsc_speed: NTSC_SPEED,
+            pal_speed: PAL_SPEED,
+            region: Region::Ntsc,
+            expansion: 0,
+            data: Vec::new(),
+        };
+        nsf.read_chunks(&raw[4..], true)?;
+        nsf.check()?;
+        Ok(nsf)
+    }
+
+    // NSFe chunks: length u32, id, data. Chunks whose id starts with an
+    // upper case letter can't be skipped.
+    fn read_chunks(&mut self, mut raw: &[u8], nsfe: bool) -> Result<(), String> {
+        let mut info = !nsfe;
+        let mut data = !nsfe;
+        let mut titles = Vec::new();
+        let mut lengths = Vec::new();
+        let mut fades = Vec::new();
+        loop {
+            if raw.len() < 8 {
+                return Err("NSFe chunk is truncated".to_string());
+            }
+            let len = u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) as usize;
+            let id = &raw[4..8];
+            let chunk = raw.get(8..8 + len).ok_or("NSFe chunk is truncated")?;
+            raw = &raw[8 + len..];
+
+            match id {
+                b"INFO" if nsfe => {
+                    if len < 8 {
+                        return Err("NSFe INFO chunk is too short".to_string());
+                    }
+                    self.load_address = le_u16(&chunk[0..]);
+                    self.init_address = le_u16(&chunk[2..]);
+                    self.play_address = le_u16(&chunk[4..]);
+                    self.region = region_flags(chunk[6]);
+                    self.expansion = chunk[7];
+                    let count = chunk.get(8).copied().unwrap_or(1).max(1) as usize;
+                    self.tracks = vec![Track::default(); count];
+                    self.starting_track = chunk.get(9).map_or(0, |&track| (track as usize).min(count - 1));
+                    info = true;
+                }
+                b"DATA" if nsfe => {
+                    self.data = chunk.to_vec();
+                    data = true;
+                }
+                b"BANK" if nsfe => {
+                    let len = chunk.len().min(8);
+                    self.banks[..len].copy_from_slice(&chunk[..len]);
+                }
+                b"RATE" if nsfe => {
+                    if len >= 2 {
+                        self.ntsc_speed = le_u16(chunk);
+                    }
+                    if len >= 4 {
+                        self.pal_speed = le_u16(&chunk[2..]);
+                    }
+                }
+                b"NEND" => break,
+                b"auth" => {
+                    let mut fields = strings(chunk).into_iter();
+                    self.title = fields.next().unwrap_or_default();
+                    self.artist = fields.next().unwrap_or_default();
+                    self.copyright = fields.next().unwrap_or_default();
+                }
+                b"tlbl" => titles = strings(chunk),
+                b"time" => lengths = times(chunk),
+                b"fade" => fades = times(chunk),
+                id if id[0].is_ascii_uppercase() => {
+                    return Err(format!("unsupported NSFe chunk {}", String::from_utf8_lossy(id)));
+                }
+                _ => {}
+            }
+        }
+        if !info || !data {
+            return Err("NSFe has no INFO or DATA chunk".to_string());
+        }
+
+        for (i, track) in self.tracks.iter_mut().enumerate() {
+            track.title = titles.get(i).cloned();
+            track.length = lengths.get(i).copied().flatten();
+            track.fade = fades.get(i).copied().flatten();
+        }
+        Ok(())
+    }
+
+    fn check(&self) -> Result<(), String> {
+        let fds = self.expansion & FDS != 0;
+        if self.load_address < if fds { 0x6000 } else { 0x8000 } {
+            return Err(format!("NSF load address ${:04X} is outside the cartridge", self.load_address));
+        }
+        if self.data.is_empty() {
+            return Err("NSF has no data".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn bankswitched(&self) -> bool {
+        self.banks.iter().any(|&bank| bank != 0)
+    }
+
+    // microseconds between PLAY calls
+    pub fn play_speed(&self, region: Region) -> u16 {
+        match region {
+            Region::Ntsc => self.ntsc_speed,
+            Region::Pal | Region::Dendy => self.pal_speed,
+        }
+    }
+}
+
+// the expansion chips an NSF can ask for, taken from their boards with only
+// the sound registers passed through
+struct Chip {
+    board: Box<dyn Mapper>,
+    writes: &'static [(u16, u16)],
+    reads: &'static [(u16, u16)],
+}
+
+impl Chip {
+    fn new(mapper: u8, submapper: u8, writes: &'static [(u16, u16)], reads: &'static [(u16, u16)]) -> Self {
+        let rom = Rom {
+            prg_rom: vec![0; 0x8000],
+            chr_rom: vec![0; 0x2000],
+            mapper,
+            submapper,
+            screen_mirroring: Mirroring::Horizontal,
+            battery: false,
+            region: Region::Ntsc,
+        };
+        Chip {
+            board: mapper::create(&rom).expect("expansion boards are supported"),
+            writes,
+            reads,
+        }
+    }
+}
+
+fn in_ranges(addr: u16, ranges: &[(u16, u16)]) -> bool {
+    ranges.iter().any(|&(start, end)| (start..=end).contains(&addr))
+}
+
+fn chips(expansion: u8) -> Vec<Chip> {
+    let mut chips = Vec::new();
+    if expansion & VRC6 != 0 {
+        chips.push(Chip::new(24, 0, &[(0x9000, 0x9003), (0xa000, 0xa002), (0xb000, 0xb002)], &[]));
+    }
+    if expansion & VRC7 != 0 {
+        chips.push(Chip::new(85, 0, &[(0x9010, 0x9010), (0x9030, 0x9030)], &[]));
+    }
+    if expansion & MMC5 != 0 {
+        let mut chip = Chip::new(5, 0, &[(0x5000, 0x5015), (0x5205, 0x5206), (0x5c00, 0x5ff5)], &[(0x5015, 0x5015), (0x5205, 0x5206), (0x5c00, 0x5ff5)]);
+        // ExRAM as plain RAM
+        chip.board.cpu_write(0x5104, 0x02);
+        chips.push(chip);
+    }
+    if expansion & NAMCO163 != 0 {
+        chips.push(Chip::new(19, 0, &[(0x4800, 0x4fff), (0xf800, 0xffff)], &[(0x4800, 0x4fff)]));
+    }
+    if expansion & SUNSOFT5B != 0 {
+        chips.push(Chip::new(69, 0, &[(0xc000, 0xffff)], &[]));
+    }
+    chips
+}
+
+// The cartridge an NSF describes: 4 KiB banks at $8000-$FFFF switched by
+// $5FF8-$5FFF, 8 KiB of RAM and the expansion chips. FDS tunes get RAM at
+// $6000-$FFFF instead, banks are copied into it.
+pub struct NsfBoard {
+    // the data after `padding` bytes, so banks start at 4 KiB boundaries
+    data: Vec<u8>,
+    initial_banks: [u8; 8],
+    banks: [u8; 8],
+    prg_ram: Vec<u8>,
+    fds_ram: Option<Vec<u8>>,
+    fds: Option<FdsAudio>,
+    chips: Vec<Chip>,
+}
+
+impl NsfBoard {
+    pub fn new(nsf: &Nsf) -> Self {
+        let fds = nsf.expansion & FDS != 0;
+        let padding = if nsf.bankswitched() { nsf.load_address & 0x0fff } else { nsf.load_address - if fds { 0x6000 } else { 0x8000 } };
+        let mut data = vec![0; padding as usize];
+        data.extend_from_slice(&nsf.data);
+        // at least what the address space holds, unused pages read as 0
+        let pages = if fds { 10 } else { 8 };
+        data.resize(data.len().div_ceil(0x1000).max(pages) * 0x1000, 0);
+        let initial_banks = if nsf.bankswitched() { nsf.banks } else { [0, 1, 2, 3, 4, 5, 6, 7] };
+
+        let mut board = NsfBoard {
+            data,
+            initial_banks,
+            banks: initial_banks,
+            prg_ram: vec![0; 0x2000],
+            fds_ram: fds.then(|| vec![0; 0xa000]),
+            fds: fds.then(FdsAudio::new),
+            chips: chips(nsf.expansion),
+        };
+        board.reset(nsf);
+        board
+    }
+
+    fn bank(&self, bank: u8, addr: u16) -> &[u8] {
+        let start = (bank as usize * 0x1000) % self.data.len();
+        &self.data[start + (addr as usize & 0x0fff)..start + 0x1000]
+    }
+
+    // RAM cleared and the banks as the file starts them
+    pub fn reset(&mut self, nsf: &Nsf) {
+        self.banks = self.initial_banks;
+        self.prg_ram.fill(0);
+        if self.fds_ram.is_some() {
+            let pages: Vec<Vec<u8>> = if nsf.bankswitched() {
+                // $6000 and $7000 take banks 6 and 7
+                [6, 7].iter().chain(self.initial_banks.iter()).map(|&bank| self.bank(bank, 0).to_vec()).collect()
+            } else {
+                (0..10).map(|page| self.bank(page, 0).to_vec()).collect()
+            };
+            self.fds_ram = Some(pages.concat());
+        }
+    }
+
+    fn write_bank(&mut self, addr: u16, data: u8) {
+        match self.fds_ram.is_some() {
+            // $5FF6 and up copy into the page of RAM at $6000 and up
+            true => {
+                let page = (addr - 0x5ff6) as usize * 0x1000;
+                let bank = self.bank(data, 0).to_vec();
+                if let Some(ram) = self.fds_ram.as_mut() {
+                    ram[page..page + 0x1000].copy_from_slice(&bank);
+                }
+            }
+            false if addr >= 0x5ff8 => self.banks[addr as usize - 0x5ff8] = data,
+            false => {}
+        }
+    }
+}
+
+impl Mapper for NsfBoard {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        if let Some(chip) = self.chips.iter().find(|chip| in_ranges(addr, chip.reads)) {
+            return chip.board.cpu_read(addr);
+        }
+        match addr {
+            // JMP IDLE
+            IDLE => 0x4c,
+            0x4101 => IDLE as u8,
+            0x4102 => (IDLE >> 8) as u8,
+            0x4040..=0x4097 => self.fds.as_ref().map_or(0, |fds| fds.read(addr)),
+            0x6000..=0xffff if self.fds_ram.is_some() => self.fds_ram.as_ref().unwrap()[addr as usize - 0x6000],
+            0x6000..=0x7fff => self.prg_ram[addr as usize - 0x6000],
+            0x8000..=0xffff => self.bank(self.banks[(addr as usize - 0x8000) / 0x1000], addr)[0],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        for chip in self.chips.iter_mut().filter(|chip| in_ranges(addr, chip.writes)) {
+            chip.board.cpu_write(addr, data);
+        }
+        match addr {
+            0x4040..=0x4097 => {
+                if let Some(fds) = self.fds.as_mut() {
+                    fds.write(addr, data);
+                }
+            }
+            0x5ff6..=0x5fff => self.write_bank(addr, data),
+            0x6000..=0xffff if self.fds_ram.is_some() => self.fds_ram.as_mut().unwrap()[addr as usize - 0x6000] = data,
+            0x6000..=0x7fff => self.prg_ram[addr as usize - 0x6000] = data,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, _addr: u16, _fetch: Fetch, _vram: &[u8; 0x800]) -> u8 {
+        0
+    }
+
+    fn ppu_write(&mut self, _addr: u16, _data: u8, _vram: &mut [u8; 0x800]) {}
+
+    fn clock(&mut self, cycles: usize) {
+        for chip in self.chips.iter_mut() {
+            chip.board.clock(cycles);
+        }
+        if let Some(fds) = self.fds.as_mut() {
+            for _ in 0..cycles {
+                fds.clock();
+            }
+        }
+    }
+
+    fn audio(&self) -> f32 {
+        let chips: f32 = self.chips.iter().map(|chip| chip.board.audio()).sum();
+        chips + self.fds.as_ref().map_or(0.0, |fds| fds.output())
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    // players start tracks over rather than restore them
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        state.bytes(&self.banks);
+        state.data
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut state = StateReader::new(data);
+        let banks = state.bytes(8)?;
+        state.finish()?;
+        self.banks.copy_from_slice(banks);
+        Ok(())
+    }
+}
+
+// Runs an NSF on the CPU: INIT once per track, then PLAY at the rate the
+// file asks for. There is no PPU, the CPU idles between calls.
+pub struct Player {
+    pub cpu: CPU,
+    pub nsf: Nsf,
+    pub region: Region,
+    play_cycles: usize,
+    next_play: usize,
+}
+
+impl Player {
+    pub fn new(nsf: Nsf, region: Region) -> Self {
+        let play_cycles = nsf.play_speed(region) as usize * region.cpu_clock() as usize / 1_000_000;
+        let mut cpu = CPU::new();
+        cpu.mapper = Some(Box::new(NsfBoard::new(&nsf)));
+        Player {
+            cpu,
+            nsf,
+            region,
+            play_cycles,
+            next_play: 0,
+        }
+    }
+
+    fn idle(&self) -> bool {
+        self.cpu.program_counter == IDLE
+    }
+
+    // jumps to `addr` as if by JSR from the idle loop
+    fn call(&mut self, addr: u16) {
+        let [lo, hi] = (IDLE - 1).to_le_bytes();
+        let sp = self.cpu.stack_pointer;
+        self.cpu.memory_write(0x0100 + sp as u16, hi);
+        self.cpu.memory_write(0x0100 + sp.wrapping_sub(1) as u16, lo);
+        self.cpu.stack_pointer = sp.wrapping_sub(2);
+        self.cpu.program_counter = addr;
+    }
+
+    // 0 based, like the NSF spec's A register
+    pub fn start(&mut self, track: usize) -> Result<(), String> {
+        if track >= self.nsf.tracks.len() {
+            return Err(format!("the NSF has {} tracks, no track {}", self.nsf.tracks.len(), track + 1));
+        }
+        // a fresh board, expansion chips included
+        self.cpu = CPU::new();
+        self.cpu.mapper = Some(Box::new(NsfBoard::new(&self.nsf)));
+        for addr in 0x4000..=0x4013 {
+            self.cpu.memory_write(addr, 0);
+        }
+        self.cpu.memory_write(0x4015, 0x0f);
+        self.cpu.memory_write(0x4017, 0x40);
+
+        self.cpu.program_counter = IDLE;
+        self.cpu.register_a = track as u8;
+        self.cpu.register_x = (self.region == Region::Pal) as u8;
+        self.call(self.nsf.init_address);
+        self.next_play = self.play_cycles;
+        Ok(())
+    }
+
+    // one instruction, calling PLAY whenever it's due and the last call
+    // has returned
+    pub fn step(&mut self) -> Result<(), String> {
+        while self.cpu.cycles >= self.next_play {
+            if self.idle() {
+                self.call(self.nsf.play_address);
+            }
+            self.next_play += self.play_cycles;
+        }
+        let pc = self.cpu.program_counter;
+        match self.cpu.step() {
+            Some(Stop::Brk) => Err(format!("BRK at ${:04X}", pc)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn run(&mut self, cycles: usize) -> Result<(), String> {
+        let end = self.cpu.cycles + cycles;
+        while self.cpu.cycles < end {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    // plays `track` for its length and fade into a WAV file
+    pub fn render(&mut self, track: usize, wav: &WavOptions) -> Result<(), String> {
+        if wav.channels {
+            return Err(String::from(NO_APU_CHANNELS));
+        }
+        self.start(track)?;
+        let (length, fade) = self.nsf.tracks[track].duration();
+        let clock = self.region.cpu_clock() as u64;
+        let fade_start = (length as u64 * clock / 1000) as usize;
+        let end = ((length + fade) as u64 * clock / 1000) as usize;
+
+        let audio_error = |e: std::io::Error| format!("cannot record audio: {}", e);
+        let mut recorder = Recorder::create(wav, clock as u32).map_err(audio_error)?;
+        while self.cpu.cycles < end {
+            let before = self.cpu.cycles;
+            self.step()?;
+            let volume = if self.cpu.cycles > fade_start {
+                1.0 - (self.cpu.cycles - fade_start) as f32 / (end - fade_start) as f32
+            } else {
+                1.0
+            };
+            recorder
+                .advance(self.cpu.cycles - before, &[0; 5], self.cpu.expansion_audio() * volume.max(0.0))
+                .map_err(audio_error)?;
+        }
+        recorder.finish().map_err(audio_error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /*
+    INIT ($8000):
+        STA $00
+        STX $01
+        RTS
+    PLAY ($8005):
+        INC $02
+        RTS
+    */
+    const PROGRAM: [u8; 8] = [0x85, 0x00, 0x86, 0x01, 0x60, 0xe6, 0x02, 0x60];
+
+    fn test_nsf(banks: [u8; 8]) -> Vec<u8> {
+        let mut raw = NSF_TAG.to_vec();
+        raw.resize(HEADER_SIZE, 0);
+        raw[5] = 1;
+        raw[6] = 3;
+        raw[7] = 2;
+        raw[0x08..0x0e].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x05, 0x80]);
+        raw[0x0e..0x13].copy_from_slice(b"Title");
+        raw[0x6e..0x70].copy_from_slice(&10000u16.to_le_bytes());
+        raw[0x70..0x78].copy_from_slice(&banks);
+        raw.extend_from_slice(&PROGRAM);
+        raw
+    }
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        [&(data.len() as u32).to_le_bytes()[..], id, data].concat()
+    }
+
+    #[test]
+    fn test_nsf_header() {
+        let nsf = Nsf::new(&test_nsf([0; 8])).unwrap();
+        assert_eq!(nsf.title, "Title");
+        assert_eq!(nsf.tracks.len(), 3);
+        assert_eq!(nsf.starting_track, 1);
+        assert_eq!((nsf.load_address, nsf.init_address, nsf.play_address), (0x8000, 0x8000, 0x8005));
+        assert_eq!((nsf.ntsc_speed, nsf.pal_speed), (10000, PAL_SPEED));
+        assert!(!nsf.bankswitched());
+        assert!(Nsf::new(&test_nsf([0; 8])[..0x40]).is_err());
+    }
+
+    #[test]
+    fn test_nsfe_chunks() {
+        let info = [0x00, 0x80, 0x00, 0x80, 0x05, 0x80, 0x00, VRC6, 2, 1];
+        let time = [&5000i32.to_le_bytes()[..], &(-1i32).to_le_bytes()].concat();
+        let raw = [
+            &NSFE_TAG[..],
+            &chunk(b"INFO", &info),
+            &chunk(b"DATA", &PROGRAM),
+            &chunk(b"auth", b"Game\0Composer\0\xa9 1990\0Ripper\0"),
+            &chunk(b"tlbl", b"Intro\0Stage 1\0"),
+            &chunk(b"time", &time),
+            &chunk(b"xtra", b"skipped"),
+            &chunk(b"NEND", b""),
+        ]
+        .concat();
+        let nsf = Nsf::new(&raw).unwrap();
+        assert_eq!((nsf.title.as_str(), nsf.artist.as_str()), ("Game", "Composer"));
+        assert_eq!(nsf.expansion, VRC6);
+        assert_eq!(nsf.starting_track, 1);
+        assert_eq!(nsf.tracks[0], Track { title: Some("Intro".to_string()), length: Some(5000), fade: None });
+        assert_eq!(nsf.tracks[1].duration(), (DEFAULT_LENGTH_MS, DEFAULT_FADE_MS));
+
+        let mandatory = [&raw[..raw.len() - 8], &chunk(b"XTRA", b""), &chunk(b"NEND", b"")].concat();
+        assert!(Nsf::new(&mandatory).is_err());
+    }
+
+    #[test]
+    fn test_bankswitching() {
+        let mut raw = test_nsf([0, 1, 0, 0, 0, 0, 0, 0]);
+        raw.resize(HEADER_SIZE + 0x2000, 0);
+        raw[HEADER_SIZE + 0x1000] = 0x42;
+        let nsf = Nsf::new(&raw).unwrap();
+        let mut board = NsfBoard::new(&nsf);
+        assert_eq!(board.cpu_read(0x8000), PROGRAM[0]);
+        assert_eq!(board.cpu_read(0x9000), 0x42);
+        board.cpu_write(0x5fff, 1);
+        assert_eq!(board.cpu_read(0xf000), 0x42);
+        assert_eq!(board.cpu_read(IDLE), 0x4c);
+    }
+
+    #[test]
+    fn test_init_and_play() {
+        let nsf = Nsf::new(&test_nsf([0; 8])).unwrap();
+        let mut player = Player::new(nsf, Region::Pal);
+        player.start(2).unwrap();
+        player.run(100).unwrap();
+        assert!(player.idle());
+        assert_eq!((player.cpu.memory_read(0x00), player.cpu.memory_read(0x01)), (2, 1));
+        assert_eq!(player.cpu.memory_read(0x02), 0);
+
+        // PAL plays come every PAL_SPEED (about 20 ms), not the 10 ms the
+        // header asks for on NTSC
+        player.run(player.play_cycles * 3).unwrap();
+        assert_eq!(player.cpu.memory_read(0x02), 3);
+        assert_eq!(player.play_cycles, PAL_SPEED as usize * Region::Pal.cpu_clock() as usize / 1_000_000);
+        assert!(player.start(3).is_err());
+    }
+
+    #[test]
+    fn test_expansion_audio() {
+        let mut raw = test_nsf([0; 8]);
+        raw[0x7b] = VRC6 | SUNSOFT5B;
+        let nsf = Nsf::new(&raw).unwrap();
+        let mut board = NsfBoard::new(&nsf);
+        assert_eq!(board.chips.len(), 2);
+        // 5B channel A at full volume, tone and noise off
+        board.cpu_write(0xc000, 7);
+        board.cpu_write(0xe000, 0x3f);
+        board.cpu_write(0xc000, 8);
+        board.cpu_write(0xe000, 0x0f);
+        assert_eq!(board.audio(), crate::fme7::LEVEL);
+        // $8000 isn't a sound register, the VRC6 doesn't see it
+        board.cpu_write(0x8000, 1);
+        assert_eq!(board.cpu_read(0x8000), PROGRAM[0]);
+    }
+}
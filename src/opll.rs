@@ -0,0 +1,463 @@
+use std::f32::consts::TAU;
+
+use crate::mapper::{StateReader, StateWriter};
+
+// The YM2413 derivative inside VRC7: six two-operator FM channels, fifteen
+// instruments in ROM and one user defined through registers $00-$07.
+
+// CPU cycles per sample, the chip runs off the 3.58 MHz master clock / 72
+pub const CLOCK_DIVIDER: usize = 36;
+pub const SAMPLE_RATE: f32 = 49716.0;
+
+// a full volume channel swings as far as a full volume APU pulse
+pub const LEVEL: f32 = 0.075;
+
+// VRC7 instrument ROM, same layout as registers $00-$07
+pub const PATCHES: [[u8; 8]; 16] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x03, 0x21, 0x05, 0x06, 0xe8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0d, 0xd8, 0xf6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xfa, 0xb2, 0x20, 0x12],
+    [0x31, 0x61, 0x0c, 0x07, 0xa8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1e, 0x06, 0xe1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xa3, 0xe2, 0xf4, 0xf4],
+    [0x21, 0x61, 0x1d, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xa2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xb5, 0x01, 0x0f, 0x0f, 0xa8, 0xa5, 0x51, 0x02],
+    [0x17, 0xc1, 0x24, 0x07, 0xf8, 0xf8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xd3, 0x05, 0xc9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0c, 0x00, 0x94, 0xc0, 0x33, 0xf6],
+    [0x21, 0x72, 0x0d, 0x00, 0xc1, 0xd5, 0x56, 0x06],
+];
+
+const MULTIPLIERS: [f32; 16] = [0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0, 12.0, 12.0, 15.0, 15.0];
+
+// key scale attenuation in dB at block 7 by the top four F-number bits
+const KSL_TABLE: [f32; 16] = [
+    0.0, 18.0, 24.0, 27.75, 30.0, 32.25, 33.75, 35.25, 36.0, 37.5, 38.25, 39.0, 39.75, 40.5, 41.25, 42.0,
+];
+const KSL_SCALE: [f32; 4] = [0.0, 0.25, 0.5, 1.0];
+
+// fully decayed
+const SILENT_DB: f32 = 96.0;
+
+// tremolo and vibrato
+const AM_RATE: f32 = 3.7;
+const AM_DEPTH_DB: f32 = 4.8;
+const VIBRATO_RATE: f32 = 6.4;
+const VIBRATO_CENTS: f32 = 14.0;
+
+// phase offset in cycles of a full scale modulator
+const MODULATION_DEPTH: f32 = 4.0;
+
+// One half of an instrument, decoded from the patch bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Operator {
+    tremolo: bool,
+    vibrato: bool,
+    // hold at the sustain level instead of decaying on
+    sustained: bool,
+    key_scale_rate: bool,
+    multiplier: f32,
+    key_scale_level: u8,
+    rectified: bool,
+    attack: u8,
+    decay: u8,
+    sustain_level: u8,
+    release: u8,
+}
+
+impl Operator {
+    fn decode(patch: &[u8; 8], carrier: bool) -> Self {
+        let n = carrier as usize;
+        Operator {
+            tremolo: patch[n] & 0x80 != 0,
+            vibrato: patch[n] & 0x40 != 0,
+            sustained: patch[n] & 0x20 != 0,
+            key_scale_rate: patch[n] & 0x10 != 0,
+            multiplier: MULTIPLIERS[(patch[n] & 0x0f) as usize],
+            key_scale_level: patch[2 + n] >> 6,
+            rectified: patch[3] & (0x08 << n) != 0,
+            attack: patch[4 + n] >> 4,
+            decay: patch[4 + n] & 0x0f,
+            sustain_level: patch[6 + n] >> 4,
+            release: patch[6 + n] & 0x0f,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Off,
+}
+
+impl Stage {
+    fn from_u8(value: u8) -> Result<Stage, String> {
+        match value {
+            0 => Ok(Stage::Attack),
+            1 => Ok(Stage::Decay),
+            2 => Ok(Stage::Sustain),
+            3 => Ok(Stage::Release),
+            4 => Ok(Stage::Off),
+            _ => Err(format!("bad envelope stage {} in mapper state", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Slot {
+    // in cycles, 0.0 to 1.0
+    phase: f32,
+    // envelope attenuation in dB
+    envelope: f32,
+    stage: Stage,
+    // last two outputs, the modulator feeds their average back
+    output: [f32; 2],
+}
+
+impl Default for Slot {
+    fn default() -> Self {
+        Slot {
+            phase: 0.0,
+            envelope: SILENT_DB,
+            stage: Stage::Off,
+            output: [0.0; 2],
+        }
+    }
+}
+
+// 0 for rate 0, otherwise 4 * rate plus the key scale offset, at most 63
+fn effective_rate(rate: u8, key_code: u8, key_scale_rate: bool) -> u8 {
+    if rate == 0 {
+        return 0;
+    }
+    let offset = if key_scale_rate { key_code } else { key_code >> 2 };
+    (rate * 4 + offset).min(63)
+}
+
+// decay and release: rate 60 and up fall through 96 dB in 2.4 ms, each step
+// of 4 below takes twice as long
+fn decay_per_sample(rate: u8) -> f32 {
+    if rate < 4 {
+        return 0.0;
+    }
+    let seconds = 0.0024 * 2f32.powf((60 - rate.min(60)) as f32 / 4.0);
+    SILENT_DB / (seconds * SAMPLE_RATE)
+}
+
+// attack is exponential and about 14 times faster than decay
+fn attack_factor(rate: u8) -> f32 {
+    if rate < 4 {
+        return 0.0;
+    }
+    let seconds = 0.0024 * 2f32.powf((60 - rate.min(60)) as f32 / 4.0) / 14.0;
+    1.0 - (1.0 / SILENT_DB).powf(1.0 / (seconds * SAMPLE_RATE))
+}
+
+// 0.0 to 1.0 and back once per cycle
+fn triangle(x: f32) -> f32 {
+    1.0 - (2.0 * x.fract() - 1.0).abs()
+}
+
+// What both operators of a channel share for one sample.
+struct Context {
+    increment: f32,
+    key_code: u8,
+    sustain: bool,
+    tremolo: f32,
+    vibrato: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Opll {
+    address: u8,
+    registers: [u8; 0x40],
+    slots: [[Slot; 2]; 6],
+    samples: u32,
+    output: f32,
+}
+
+impl Default for Opll {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Opll {
+    pub fn new() -> Self {
+        Opll {
+            address: 0,
+            registers: [0; 0x40],
+            slots: [[Slot::default(); 2]; 6],
+            samples: 0,
+            output: 0.0,
+        }
+    }
+
+    pub fn write_address(&mut self, data: u8) {
+        self.address = data & 0x3f;
+    }
+
+    pub fn write_data(&mut self, data: u8) {
+        let address = self.address as usize;
+        // key on restarts both operators, key off releases them
+        if (0x20..0x26).contains(&address) {
+            let channel = address - 0x20;
+            let was_on = self.registers[address] & 0x10 != 0;
+            let on = data & 0x10 != 0;
+            for slot in self.slots[channel].iter_mut() {
+                if on && !was_on {
+                    slot.phase = 0.0;
+                    slot.stage = Stage::Attack;
+                } else if !on && was_on && slot.stage != Stage::Off {
+                    slot.stage = Stage::Release;
+                }
+            }
+        }
+        self.registers[address] = data;
+    }
+
+    pub fn register(&self, address: u8) -> u8 {
+        self.registers[address as usize & 0x3f]
+    }
+
+    pub fn patch(&self, channel: usize) -> [u8; 8] {
+        match self.registers[0x30 + channel] >> 4 {
+            0 => self.registers[0..8].try_into().unwrap(),
+            instrument => PATCHES[instrument as usize],
+        }
+    }
+
+    // the last sample, about -LEVEL * 6 to LEVEL * 6
+    pub fn output(&self) -> f32 {
+        self.output
+    }
+
+    // one sample, every CLOCK_DIVIDER CPU cycles
+    pub fn step(&mut self) {
+        let time = self.samples as f32 / SAMPLE_RATE;
+        self.samples = self.samples.wrapping_add(1);
+        let tremolo = AM_DEPTH_DB * triangle(time * AM_RATE);
+        let vibrato = 2f32.powf(VIBRATO_CENTS * (2.0 * triangle(time * VIBRATO_RATE) - 1.0) / 1200.0);
+
+        let mut total = 0.0;
+        for channel in 0..6 {
+            total += self.channel(channel, tremolo, vibrato);
+        }
+        self.output = total * LEVEL;
+    }
+
+    fn channel(&mut self, channel: usize, tremolo: f32, vibrato: f32) -> f32 {
+        let patch = self.patch(channel);
+        let fnum = self.registers[0x10 + channel] as u16 | (self.registers[0x20 + channel] as u16 & 0x01) << 8;
+        let block = (self.registers[0x20 + channel] >> 1) & 0x07;
+        let context = Context {
+            increment: fnum as f32 * (1 << block) as f32 / (1 << 19) as f32,
+            key_code: block << 1 | (fnum >> 8) as u8,
+            sustain: self.registers[0x20 + channel] & 0x20 != 0,
+            tremolo,
+            vibrato,
+        };
+
+        let key_scale = |operator: &Operator| {
+            let level = KSL_TABLE[(fnum >> 5) as usize] - 6.0 * (7 - block) as f32;
+            level.max(0.0) * KSL_SCALE[operator.key_scale_level as usize]
+        };
+
+        let modulator = Operator::decode(&patch, false);
+        let carrier = Operator::decode(&patch, true);
+        let feedback = patch[3] & 0x07;
+        let total_level = (patch[2] & 0x3f) as f32 * 0.75;
+        let volume = (self.registers[0x30 + channel] & 0x0f) as f32 * 3.0;
+
+        let [mod_slot, car_slot] = &mut self.slots[channel];
+
+        let offset = if feedback == 0 {
+            0.0
+        } else {
+            (mod_slot.output[0] + mod_slot.output[1]) / 2.0 * 2f32.powi(feedback as i32 - 6)
+        };
+        let attenuation = total_level + key_scale(&modulator);
+        let modulation = Self::operator(mod_slot, &modulator, &context, offset, attenuation);
+
+        let attenuation = volume + key_scale(&carrier);
+        Self::operator(car_slot, &carrier, &context, modulation * MODULATION_DEPTH, attenuation)
+    }
+
+    fn operator(slot: &mut Slot, operator: &Operator, context: &Context, offset: f32, attenuation: f32) -> f32 {
+        Self::envelope(slot, operator, context);
+
+        let vibrato = if operator.vibrato { context.vibrato } else { 1.0 };
+        slot.phase = (slot.phase + context.increment * operator.multiplier * vibrato).fract();
+
+        let tremolo = if operator.tremolo { context.tremolo } else { 0.0 };
+        let attenuation = slot.envelope + attenuation + tremolo;
+        let output = if slot.stage == Stage::Off || attenuation >= SILENT_DB {
+            0.0
+        } else {
+            let wave = (TAU * (slot.phase + offset)).sin();
+            let wave = if operator.rectified { wave.max(0.0) } else { wave };
+            wave * 10f32.powf(-attenuation / 20.0)
+        };
+        slot.output = [output, slot.output[0]];
+        output
+    }
+
+    fn envelope(slot: &mut Slot, operator: &Operator, context: &Context) {
+        let rate = |rate| effective_rate(rate, context.key_code, operator.key_scale_rate);
+        match slot.stage {
+            Stage::Attack => {
+                let rate = rate(operator.attack);
+                if rate >= 60 {
+                    slot.envelope = 0.0;
+                } else {
+                    slot.envelope -= slot.envelope * attack_factor(rate);
+                }
+                if slot.envelope < 0.1 {
+                    slot.envelope = 0.0;
+                    slot.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                let level = operator.sustain_level as f32 * 3.0;
+                slot.envelope += decay_per_sample(rate(operator.decay));
+                if slot.envelope >= level {
+                    slot.envelope = level;
+                    slot.stage = Stage::Sustain;
+                }
+            }
+            // percussive tones keep decaying at the release rate
+            Stage::Sustain if !operator.sustained => slot.envelope += decay_per_sample(rate(operator.release)),
+            Stage::Sustain => {}
+            Stage::Release => {
+                let release = if context.sustain {
+                    5
+                } else if operator.sustained {
+                    operator.release
+                } else {
+                    7
+                };
+                slot.envelope += decay_per_sample(rate(release));
+            }
+            Stage::Off => {}
+        }
+        if slot.envelope >= SILENT_DB {
+            slot.envelope = SILENT_DB;
+            slot.stage = Stage::Off;
+        }
+    }
+
+    pub fn save(&self, state: &mut StateWriter) {
+        state.u8(self.address);
+        state.bytes(&self.registers);
+        for slot in self.slots.iter().flatten() {
+            state.u32(slot.phase.to_bits());
+            state.u32(slot.envelope.to_bits());
+            state.u8(slot.stage as u8);
+            state.u32(slot.output[0].to_bits());
+            state.u32(slot.output[1].to_bits());
+        }
+        state.u32(self.samples);
+        state.u32(self.output.to_bits());
+    }
+
+    pub fn load(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.address = state.u8()?;
+        self.registers.copy_from_slice(state.bytes(0x40)?);
+        for slot in self.slots.iter_mut().flatten() {
+            slot.phase = f32::from_bits(state.u32()?);
+            slot.envelope = f32::from_bits(state.u32()?);
+            slot.stage = Stage::from_u8(state.u8()?)?;
+            slot.output = [f32::from_bits(state.u32()?), f32::from_bits(state.u32()?)];
+        }
+        self.samples = state.u32()?;
+        self.output = f32::from_bits(state.u32()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(opll: &mut Opll, address: u8, data: u8) {
+        opll.write_address(address);
+        opll.write_data(data);
+    }
+
+    // a plain sine: silent modulator, carrier with instant attack and no decay
+    fn sine_patch(opll: &mut Opll) {
+        for (address, data) in [0x01, 0x21, 0x3f, 0x00, 0xf0, 0xf0, 0x0f, 0x0f].into_iter().enumerate() {
+            write(opll, address as u8, data);
+        }
+    }
+
+    #[test]
+    fn test_patch_selection() {
+        let mut opll = Opll::new();
+        sine_patch(&mut opll);
+        assert_eq!(opll.patch(0)[2], 0x3f);
+        write(&mut opll, 0x33, 0x90);
+        assert_eq!(opll.patch(3), PATCHES[9]);
+        assert_eq!(opll.patch(2)[0], 0x01);
+    }
+
+    #[test]
+    fn test_tone_pitch_and_release() {
+        let mut opll = Opll::new();
+        sine_patch(&mut opll);
+        // 440 Hz: F-number 290 in block 4, full volume
+        write(&mut opll, 0x30, 0x00);
+        write(&mut opll, 0x10, (290 & 0xff) as u8);
+        write(&mut opll, 0x20, 0x10 | 4 << 1 | 1);
+
+        let mut crossings = 0;
+        let mut previous = 0.0;
+        let mut peak: f32 = 0.0;
+        for _ in 0..SAMPLE_RATE as usize {
+            opll.step();
+            let output = opll.output();
+            if previous < 0.0 && output >= 0.0 {
+                crossings += 1;
+            }
+            previous = output;
+            peak = peak.max(output);
+        }
+        assert!((438..=442).contains(&crossings), "{} cycles", crossings);
+        assert!((peak - LEVEL).abs() < 0.001);
+
+        // key off, RR 15 releases within a few milliseconds
+        write(&mut opll, 0x20, 4 << 1 | 1);
+        for _ in 0..500 {
+            opll.step();
+        }
+        assert_eq!(opll.output(), 0.0);
+        assert_eq!(opll.slots[0][1].stage, Stage::Off);
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut opll = Opll::new();
+        write(&mut opll, 0x31, 0x30);
+        write(&mut opll, 0x11, 0x80);
+        write(&mut opll, 0x21, 0x1a);
+        for _ in 0..100 {
+            opll.step();
+        }
+        let mut state = StateWriter::default();
+        opll.save(&mut state);
+
+        let mut restored = Opll::new();
+        restored.load(&mut StateReader::new(&state.data)).unwrap();
+        assert_eq!(restored, opll);
+        opll.step();
+        restored.step();
+        assert_eq!(restored.output(), opll.output());
+    }
+}
@@ -0,0 +1,143 @@
+use std::any::Any;
+use std::cell::Cell;
+
+use crate::input::{InputDevice, Screen};
+use crate::mapper::{StateReader, StateWriter};
+
+// the potentiometer's range, what Arkanoid calibrates for
+pub const MIN_POSITION: u8 = 0x62;
+pub const MAX_POSITION: u8 = 0xf2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaddleModel {
+    // controller port 2: button on bit 3, knob on bit 4 of $4017
+    Nes,
+    // expansion port: button on bit 1 of $4016, knob on bit 1 of $4017
+    Famicom,
+}
+
+// Arkanoid's Vaus controller. The strobe latches the knob's position into
+// a shift register that $4017 reads clock out inverted, most significant
+// bit first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Paddle {
+    model: PaddleModel,
+    position: u8,
+    button: bool,
+    strobe: bool,
+    shift: Cell<u8>,
+}
+
+impl Paddle {
+    pub fn new(model: PaddleModel) -> Self {
+        Paddle {
+            model,
+            position: MIN_POSITION,
+            button: false,
+            strobe: false,
+            shift: Cell::new(0),
+        }
+    }
+
+    pub fn set_position(&mut self, position: u8) {
+        self.position = position.clamp(MIN_POSITION, MAX_POSITION);
+        if self.strobe {
+            self.shift.set(self.position);
+        }
+    }
+
+    pub fn set_button(&mut self, pressed: bool) {
+        self.button = pressed;
+    }
+
+    fn knob(&self) -> u8 {
+        let shift = self.shift.get();
+        if !self.strobe {
+            self.shift.set(shift << 1);
+        }
+        !shift >> 7
+    }
+}
+
+impl InputDevice for Paddle {
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.shift.set(self.position);
+        }
+    }
+
+    fn read(&self, port: usize, _screen: &Screen) -> u8 {
+        let button = self.button as u8;
+        match (self.model, port) {
+            (PaddleModel::Nes, 1) => button << 3 | self.knob() << 4,
+            (PaddleModel::Famicom, 0) => button << 1,
+            (PaddleModel::Famicom, 1) => self.knob() << 1,
+            _ => 0,
+        }
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        state.u8(self.position);
+        state.bool(self.button);
+        state.bool(self.strobe);
+        state.u8(self.shift.get());
+        state.data
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut state = StateReader::new(data);
+        let mut restored = self.clone();
+        restored.position = state.u8()?.clamp(MIN_POSITION, MAX_POSITION);
+        restored.button = state.bool()?;
+        restored.strobe = state.bool()?;
+        restored.shift.set(state.u8()?);
+        state.finish()?;
+        *self = restored;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::input::test::read;
+
+    fn knob_value(paddle: &mut Paddle, port: usize, bit: u8) -> u8 {
+        paddle.write(1);
+        paddle.write(0);
+        (0..8).fold(0, |value, _| value << 1 | (read(paddle, port) >> bit & 1))
+    }
+
+    #[test]
+    fn test_nes_paddle() {
+        let mut paddle = Paddle::new(PaddleModel::Nes);
+        paddle.set_position(0xa5);
+        paddle.set_button(true);
+        assert_eq!(read(&paddle, 1) & 0b1000, 0b1000);
+        assert_eq!(knob_value(&mut paddle, 1, 4), !0xa5);
+        assert_eq!(read(&paddle, 0), 0);
+
+        paddle.set_position(0);
+        assert_eq!(knob_value(&mut paddle, 1, 4), !MIN_POSITION);
+    }
+
+    #[test]
+    fn test_famicom_paddle() {
+        let mut paddle = Paddle::new(PaddleModel::Famicom);
+        paddle.set_position(0x80);
+        paddle.set_button(true);
+        assert_eq!(knob_value(&mut paddle, 1, 1), !0x80);
+        // reading the button doesn't clock the knob
+        paddle.write(1);
+        paddle.write(0);
+        assert_eq!(read(&paddle, 0), 0b10);
+        assert_eq!(read(&paddle, 1), 0);
+        assert_eq!(read(&paddle, 1), 0b10);
+    }
+}
@@ -0,0 +1,248 @@
+use std::fs;
+use std::path::Path;
+
+use crate::frame::{pixel_emphasis, pixel_index};
+
+#[rustfmt::skip]
+pub static SYSTEM_PALETTE: [(u8, u8, u8); 64] = [
+   (0x80, 0x80, 0x80), (0x00, 0x3D, 0xA6), (0x00, 0x12, 0xB0), (0x44, 0x00, 0x96), (0xA1, 0x00, 0x5E),
+   (0xC7, 0x00, 0x28), (0xBA, 0x06, 0x00), (0x8C, 0x17, 0x00), (0x5C, 0x2F, 0x00), (0x10, 0x45, 0x00),
+   (0x05, 0x4A, 0x00), (0x00, 0x47, 0x2E), (0x00, 0x41, 0x66), (0x00, 0x00, 0x00), (0x05, 0x05, 0x05),
+   (0x05, 0x05, 0x05), (0xC7, 0xC7, 0xC7), (0x00, 0x77, 0xFF), (0x21, 0x55, 0xFF), (0x82, 0x37, 0xFA),
+   (0xEB, 0x2F, 0xB5), (0xFF, 0x29, 0x50), (0xFF, 0x22, 0x00), (0xD6, 0x32, 0x00), (0xC4, 0x62, 0x00),
+   (0x35, 0x80, 0x00), (0x05, 0x8F, 0x00), (0x00, 0x8A, 0x55), (0x00, 0x99, 0xCC), (0x21, 0x21, 0x21),
+   (0x09, 0x09, 0x09), (0x09, 0x09, 0x09), (0xFF, 0xFF, 0xFF), (0x0F, 0xD7, 0xFF), (0x69, 0xA2, 0xFF),
+   (0xD4, 0x80, 0xFF), (0xFF, 0x45, 0xF3), (0xFF, 0x61, 0x8B), (0xFF, 0x88, 0x33), (0xFF, 0x9C, 0x12),
+   (0xFA, 0xBC, 0x20), (0x9F, 0xE3, 0x0E), (0x2B, 0xF0, 0x35), (0x0C, 0xF0, 0xA4), (0x05, 0xFB, 0xFF),
+   (0x5E, 0x5E, 0x5E), (0x0D, 0x0D, 0x0D), (0x0D, 0x0D, 0x0D), (0xFF, 0xFF, 0xFF), (0xA6, 0xFC, 0xFF),
+   (0xB3, 0xEC, 0xFF), (0xDA, 0xAB, 0xEB), (0xFF, 0xA8, 0xF9), (0xFF, 0xAB, 0xB3), (0xFF, 0xD2, 0xB0),
+   (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA),
+   (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
+];
+
+// .pal files hold 64 RGB triples, optionally followed by the same 64 colors
+// for each of the seven PPUMASK emphasis combinations
+pub const PAL_SIZE: usize = 64 * 3;
+pub const PAL_EMPHASIS_SIZE: usize = 8 * PAL_SIZE;
+
+// how much emphasis dims the other channels on a 2C02
+const EMPHASIS_ATTENUATION: f32 = 0.816;
+
+// One table per emphasis setting (PPUMASK bits 5-7: red, green, blue),
+// table 0 is the plain palette.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    pub colors: [[(u8, u8, u8); 64]; 8],
+}
+
+impl Palette {
+    // Emphasis tables are approximated by dimming the channels that are not
+    // emphasized.
+    pub fn new(colors: [(u8, u8, u8); 64]) -> Self {
+        let mut tables = [colors; 8];
+        for (emphasis, table) in tables.iter_mut().enumerate().skip(1) {
+            let dim = |value: u8, bit: usize| {
+                if emphasis & bit != 0 {
+                    value
+                } else {
+                    (value as f32 * EMPHASIS_ATTENUATION).round() as u8
+                }
+            };
+            for color in table.iter_mut() {
+                *color = (dim(color.0, 1), dim(color.1, 2), dim(color.2, 4));
+            }
+        }
+        Palette { colors: tables }
+    }
+
+    pub fn from_pal(bytes: &[u8]) -> Result<Palette, String> {
+        let color = |i: usize| (bytes[i * 3], bytes[i * 3 + 1], bytes[i * 3 + 2]);
+        match bytes.len() {
+            PAL_SIZE => Ok(Palette::new(std::array::from_fn(color))),
+            PAL_EMPHASIS_SIZE => Ok(Palette {
+                colors: std::array::from_fn(|table| std::array::from_fn(|i| color(table * 64 + i))),
+            }),
+            len => Err(format!(
+                "palette must be {} or {} bytes, got {}",
+                PAL_SIZE, PAL_EMPHASIS_SIZE, len
+            )),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Palette, String> {
+        let bytes = fs::read(path.as_ref()).map_err(|e| format!("{}: {}", path.as_ref().display(), e))?;
+        Palette::from_pal(&bytes).map_err(|e| format!("{}: {}", path.as_ref().display(), e))
+    }
+
+    // always the 1536 byte variant so emphasis survives a round trip
+    pub fn to_pal(&self) -> Vec<u8> {
+        self.colors
+            .iter()
+            .flatten()
+            .flat_map(|(r, g, b)| [*r, *g, *b])
+            .collect()
+    }
+
+    pub fn rgb(&self, index: u8) -> (u8, u8, u8) {
+        self.colors[0][(index & 0x3f) as usize]
+    }
+
+    pub fn rgb_emphasized(&self, index: u8, emphasis: u8) -> (u8, u8, u8) {
+        self.colors[(emphasis & 0x07) as usize][(index & 0x3f) as usize]
+    }
+
+    pub fn rgb_pixel(&self, pixel: u16) -> (u8, u8, u8) {
+        self.rgb_emphasized(pixel_index(pixel), pixel_emphasis(pixel))
+    }
+
+    pub fn generate(ntsc: &Ntsc) -> Palette {
+        Palette {
+            colors: std::array::from_fn(|emphasis| {
+                std::array::from_fn(|index| ntsc.color(index as u8, emphasis as u8))
+            }),
+        }
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::new(SYSTEM_PALETTE)
+    }
+}
+
+// Parameters for decoding the composite signal of the 2C02 into RGB, the
+// way a TV would. Hue is in degrees, the rest are factors around 1.0
+// except brightness, which is added to the luma.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ntsc {
+    pub hue: f32,
+    pub saturation: f32,
+    pub contrast: f32,
+    pub brightness: f32,
+    pub gamma: f32,
+}
+
+impl Default for Ntsc {
+    fn default() -> Self {
+        Ntsc {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: 1.0,
+        }
+    }
+}
+
+// signal voltages for luma levels 0-3, relative to sync
+const SIGNAL_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const SIGNAL_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const SIGNAL_BLACK: f32 = 0.518;
+const SIGNAL_WHITE: f32 = 1.962;
+const SIGNAL_EMPHASIS: f32 = 0.746;
+
+// the PPU generates color as a square wave over 12 phases of the subcarrier
+fn in_color_phase(color: u8, phase: u8) -> bool {
+    (color + phase) % 12 < 6
+}
+
+impl Ntsc {
+    pub fn color(&self, index: u8, emphasis: u8) -> (u8, u8, u8) {
+        let hue = index & 0x0f;
+        let level = ((index >> 4) & 0x03) as usize;
+        // $xE and $xF output black
+        if hue >= 0x0e {
+            return (0, 0, 0);
+        }
+
+        let (mut y, mut i, mut q) = (0.0f32, 0.0f32, 0.0f32);
+        for phase in 0..12 {
+            let mut signal = match hue {
+                0x00 => SIGNAL_HIGH[level],
+                0x0d => SIGNAL_LOW[level],
+                _ if in_color_phase(hue, phase) => SIGNAL_HIGH[level],
+                _ => SIGNAL_LOW[level],
+            };
+            let attenuate = (emphasis & 1 != 0 && in_color_phase(0x0c, phase))
+                || (emphasis & 2 != 0 && in_color_phase(0x04, phase))
+                || (emphasis & 4 != 0 && in_color_phase(0x08, phase));
+            if attenuate {
+                signal *= SIGNAL_EMPHASIS;
+            }
+
+            let value = (signal - SIGNAL_BLACK) / (SIGNAL_WHITE - SIGNAL_BLACK) / 12.0;
+            let angle = std::f32::consts::PI * (phase as f32 + 3.0) / 6.0 + self.hue.to_radians();
+            y += value;
+            i += value * angle.cos();
+            q += value * angle.sin();
+        }
+
+        let y = y * self.contrast + self.brightness;
+        let (i, q) = (i * self.saturation * 2.0, q * self.saturation * 2.0);
+        let channel = |value: f32| {
+            let value = value.clamp(0.0, 1.0).powf(1.0 / self.gamma);
+            (value * 255.0).round() as u8
+        };
+
+        (
+            channel(y + 0.946_882 * i + 0.623_557 * q),
+            channel(y - 0.274_788 * i - 0.635_691 * q),
+            channel(y - 1.108_545 * i + 1.709_007 * q),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load_64_color_pal() {
+        let bytes: Vec<u8> = (0..PAL_SIZE).map(|i| i as u8).collect();
+        let palette = Palette::from_pal(&bytes).unwrap();
+
+        assert_eq!(palette.rgb(1), (3, 4, 5));
+        assert_eq!(palette.rgb(0x41), (3, 4, 5));
+        // red emphasis keeps red and dims the rest
+        let (r, g, b) = palette.rgb_emphasized(0x3f, 1);
+        assert_eq!(r, 189);
+        assert!(g < 190 && b < 191);
+    }
+
+    #[test]
+    fn test_emphasis_pal_round_trip() {
+        let bytes: Vec<u8> = (0..PAL_EMPHASIS_SIZE).map(|i| (i * 7) as u8).collect();
+        let palette = Palette::from_pal(&bytes).unwrap();
+
+        assert_eq!(palette.rgb_emphasized(0, 7), (bytes[7 * PAL_SIZE], bytes[7 * PAL_SIZE + 1], bytes[7 * PAL_SIZE + 2]));
+        assert_eq!(palette.to_pal(), bytes);
+        assert!(Palette::from_pal(&bytes[..100]).is_err());
+    }
+
+    #[test]
+    fn test_generated_palette() {
+        let palette = Palette::generate(&Ntsc::default());
+
+        assert_eq!(palette.rgb(0x0f), (0, 0, 0));
+        let (r, g, b) = palette.rgb(0x30);
+        assert!(r > 0xf0 && g > 0xf0 && b > 0xf0);
+        // $x2 is blue, $x6 red, $xA green
+        let (r, g, b) = palette.rgb(0x12);
+        assert!(b > r && b > g, "{:?}", (r, g, b));
+        let (r, g, b) = palette.rgb(0x16);
+        assert!(r > g && r > b, "{:?}", (r, g, b));
+        let (r, g, b) = palette.rgb(0x1a);
+        assert!(g > r && g > b, "{:?}", (r, g, b));
+
+        let dull = Palette::generate(&Ntsc {
+            saturation: 0.0,
+            ..Ntsc::default()
+        });
+        let (r, g, b) = dull.rgb(0x16);
+        assert!(r == g && g == b);
+
+        // emphasis darkens
+        let (r, _, _) = palette.rgb_emphasized(0x20, 6);
+        assert!(r < palette.rgb(0x20).0);
+    }
+}
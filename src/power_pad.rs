@@ -0,0 +1,126 @@
+use std::any::Any;
+use std::cell::Cell;
+
+use crate::input::{InputDevice, Screen};
+use crate::mapper::{StateReader, StateWriter};
+
+// the order the mat shifts its buttons out, numbered 1-12 as printed on
+// side B
+const BIT3_ORDER: [usize; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+const BIT4_ORDER: [usize; 4] = [4, 3, 12, 8];
+const BUTTON_COUNT: usize = 12;
+
+// Power Pad / Family Trainer mat on controller port 2. Two shift registers
+// answer each $4017 read, one on bit 3 and one on bit 4, and return 1 once
+// they're empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PowerPad {
+    // bit n - 1 for button n
+    pub buttons: u16,
+    strobe: bool,
+    shift: [Cell<u8>; 2],
+}
+
+impl PowerPad {
+    pub fn new() -> Self {
+        PowerPad::default()
+    }
+
+    pub fn set_button(&mut self, button: usize, pressed: bool) -> Result<(), String> {
+        if !(1..=BUTTON_COUNT).contains(&button) {
+            return Err(format!("the mat has buttons 1 to {}, no button {}", BUTTON_COUNT, button));
+        }
+        let mask = 1 << (button - 1);
+        if pressed {
+            self.buttons |= mask;
+        } else {
+            self.buttons &= !mask;
+        }
+        if self.strobe {
+            self.latch();
+        }
+        Ok(())
+    }
+
+    fn latch(&self) {
+        let pack = |order: &[usize]| {
+            order
+                .iter()
+                .enumerate()
+                .fold(0u8, |bits, (i, button)| bits | ((self.buttons >> (button - 1)) as u8 & 1) << i)
+        };
+        self.shift[0].set(pack(&BIT3_ORDER));
+        self.shift[1].set(pack(&BIT4_ORDER) | 0xf0);
+    }
+}
+
+impl InputDevice for PowerPad {
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    fn read(&self, port: usize, _screen: &Screen) -> u8 {
+        if port != 1 {
+            return 0;
+        }
+        let (low, high) = (self.shift[0].get(), self.shift[1].get());
+        if !self.strobe {
+            self.shift[0].set(low >> 1 | 0x80);
+            self.shift[1].set(high >> 1 | 0x80);
+        }
+        (low & 1) << 3 | (high & 1) << 4
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        state.u16(self.buttons);
+        state.bool(self.strobe);
+        state.u8(self.shift[0].get());
+        state.u8(self.shift[1].get());
+        state.data
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut state = StateReader::new(data);
+        let mut restored = self.clone();
+        restored.buttons = state.u16()? & ((1 << BUTTON_COUNT) - 1);
+        restored.strobe = state.bool()?;
+        restored.shift[0].set(state.u8()?);
+        restored.shift[1].set(state.u8()?);
+        state.finish()?;
+        *self = restored;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::input::test::read;
+
+    #[test]
+    fn test_serial_report() {
+        let mut pad = PowerPad::new();
+        pad.set_button(1, true).unwrap();
+        pad.set_button(12, true).unwrap();
+        pad.set_button(7, true).unwrap();
+        assert!(pad.set_button(0, true).is_err());
+        assert!(pad.set_button(13, true).is_err());
+        pad.write(1);
+        pad.write(0);
+
+        let reads: Vec<u8> = (0..10).map(|_| read(&pad, 1)).collect();
+        let bit3: Vec<u8> = reads.iter().map(|data| data >> 3 & 1).collect();
+        let bit4: Vec<u8> = reads.iter().map(|data| data >> 4 & 1).collect();
+        assert_eq!(bit3, vec![0, 1, 0, 0, 0, 0, 0, 1, 1, 1]);
+        assert_eq!(bit4, vec![0, 0, 1, 0, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(read(&pad, 0), 0);
+    }
+}
@@ -0,0 +1,80 @@
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    // Famiclone timing: PAL frame with an NTSC-like CPU divider
+    Dendy,
+}
+
+impl Region {
+    pub fn cpu_clock(&self) -> u32 {
+        match self {
+            Region::Ntsc => 1_789_773,
+            Region::Pal => 1_662_607,
+            Region::Dendy => 1_773_448,
+        }
+    }
+
+    // PPU dots per frame over the dots per CPU cycle, 3.2 on PAL, rounded
+    // up to whole cycles. NTSC skipping a dot on odd frames is left out.
+    pub fn cycles_per_frame(&self) -> usize {
+        let (scanlines, dots, cycles): (usize, usize, usize) = match self {
+            Region::Ntsc => (262, 3, 1),
+            Region::Pal => (312, 16, 5),
+            Region::Dendy => (312, 3, 1),
+        };
+        (scanlines * 341 * cycles).div_ceil(dots)
+    }
+
+    // PPUMASK bits 5 and 6 mean green and red on PAL PPUs
+    pub fn swaps_red_green_emphasis(&self) -> bool {
+        *self != Region::Ntsc
+    }
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ntsc" => Ok(Region::Ntsc),
+            "pal" => Ok(Region::Pal),
+            "dendy" => Ok(Region::Dendy),
+            _ => Err(format!("unknown region '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Region::Ntsc => "ntsc",
+            Region::Pal => "pal",
+            Region::Dendy => "dendy",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_frame_timing() {
+        assert_eq!(Region::Ntsc.cycles_per_frame(), 29781);
+        assert_eq!(Region::Pal.cycles_per_frame(), 33248);
+        assert_eq!(Region::Dendy.cycles_per_frame(), 35464);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("PAL".parse::<Region>(), Ok(Region::Pal));
+        assert_eq!(Region::Dendy.to_string().parse::<Region>(), Ok(Region::Dendy));
+        assert!("secam".parse::<Region>().is_err());
+    }
+}
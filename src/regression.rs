@@ -0,0 +1,444 @@
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cpu::{Stop, CPU};
+use crate::frame::Frame;
+use crate::joypad::BUTTONS;
+use crate::palette::Palette;
+use crate::region::Region;
+use crate::rom::Rom;
+use crate::screenshot::{self, Image};
+
+// set to rewrite golden hashes instead of comparing against them
+pub const BLESS_VAR: &str = "NES_BLESS";
+
+// Anything that can be driven a frame at a time with controller input.
+pub trait FrameSource {
+    fn set_buttons(&mut self, port: usize, buttons: u8);
+
+    fn run_frame(&mut self) -> &Frame;
+}
+
+// A console running a ROM, each frame is `cpu.frame` once the frame's
+// cycles have run. There is no PPU yet, so until there is the picture is
+// whatever the host draws there and no ROM goldens are committed.
+pub struct RomSource {
+    pub cpu: CPU,
+    pub region: Region,
+}
+
+impl RomSource {
+    pub fn new(rom: &Rom) -> Result<Self, String> {
+        let mut cpu = CPU::new();
+        cpu.load_rom(rom)?;
+        cpu.reset();
+        Ok(RomSource { cpu, region: rom.region })
+    }
+}
+
+impl FrameSource for RomSource {
+    fn set_buttons(&mut self, port: usize, buttons: u8) {
+        self.cpu.joypads[port].set_buttons(buttons);
+    }
+
+    fn run_frame(&mut self) -> &Frame {
+        let end = self.cpu.cycles + self.region.cycles_per_frame();
+        while self.cpu.cycles < end {
+            // a halted game keeps showing its last frame
+            if let Some(Stop::Brk) = self.cpu.run_for(1) {
+                break;
+            }
+        }
+        &self.cpu.frame
+    }
+}
+
+// FNV-1a over the little-endian pixels, stable across platforms and releases
+pub fn frame_hash(frame: &Frame) -> u64 {
+    frame_bytes(frame).iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+fn frame_bytes(frame: &Frame) -> Vec<u8> {
+    frame.data.iter().flat_map(|pixel| pixel.to_le_bytes()).collect()
+}
+
+// Controller state changes keyed by frame, one per line:
+//
+//   # frame port buttons
+//   60 0 START
+//   62 0 -
+//   90 1 A+RIGHT
+//
+// Buttons hold until the next change on the same port, `-` releases all.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InputScript {
+    changes: BTreeMap<usize, Vec<(usize, u8)>>,
+}
+
+pub fn parse_buttons(text: &str) -> Result<u8, String> {
+    if text == "-" {
+        return Ok(0);
+    }
+    text.split('+').try_fold(0u8, |buttons, name| {
+        BUTTONS
+            .iter()
+            .find(|(button, _)| button.eq_ignore_ascii_case(name))
+            .map(|(_, bit)| buttons | bit)
+            .ok_or(format!("unknown button '{}'", name))
+    })
+}
+
+impl InputScript {
+    pub fn parse(text: &str) -> Result<InputScript, String> {
+        let mut script = InputScript::default();
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [frame, port, buttons] = fields[..] else {
+                return Err(format!("line {}: expected 'frame port buttons'", n + 1));
+            };
+            let frame = frame.parse().map_err(|_| format!("line {}: invalid frame '{}'", n + 1, frame))?;
+            let port = port.parse().map_err(|_| format!("line {}: invalid port '{}'", n + 1, port))?;
+            let buttons = parse_buttons(buttons).map_err(|e| format!("line {}: {}", n + 1, e))?;
+            script.press(frame, port, buttons);
+        }
+
+        Ok(script)
+    }
+
+    pub fn press(&mut self, frame: usize, port: usize, buttons: u8) {
+        self.changes.entry(frame).or_default().push((port, buttons));
+    }
+
+    pub fn changes_at(&self, frame: usize) -> &[(usize, u8)] {
+        self.changes.get(&frame).map_or(&[], Vec::as_slice)
+    }
+}
+
+// Runs `frames` frames feeding the script and calls `visit` with each one.
+pub fn play<S: FrameSource>(source: &mut S, script: &InputScript, frames: usize, mut visit: impl FnMut(usize, &Frame)) {
+    for n in 0..frames {
+        for (port, buttons) in script.changes_at(n) {
+            source.set_buttons(*port, *buttons);
+        }
+        visit(n, source.run_frame());
+    }
+}
+
+pub fn hashes<S: FrameSource>(source: &mut S, script: &InputScript, frames: usize) -> Vec<u64> {
+    let mut hashes = Vec::with_capacity(frames);
+    play(source, script, frames, |_, frame| hashes.push(frame_hash(frame)));
+    hashes
+}
+
+pub fn format_golden(hashes: &[u64]) -> String {
+    let mut out = String::from("# frame hash\n");
+    for (n, hash) in hashes.iter().enumerate() {
+        out.push_str(&format!("{} {:016x}\n", n, hash));
+    }
+    out
+}
+
+pub fn parse_golden(text: &str) -> Result<Vec<u64>, String> {
+    let mut hashes = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (frame, hash) = line.split_once(' ').ok_or(format!("line {}: expected 'frame hash'", n + 1))?;
+        if frame.parse::<usize>() != Ok(hashes.len()) {
+            return Err(format!("line {}: expected frame {}", n + 1, hashes.len()));
+        }
+        hashes.push(u64::from_str_radix(hash.trim(), 16).map_err(|_| format!("line {}: invalid hash", n + 1))?);
+    }
+    Ok(hashes)
+}
+
+// Every distinct frame of a run stacked top to bottom in a 16-bit grayscale
+// PNG, so the exact pixel values survive. Runs repeat frames a lot and
+// NES pictures compress well, small enough to commit next to the hashes.
+pub fn write_reference<W: Write>(frames: &[Frame], out: W) -> io::Result<()> {
+    let mut seen = HashSet::new();
+    let unique: Vec<&Frame> = frames.iter().filter(|frame| seen.insert(frame_hash(frame))).collect();
+    let mut encoder = png::Encoder::new(out, Frame::WIDTH as u32, (Frame::HEIGHT * unique.len()) as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Sixteen);
+    let mut writer = encoder.write_header().map_err(io::Error::other)?;
+    let data: Vec<u8> = unique.iter().flat_map(|frame| frame.data.iter().flat_map(|pixel| pixel.to_be_bytes())).collect();
+    writer.write_image_data(&data).map_err(io::Error::other)?;
+    writer.finish().map_err(io::Error::other)
+}
+
+pub fn read_reference<R: Read>(input: R) -> Result<Vec<Frame>, String> {
+    let mut reader = png::Decoder::new(input).read_info().map_err(|e| e.to_string())?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).map_err(|e| e.to_string())?;
+    if info.width as usize != Frame::WIDTH
+        || !(info.height as usize).is_multiple_of(Frame::HEIGHT)
+        || info.color_type != png::ColorType::Grayscale
+        || info.bit_depth != png::BitDepth::Sixteen
+    {
+        return Err(String::from("not a strip of reference frames"));
+    }
+    let pixels: Vec<u16> =
+        buf[..info.buffer_size()].chunks_exact(2).map(|pixel| u16::from_be_bytes([pixel[0], pixel[1]])).collect();
+    Ok(pixels.chunks(Frame::WIDTH * Frame::HEIGHT).map(|data| Frame { data: data.to_vec() }).collect())
+}
+
+// Unchanged pixels are dimmed, changed ones are drawn in red.
+pub fn diff_image(expected: &Frame, actual: &Frame, palette: &Palette) -> Image {
+    let mut rgb = Vec::with_capacity(Frame::WIDTH * Frame::HEIGHT * 3);
+    for (old, new) in expected.data.iter().zip(&actual.data) {
+        if old == new {
+            let (r, g, b) = palette.rgb_pixel(*old);
+            let luma = ((r as u16 * 3 + g as u16 * 6 + b as u16) / 10) as u8;
+            rgb.extend_from_slice(&[luma / 3; 3]);
+        } else {
+            rgb.extend_from_slice(&[0xff, 0x00, 0x00]);
+        }
+    }
+    Image {
+        width: Frame::WIDTH,
+        height: Frame::HEIGHT,
+        rgb,
+    }
+}
+
+pub struct RegressionTest {
+    pub name: String,
+    pub frames: usize,
+    pub script: InputScript,
+    // holds the committed `<name>.hashes` and `<name>.frames.png` files
+    pub golden_dir: PathBuf,
+    // where mismatch images go
+    pub output_dir: PathBuf,
+    pub palette: Palette,
+}
+
+impl RegressionTest {
+    pub fn new(name: &str, frames: usize, golden_dir: &Path, output_dir: &Path) -> Self {
+        RegressionTest {
+            name: name.to_string(),
+            frames,
+            script: InputScript::default(),
+            golden_dir: golden_dir.to_path_buf(),
+            output_dir: output_dir.to_path_buf(),
+            palette: Palette::default(),
+        }
+    }
+
+    fn golden_path(&self) -> PathBuf {
+        self.golden_dir.join(format!("{}.hashes", self.name))
+    }
+
+    fn reference_path(&self) -> PathBuf {
+        self.golden_dir.join(format!("{}.frames.png", self.name))
+    }
+
+    // the committed frame with this hash
+    fn reference_frame(&self, hash: u64) -> Result<Frame, String> {
+        let path = self.reference_path();
+        let file = File::open(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        read_reference(BufReader::new(file))
+            .map_err(|e| format!("{}: {}", path.display(), e))?
+            .into_iter()
+            .find(|frame| frame_hash(frame) == hash)
+            .ok_or(format!("{}: no frame with hash {:016x}", path.display(), hash))
+    }
+
+    fn write_mismatch(&self, n: usize, expected_hash: u64, actual: &Frame) -> Result<Vec<PathBuf>, String> {
+        let dir = self.output_dir.join(&self.name);
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        let expected = self.reference_frame(expected_hash)?;
+
+        let actual_path = dir.join(format!("{}-actual.png", n));
+        let expected_path = dir.join(format!("{}-expected.png", n));
+        let diff_path = dir.join(format!("{}-diff.png", n));
+        screenshot::render(actual, &self.palette, 1).save(&actual_path).map_err(|e| e.to_string())?;
+        screenshot::render(&expected, &self.palette, 1).save(&expected_path).map_err(|e| e.to_string())?;
+        diff_image(&expected, actual, &self.palette).save(&diff_path).map_err(|e| e.to_string())?;
+        Ok(vec![actual_path, expected_path, diff_path])
+    }
+
+    fn write_golden(&self, frames: &[Frame]) -> io::Result<()> {
+        fs::create_dir_all(&self.golden_dir)?;
+        let hashes: Vec<u64> = frames.iter().map(frame_hash).collect();
+        fs::write(self.golden_path(), format_golden(&hashes))?;
+        let mut out = BufWriter::new(File::create(self.reference_path())?);
+        write_reference(frames, &mut out)?;
+        out.flush()
+    }
+
+    // Runs the test and writes its hashes and reference frames.
+    pub fn bless<S: FrameSource>(&self, source: &mut S) -> Result<(), String> {
+        let mut frames = Vec::with_capacity(self.frames);
+        play(source, &self.script, self.frames, |_, frame| frames.push(frame.clone()));
+        self.write_golden(&frames).map_err(|e| e.to_string())
+    }
+
+    // Compares every frame against the golden hashes, or rewrites them when
+    // NES_BLESS is set. On mismatch the first differing frame is written out.
+    pub fn check<S: FrameSource>(&self, source: &mut S) -> Result<(), String> {
+        let mut frames = Vec::with_capacity(self.frames);
+        play(source, &self.script, self.frames, |_, frame| frames.push(frame.clone()));
+        let actual: Vec<u64> = frames.iter().map(frame_hash).collect();
+
+        if env::var_os(BLESS_VAR).is_some() {
+            return self.write_golden(&frames).map_err(|e| e.to_string());
+        }
+
+        let golden = fs::read_to_string(self.golden_path())
+            .map_err(|e| format!("{}: {} (run with {}=1 to create it)", self.golden_path().display(), e, BLESS_VAR))?;
+        let expected = parse_golden(&golden)?;
+
+        if expected.len() != actual.len() {
+            return Err(format!(
+                "{}: golden file has {} frames, ran {}",
+                self.name,
+                expected.len(),
+                actual.len()
+            ));
+        }
+
+        match (0..actual.len()).find(|n| actual[*n] != expected[*n]) {
+            None => Ok(()),
+            Some(n) => {
+                let written = self.write_mismatch(n, expected[n], &frames[n])?;
+                let written: Vec<String> = written.iter().map(|path| path.display().to_string()).collect();
+                Err(format!(
+                    "{}: frame {} hash {:016x}, expected {:016x}; wrote {}",
+                    self.name,
+                    n,
+                    actual[n],
+                    expected[n],
+                    written.join(", ")
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::test::test_rom;
+
+    // draws a bar whose position follows the number of frames with A held
+    struct Fake {
+        buttons: u8,
+        position: usize,
+        broken: bool,
+        frame: Frame,
+    }
+
+    impl Fake {
+        fn new(broken: bool) -> Self {
+            Fake {
+                buttons: 0,
+                position: 0,
+                broken,
+                frame: Frame::new(),
+            }
+        }
+    }
+
+    impl FrameSource for Fake {
+        fn set_buttons(&mut self, _port: usize, buttons: u8) {
+            self.buttons = buttons;
+        }
+
+        fn run_frame(&mut self) -> &Frame {
+            if self.buttons & 0b0000_0001 != 0 {
+                self.position += 1;
+            }
+            self.frame = Frame::new();
+            for y in 0..Frame::HEIGHT {
+                self.frame.set_pixel(self.position, y, 0x30);
+            }
+            if self.broken && self.position > 2 {
+                self.frame.set_pixel(100, 100, 0x16);
+            }
+            &self.frame
+        }
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("nes-regression-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_parse_script() {
+        let script = InputScript::parse("# comment\n1 0 A+start\n3 0 -\n3 1 RIGHT\n").unwrap();
+        assert_eq!(script.changes_at(1), &[(0, 0b0000_1001)]);
+        assert_eq!(script.changes_at(3), &[(0, 0), (1, 0b1000_0000)]);
+        assert!(script.changes_at(2).is_empty());
+
+        assert!(InputScript::parse("1 0 TURBO").is_err());
+        assert!(InputScript::parse("1 A").is_err());
+    }
+
+    #[test]
+    fn test_hash_is_stable_and_sensitive() {
+        let mut frame = Frame::new();
+        let blank = frame_hash(&frame);
+        assert_eq!(blank, frame_hash(&Frame::new()));
+
+        frame.set_pixel(10, 10, 1);
+        assert_ne!(frame_hash(&frame), blank);
+    }
+
+    #[test]
+    fn test_golden_round_trip() {
+        let hashes = vec![1, 0xdead_beef, u64::MAX];
+        assert_eq!(parse_golden(&format_golden(&hashes)).unwrap(), hashes);
+        assert!(parse_golden("1 00\n").is_err());
+    }
+
+    #[test]
+    fn test_check_writes_mismatch_images() {
+        let dir = scratch("check");
+        let mut test = RegressionTest::new("bar", 6, &dir.join("golden"), &dir.join("out"));
+        test.script = InputScript::parse("1 0 A\n").unwrap();
+
+        test.bless(&mut Fake::new(false)).unwrap();
+        let reference = read_reference(File::open(test.reference_path()).unwrap()).unwrap();
+        // the bar stands still on frame 0, then moves every frame
+        assert_eq!(reference.len(), 6);
+
+        // a fresh checkout has nothing but the golden files
+        test.check(&mut Fake::new(false)).unwrap();
+        assert!(!test.output_dir.exists());
+
+        let error = test.check(&mut Fake::new(true)).unwrap_err();
+        assert!(error.contains("frame 3"), "{}", error);
+        let out = test.output_dir.join("bar");
+        assert!(out.join("3-actual.png").exists());
+        assert!(out.join("3-expected.png").exists());
+        assert!(out.join("3-diff.png").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rom_source_runs_frames() {
+        // LDA $4016, JMP $8000
+        let rom = Rom::new(&test_rom(&[0xad, 0x16, 0x40, 0x4c, 0x00, 0x80])).unwrap();
+        let mut source = RomSource::new(&rom).unwrap();
+        let script = InputScript::parse("1 0 A\n").unwrap();
+        assert_eq!(hashes(&mut source, &script, 3).len(), 3);
+        assert!(source.cpu.cycles >= 3 * source.region.cycles_per_frame());
+        assert_eq!(source.cpu.joypads[0].button_status, 0b0000_0001);
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::VecDeque;
+
+use crate::cpu::CPU;
+use crate::savestate;
+
+// Ring buffer of save states. Only the newest snapshot is kept whole,
+// each older one is stored as a delta that rebuilds it from the snapshot
+// recorded after it, so stepping back walks the chain newest first.
+pub struct Rewind {
+    interval: usize,
+    capacity: usize,
+    frames: usize,
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    // keeps up to `capacity` snapshots, one every `interval` frames
+    pub fn new(capacity: usize, interval: usize) -> Self {
+        assert!(capacity > 0, "rewind capacity must be at least 1");
+        assert!(interval > 0, "rewind interval must be at least 1");
+        Rewind {
+            interval,
+            capacity,
+            frames: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    // call once per emulated frame
+    pub fn record(&mut self, cpu: &CPU) {
+        if self.frames.is_multiple_of(self.interval) {
+            self.push(savestate::save(cpu));
+        }
+        self.frames += 1;
+    }
+
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        if let Some(previous) = self.latest.take() {
+            self.deltas.push_back(encode(&snapshot, &previous));
+            if self.deltas.len() >= self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+        self.latest = Some(snapshot);
+    }
+
+    // restores the newest snapshot and drops it, false once nothing is left.
+    // A snapshot of the state `cpu` is already in, as when recording at the
+    // end of a frame, is skipped so every step goes back in time.
+    pub fn step_back(&mut self, cpu: &mut CPU) -> Result<bool, String> {
+        if self.latest.as_ref() == Some(&savestate::save(cpu)) {
+            self.pop();
+        }
+        let snapshot = match self.pop() {
+            Some(snapshot) => snapshot,
+            None => return Ok(false),
+        };
+        savestate::restore(cpu, &snapshot)?;
+        self.frames = 0;
+        Ok(true)
+    }
+
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let snapshot = self.latest.take()?;
+        if let Some(delta) = self.deltas.pop_back() {
+            self.latest = Some(decode(&snapshot, &delta));
+        }
+        Some(snapshot)
+    }
+
+    pub fn len(&self) -> usize {
+        self.latest.iter().count() + self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.frames = 0;
+    }
+
+    // bytes held by stored snapshots and deltas
+    pub fn memory_usage(&self) -> usize {
+        self.latest.as_ref().map_or(0, Vec::len) + self.deltas.iter().map(Vec::len).sum::<usize>()
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+// Encodes `target` against `base` as the target length followed by runs of
+// (unchanged count, changed count, target xor base bytes).
+fn encode(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_varint(&mut out, target.len());
+
+    let byte_at = |data: &[u8], i: usize| data.get(i).copied().unwrap_or(0);
+    let mut i = 0;
+    while i < target.len() {
+        let start = i;
+        while i < target.len() && target[i] == byte_at(base, i) {
+            i += 1;
+        }
+        let same = i - start;
+
+        let start = i;
+        while i < target.len() && target[i] != byte_at(base, i) {
+            i += 1;
+        }
+        write_varint(&mut out, same);
+        write_varint(&mut out, i - start);
+        out.extend((start..i).map(|j| target[j] ^ byte_at(base, j)));
+    }
+
+    out
+}
+
+fn decode(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let len = read_varint(delta, &mut pos);
+    let mut out: Vec<u8> = (0..len).map(|i| base.get(i).copied().unwrap_or(0)).collect();
+
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos);
+        let changed = read_varint(delta, &mut pos);
+        for byte in &mut out[i..i + changed] {
+            *byte ^= delta[pos];
+            pos += 1;
+        }
+        i += changed;
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_delta_round_trip() {
+        let base = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        for target in [vec![1, 2, 9, 4, 5, 6, 0, 0], vec![1, 2, 3], vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10], vec![]] {
+            assert_eq!(decode(&base, &encode(&base, &target)), target);
+        }
+    }
+
+    #[test]
+    fn test_step_back_restores_snapshots_in_reverse() {
+        let mut cpu = CPU::new();
+        // INX, JMP $8000
+        cpu.load(vec![0xe8, 0x4c, 0x00, 0x80]);
+        cpu.reset();
+
+        let mut rewind = Rewind::new(8, 2);
+        let mut recorded = Vec::new();
+        for frame in 0..10 {
+            if frame % 2 == 0 {
+                recorded.push(cpu.register_x);
+            }
+            rewind.record(&cpu);
+            cpu.run_for(2);
+        }
+        assert_eq!(rewind.len(), 5);
+
+        while let Some(x) = recorded.pop() {
+            assert!(rewind.step_back(&mut cpu).unwrap());
+            assert_eq!(cpu.register_x, x);
+        }
+        assert!(!rewind.step_back(&mut cpu).unwrap());
+    }
+
+    #[test]
+    fn test_step_back_skips_current_state() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xe8, 0x4c, 0x00, 0x80]);
+        cpu.reset();
+
+        // recording after each frame, the newest snapshot is where the CPU is
+        let mut rewind = Rewind::new(8, 1);
+        for _ in 0..4 {
+            cpu.run_for(2);
+            rewind.record(&cpu);
+        }
+        assert_eq!(cpu.register_x, 4);
+
+        assert!(rewind.step_back(&mut cpu).unwrap());
+        assert_eq!(cpu.register_x, 3);
+        assert!(rewind.step_back(&mut cpu).unwrap());
+        assert_eq!(cpu.register_x, 2);
+    }
+
+    #[test]
+    fn test_capacity_drops_oldest_and_deltas_stay_small() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xe8, 0x4c, 0x00, 0x80]);
+        cpu.reset();
+
+        let mut rewind = Rewind::new(3, 1);
+        for _ in 0..10 {
+            rewind.record(&cpu);
+            cpu.run_for(2);
+        }
+
+        assert_eq!(rewind.len(), 3);
+        assert!(rewind.memory_usage() < 2 * savestate::save(&cpu).len());
+
+        for x in [9, 8, 7] {
+            assert!(rewind.step_back(&mut cpu).unwrap());
+            assert_eq!(cpu.register_x, x);
+        }
+        assert!(rewind.is_empty());
+    }
+}
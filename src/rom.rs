@@ -0,0 +1,217 @@
+use crate::region::Region;
+
+const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+const PRG_ROM_PAGE_SIZE: usize = 16384;
+const CHR_ROM_PAGE_SIZE: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirroring {
+    Vertical,
+    Horizontal,
+    FourScreen,
+    // only set by mappers
+    OneScreenLower,
+    OneScreenUpper,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rom {
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    pub mapper: u8,
+    // NES 2.0 byte 8, tells apart boards that share a mapper number
+    pub submapper: u8,
+    pub screen_mirroring: Mirroring,
+    // PRG RAM is kept alive by a battery
+    pub battery: bool,
+    // from the header, NTSC when it doesn't say
+    pub region: Region,
+}
+
+// NES 2.0 byte 12, iNES 1.0 only has a PAL flag in byte 9 that few dumps set
+fn header_region(raw: &[u8], nes2: bool) -> Region {
+    if nes2 {
+        match raw[12] & 0b11 {
+            1 => Region::Pal,
+            3 => Region::Dendy,
+            // 2 is multi-region, those run fine as NTSC
+            _ => Region::Ntsc,
+        }
+    } else if raw[9] & 1 != 0 {
+        Region::Pal
+    } else {
+        Region::Ntsc
+    }
+}
+
+impl Rom {
+    pub fn new(raw: &[u8]) -> Result<Rom, String> {
+        if raw.len() < 16 || raw[0..4] != NES_TAG {
+            return Err("File is not in iNES file format".to_string());
+        }
+
+        let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
+
+        let ines_ver = (raw[7] >> 2) & 0b11;
+        if ines_ver == 1 || ines_ver == 3 {
+            return Err("Unknown iNES format version".to_string());
+        }
+
+        let four_screen = raw[6] & 0b1000 != 0;
+        let vertical_mirroring = raw[6] & 0b1 != 0;
+        let screen_mirroring = match (four_screen, vertical_mirroring) {
+            (true, _) => Mirroring::FourScreen,
+            (false, true) => Mirroring::Vertical,
+            (false, false) => Mirroring::Horizontal,
+        };
+
+        let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
+        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+
+        let skip_trainer = raw[6] & 0b100 != 0;
+
+        let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
+        let chr_rom_start = prg_rom_start + prg_rom_size;
+
+        if raw.len() < chr_rom_start + chr_rom_size {
+            return Err("iNES file is truncated".to_string());
+        }
+
+        Ok(Rom {
+            prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
+            chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
+            mapper,
+            submapper: if ines_ver == 2 { raw[8] >> 4 } else { 0 },
+            screen_mirroring,
+            battery: raw[6] & 0b10 != 0,
+            region: header_region(raw, ines_ver == 2),
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    pub struct TestRom {
+        pub header: Vec<u8>,
+        pub trainer: Option<Vec<u8>>,
+        pub prg_rom: Vec<u8>,
+        pub chr_rom: Vec<u8>,
+    }
+
+    pub fn create_rom(rom: TestRom) -> Vec<u8> {
+        let mut result = Vec::with_capacity(
+            rom.header.len()
+                + rom.trainer.as_ref().map_or(0, |t| t.len())
+                + rom.prg_rom.len()
+                + rom.chr_rom.len(),
+        );
+
+        result.extend(&rom.header);
+        if let Some(t) = rom.trainer {
+            result.extend(t);
+        }
+        result.extend(&rom.prg_rom);
+        result.extend(&rom.chr_rom);
+
+        result
+    }
+
+    // NROM image with the program at $8000 and the reset vector pointing at it
+    pub fn test_rom(program: &[u8]) -> Vec<u8> {
+        let mut prg_rom = vec![0; PRG_ROM_PAGE_SIZE];
+        prg_rom[..program.len()].copy_from_slice(program);
+        prg_rom[0x3ffc] = 0x00;
+        prg_rom[0x3ffd] = 0x80;
+
+        create_rom(TestRom {
+            header: vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00],
+            trainer: None,
+            prg_rom,
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        })
+    }
+
+    #[test]
+    fn test() {
+        let test_rom = create_rom(TestRom {
+            header: vec![0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00],
+            trainer: None,
+            prg_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom: Rom = Rom::new(&test_rom).unwrap();
+
+        assert_eq!(rom.chr_rom, vec!(2; CHR_ROM_PAGE_SIZE));
+        assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+        assert!(!rom.battery);
+    }
+
+    #[test]
+    fn test_with_trainer() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31 | 0b100, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: Some(vec![0; 512]),
+            prg_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom: Rom = Rom::new(&test_rom).unwrap();
+
+        assert_eq!(rom.chr_rom, vec!(2; CHR_ROM_PAGE_SIZE));
+        assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+    }
+
+    #[test]
+    fn test_battery_flag() {
+        let mut raw = test_rom(&[]);
+        assert!(!Rom::new(&raw).unwrap().battery);
+        raw[6] |= 0b10;
+        assert!(Rom::new(&raw).unwrap().battery);
+    }
+
+    #[test]
+    fn test_region_from_header() {
+        let mut raw = test_rom(&[]);
+        assert_eq!(Rom::new(&raw).unwrap().region, Region::Ntsc);
+
+        raw[9] = 1;
+        assert_eq!(Rom::new(&raw).unwrap().region, Region::Pal);
+
+        raw[9] = 0;
+        raw[7] = 0b1000;
+        raw[12] = 3;
+        assert_eq!(Rom::new(&raw).unwrap().region, Region::Dendy);
+        raw[12] = 2;
+        assert_eq!(Rom::new(&raw).unwrap().region, Region::Ntsc);
+    }
+
+    #[test]
+    fn test_submapper_needs_nes2() {
+        let mut raw = test_rom(&[]);
+        raw[8] = 0x20;
+        assert_eq!(Rom::new(&raw).unwrap().submapper, 0);
+        raw[7] = 0b1000;
+        assert_eq!(Rom::new(&raw).unwrap().submapper, 2);
+    }
+
+    #[test]
+    fn test_invalid_and_truncated() {
+        assert!(Rom::new(&[0x4E, 0x45, 0x53]).is_err());
+
+        let mut raw = test_rom(&[]);
+        raw[0] = b'X';
+        assert!(Rom::new(&raw).is_err());
+
+        let raw = test_rom(&[]);
+        assert!(Rom::new(&raw[..raw.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,344 @@
+use crate::cpu::CPU;
+use crate::mapper::{StateReader, StateWriter};
+
+// Layout, all integers little endian:
+//
+//   magic "NESS" | version u16 | program crc32 u32
+//   then sections of: tag [u8; 4] | length u32 | data
+//
+// Every section a version knows about must be present exactly once, MAPR
+// (the cartridge board's own state) only when the program uses a mapper.
+// INPT holds the controllers' strobe and shift state, so a state taken in
+// the middle of reading them carries on where it left off.
+//
+// There is no PPU or APU yet, so their state isn't part of the format; it
+// will need a new version when they exist.
+const MAGIC: &[u8; 4] = b"NESS";
+pub const VERSION: u16 = 2;
+
+const CPU_TAG: &[u8; 4] = b"CPU ";
+const RAM_TAG: &[u8; 4] = b"RAM ";
+const MAPPER_TAG: &[u8; 4] = b"MAPR";
+const INPUT_TAG: &[u8; 4] = b"INPT";
+
+const CPU_SECTION_LEN: usize = 15;
+const RAM_SECTION_LEN: usize = 0x10000;
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn push_section(out: &mut Vec<u8>, tag: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(tag);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+pub fn save(cpu: &CPU) -> Vec<u8> {
+    let mut out = Vec::with_capacity(10 + 2 * 8 + CPU_SECTION_LEN + RAM_SECTION_LEN);
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&cpu.program_checksum().to_le_bytes());
+
+    let mut registers = vec![
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.processor_status,
+        cpu.stack_pointer,
+    ];
+    registers.extend_from_slice(&cpu.program_counter.to_le_bytes());
+    registers.extend_from_slice(&(cpu.cycles as u64).to_le_bytes());
+    push_section(&mut out, CPU_TAG, &registers);
+
+    push_section(&mut out, RAM_TAG, &cpu.memory);
+    push_section(&mut out, INPUT_TAG, &save_input(cpu));
+
+    if let Some(mapper) = cpu.mapper.as_ref() {
+        push_section(&mut out, MAPPER_TAG, &mapper.save_state());
+    }
+
+    out
+}
+
+// the four joypads, the Four Score, then whether each of port 1, port 2
+// and the expansion port has a device and its state
+fn save_input(cpu: &CPU) -> Vec<u8> {
+    let mut state = StateWriter::default();
+    for joypad in &cpu.joypads {
+        joypad.save(&mut state);
+    }
+    cpu.four_score.save(&mut state);
+    for device in cpu.port_devices.iter().chain([&cpu.expansion]) {
+        state.bool(device.is_some());
+        if let Some(device) = device {
+            let data = device.save_state();
+            state.u32(data.len() as u32);
+            state.bytes(&data);
+        }
+    }
+    state.data
+}
+
+// devices may be left half restored on error, `restore` puts back what it
+// saved before
+fn load_input(cpu: &mut CPU, data: &[u8]) -> Result<(), String> {
+    let mut state = StateReader::new(data);
+    let mut joypads = cpu.joypads.clone();
+    for joypad in joypads.iter_mut() {
+        joypad.load(&mut state)?;
+    }
+    let mut four_score = cpu.four_score.clone();
+    four_score.load(&mut state)?;
+
+    let mut devices = Vec::new();
+    for (name, device) in ["port 1", "port 2", "the expansion port"]
+        .iter()
+        .zip(cpu.port_devices.iter().chain([&cpu.expansion]))
+    {
+        let plugged = state.bool()?;
+        if plugged != device.is_some() {
+            return Err(format!(
+                "save state has {} device on {}",
+                if plugged { "a" } else { "no" },
+                name
+            ));
+        }
+        if plugged {
+            let len = state.u32()? as usize;
+            devices.push(Some(state.bytes(len)?));
+        } else {
+            devices.push(None);
+        }
+    }
+    state.finish()?;
+
+    for (device, data) in cpu.port_devices.iter_mut().chain([&mut cpu.expansion]).zip(devices) {
+        if let (Some(device), Some(data)) = (device, data) {
+            device.load_state(data)?;
+        }
+    }
+    cpu.joypads = joypads;
+    cpu.four_score = four_score;
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < len {
+            return Err(String::from("save state is truncated"));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn done(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+// Restores a state produced by `save`. Nothing is modified unless the
+// whole state is valid and was taken from the program loaded in `cpu`.
+pub fn restore(cpu: &mut CPU, data: &[u8]) -> Result<(), String> {
+    let mut reader = Reader { data, pos: 0 };
+
+    if reader.take(4)? != MAGIC {
+        return Err(String::from("not a save state"));
+    }
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(format!("unsupported save state version {}", version));
+    }
+    let crc = reader.u32()?;
+    if crc != cpu.program_checksum() {
+        return Err(format!(
+            "save state belongs to another program (crc {:08x}, loaded {:08x})",
+            crc,
+            cpu.program_checksum()
+        ));
+    }
+
+    let mut registers = None;
+    let mut ram = None;
+    let mut mapper = None;
+    let mut input = None;
+    while !reader.done() {
+        let tag = reader.take(4)?;
+        let len = reader.u32()? as usize;
+        let section = reader.take(len)?;
+
+        let (slot, expected_len) = match tag {
+            t if t == CPU_TAG => (&mut registers, Some(CPU_SECTION_LEN)),
+            t if t == RAM_TAG => (&mut ram, Some(RAM_SECTION_LEN)),
+            t if t == MAPPER_TAG && cpu.mapper.is_some() => (&mut mapper, None),
+            t if t == INPUT_TAG => (&mut input, None),
+            _ => return Err(format!("unknown section {:?}", String::from_utf8_lossy(tag))),
+        };
+        if expected_len.is_some_and(|expected_len| len != expected_len) {
+            return Err(format!("section {:?} has length {}", String::from_utf8_lossy(tag), len));
+        }
+        if slot.replace(section).is_some() {
+            return Err(format!("duplicate section {:?}", String::from_utf8_lossy(tag)));
+        }
+    }
+
+    let registers = registers.ok_or("save state has no CPU section")?;
+    let ram = ram.ok_or("save state has no RAM section")?;
+    let input = input.ok_or("save state has no input section")?;
+    let mapper = match cpu.mapper {
+        Some(_) => Some(mapper.ok_or("save state has no mapper section")?),
+        None => None,
+    };
+
+    let backup = save_input(cpu);
+    let result = load_input(cpu, input).and_then(|()| match (cpu.mapper.as_mut(), mapper) {
+        (Some(board), Some(mapper)) => board.load_state(mapper),
+        _ => Ok(()),
+    });
+    if let Err(e) = result {
+        // what was just saved always loads
+        let _ = load_input(cpu, &backup);
+        return Err(e);
+    }
+
+    cpu.register_a = registers[0];
+    cpu.register_x = registers[1];
+    cpu.register_y = registers[2];
+    cpu.processor_status = registers[3];
+    cpu.stack_pointer = registers[4];
+    cpu.program_counter = u16::from_le_bytes([registers[5], registers[6]]);
+    let mut cycles = [0u8; 8];
+    cycles.copy_from_slice(&registers[7..]);
+    cpu.cycles = u64::from_le_bytes(cycles) as usize;
+    cpu.memory.copy_from_slice(ram);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::Memory;
+    use crate::input::Connector;
+    use crate::power_pad::PowerPad;
+
+    /*
+        LDX #$00
+    loop:
+        INX
+        STX $10
+        CPX #$40
+        BNE loop
+        BRK
+    */
+    const PROGRAM: [u8; 10] = [0xa2, 0x00, 0xe8, 0x86, 0x10, 0xe0, 0x40, 0xd0, 0xf9, 0x00];
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_restore_continues_deterministically() {
+        let mut cpu = CPU::new();
+        cpu.load(PROGRAM.to_vec());
+        cpu.reset();
+        for _ in 0..20 {
+            cpu.step();
+        }
+        let state = save(&cpu);
+
+        cpu.execute();
+        let (x, cycles, value) = (cpu.register_x, cpu.cycles, cpu.memory_read(0x10));
+
+        let mut other = CPU::new();
+        other.load(PROGRAM.to_vec());
+        restore(&mut other, &state).unwrap();
+        assert_eq!(save(&other), state);
+
+        other.execute();
+        assert_eq!((other.register_x, other.cycles, other.memory_read(0x10)), (x, cycles, value));
+    }
+
+    #[test]
+    fn test_restore_mid_controller_read() {
+        let mut cpu = CPU::new();
+        cpu.load(PROGRAM.to_vec());
+        cpu.plug(Connector::Port2, Box::new(PowerPad::new()));
+        cpu.joypads[0].set_buttons(0b1010_0110);
+        cpu.input_device_mut::<PowerPad>().unwrap().set_button(2, true).unwrap();
+        cpu.memory_write(0x4016, 1);
+        cpu.memory_write(0x4016, 0);
+        cpu.input_device_mut::<PowerPad>().unwrap().set_button(2, false).unwrap();
+        for _ in 0..3 {
+            cpu.memory_read(0x4016);
+        }
+        let state = save(&cpu);
+        let reads = |cpu: &CPU| (0..6).map(|_| (cpu.memory_read(0x4016), cpu.memory_read(0x4017))).collect::<Vec<_>>();
+        let expected = reads(&cpu);
+
+        let mut other = CPU::new();
+        other.load(PROGRAM.to_vec());
+        assert!(restore(&mut other, &state).is_err());
+        other.plug(Connector::Port2, Box::new(PowerPad::new()));
+        restore(&mut other, &state).unwrap();
+        assert_eq!(reads(&other), expected);
+    }
+
+    #[test]
+    fn test_restore_rejects_other_program() {
+        let mut cpu = CPU::new();
+        cpu.load(PROGRAM.to_vec());
+        let state = save(&cpu);
+
+        let mut other = CPU::new();
+        other.load(vec![0xa9, 0x01, 0x00]);
+        other.reset();
+        let pc = other.program_counter;
+
+        assert!(restore(&mut other, &state).is_err());
+        assert_eq!(other.program_counter, pc);
+    }
+
+    #[test]
+    fn test_restore_rejects_corrupt_state() {
+        let mut cpu = CPU::new();
+        cpu.load(PROGRAM.to_vec());
+        let state = save(&cpu);
+
+        assert!(restore(&mut cpu, &state[..state.len() - 1]).is_err());
+        assert!(restore(&mut cpu, &state[..10]).is_err());
+
+        let mut bad_version = state.clone();
+        bad_version[4] = 0xff;
+        assert!(restore(&mut cpu, &bad_version).is_err());
+
+        let mut bad_magic = state;
+        bad_magic[0] = b'X';
+        assert!(restore(&mut cpu, &bad_magic).is_err());
+    }
+}
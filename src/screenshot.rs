@@ -0,0 +1,171 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::frame::Frame;
+use crate::palette::Palette;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub rgb: Vec<u8>,
+}
+
+// the headless runner's final frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScreenshotOptions {
+    pub path: PathBuf,
+    pub scale: usize,
+    pub palette: Palette,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Png,
+    Ppm,
+}
+
+impl Format {
+    // .ppm selects PPM, anything else PNG
+    pub fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("ppm") => Format::Ppm,
+            _ => Format::Png,
+        }
+    }
+}
+
+// Converts a frame to RGB, each pixel becomes a `scale` x `scale` block.
+pub fn render(frame: &Frame, palette: &Palette, scale: usize) -> Image {
+    let scale = scale.max(1);
+    let width = Frame::WIDTH * scale;
+    let height = Frame::HEIGHT * scale;
+    let mut rgb = Vec::with_capacity(width * height * 3);
+
+    for y in 0..Frame::HEIGHT {
+        let mut row = Vec::with_capacity(width * 3);
+        for x in 0..Frame::WIDTH {
+            let (r, g, b) = palette.rgb_pixel(frame.pixel(x, y));
+            for _ in 0..scale {
+                row.extend_from_slice(&[r, g, b]);
+            }
+        }
+        for _ in 0..scale {
+            rgb.extend_from_slice(&row);
+        }
+    }
+
+    Image { width, height, rgb }
+}
+
+impl Image {
+    pub fn write_ppm<W: Write>(&self, mut out: W) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        out.write_all(&self.rgb)?;
+        out.flush()
+    }
+
+    pub fn write_png<W: Write>(&self, out: W) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(out, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(io::Error::other)?;
+        writer.write_image_data(&self.rgb).map_err(io::Error::other)?;
+        writer.finish().map_err(io::Error::other)
+    }
+
+    pub fn write<W: Write>(&self, out: W, format: Format) -> io::Result<()> {
+        match format {
+            Format::Png => self.write_png(out),
+            Format::Ppm => self.write_ppm(out),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let out = BufWriter::new(File::create(path)?);
+        self.write(out, Format::from_path(path))
+    }
+}
+
+pub fn save(path: &Path, frame: &Frame, palette: &Palette, scale: usize) -> io::Result<()> {
+    render(frame, palette, scale).save(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_frame() -> Frame {
+        let mut frame = Frame::new();
+        frame.set_pixel(0, 0, 0x30);
+        frame.set_pixel(255, 239, 0x16);
+        frame
+    }
+
+    #[test]
+    fn test_render_scales_pixels() {
+        let palette = Palette::default();
+        let image = render(&test_frame(), &palette, 2);
+
+        assert_eq!((image.width, image.height), (512, 480));
+        let white = [0xff, 0xff, 0xff];
+        assert_eq!(&image.rgb[0..3], &white);
+        assert_eq!(&image.rgb[3..6], &white);
+        assert_eq!(&image.rgb[512 * 3..512 * 3 + 3], &white);
+        assert_eq!(&image.rgb[6..9], &[0x80, 0x80, 0x80]);
+        let (r, g, b) = palette.rgb(0x16);
+        assert_eq!(&image.rgb[image.rgb.len() - 3..], &[r, g, b]);
+    }
+
+    #[test]
+    fn test_render_uses_given_palette() {
+        let palette = Palette::new([(1, 2, 3); 64]);
+        let image = render(&test_frame(), &palette, 1);
+        assert!(image.rgb.chunks(3).all(|pixel| pixel == [1, 2, 3]));
+    }
+
+    #[test]
+    fn test_render_applies_emphasis() {
+        let palette = Palette::default();
+        let mut frame = Frame::new();
+        frame.set_pixel(0, 0, 0x30 | 0b001 << 6);
+        let image = render(&frame, &palette, 1);
+
+        let (r, g, b) = palette.rgb_emphasized(0x30, 0b001);
+        assert_eq!(&image.rgb[0..3], &[r, g, b]);
+        assert!(g < 0xff);
+    }
+
+    #[test]
+    fn test_write_ppm() {
+        let image = render(&test_frame(), &Palette::default(), 1);
+        let mut out = Vec::new();
+        image.write_ppm(&mut out).unwrap();
+
+        let header = b"P6\n256 240\n255\n";
+        assert_eq!(&out[..header.len()], header);
+        assert_eq!(&out[header.len()..], &image.rgb[..]);
+    }
+
+    #[test]
+    fn test_write_png_round_trip() {
+        let image = render(&test_frame(), &Palette::default(), 1);
+        let mut out = Vec::new();
+        image.write_png(&mut out).unwrap();
+
+        let decoder = png::Decoder::new(&out[..]);
+        let mut reader = decoder.read_info().unwrap();
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).unwrap();
+        assert_eq!((info.width, info.height), (256, 240));
+        assert_eq!(&buf[..info.buffer_size()], &image.rgb[..]);
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(Format::from_path(Path::new("shot.PPM")), Format::Ppm);
+        assert_eq!(Format::from_path(Path::new("shot.png")), Format::Png);
+        assert_eq!(Format::from_path(Path::new("shot")), Format::Png);
+    }
+}
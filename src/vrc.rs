@@ -0,0 +1,436 @@
+use crate::mapper::{bank_offset, chr_memory, mirror_vram, mirroring_from_u8, Fetch, Mapper, StateReader, StateWriter};
+use crate::rom::{Mirroring, Rom};
+
+const PRG_RAM_SIZE: usize = 0x2000;
+
+// The IRQ counter shared by VRC4, VRC6 and VRC7. It counts up from the
+// latch and fires on overflow, either every CPU cycle or once per
+// scanline, approximated by a prescaler that ticks every 113.67 cycles.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VrcIrq {
+    pub latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    enable_after_ack: bool,
+    cycle_mode: bool,
+    pending: bool,
+}
+
+impl Default for VrcIrq {
+    fn default() -> Self {
+        VrcIrq {
+            latch: 0,
+            counter: 0,
+            prescaler: 341,
+            enabled: false,
+            enable_after_ack: false,
+            cycle_mode: false,
+            pending: false,
+        }
+    }
+}
+
+impl VrcIrq {
+    pub fn write_control(&mut self, data: u8) {
+        self.enable_after_ack = data & 0x01 != 0;
+        self.enabled = data & 0x02 != 0;
+        self.cycle_mode = data & 0x04 != 0;
+        self.pending = false;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = 341;
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enable_after_ack;
+    }
+
+    // one CPU cycle
+    pub fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if !self.cycle_mode {
+            self.prescaler -= 3;
+            if self.prescaler > 0 {
+                return;
+            }
+            self.prescaler += 341;
+        }
+        if self.counter == 0xff {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+
+    pub fn pending(&self) -> bool {
+        self.pending
+    }
+
+    pub fn save(&self, state: &mut StateWriter) {
+        state.u8(self.latch);
+        state.u8(self.counter);
+        state.u16(self.prescaler as u16);
+        state.bool(self.enabled);
+        state.bool(self.enable_after_ack);
+        state.bool(self.cycle_mode);
+        state.bool(self.pending);
+    }
+
+    pub fn load(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.latch = state.u8()?;
+        self.counter = state.u8()?;
+        self.prescaler = state.u16()? as i16;
+        self.enabled = state.bool()?;
+        self.enable_after_ack = state.bool()?;
+        self.cycle_mode = state.bool()?;
+        self.pending = state.bool()?;
+        Ok(())
+    }
+}
+
+// Register select from two address lines, boards that can't be told apart
+// without a submapper decode every candidate pair at once.
+pub fn register_index(addr: u16, lines: &[(u8, u8)]) -> u16 {
+    lines.iter().fold(0, |index, &(a0, a1)| index | (addr >> a0) & 1 | ((addr >> a1) & 1) << 1)
+}
+
+// Which chip and which address lines pick the register for a mapper 21,
+// 22, 23 or 25 board.
+fn wiring(mapper: u8, submapper: u8) -> (&'static [(u8, u8)], bool) {
+    match (mapper, submapper) {
+        // VRC4a, VRC4c
+        (21, 1) => (&[(1, 2)], false),
+        (21, 2) => (&[(6, 7)], false),
+        (21, _) => (&[(1, 2), (6, 7)], false),
+        // VRC2a
+        (22, _) => (&[(1, 0)], true),
+        // VRC4f, VRC4e, VRC2b, and VRC2b or VRC4e when it doesn't say
+        (23, 1) => (&[(0, 1)], false),
+        (23, 2) => (&[(2, 3)], false),
+        (23, 3) => (&[(0, 1)], true),
+        (23, _) => (&[(0, 1), (2, 3)], false),
+        // VRC4b, VRC4d, VRC2c
+        (25, 1) => (&[(1, 0)], false),
+        (25, 2) => (&[(3, 2)], false),
+        (25, 3) => (&[(1, 0)], true),
+        (_, _) => (&[(1, 0), (3, 2)], false),
+    }
+}
+
+// Konami VRC2 and VRC4 (mappers 21, 22, 23 and 25): Contra, Gradius II,
+// Ganbare Goemon.
+#[derive(Clone)]
+pub struct Vrc24 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    prg_ram: Vec<u8>,
+    lines: &'static [(u8, u8)],
+    vrc2: bool,
+    // mapper 23 without a submapper, $9000 is VRC2b mirroring on A0/A1
+    // and VRC4e mirroring or PRG swap on A2/A3
+    vrc2b_or_vrc4e: bool,
+    // VRC2a ignores the low bit of CHR banks
+    chr_shift: u8,
+    // VRC2 boards without RAM have a one bit latch at $6000
+    ram: bool,
+    latch: u8,
+
+    prg_banks: [u8; 2],
+    prg_swap: bool,
+    mirroring: Mirroring,
+    chr_banks: [u16; 8],
+    irq: VrcIrq,
+}
+
+impl Vrc24 {
+    pub fn new(rom: &Rom) -> Self {
+        let (chr, chr_ram) = chr_memory(rom);
+        let (lines, vrc2) = wiring(rom.mapper, rom.submapper);
+        Vrc24 {
+            prg_rom: rom.prg_rom.clone(),
+            chr,
+            chr_ram,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            lines,
+            vrc2,
+            vrc2b_or_vrc4e: rom.mapper == 23 && rom.submapper == 0,
+            chr_shift: if rom.mapper == 22 { 1 } else { 0 },
+            ram: !vrc2 || rom.battery,
+            latch: 0,
+            prg_banks: [0, 0],
+            prg_swap: false,
+            mirroring: rom.screen_mirroring,
+            chr_banks: [0; 8],
+            irq: VrcIrq::default(),
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let last = self.prg_rom.len() / 0x2000 - 1;
+        let bank = match ((addr - 0x8000) / 0x2000, self.prg_swap) {
+            (0, false) | (2, true) => self.prg_banks[0] as usize,
+            (0, true) | (2, false) => last - 1,
+            (1, _) => self.prg_banks[1] as usize,
+            _ => last,
+        };
+        bank_offset(self.prg_rom.len(), bank, 0x2000, addr)
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[addr as usize / 0x400] >> self.chr_shift;
+        bank_offset(self.chr.len(), bank as usize, 0x400, addr)
+    }
+}
+
+impl Mapper for Vrc24 {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7fff if self.ram => self.prg_ram[addr as usize - 0x6000],
+            0x6000..=0x6fff => self.latch,
+            0x8000..=0xffff => self.prg_rom[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        let index = register_index(addr, self.lines);
+        match (addr & 0xf000, index) {
+            (0x6000 | 0x7000, _) if self.ram => self.prg_ram[addr as usize - 0x6000] = data,
+            (0x6000, _) => self.latch = data & 0x01,
+            (0x8000, _) => self.prg_banks[0] = data & 0x1f,
+            // VRC2 repeats its mirroring register over $9000-$9003
+            (0x9000, _) if self.vrc2 || self.vrc2b_or_vrc4e && addr & 0x03 != 0 => {
+                self.mirroring = if data & 0x01 == 0 { Mirroring::Vertical } else { Mirroring::Horizontal };
+            }
+            (0x9000, 0 | 1) => {
+                self.mirroring = match data & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::OneScreenLower,
+                    _ => Mirroring::OneScreenUpper,
+                };
+            }
+            // bit 0 enables RAM on VRC4, left on since some games never set it
+            (0x9000, _) if !self.vrc2 => self.prg_swap = data & 0x02 != 0,
+            (0xa000, _) => self.prg_banks[1] = data & 0x1f,
+            (0xb000..=0xe000, index) => {
+                let bank = ((addr & 0xf000) - 0xb000) as usize / 0x1000 * 2 + (index as usize >> 1);
+                let value = self.chr_banks[bank];
+                self.chr_banks[bank] = if index & 1 == 0 {
+                    (value & 0x1f0) | (data & 0x0f) as u16
+                } else {
+                    let high = if self.vrc2 { data & 0x0f } else { data & 0x1f };
+                    (value & 0x0f) | (high as u16) << 4
+                };
+            }
+            (0xf000, _) if self.vrc2 => {}
+            (0xf000, 0) => self.irq.latch = (self.irq.latch & 0xf0) | (data & 0x0f),
+            (0xf000, 1) => self.irq.latch = (self.irq.latch & 0x0f) | (data << 4),
+            (0xf000, 2) => self.irq.write_control(data),
+            (0xf000, _) => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16, _fetch: Fetch, vram: &[u8; 0x800]) -> u8 {
+        let addr = addr & 0x3fff;
+        if addr < 0x2000 {
+            self.chr[self.chr_offset(addr)]
+        } else {
+            vram[mirror_vram(addr, self.mirroring)]
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8, vram: &mut [u8; 0x800]) {
+        let addr = addr & 0x3fff;
+        if addr >= 0x2000 {
+            vram[mirror_vram(addr, self.mirroring)] = data;
+        } else if self.chr_ram {
+            let offset = self.chr_offset(addr);
+            self.chr[offset] = data;
+        }
+    }
+
+    fn clock(&mut self, cycles: usize) {
+        if self.vrc2 {
+            return;
+        }
+        for _ in 0..cycles {
+            self.irq.clock();
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        state.bytes(&self.prg_ram);
+        if self.chr_ram {
+            state.bytes(&self.chr);
+        }
+        state.u8(self.latch);
+        state.bytes(&self.prg_banks);
+        state.bool(self.prg_swap);
+        state.u8(self.mirroring as u8);
+        for bank in self.chr_banks {
+            state.u16(bank);
+        }
+        self.irq.save(&mut state);
+        state.data
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut state = StateReader::new(data);
+        let mut restored = self.clone();
+        restored.prg_ram.copy_from_slice(state.bytes(PRG_RAM_SIZE)?);
+        if self.chr_ram {
+            restored.chr.copy_from_slice(state.bytes(0x2000)?);
+        }
+        restored.latch = state.u8()?;
+        restored.prg_banks.copy_from_slice(state.bytes(2)?);
+        restored.prg_swap = state.bool()?;
+        restored.mirroring = mirroring_from_u8(state.u8()?)?;
+        for bank in restored.chr_banks.iter_mut() {
+            *bank = state.u16()?;
+        }
+        restored.irq.load(&mut state)?;
+        state.finish()?;
+        *self = restored;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 8 KiB PRG banks and 1 KiB CHR banks filled with their numbers
+    fn test_rom(mapper: u8, submapper: u8) -> Rom {
+        Rom {
+            prg_rom: (0..32).flat_map(|bank| vec![bank as u8; 0x2000]).collect(),
+            chr_rom: (0..256).flat_map(|bank| vec![bank as u8; 0x400]).collect(),
+            mapper,
+            submapper,
+            screen_mirroring: Mirroring::Vertical,
+            battery: false,
+            region: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_register_decoding_per_submapper() {
+        let vram = [0; 0x800];
+        // VRC4c selects with A6/A7, VRC4a with A1/A2
+        let mut vrc4c = Vrc24::new(&test_rom(21, 2));
+        vrc4c.cpu_write(0xb000, 0x05);
+        vrc4c.cpu_write(0xb040, 0x01);
+        vrc4c.cpu_write(0xb080, 0x07);
+        assert_eq!(vrc4c.ppu_read(0x0000, Fetch::Data, &vram), 0x15);
+        assert_eq!(vrc4c.ppu_read(0x0400, Fetch::Data, &vram), 0x07);
+        let mut vrc4a = Vrc24::new(&test_rom(21, 1));
+        vrc4a.cpu_write(0xb002, 0x03);
+        assert_eq!(vrc4a.ppu_read(0x0000, Fetch::Data, &vram), 0x30);
+
+        // VRC2a swaps A0/A1 and drops the low CHR bit
+        let mut vrc2a = Vrc24::new(&test_rom(22, 0));
+        vrc2a.cpu_write(0xd000, 0x09);
+        vrc2a.cpu_write(0xd002, 0x01);
+        assert_eq!(vrc2a.ppu_read(0x1000, Fetch::Data, &vram), 0x0c);
+
+        // without a submapper both VRC2b and VRC4e wirings respond
+        let mut vrc4 = Vrc24::new(&test_rom(23, 0));
+        vrc4.cpu_write(0xb000, 0x02);
+        vrc4.cpu_write(0xb004, 0x01);
+        assert_eq!(vrc4.ppu_read(0x0000, Fetch::Data, &vram), 0x12);
+        vrc4.cpu_write(0xb002, 0x03);
+        assert_eq!(vrc4.ppu_read(0x0400, Fetch::Data, &vram), 0x03);
+    }
+
+    #[test]
+    fn test_vrc2_mirroring_register() {
+        let banks = |vrc: &Vrc24| [0x8000, 0xa000, 0xc000, 0xe000].map(|addr| vrc.cpu_read(addr));
+        for (mapper, submapper) in [(23, 3), (23, 0), (22, 0)] {
+            let mut vrc2 = Vrc24::new(&test_rom(mapper, submapper));
+            for addr in [0x9001, 0x9002, 0x9003] {
+                vrc2.cpu_write(addr, 0x01);
+                assert_eq!(vrc2.mirroring, Mirroring::Horizontal);
+                vrc2.cpu_write(addr, 0x02);
+                assert_eq!(vrc2.mirroring, Mirroring::Vertical);
+            }
+            // no PRG swap on VRC2
+            assert_eq!(banks(&vrc2), [0, 0, 30, 31]);
+        }
+
+        // VRC4e still swaps through $9008
+        let mut vrc4e = Vrc24::new(&test_rom(23, 0));
+        vrc4e.cpu_write(0x9008, 0x02);
+        assert_eq!(banks(&vrc4e), [30, 0, 0, 31]);
+        vrc4e.cpu_write(0x9004, 0x03);
+        assert_eq!(vrc4e.mirroring, Mirroring::OneScreenUpper);
+    }
+
+    #[test]
+    fn test_prg_swap_and_mirroring() {
+        let mut vrc4 = Vrc24::new(&test_rom(25, 1));
+        vrc4.cpu_write(0x8000, 3);
+        vrc4.cpu_write(0xa000, 4);
+        let banks = |vrc4: &Vrc24| [0x8000, 0xa000, 0xc000, 0xe000].map(|addr| vrc4.cpu_read(addr));
+        assert_eq!(banks(&vrc4), [3, 4, 30, 31]);
+        // VRC4b has A0 and A1 swapped, so $9001 is register 2
+        vrc4.cpu_write(0x9001, 0x02);
+        assert_eq!(banks(&vrc4), [30, 4, 3, 31]);
+
+        vrc4.cpu_write(0x9000, 0x03);
+        assert_eq!(vrc4.mirroring, Mirroring::OneScreenUpper);
+
+        let mut vrc2 = Vrc24::new(&test_rom(22, 0));
+        vrc2.cpu_write(0x6000, 0xff);
+        assert_eq!(vrc2.cpu_read(0x6000), 0x01);
+    }
+
+    #[test]
+    fn test_irq_counter() {
+        let mut irq = VrcIrq { latch: 0xfe, ..Default::default() };
+        // cycle mode: two cycles from $FE to the overflow
+        irq.write_control(0x07);
+        irq.clock();
+        assert!(!irq.pending());
+        irq.clock();
+        assert!(irq.pending());
+        irq.acknowledge();
+        assert!(!irq.pending());
+
+        // scanline mode counts every 341/3 cycles
+        irq.latch = 0xff;
+        irq.write_control(0x02);
+        for _ in 0..113 {
+            irq.clock();
+        }
+        assert!(!irq.pending());
+        irq.clock();
+        assert!(irq.pending());
+        // enabled again only if asked to be when written
+        irq.acknowledge();
+        for _ in 0..1000 {
+            irq.clock();
+        }
+        assert!(!irq.pending());
+    }
+}
@@ -0,0 +1,445 @@
+use crate::mapper::{bank_offset, chr_memory, mirror_vram, mirroring_from_u8, Fetch, Mapper, StateReader, StateWriter};
+use crate::rom::{Mirroring, Rom};
+use crate::vrc::{register_index, VrcIrq};
+
+const PRG_RAM_SIZE: usize = 0x2000;
+
+// one step of an APU pulse in the linear approximation of the mixer, the
+// VRC6 channels sit at about the same level
+pub const LEVEL: f32 = 0.00752;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Pulse {
+    volume: u8,
+    duty: u8,
+    // ignore the duty and output the volume constantly
+    digitized: bool,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    step: u8,
+}
+
+impl Pulse {
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.digitized = data & 0x80 != 0;
+                self.duty = (data >> 4) & 0x07;
+                self.volume = data & 0x0f;
+            }
+            1 => self.period = (self.period & 0x0f00) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00ff) | ((data as u16 & 0x0f) << 8);
+                self.enabled = data & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    pub fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.checked_sub(1).unwrap_or(15);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.enabled && (self.digitized || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        state.u8(self.volume);
+        state.u8(self.duty);
+        state.bool(self.digitized);
+        state.u16(self.period);
+        state.bool(self.enabled);
+        state.u16(self.timer);
+        state.u8(self.step);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.volume = state.u8()?;
+        self.duty = state.u8()?;
+        self.digitized = state.bool()?;
+        self.period = state.u16()?;
+        self.enabled = state.bool()?;
+        self.timer = state.u16()?;
+        self.step = state.u8()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sawtooth {
+    rate: u8,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+impl Sawtooth {
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => self.rate = data & 0x3f,
+            1 => self.period = (self.period & 0x0f00) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00ff) | ((data as u16 & 0x0f) << 8);
+                self.enabled = data & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    // the accumulator grows on every other timer tick and resets after seven
+    pub fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step.is_multiple_of(2) {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        state.u8(self.rate);
+        state.u16(self.period);
+        state.bool(self.enabled);
+        state.u16(self.timer);
+        state.u8(self.step);
+        state.u8(self.accumulator);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.rate = state.u8()?;
+        self.period = state.u16()?;
+        self.enabled = state.bool()?;
+        self.timer = state.u16()?;
+        self.step = state.u8()?;
+        self.accumulator = state.u8()?;
+        Ok(())
+    }
+}
+
+// Konami VRC6 (mappers 24 and 26): Akumajou Densetsu, Madara, Esper
+// Dream 2. Only CIRAM nametables are supported, not CHR ROM ones.
+#[derive(Clone)]
+pub struct Vrc6 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    prg_ram: Vec<u8>,
+    lines: &'static [(u8, u8)],
+
+    prg_16k: u8,
+    prg_8k: u8,
+    chr_banks: [u8; 8],
+    // $B003
+    control: u8,
+    mirroring: Mirroring,
+    irq: VrcIrq,
+
+    halt: bool,
+    shift: u8,
+    pulses: [Pulse; 2],
+    sawtooth: Sawtooth,
+}
+
+impl Vrc6 {
+    pub fn new(rom: &Rom) -> Self {
+        let (chr, chr_ram) = chr_memory(rom);
+        Vrc6 {
+            prg_rom: rom.prg_rom.clone(),
+            chr,
+            chr_ram,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            // mapper 26 swaps A0 and A1
+            lines: if rom.mapper == 26 { &[(1, 0)] } else { &[(0, 1)] },
+            prg_16k: 0,
+            prg_8k: 0,
+            chr_banks: [0; 8],
+            control: 0,
+            mirroring: rom.screen_mirroring,
+            irq: VrcIrq::default(),
+            halt: false,
+            shift: 0,
+            pulses: [Pulse::default(), Pulse::default()],
+            sawtooth: Sawtooth::default(),
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let len = self.prg_rom.len();
+        match addr {
+            0x8000..=0xbfff => bank_offset(len, self.prg_16k as usize, 0x4000, addr),
+            0xc000..=0xdfff => bank_offset(len, self.prg_8k as usize, 0x2000, addr),
+            _ => bank_offset(len, len / 0x2000 - 1, 0x2000, addr),
+        }
+    }
+
+    // $B003 bits 0-1: 1 KiB banks, 2 KiB banks, or 1 KiB then 2 KiB
+    fn chr_offset(&self, addr: u16) -> usize {
+        let slot = addr as usize / 0x400;
+        let (register, size) = match (self.control & 0x03, slot) {
+            (0, slot) => (slot, 0x400),
+            (1, slot) => (slot / 2, 0x800),
+            (_, 0..=3) => (slot, 0x400),
+            (_, slot) => (4 + (slot - 4) / 2, 0x800),
+        };
+        bank_offset(self.chr.len(), self.chr_banks[register] as usize, size, addr)
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.control & 0x80 != 0
+    }
+}
+
+impl Mapper for Vrc6 {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7fff if self.ram_enabled() => self.prg_ram[addr as usize - 0x6000],
+            0x8000..=0xffff => self.prg_rom[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        let index = register_index(addr, self.lines);
+        match (addr & 0xf000, index) {
+            (0x6000 | 0x7000, _) if self.ram_enabled() => self.prg_ram[addr as usize - 0x6000] = data,
+            (0x8000, _) => self.prg_16k = data & 0x0f,
+            (0x9000, 3) => {
+                self.halt = data & 0x01 != 0;
+                self.shift = if data & 0x04 != 0 {
+                    8
+                } else if data & 0x02 != 0 {
+                    4
+                } else {
+                    0
+                };
+            }
+            (0x9000, index) => self.pulses[0].write(index, data),
+            (0xa000, 3) => {}
+            (0xa000, index) => self.pulses[1].write(index, data),
+            (0xb000, 3) => {
+                self.control = data;
+                self.mirroring = match (data >> 2) & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::OneScreenLower,
+                    _ => Mirroring::OneScreenUpper,
+                };
+            }
+            (0xb000, index) => self.sawtooth.write(index, data),
+            (0xc000, _) => self.prg_8k = data & 0x1f,
+            (0xd000, index) => self.chr_banks[index as usize] = data,
+            (0xe000, index) => self.chr_banks[4 + index as usize] = data,
+            (0xf000, 0) => self.irq.latch = data,
+            (0xf000, 1) => self.irq.write_control(data),
+            (0xf000, 2) => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16, _fetch: Fetch, vram: &[u8; 0x800]) -> u8 {
+        let addr = addr & 0x3fff;
+        if addr < 0x2000 {
+            self.chr[self.chr_offset(addr)]
+        } else {
+            vram[mirror_vram(addr, self.mirroring)]
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8, vram: &mut [u8; 0x800]) {
+        let addr = addr & 0x3fff;
+        if addr >= 0x2000 {
+            vram[mirror_vram(addr, self.mirroring)] = data;
+        } else if self.chr_ram {
+            let offset = self.chr_offset(addr);
+            self.chr[offset] = data;
+        }
+    }
+
+    fn clock(&mut self, cycles: usize) {
+        for _ in 0..cycles {
+            self.irq.clock();
+            if !self.halt {
+                self.pulses[0].clock(self.shift);
+                self.pulses[1].clock(self.shift);
+                self.sawtooth.clock(self.shift);
+            }
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn audio(&self) -> f32 {
+        let total = self.pulses[0].output() + self.pulses[1].output() + self.sawtooth.output();
+        total as f32 * LEVEL
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        state.bytes(&self.prg_ram);
+        if self.chr_ram {
+            state.bytes(&self.chr);
+        }
+        state.u8(self.prg_16k);
+        state.u8(self.prg_8k);
+        state.bytes(&self.chr_banks);
+        state.u8(self.control);
+        state.u8(self.mirroring as u8);
+        self.irq.save(&mut state);
+        state.bool(self.halt);
+        state.u8(self.shift);
+        for pulse in &self.pulses {
+            pulse.save(&mut state);
+        }
+        self.sawtooth.save(&mut state);
+        state.data
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut state = StateReader::new(data);
+        let mut restored = self.clone();
+        restored.prg_ram.copy_from_slice(state.bytes(PRG_RAM_SIZE)?);
+        if self.chr_ram {
+            restored.chr.copy_from_slice(state.bytes(0x2000)?);
+        }
+        restored.prg_16k = state.u8()?;
+        restored.prg_8k = state.u8()?;
+        restored.chr_banks.copy_from_slice(state.bytes(8)?);
+        restored.control = state.u8()?;
+        restored.mirroring = mirroring_from_u8(state.u8()?)?;
+        restored.irq.load(&mut state)?;
+        restored.halt = state.bool()?;
+        restored.shift = state.u8()?;
+        for pulse in restored.pulses.iter_mut() {
+            pulse.load(&mut state)?;
+        }
+        restored.sawtooth.load(&mut state)?;
+        state.finish()?;
+        *self = restored;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_rom(mapper: u8) -> Rom {
+        Rom {
+            prg_rom: (0..16).flat_map(|bank| vec![bank as u8; 0x2000]).collect(),
+            chr_rom: (0..128).flat_map(|bank| vec![bank as u8; 0x400]).collect(),
+            mapper,
+            submapper: 0,
+            screen_mirroring: Mirroring::Vertical,
+            battery: false,
+            region: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_banking() {
+        let vram = [0; 0x800];
+        let mut vrc6 = Vrc6::new(&test_rom(26));
+        vrc6.cpu_write(0x8000, 2);
+        vrc6.cpu_write(0xc000, 9);
+        let banks = [0x8000, 0xa000, 0xc000, 0xe000].map(|addr| vrc6.cpu_read(addr));
+        assert_eq!(banks, [4, 5, 9, 15]);
+
+        // mapper 26 has A0 and A1 swapped, $D002 is CHR register 1
+        vrc6.cpu_write(0xd002, 0x21);
+        vrc6.cpu_write(0xb003, 0x84 | 0x01);
+        assert_eq!(vrc6.ppu_read(0x0800, Fetch::Data, &vram), 0x42);
+        assert_eq!(vrc6.ppu_read(0x0c00, Fetch::Data, &vram), 0x43);
+        assert_eq!(vrc6.mirroring, Mirroring::Horizontal);
+
+        vrc6.cpu_write(0x6000, 0x55);
+        assert_eq!(vrc6.cpu_read(0x6000), 0x55);
+    }
+
+    #[test]
+    fn test_audio_channels() {
+        let mut pulse = Pulse::default();
+        // duty 4 of 16, period 1
+        pulse.write(0, 0x3a);
+        pulse.write(1, 0x01);
+        pulse.write(2, 0x80);
+        let mut levels = Vec::new();
+        for _ in 0..32 {
+            pulse.clock(0);
+            levels.push(pulse.output());
+        }
+        assert_eq!(levels.iter().filter(|&&level| level == 10).count(), 8);
+
+        let mut sawtooth = Sawtooth::default();
+        sawtooth.write(0, 0x2a);
+        sawtooth.write(2, 0x80);
+        let mut peak = 0;
+        for _ in 0..14 {
+            sawtooth.clock(0);
+            peak = peak.max(sawtooth.output());
+        }
+        // six additions of 42 before the reset
+        assert_eq!(peak, (6 * 42) >> 3);
+        assert_eq!(sawtooth.output(), 0);
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut vrc6 = Vrc6::new(&test_rom(24));
+        vrc6.cpu_write(0x9000, 0x8f);
+        vrc6.cpu_write(0x9002, 0x80);
+        vrc6.cpu_write(0xf000, 0xf0);
+        vrc6.cpu_write(0xf001, 0x06);
+        vrc6.clock(100);
+        let state = vrc6.save_state();
+
+        let mut restored = Vrc6::new(&test_rom(24));
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+        assert_eq!(restored.audio(), 15.0 * LEVEL);
+        assert!(restored.irq());
+    }
+}
@@ -0,0 +1,279 @@
+use crate::mapper::{bank_offset, chr_memory, mirror_vram, mirroring_from_u8, Fetch, Mapper, StateReader, StateWriter};
+use crate::opll::{self, Opll};
+use crate::rom::{Mirroring, Rom};
+use crate::vrc::VrcIrq;
+
+const PRG_RAM_SIZE: usize = 0x2000;
+
+// Konami VRC7 (mapper 85): Lagrange Point, Tiny Toon Adventures 2.
+#[derive(Clone)]
+pub struct Vrc7 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    prg_ram: Vec<u8>,
+    // VRC7b selects registers with A3, VRC7a with A4
+    select: u16,
+
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    // $E000
+    control: u8,
+    mirroring: Mirroring,
+    irq: VrcIrq,
+
+    opll: Opll,
+    // CPU cycles towards the next OPLL sample
+    audio_cycles: usize,
+}
+
+impl Vrc7 {
+    pub fn new(rom: &Rom) -> Self {
+        let (chr, chr_ram) = chr_memory(rom);
+        Vrc7 {
+            prg_rom: rom.prg_rom.clone(),
+            chr,
+            chr_ram,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            select: match rom.submapper {
+                1 => 0x08,
+                2 => 0x10,
+                _ => 0x18,
+            },
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            control: 0,
+            mirroring: rom.screen_mirroring,
+            irq: VrcIrq::default(),
+            opll: Opll::new(),
+            audio_cycles: 0,
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let len = self.prg_rom.len();
+        let bank = match addr {
+            0x8000..=0xdfff => self.prg_banks[(addr as usize - 0x8000) / 0x2000] as usize,
+            _ => len / 0x2000 - 1,
+        };
+        bank_offset(len, bank, 0x2000, addr)
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        bank_offset(self.chr.len(), self.chr_banks[addr as usize / 0x400] as usize, 0x400, addr)
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.control & 0x80 != 0
+    }
+}
+
+impl Mapper for Vrc7 {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7fff if self.ram_enabled() => self.prg_ram[addr as usize - 0x6000],
+            0x8000..=0xffff => self.prg_rom[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        // the sound chip always decodes A4 and A5
+        match addr & 0xf030 {
+            0x9010 => {
+                self.opll.write_address(data);
+                return;
+            }
+            0x9030 => {
+                self.opll.write_data(data);
+                return;
+            }
+            _ => {}
+        }
+        let high = addr & self.select != 0;
+        match (addr & 0xf000, high) {
+            (0x6000 | 0x7000, _) if self.ram_enabled() => self.prg_ram[addr as usize - 0x6000] = data,
+            (0x8000, false) => self.prg_banks[0] = data & 0x3f,
+            (0x8000, true) => self.prg_banks[1] = data & 0x3f,
+            (0x9000, false) => self.prg_banks[2] = data & 0x3f,
+            (0xa000..=0xd000, high) => {
+                let bank = ((addr & 0xf000) - 0xa000) as usize / 0x1000 * 2 + high as usize;
+                self.chr_banks[bank] = data;
+            }
+            (0xe000, false) => {
+                // bit 6 holds the sound chip in reset
+                if data & 0x40 != 0 {
+                    self.opll = Opll::new();
+                }
+                self.control = data;
+                self.mirroring = match data & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::OneScreenLower,
+                    _ => Mirroring::OneScreenUpper,
+                };
+            }
+            (0xe000, true) => self.irq.latch = data,
+            (0xf000, false) => self.irq.write_control(data),
+            (0xf000, true) => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16, _fetch: Fetch, vram: &[u8; 0x800]) -> u8 {
+        let addr = addr & 0x3fff;
+        if addr < 0x2000 {
+            self.chr[self.chr_offset(addr)]
+        } else {
+            vram[mirror_vram(addr, self.mirroring)]
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8, vram: &mut [u8; 0x800]) {
+        let addr = addr & 0x3fff;
+        if addr >= 0x2000 {
+            vram[mirror_vram(addr, self.mirroring)] = data;
+        } else if self.chr_ram {
+            let offset = self.chr_offset(addr);
+            self.chr[offset] = data;
+        }
+    }
+
+    fn clock(&mut self, cycles: usize) {
+        for _ in 0..cycles {
+            self.irq.clock();
+        }
+        self.audio_cycles += cycles;
+        while self.audio_cycles >= opll::CLOCK_DIVIDER {
+            self.audio_cycles -= opll::CLOCK_DIVIDER;
+            if self.control & 0x40 == 0 {
+                self.opll.step();
+            }
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn audio(&self) -> f32 {
+        self.opll.output()
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        state.bytes(&self.prg_ram);
+        if self.chr_ram {
+            state.bytes(&self.chr);
+        }
+        state.bytes(&self.prg_banks);
+        state.bytes(&self.chr_banks);
+        state.u8(self.control);
+        state.u8(self.mirroring as u8);
+        self.irq.save(&mut state);
+        self.opll.save(&mut state);
+        state.u32(self.audio_cycles as u32);
+        state.data
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut state = StateReader::new(data);
+        let mut restored = self.clone();
+        restored.prg_ram.copy_from_slice(state.bytes(PRG_RAM_SIZE)?);
+        if self.chr_ram {
+            restored.chr.copy_from_slice(state.bytes(0x2000)?);
+        }
+        restored.prg_banks.copy_from_slice(state.bytes(3)?);
+        restored.chr_banks.copy_from_slice(state.bytes(8)?);
+        restored.control = state.u8()?;
+        restored.mirroring = mirroring_from_u8(state.u8()?)?;
+        restored.irq.load(&mut state)?;
+        restored.opll.load(&mut state)?;
+        restored.audio_cycles = state.u32()? as usize;
+        state.finish()?;
+        *self = restored;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_rom(submapper: u8) -> Rom {
+        Rom {
+            prg_rom: (0..16).flat_map(|bank| vec![bank as u8; 0x2000]).collect(),
+            chr_rom: Vec::new(),
+            mapper: 85,
+            submapper,
+            screen_mirroring: Mirroring::Vertical,
+            battery: true,
+            region: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_banking_per_submapper() {
+        // VRC7a selects with A4, so $8008 is still PRG bank 0
+        let mut vrc7a = Vrc7::new(&test_rom(2));
+        vrc7a.cpu_write(0x8008, 3);
+        vrc7a.cpu_write(0x8010, 4);
+        vrc7a.cpu_write(0x9000, 5);
+        let banks = [0x8000, 0xa000, 0xc000, 0xe000].map(|addr| vrc7a.cpu_read(addr));
+        assert_eq!(banks, [3, 4, 5, 15]);
+
+        let mut vrc7b = Vrc7::new(&test_rom(1));
+        vrc7b.cpu_write(0x8008, 6);
+        vrc7b.cpu_write(0xe000, 0x83);
+        assert_eq!(vrc7b.cpu_read(0xa000), 6);
+        assert_eq!(vrc7b.mirroring, Mirroring::OneScreenUpper);
+        vrc7b.cpu_write(0x7fff, 0x12);
+        assert_eq!(vrc7b.prg_ram()[0x1fff], 0x12);
+
+        // CHR RAM through bank 2 lands in the same place as bank 2 anywhere
+        let mut vram = [0; 0x800];
+        vrc7b.cpu_write(0xa008, 2);
+        vrc7b.ppu_write(0x0401, 0x99, &mut vram);
+        assert_eq!(vrc7b.chr[0x801], 0x99);
+    }
+
+    #[test]
+    fn test_irq_and_audio() {
+        let mut vrc7 = Vrc7::new(&test_rom(0));
+        vrc7.cpu_write(0xe010, 0xfd);
+        vrc7.cpu_write(0xf000, 0x06);
+        vrc7.clock(2);
+        assert!(!vrc7.irq());
+        vrc7.clock(1);
+        assert!(vrc7.irq());
+        vrc7.cpu_write(0xf010, 0);
+        assert!(!vrc7.irq());
+
+        // instrument 3 at full volume, keyed on
+        for (address, data) in [(0x30, 0x30), (0x10, 0x80), (0x20, 0x18)] {
+            vrc7.cpu_write(0x9010, address);
+            vrc7.cpu_write(0x9030, data);
+        }
+        assert_eq!(vrc7.opll.register(0x10), 0x80);
+        vrc7.clock(opll::CLOCK_DIVIDER * 100);
+        assert_ne!(vrc7.audio(), 0.0);
+
+        let state = vrc7.save_state();
+        let mut restored = Vrc7::new(&test_rom(0));
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+
+        // and the reset bit silences it
+        vrc7.cpu_write(0xe000, 0x40);
+        vrc7.clock(opll::CLOCK_DIVIDER);
+        assert_eq!(vrc7.audio(), 0.0);
+    }
+}
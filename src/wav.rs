@@ -0,0 +1,239 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+// APU channel outputs in the order they are mixed, with their DAC range
+pub const CHANNELS: [(&str, u8); 5] = [("pulse1", 15), ("pulse2", 15), ("triangle", 15), ("noise", 15), ("dmc", 127)];
+
+// 16-bit mono PCM, the sizes in the header are patched by `finish`
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    samples: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32) -> io::Result<Self> {
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        // PCM, one channel
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * 2).to_le_bytes())?;
+        // block align, bits per sample
+        out.write_all(&2u16.to_le_bytes())?;
+        out.write_all(&16u16.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter { out, samples: 0 })
+    }
+
+    pub fn write_sample(&mut self, sample: i16) -> io::Result<()> {
+        self.samples += 1;
+        self.out.write_all(&sample.to_le_bytes())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        let data = self.samples * 2;
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(36 + data).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&data.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+// Nonlinear DAC of the 2A03, 0.0 to about 1.0
+pub fn mix(levels: &[u8; 5]) -> f32 {
+    let [pulse1, pulse2, triangle, noise, dmc] = levels.map(|level| level as f32);
+    let pulse = if pulse1 + pulse2 == 0.0 {
+        0.0
+    } else {
+        95.88 / (8128.0 / (pulse1 + pulse2) + 100.0)
+    };
+    let tnd = triangle / 8227.0 + noise / 12241.0 + dmc / 22638.0;
+    let tnd = if tnd == 0.0 { 0.0 } else { 159.79 / (1.0 / tnd + 100.0) };
+    pulse + tnd
+}
+
+// expansion audio like the VRC7's swings below zero
+fn to_sample(value: f32) -> i16 {
+    (value.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WavOptions {
+    pub path: PathBuf,
+    pub sample_rate: u32,
+    // also write <name>.pulse1.wav and so on next to the mix
+    pub channels: bool,
+}
+
+// There is no APU yet, callers feed silent channel levels and only the
+// cartridge's expansion audio is heard, so they refuse per-channel files
+pub const NO_APU_CHANNELS: &str = "per-channel recording needs the APU, which isn't emulated yet";
+
+// game.wav -> game.triangle.wav
+pub fn channel_path(path: &Path, channel: &str) -> PathBuf {
+    let stem = path.file_stem().map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+    path.with_file_name(format!("{}.{}.wav", stem, channel))
+}
+
+// Takes the channel levels once per CPU cycle and averages them down to
+// the output sample rate.
+pub struct Recorder {
+    clock_rate: u32,
+    sample_rate: u32,
+    phase: u32,
+    count: u32,
+    mixed_sum: f32,
+    channel_sums: [f32; 5],
+    mixed: WavWriter<BufWriter<File>>,
+    channels: Vec<WavWriter<BufWriter<File>>>,
+}
+
+fn create(path: &Path, sample_rate: u32) -> io::Result<WavWriter<BufWriter<File>>> {
+    WavWriter::new(BufWriter::new(File::create(path)?), sample_rate)
+}
+
+impl Recorder {
+    pub fn create(options: &WavOptions, clock_rate: u32) -> io::Result<Self> {
+        if options.sample_rate == 0 || options.sample_rate > clock_rate {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("sample rate must be between 1 and {}", clock_rate),
+            ));
+        }
+        let channels = if options.channels {
+            CHANNELS
+                .iter()
+                .map(|(name, _)| create(&channel_path(&options.path, name), options.sample_rate))
+                .collect::<io::Result<_>>()?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Recorder {
+            clock_rate,
+            sample_rate: options.sample_rate,
+            phase: 0,
+            count: 0,
+            mixed_sum: 0.0,
+            channel_sums: [0.0; 5],
+            mixed: create(&options.path, options.sample_rate)?,
+            channels,
+        })
+    }
+
+    pub fn clock(&mut self, levels: &[u8; 5], expansion: f32) -> io::Result<()> {
+        self.advance(1, levels, expansion)
+    }
+
+    // the same levels for `cycles` CPU cycles, `expansion` is cartridge audio
+    // already on the mix scale and only goes into the mixed file
+    pub fn advance(&mut self, cycles: usize, levels: &[u8; 5], expansion: f32) -> io::Result<()> {
+        let mixed = mix(levels) + expansion;
+        for _ in 0..cycles {
+            self.count += 1;
+            self.mixed_sum += mixed;
+            for (sum, (level, (_, max))) in self.channel_sums.iter_mut().zip(levels.iter().zip(CHANNELS)) {
+                *sum += *level as f32 / max as f32;
+            }
+
+            self.phase += self.sample_rate;
+            if self.phase >= self.clock_rate {
+                self.phase -= self.clock_rate;
+                self.emit()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self) -> io::Result<()> {
+        let count = self.count as f32;
+        self.mixed.write_sample(to_sample(self.mixed_sum / count))?;
+        for (writer, sum) in self.channels.iter_mut().zip(self.channel_sums) {
+            writer.write_sample(to_sample(sum / count))?;
+        }
+        self.count = 0;
+        self.mixed_sum = 0.0;
+        self.channel_sums = [0.0; 5];
+        Ok(())
+    }
+
+    pub fn samples(&self) -> u32 {
+        self.mixed.samples
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.mixed.finish()?;
+        for writer in self.channels {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_header_sizes() {
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), 8000).unwrap();
+        for sample in [0, 1000, -1000] {
+            writer.write_sample(sample).unwrap();
+        }
+        let bytes = writer.finish().unwrap().into_inner();
+
+        assert_eq!(bytes.len(), 44 + 6);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()), 42);
+        assert_eq!(u32::from_le_bytes(bytes[24..28].try_into().unwrap()), 8000);
+        assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), 6);
+        assert_eq!(i16::from_le_bytes([bytes[46], bytes[47]]), 1000);
+    }
+
+    #[test]
+    fn test_mix() {
+        assert_eq!(mix(&[0; 5]), 0.0);
+        assert!((mix(&[15, 15, 0, 0, 0]) - 0.2588).abs() < 0.001);
+        assert!((mix(&[15, 15, 15, 15, 127]) - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_recorder_resamples_and_splits_channels() {
+        let dir = env::temp_dir().join(format!("nes-wav-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let options = WavOptions {
+            path: dir.join("out.wav"),
+            sample_rate: 1000,
+            channels: true,
+        };
+
+        let mut recorder = Recorder::create(&options, 10_000).unwrap();
+        recorder.advance(50, &[0, 0, 15, 0, 0], 0.0).unwrap();
+        recorder.advance(50, &[0; 5], 0.0).unwrap();
+        assert_eq!(recorder.samples(), 10);
+        recorder.finish().unwrap();
+
+        let mixed = fs::read(&options.path).unwrap();
+        assert_eq!(mixed.len(), 44 + 20);
+        let triangle = fs::read(channel_path(&options.path, "triangle")).unwrap();
+        assert_eq!(i16::from_le_bytes([triangle[44], triangle[45]]), i16::MAX);
+        assert_eq!(i16::from_le_bytes([triangle[62], triangle[63]]), 0);
+        let noise = fs::read(dir.join("out.noise.wav")).unwrap();
+        assert!(noise[44..].iter().all(|byte| *byte == 0));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
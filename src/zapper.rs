@@ -0,0 +1,156 @@
+use crate::frame::{pixel_index, Frame};
+use crate::mapper::{StateReader, StateWriter};
+use crate::palette::SYSTEM_PALETTE;
+
+// pixels around the aim point the photodiode sees
+const SENSE_RADIUS: i32 = 2;
+// how long a drawn pixel keeps glowing bright enough, in scanlines
+const GLOW_SCANLINES: i32 = 20;
+// average of R, G and B a pixel needs to trip the sensor
+const BRIGHTNESS: u32 = 85;
+
+// bits on $4017
+const NO_LIGHT: u8 = 0b0000_1000;
+const TRIGGER: u8 = 0b0001_0000;
+
+// Where the PPU is drawing: scanlines 0-239 are visible, dots 1-256 draw
+// pixels 0-255.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Beam {
+    pub scanline: usize,
+    pub dot: usize,
+}
+
+// NES Zapper on controller port 2. It sees light only when the pixels
+// around where it points are bright and the beam went over them moments
+// ago, which is how games tell a hit from a bright TV. Both come from
+// CPU::frame and CPU::beam, which only a PPU would keep up to date; until
+// there is one nothing fills them in and the Zapper stays dark.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Zapper {
+    // None while pointing away from the screen
+    aim: Option<(i32, i32)>,
+    trigger: bool,
+}
+
+impl Zapper {
+    pub fn new() -> Self {
+        Zapper { aim: None, trigger: false }
+    }
+
+    // screen coordinates, off-screen ones point away
+    pub fn aim(&mut self, x: i32, y: i32) {
+        let on_screen = (0..Frame::WIDTH as i32).contains(&x) && (0..Frame::HEIGHT as i32).contains(&y);
+        self.aim = on_screen.then_some((x, y));
+    }
+
+    pub fn aim_off_screen(&mut self) {
+        self.aim = None;
+    }
+
+    pub fn set_trigger(&mut self, pulled: bool) {
+        self.trigger = pulled;
+    }
+
+    pub fn save(&self, state: &mut StateWriter) {
+        state.bool(self.aim.is_some());
+        let (x, y) = self.aim.unwrap_or_default();
+        state.u16(x as u16);
+        state.u16(y as u16);
+        state.bool(self.trigger);
+    }
+
+    pub fn load(&mut self, state: &mut StateReader) -> Result<(), String> {
+        let on_screen = state.bool()?;
+        let (x, y) = (state.u16()? as i32, state.u16()? as i32);
+        self.aim_off_screen();
+        if on_screen {
+            self.aim(x, y);
+        }
+        self.trigger = state.bool()?;
+        Ok(())
+    }
+
+    pub fn light(&self, frame: &Frame, beam: Beam) -> bool {
+        let Some((x, y)) = self.aim else {
+            return false;
+        };
+        let (scanline, dot) = (beam.scanline as i32, beam.dot as i32);
+        for py in (y - SENSE_RADIUS).max(0)..=(y + SENSE_RADIUS).min(Frame::HEIGHT as i32 - 1) {
+            // not drawn yet this frame, or faded already
+            if scanline < py || scanline - py > GLOW_SCANLINES {
+                continue;
+            }
+            for px in (x - SENSE_RADIUS).max(0)..=(x + SENSE_RADIUS).min(Frame::WIDTH as i32 - 1) {
+                if scanline == py && dot <= px {
+                    continue;
+                }
+                let (r, g, b) = SYSTEM_PALETTE[pixel_index(frame.pixel(px as usize, py as usize)) as usize];
+                if (r as u32 + g as u32 + b as u32) / 3 >= BRIGHTNESS {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    // bit 3 is low while light is seen, bit 4 high while the trigger is
+    // pulled
+    pub fn read(&self, frame: &Frame, beam: Beam) -> u8 {
+        let mut data = if self.light(frame, beam) { 0 } else { NO_LIGHT };
+        if self.trigger {
+            data |= TRIGGER;
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // white is $30, black $0F
+    fn target_frame() -> Frame {
+        let mut frame = Frame::new();
+        for pixel in frame.data.iter_mut() {
+            *pixel = 0x0f;
+        }
+        for y in 100..110 {
+            for x in 50..60 {
+                frame.set_pixel(x, y, 0x30);
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn test_light_follows_the_beam() {
+        let frame = target_frame();
+        let mut zapper = Zapper::new();
+        zapper.aim(55, 105);
+
+        let at = |scanline, dot| Beam { scanline, dot };
+        // the target isn't drawn yet
+        assert_eq!(zapper.read(&frame, at(50, 0)), NO_LIGHT);
+        assert_eq!(zapper.read(&frame, at(103, 0)), NO_LIGHT);
+        assert_eq!(zapper.read(&frame, at(103, 60)), 0);
+        assert_eq!(zapper.read(&frame, at(120, 0)), 0);
+        // long gone
+        assert_eq!(zapper.read(&frame, at(200, 0)), NO_LIGHT);
+    }
+
+    #[test]
+    fn test_dark_pixels_and_aiming_away() {
+        let frame = target_frame();
+        let mut zapper = Zapper::new();
+        zapper.aim(150, 105);
+        assert_eq!(zapper.read(&frame, Beam { scanline: 110, dot: 0 }), NO_LIGHT);
+        // the edge of the target is within the sensor's reach
+        zapper.aim(61, 105);
+        assert_eq!(zapper.read(&frame, Beam { scanline: 110, dot: 0 }), 0);
+
+        zapper.aim(-5, 105);
+        zapper.set_trigger(true);
+        assert_eq!(zapper.read(&frame, Beam { scanline: 110, dot: 0 }), NO_LIGHT | TRIGGER);
+    }
+}
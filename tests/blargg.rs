@@ -0,0 +1,115 @@
+// Runs blargg's accuracy test ROMs. The ROMs are not part of the
+// repository, so these are ignored by default: point NES_TEST_ROMS at a
+// checkout of the nes-test-roms collection (defaults to ./test_roms) and
+// run `cargo test --test blargg -- --ignored instr_test cpu_timing_test`.
+// Missing ROMs fail the suite. The PPU and APU suites are here for when
+// those exist and fail until then.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use nes_rust_project::blargg::{self, Verdict};
+
+// most ROMs finish well within this, timing tests take the longest
+const MAX_FRAMES: usize = 60 * 60;
+
+fn roms_dir() -> PathBuf {
+    env::var_os("NES_TEST_ROMS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_roms"))
+}
+
+fn run_suite(suite: &str, roms: &[&str]) {
+    let dir = roms_dir().join(suite);
+    let mut failures = Vec::new();
+
+    for name in roms {
+        let path = dir.join(name);
+        let raw = match fs::read(&path) {
+            Ok(raw) => raw,
+            Err(e) => {
+                failures.push(format!("{}: {}", path.display(), e));
+                continue;
+            }
+        };
+
+        match blargg::run_rom(&raw, MAX_FRAMES) {
+            Ok(result) if result.verdict == Verdict::Passed => {}
+            Ok(result) => failures.push(format!("{}: {:?} {}", name, result.verdict, result.message.trim())),
+            Err(e) => failures.push(format!("{}: {}", name, e)),
+        }
+    }
+
+    assert!(failures.is_empty(), "{} failures:\n{}", suite, failures.join("\n"));
+}
+
+#[test]
+#[ignore = "needs the nes-test-roms collection in NES_TEST_ROMS"]
+fn instr_test() {
+    run_suite(
+        "instr_test-v5/rom_singles",
+        &[
+            "01-basics.nes",
+            "02-implied.nes",
+            "03-immediate.nes",
+            "04-zero_page.nes",
+            "05-zp_xy.nes",
+            "06-absolute.nes",
+            "07-abs_xy.nes",
+            "08-ind_x.nes",
+            "09-ind_y.nes",
+            "10-branches.nes",
+            "11-stack.nes",
+            "12-jmp_jsr.nes",
+            "13-rts.nes",
+            "14-rti.nes",
+            "15-brk.nes",
+            "16-special.nes",
+        ],
+    );
+}
+
+#[test]
+#[ignore = "needs the nes-test-roms collection in NES_TEST_ROMS"]
+fn cpu_timing_test() {
+    run_suite("cpu_timing_test6", &["cpu_timing_test.nes"]);
+}
+
+#[test]
+#[ignore = "there is no PPU yet, this fails until there is"]
+fn ppu_vbl_nmi() {
+    run_suite(
+        "ppu_vbl_nmi/rom_singles",
+        &[
+            "01-vbl_basics.nes",
+            "02-vbl_set_time.nes",
+            "03-vbl_clear_time.nes",
+            "04-nmi_control.nes",
+            "05-nmi_timing.nes",
+            "06-suppression.nes",
+            "07-nmi_on_timing.nes",
+            "08-nmi_off_timing.nes",
+            "09-even_odd_frames.nes",
+            "10-even_odd_timing.nes",
+        ],
+    );
+}
+
+#[test]
+#[ignore = "there is no APU yet, this fails until there is"]
+fn apu_test() {
+    run_suite(
+        "apu_test/rom_singles",
+        &[
+            "1-len_ctr.nes",
+            "2-len_table.nes",
+            "3-irq_flag.nes",
+            "4-jitter.nes",
+            "5-len_timing.nes",
+            "6-irq_flag_timing.nes",
+            "7-dmc_basics.nes",
+            "8-dmc_rates.nes",
+        ],
+    );
+}